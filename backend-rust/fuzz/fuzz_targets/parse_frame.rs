@@ -0,0 +1,61 @@
+//! Feeds arbitrary bytes to `handle_frame` as if they arrived in a text or binary
+//! frame. Every input must produce a JSON reply, a binary slice for a session that
+//! negotiated one, or be handed to a background task, all without panicking.
+//!
+//! Run from `backend-rust/` with `cargo +nightly fuzz run parse_frame`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sheets_ws_server::{
+    binary, config::Config, handle_frame, outbound, session::SessionState, source::SyntheticSource,
+    AppState,
+};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::runtime::Runtime;
+
+struct Harness {
+    runtime: Runtime,
+    state: Arc<AppState>,
+    session: Mutex<SessionState>,
+}
+
+fn harness() -> &'static Harness {
+    static HARNESS: OnceLock<Harness> = OnceLock::new();
+    HARNESS.get_or_init(|| {
+        let runtime = Runtime::new().expect("tokio runtime");
+        // A small table keeps background scans cheap.
+        let source = SyntheticSource {
+            rows: 1_000,
+            cols: 50,
+            seed: None,
+            columns: Vec::new(),
+            sparsity: 0.0,
+        };
+        let state = Arc::new(AppState::new(Config::default(), Box::new(source)));
+        let session = {
+            let _guard = runtime.enter();
+            let (outbound, _writer) = outbound::spawn(futures_util::sink::drain());
+            SessionState::new(0, outbound)
+        };
+        Harness {
+            runtime,
+            state,
+            session: Mutex::new(session),
+        }
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let harness = harness();
+    let _guard = harness.runtime.enter();
+    let mut session = harness.session.lock().unwrap();
+    if let Some(reply) = handle_frame(&harness.state, &mut session, data) {
+        let reply = reply.into_data();
+        if reply.starts_with(binary::MAGIC) {
+            return;
+        }
+        let reply: serde_json::Value = serde_json::from_slice(&reply).expect("reply is JSON");
+        assert!(reply.get("type").and_then(|t| t.as_str()).is_some());
+    }
+});
@@ -0,0 +1,203 @@
+//! Compact binary slices, for clients that advertise the `binary` capability.
+//!
+//! Integers are little-endian; a string is a `u32` byte length followed by
+//! UTF-8. In order:
+//!
+//! | field       | encoding                                        |
+//! |-------------|-------------------------------------------------|
+//! | magic       | the 4 bytes [`MAGIC`]                           |
+//! | startRow    | `u64`                                           |
+//! | rowCount    | `u32`                                           |
+//! | startCol    | `u32`                                           |
+//! | colCount    | `u32`                                           |
+//! | clamped     | `u8`, 0 or 1                                    |
+//! | colLetters  | `colCount` strings                              |
+//! | rowIds      | `rowCount` `u64`s                               |
+//! | cellsByRow  | `rowCount * colCount` strings, row by row       |
+//! | merges      | `u32` count, then `u64 u32 u32 u32` per merge   |
+//! | etag        | string                                          |
+//!
+//! Blank cells are empty strings whether or not the request was sparse.
+//! Styles, sort keys, neighbor hints, dirty cells, originals, nulls, pinned
+//! columns, line counts, column orientation and the view generation are not
+//! carried, and every field above always is, so slices with any of those, with
+//! `fields` or with `viewGeneration` are answered in JSON.
+//!
+//! Clients may send a `cell_update_batch` the same way, for large pastes:
+//!
+//! | field       | encoding                                        |
+//! |-------------|-------------------------------------------------|
+//! | magic       | the 4 bytes [`UPDATE_BATCH_MAGIC`]              |
+//! | row         | `u64`                                           |
+//! | col         | `u32`                                           |
+//! | rowCount    | `u32`                                           |
+//! | colCount    | `u32`                                           |
+//! | cells       | `rowCount * colCount` strings, row by row       |
+
+use crate::protocol::{Cells, SliceResponse, UpdateBatch};
+
+pub const MAGIC: &[u8; 4] = b"BRT1";
+pub const UPDATE_BATCH_MAGIC: &[u8; 4] = b"BRU1";
+
+pub fn encode_slice(slice: &SliceResponse) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&slice.start_row.to_le_bytes());
+    out.extend_from_slice(&slice.row_count.to_le_bytes());
+    out.extend_from_slice(&slice.start_col.to_le_bytes());
+    out.extend_from_slice(&slice.col_count.to_le_bytes());
+    out.push(slice.clamped as u8);
+    for letters in slice.col_letters.iter().flatten() {
+        put_str(&mut out, letters);
+    }
+    for row in slice.row_ids.iter().flatten() {
+        out.extend_from_slice(&row.to_le_bytes());
+    }
+    match &slice.cells_by_row {
+        Some(Cells::Dense(rows)) => {
+            for cell in rows.iter().flatten() {
+                put_str(&mut out, cell);
+            }
+        }
+        // Column slices are sent as JSON; keep the frame well formed anyway.
+        None => {
+            for _ in 0..slice.row_count as u64 * slice.col_count as u64 {
+                put_str(&mut out, "");
+            }
+        }
+        Some(Cells::Sparse(rows)) => {
+            for row in rows {
+                for c in 0..slice.col_count as usize {
+                    let cell = row.as_ref().and_then(|row| row.get(c)?.as_deref());
+                    put_str(&mut out, cell.unwrap_or(""));
+                }
+            }
+        }
+        Some(Cells::Nullable(rows)) => {
+            for cell in rows.iter().flatten() {
+                put_str(&mut out, cell.as_deref().unwrap_or(""));
+            }
+        }
+    }
+    out.extend_from_slice(&(slice.merges.len() as u32).to_le_bytes());
+    for &(row, col, rows, cols) in &slice.merges {
+        out.extend_from_slice(&row.to_le_bytes());
+        out.extend_from_slice(&col.to_le_bytes());
+        out.extend_from_slice(&rows.to_le_bytes());
+        out.extend_from_slice(&cols.to_le_bytes());
+    }
+    put_str(&mut out, &slice.etag);
+    out
+}
+
+/// Encodes a batch whose rows are all as wide as the first.
+pub fn encode_update_batch(batch: &UpdateBatch) -> Vec<u8> {
+    let cols = batch.cells.first().map_or(0, Vec::len);
+    let mut out = Vec::new();
+    out.extend_from_slice(UPDATE_BATCH_MAGIC);
+    out.extend_from_slice(&batch.row.to_le_bytes());
+    out.extend_from_slice(&batch.col.to_le_bytes());
+    out.extend_from_slice(&(batch.cells.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(cols as u32).to_le_bytes());
+    for cell in batch.cells.iter().flatten() {
+        put_str(&mut out, cell);
+    }
+    out
+}
+
+/// Reads a frame written by [`encode_update_batch`]. Short, overlong or
+/// otherwise malformed input is an error, never a panic, and nothing is
+/// allocated for counts the frame is too short to hold.
+pub fn decode_update_batch(bytes: &[u8]) -> Result<UpdateBatch, String> {
+    let mut reader = match bytes.strip_prefix(UPDATE_BATCH_MAGIC) {
+        Some(rest) => Reader(rest),
+        None => return Err("missing update batch magic".to_string()),
+    };
+    let row = u64::from_le_bytes(reader.take()?);
+    let col = u32::from_le_bytes(reader.take()?);
+    let rows = u32::from_le_bytes(reader.take()?) as usize;
+    let cols = u32::from_le_bytes(reader.take()?) as usize;
+    // Every cell takes at least its 4 length bytes.
+    let count = rows.checked_mul(cols).filter(|&count| count <= reader.0.len() / 4);
+    if count.is_none() || (cols == 0 && rows > 0) {
+        return Err(format!("{} x {} cells do not fit in the frame", rows, cols));
+    }
+    let mut cells = Vec::with_capacity(rows);
+    for _ in 0..rows {
+        let row: Result<Vec<String>, String> = (0..cols).map(|_| reader.string()).collect();
+        cells.push(row?);
+    }
+    if !reader.0.is_empty() {
+        return Err(format!("{} bytes left over after the cells", reader.0.len()));
+    }
+    Ok(UpdateBatch { row, col, cells })
+}
+
+/// The unread rest of a frame.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        if self.0.len() < N {
+            return Err("the frame ends early".to_string());
+        }
+        let (head, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = u32::from_le_bytes(self.take()?) as usize;
+        if self.0.len() < len {
+            return Err("the frame ends early".to_string());
+        }
+        let (text, rest) = self.0.split_at(len);
+        self.0 = rest;
+        String::from_utf8(text.to_vec()).map_err(|_| "a cell is not UTF-8".to_string())
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch() -> UpdateBatch {
+        let long = "x".repeat(300);
+        let cells = [["a", "", "ünï"], ["line\nbreak", "4", long.as_str()]];
+        UpdateBatch {
+            row: u64::MAX - 1,
+            col: 7,
+            cells: cells.iter().map(|row| row.map(String::from).to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn an_update_batch_survives_encoding_and_decoding() {
+        let decoded = decode_update_batch(&encode_update_batch(&batch())).unwrap();
+        assert_eq!((decoded.row, decoded.col), (u64::MAX - 1, 7));
+        assert_eq!(decoded.cells, batch().cells);
+    }
+
+    #[test]
+    fn a_truncated_or_padded_update_batch_is_an_error() {
+        let bytes = encode_update_batch(&batch());
+        for len in 0..bytes.len() {
+            assert!(decode_update_batch(&bytes[..len]).is_err(), "cut at {}", len);
+        }
+        let mut padded = bytes.clone();
+        padded.push(0);
+        let err = decode_update_batch(&padded).err().unwrap();
+        assert_eq!(err, "1 bytes left over after the cells");
+        // Counts far larger than the frame are refused before allocating.
+        let mut huge = bytes[..16].to_vec();
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        huge.extend_from_slice(&u32::MAX.to_le_bytes());
+        let err = decode_update_batch(&huge).err().unwrap();
+        assert_eq!(err, "4294967295 x 4294967295 cells do not fit in the frame");
+    }
+}
@@ -0,0 +1,160 @@
+//! A bounded cache of source cell values, for sources where `cell` is costly.
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+type Key = (u64, u32);
+
+/// Rough bytes an entry costs beyond its text: the key, tick, both map slots
+/// and the string header.
+const ENTRY_OVERHEAD: u64 = 80;
+
+fn entry_bytes(value: &Option<String>) -> u64 {
+    ENTRY_OVERHEAD + value.as_ref().map_or(0, |value| value.len() as u64)
+}
+
+/// Least-recently-used cache of `DataSource::cell` results, shared by every
+/// connection. An edited cell's entry is dropped, since the override map
+/// answers for it from then on; a reset clears everything.
+///
+/// Besides the entry count the cache can be held to a byte limit, which
+/// `--max-memory-mb` lowers as edits take up more of the budget.
+pub struct CellCache {
+    capacity: usize,
+    byte_limit: AtomicU64,
+    inner: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lru {
+    /// Value and the tick it was last used at.
+    entries: HashMap<Key, (Option<String>, u64)>,
+    /// Keys by last use, oldest first.
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    /// Estimated size of every entry.
+    bytes: u64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+    pub capacity: u64,
+    /// Estimated size of the cached entries.
+    pub bytes: u64,
+}
+
+impl CellCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            byte_limit: AtomicU64::new(u64::MAX),
+            inner: Mutex::default(),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+        }
+    }
+
+    /// The cached value for `key`, or `load`'s result, which is then cached.
+    pub fn get_or_load(&self, key: Key, load: impl FnOnce() -> Option<String>) -> Option<String> {
+        if let Some(value) = self.inner.lock().unwrap().get(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return value;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // Loaded without the lock so a slow source does not stall other readers.
+        let value = load();
+        let byte_limit = self.byte_limit.load(Ordering::Relaxed);
+        self.inner
+            .lock()
+            .unwrap()
+            .insert(key, value.clone(), self.capacity, byte_limit);
+        value
+    }
+
+    /// Caps the cache's estimated size, evicting the oldest entries now if it
+    /// is over.
+    pub fn set_byte_limit(&self, limit: u64) {
+        self.byte_limit.store(limit, Ordering::Relaxed);
+        self.inner.lock().unwrap().shrink_to(limit);
+    }
+
+    pub fn invalidate(&self, key: Key) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        *self.inner.lock().unwrap() = Lru::default();
+    }
+
+    pub fn counters(&self) -> CacheCounters {
+        let inner = self.inner.lock().unwrap();
+        CacheCounters {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: inner.entries.len() as u64,
+            capacity: self.capacity as u64,
+            bytes: inner.bytes,
+        }
+    }
+}
+
+impl Lru {
+    fn get(&mut self, key: Key) -> Option<Option<String>> {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(&key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: Key, value: Option<String>, capacity: usize, byte_limit: u64) {
+        self.remove(key);
+        let size = entry_bytes(&value);
+        if size > byte_limit {
+            return;
+        }
+        while self.entries.len() >= capacity || self.bytes + size > byte_limit {
+            if !self.evict_oldest() {
+                return;
+            }
+        }
+        self.tick += 1;
+        self.bytes += size;
+        self.entries.insert(key, (value, self.tick));
+        self.order.insert(self.tick, key);
+    }
+
+    fn shrink_to(&mut self, byte_limit: u64) {
+        while self.bytes > byte_limit && self.evict_oldest() {}
+    }
+
+    /// `false` when there was nothing to evict.
+    fn evict_oldest(&mut self) -> bool {
+        match self.order.pop_first() {
+            Some((_, oldest)) => {
+                if let Some((value, _)) = self.entries.remove(&oldest) {
+                    self.bytes -= entry_bytes(&value);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, key: Key) {
+        if let Some((value, used)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+            self.bytes -= entry_bytes(&value);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Gzipped replies, for clients that advertise the `gzip` capability.
+//!
+//! Once it is negotiated, every text or binary message goes out as a binary
+//! frame: the 4 bytes [`MAGIC`], a codec byte, then the message itself (its
+//! JSON text, or a binary slice starting with [`crate::binary::MAGIC`]),
+//! gzipped when the codec is [`GZIP`]. Messages shorter than
+//! `--compress-min-bytes` keep codec [`NONE`], as compressing them would cost
+//! more than it saves.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+pub const MAGIC: &[u8; 4] = b"BRTZ";
+
+/// The codec byte of a payload sent as is.
+pub const NONE: u8 = 0;
+/// The codec byte of a gzipped payload.
+pub const GZIP: u8 = 1;
+
+/// Frames `payload`, gzipping it if it is at least `min_bytes` long.
+pub fn wrap(payload: &[u8], min_bytes: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MAGIC.len() + 1 + payload.len());
+    out.extend_from_slice(MAGIC);
+    if payload.len() < min_bytes {
+        out.push(NONE);
+        out.extend_from_slice(payload);
+        return out;
+    }
+    out.push(GZIP);
+    let mut encoder = GzEncoder::new(out, Compression::fast());
+    encoder.write_all(payload).unwrap();
+    encoder.finish().unwrap()
+}
@@ -0,0 +1,548 @@
+//! Startup options taken from the command line.
+
+use crate::demo::{self, DemoStep};
+use crate::formula::Formula;
+use crate::outbound::FlushPolicy;
+use crate::source::SyntheticColumnSpec;
+use std::time::Duration;
+
+/// Settings taken at startup. Those in [`Settings`] can be changed later by
+/// reloading `--config-file`; the rest are fixed for the life of the process.
+#[derive(Debug)]
+pub struct Config {
+    /// Flags read from this file, one per line, before the command line's
+    /// own, so the command line wins. `SIGHUP` reads it again.
+    pub config_file: Option<String>,
+    /// What `config_file` held at startup.
+    pub file_flags: Vec<ConfigFlag>,
+    /// The command line, kept for laying over the file again on reload.
+    pub args: Vec<String>,
+    /// A JSON 2D array of strings to serve instead of the synthetic table.
+    pub inline_data: Option<String>,
+    /// A CSV file, optionally gzipped, to serve instead of the synthetic table.
+    pub csv_path: Option<String>,
+    pub csv: CsvOptions,
+    /// An Excel workbook to serve a worksheet of instead of the synthetic table.
+    pub xlsx_path: Option<String>,
+    /// The `--xlsx` worksheet to serve; the first one when unset.
+    pub sheet: Option<String>,
+    /// Every worksheet of the `--xlsx` workbook, filled in when it is opened
+    /// and listed in metadata.
+    pub sheets: Vec<String>,
+    /// A Parquet file to serve instead of the synthetic table.
+    pub parquet_path: Option<String>,
+    /// Serve the `--csv` file while it is still loading, with an approximate
+    /// row count until it is done.
+    pub csv_background: bool,
+    /// Most cells a single slice may carry, whatever its shape.
+    pub max_cells_per_slice: u64,
+    /// Largest `verticalBuffer` or `horizontalBuffer` honoured; bigger ones
+    /// are clamped to it.
+    pub max_buffer: u32,
+    /// Longest a slice may wait on the data source before the client is sent
+    /// `source_timeout`. Slices are then built off the connection's task, so
+    /// replies to later messages can overtake them.
+    pub source_timeout: Option<Duration>,
+    /// Reject every message that would change data.
+    pub read_only: bool,
+    /// Column header names; columns past the end fall back to letters.
+    pub headers: Vec<String>,
+    /// Shown for cells the source has no value for, e.g. past the end of a short row.
+    pub missing_value: String,
+    /// Computed columns, at most one per column. Formulas read only plain
+    /// columns, never each other.
+    pub formulas: Vec<Formula>,
+    /// Steps of `--demo-script`, in the order they run.
+    pub demo_script: Vec<DemoStep>,
+    /// Most column filters one session may have at once.
+    pub max_filters: usize,
+    /// Most keys one `sort_request` may sort by.
+    pub max_sort_keys: usize,
+    /// Background operations (scans, exports, view rebuilds) one connection
+    /// may have running at once.
+    pub max_inflight: usize,
+    /// Most values a `distinct_values_request` returns.
+    pub distinct_values_cap: usize,
+    /// Most rows a scan (stats, distinct values, view rebuilds) reads before
+    /// it reports `budgetExhausted` and answers from what it has.
+    pub scan_budget: u64,
+    /// Fill the synthetic table with varied values derived from this seed.
+    pub seed: Option<u64>,
+    /// The value types seeded columns cycle through, from
+    /// `--synthetic-columns currency,date,boolean`.
+    pub synthetic_columns: Vec<SyntheticColumnSpec>,
+    /// Fraction of synthetic cells, from 0 to 1, left blank.
+    pub sparsity: f64,
+    /// Source cells kept in the shared LRU cache; 0 turns the cache off.
+    pub cell_cache_size: usize,
+    /// Budget shared by client edits and the cell cache; edits are refused
+    /// once they alone would exceed it.
+    pub max_memory_mb: Option<u64>,
+    /// Longest value, in UTF-8 bytes, an edit may store in one cell.
+    pub max_cell_length: usize,
+    /// Cells a `range_request` or `export_request` may cover; larger ones
+    /// are refused with `range_too_large`. File exports are not limited.
+    pub max_export_cells: u64,
+    /// Secret an `admin_reset` must carry; admin messages are refused when unset.
+    pub admin_token: Option<String>,
+    /// Directory `export_to_file_request` writes into; file exports are
+    /// refused when unset.
+    pub export_dir: Option<String>,
+    /// `cells_updated` pushes per second on `/ws-stress`; 0 leaves the route off.
+    pub stress_rate: u32,
+    /// Size of the top-left region the stress edits land in.
+    pub stress_rows: u64,
+    pub stress_cols: u32,
+    /// Check the `--csv` file, print a report and exit instead of serving.
+    pub validate_only: bool,
+    /// Print the JSON Schema of every message and exit instead of serving.
+    pub dump_schema: bool,
+    /// When each connection's writer flushes its socket.
+    pub flush_policy: FlushPolicy,
+    /// Offers the `gzip` capability, compressing replies of at least this
+    /// many bytes for connections that take it up.
+    pub compress_min_bytes: Option<usize>,
+    /// Greet every connection with a `welcome` message before it asks for
+    /// anything. Off by default, for clients that do not expect it.
+    pub welcome: bool,
+    /// Pending connections the listening socket queues before refusing more.
+    pub listen_backlog: u32,
+    /// Open connections served at once; later ones are closed as overloaded
+    /// straight after the upgrade. `None` means no limit.
+    pub max_connections: Option<usize>,
+    /// Base of the reconnect delay close frames suggest, which each
+    /// [`CloseReason`](crate::outbound::CloseReason) scales.
+    pub reconnect_delay: Duration,
+    /// Tokio worker threads; `None` means `TOKIO_WORKER_THREADS` or the core count.
+    pub worker_threads: Option<usize>,
+}
+
+/// The settings a reload can change while serving. They are read from
+/// `AppState` on every use rather than from the startup [`Config`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub max_cells_per_slice: u64,
+    pub max_buffer: u32,
+    pub source_timeout: Option<Duration>,
+    pub read_only: bool,
+    pub distinct_values_cap: usize,
+    pub scan_budget: u64,
+}
+
+/// The flags behind [`Settings`]; changing any other in the config file
+/// takes a restart.
+pub const RELOADABLE_FLAGS: &[&str] = &[
+    "--max-cells-per-slice",
+    "--max-buffer",
+    "--source-timeout-ms",
+    "--read-only",
+    "--distinct-values-cap",
+    "--scan-budget",
+];
+
+impl Settings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_cells_per_slice: config.max_cells_per_slice,
+            max_buffer: config.max_buffer,
+            source_timeout: config.source_timeout,
+            read_only: config.read_only,
+            distinct_values_cap: config.distinct_values_cap,
+            scan_budget: config.scan_budget,
+        }
+    }
+
+    /// One `name: old -> new` line per setting that differs in `new`.
+    pub fn changes(&self, new: &Settings) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, old: &dyn std::fmt::Debug, new: &dyn std::fmt::Debug| {
+            let (old, new) = (format!("{:?}", old), format!("{:?}", new));
+            if old != new {
+                changes.push(format!("{}: {} -> {}", name, old, new));
+            }
+        };
+        compare("max_cells_per_slice", &self.max_cells_per_slice, &new.max_cells_per_slice);
+        compare("max_buffer", &self.max_buffer, &new.max_buffer);
+        compare("source_timeout", &self.source_timeout, &new.source_timeout);
+        compare("read_only", &self.read_only, &new.read_only);
+        compare("distinct_values_cap", &self.distinct_values_cap, &new.distinct_values_cap);
+        compare("scan_budget", &self.scan_budget, &new.scan_budget);
+        changes
+    }
+}
+
+/// Flags outside [`RELOADABLE_FLAGS`] whose lines differ between two reads
+/// of a config file.
+pub fn restart_only_changes(old: &[ConfigFlag], new: &[ConfigFlag]) -> Vec<String> {
+    let values = |flags: &[ConfigFlag], name: &str| -> Vec<Option<String>> {
+        let named = flags.iter().filter(|flag| flag.flag == name);
+        named.map(|flag| flag.value.clone()).collect()
+    };
+    let mut changed: Vec<String> = Vec::new();
+    for flag in old.iter().chain(new) {
+        let name = flag.flag.as_str();
+        if RELOADABLE_FLAGS.contains(&name) || changed.iter().any(|seen| seen == name) {
+            continue;
+        }
+        if values(old, name) != values(new, name) {
+            changed.push(name.to_string());
+        }
+    }
+    changed
+}
+
+/// One line of a config file: a flag and the rest of the line as its value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigFlag {
+    pub flag: String,
+    pub value: Option<String>,
+}
+
+impl ConfigFlag {
+    fn to_arg(&self) -> String {
+        match &self.value {
+            Some(value) => format!("{}={}", self.flag, value),
+            None => self.flag.clone(),
+        }
+    }
+}
+
+/// The file named by `--config-file` on the command line, if any.
+fn config_file_arg(args: &[String]) -> Option<&str> {
+    args.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix("--config-file") {
+        Some("") => args.get(i + 1).map(String::as_str),
+        Some(rest) => rest.strip_prefix('='),
+        None => None,
+    })
+}
+
+/// Reads a config file: one `--flag` or `--flag value` per line, with blank
+/// lines and `#` comments skipped. The value is the rest of the line, so it
+/// may hold spaces.
+pub fn read_config_file(path: &str) -> Result<Vec<ConfigFlag>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("cannot read config file {}: {}", path, err))?;
+    let mut flags = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (flag, value) = match line.split_once(char::is_whitespace) {
+            Some((flag, value)) => (flag, Some(value.trim().to_string())),
+            None => match line.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (line, None),
+            },
+        };
+        if !flag.starts_with("--") || flag == "--config-file" {
+            return Err(format!("{} line {}: expected a flag, got {:?}", path, i + 1, flag));
+        }
+        flags.push(ConfigFlag {
+            flag: flag.to_string(),
+            value,
+        });
+    }
+    Ok(flags)
+}
+
+/// How the CSV file is split into cells.
+#[derive(Debug)]
+pub struct CsvOptions {
+    /// Field separator; `None` guesses it from the header line.
+    pub delimiter: Option<u8>,
+    pub quote: u8,
+    /// Escapes a quote inside a quoted field. Without it, quotes are escaped by doubling.
+    pub escape: Option<u8>,
+    /// Widest file that will be loaded; a record with more fields is refused.
+    pub max_cols: u32,
+    /// Drops the fields of a row past the header's width, rather than
+    /// widening the table to fit them.
+    pub truncate_long_rows: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: None,
+            quote: b'"',
+            escape: None,
+            max_cols: crate::SERVER_MAX_COLS,
+            truncate_long_rows: false,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            config_file: None,
+            file_flags: Vec::new(),
+            args: Vec::new(),
+            inline_data: None,
+            csv_path: None,
+            csv: CsvOptions::default(),
+            xlsx_path: None,
+            sheet: None,
+            sheets: Vec::new(),
+            parquet_path: None,
+            csv_background: false,
+            max_cells_per_slice: 50_000,
+            max_buffer: 1_000,
+            source_timeout: None,
+            read_only: false,
+            headers: Vec::new(),
+            missing_value: String::new(),
+            formulas: Vec::new(),
+            demo_script: Vec::new(),
+            max_filters: 32,
+            max_sort_keys: 8,
+            max_inflight: 16,
+            distinct_values_cap: 1_000,
+            scan_budget: 100_000,
+            seed: None,
+            synthetic_columns: Vec::new(),
+            sparsity: 0.0,
+            cell_cache_size: 0,
+            max_memory_mb: None,
+            max_cell_length: 1 << 20,
+            max_export_cells: 10_000_000,
+            admin_token: None,
+            export_dir: None,
+            stress_rate: 0,
+            stress_rows: 100,
+            stress_cols: 26,
+            validate_only: false,
+            dump_schema: false,
+            flush_policy: FlushPolicy::Immediate,
+            compress_min_bytes: None,
+            welcome: false,
+            listen_backlog: 1024,
+            max_connections: None,
+            reconnect_delay: Duration::from_secs(1),
+            worker_threads: None,
+        }
+    }
+}
+
+impl Config {
+    /// Parses `--flag value` / `--flag=value` style arguments (without the
+    /// program name), after the flags in any `--config-file` they name.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let args: Vec<String> = args.into_iter().collect();
+        let file_flags = match config_file_arg(&args) {
+            Some(path) => read_config_file(path)?,
+            None => Vec::new(),
+        };
+        let file_args = file_flags.iter().map(ConfigFlag::to_arg);
+        let mut config = Self::parse(file_args.chain(args.iter().cloned()))?;
+        config.file_flags = file_flags;
+        config.args = args;
+        Ok(config)
+    }
+
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", flag))
+            };
+            match flag.as_str() {
+                "--config-file" => config.config_file = Some(value()?),
+                "--inline-data" => config.inline_data = Some(value()?),
+                "--csv" => config.csv_path = Some(value()?),
+                "--parquet" => config.parquet_path = Some(value()?),
+                "--csv-delimiter" => config.csv.delimiter = Some(parse_byte(&flag, &value()?)?),
+                "--csv-quote" => config.csv.quote = parse_byte(&flag, &value()?)?,
+                "--csv-escape" => config.csv.escape = Some(parse_byte(&flag, &value()?)?),
+                "--csv-max-cols" => config.csv.max_cols = parse_number(&flag, &value()?)?,
+                "--csv-truncate-long-rows" => config.csv.truncate_long_rows = true,
+                "--xlsx" => config.xlsx_path = Some(value()?),
+                "--sheet" => config.sheet = Some(value()?),
+                "--max-cells-per-slice" => {
+                    config.max_cells_per_slice = parse_number(&flag, &value()?)?
+                }
+                "--source-timeout-ms" => {
+                    config.source_timeout =
+                        Some(Duration::from_millis(parse_number(&flag, &value()?)?))
+                }
+                "--max-buffer" => config.max_buffer = parse_number(&flag, &value()?)?,
+                "--csv-background" => config.csv_background = true,
+                "--read-only" => config.read_only = true,
+                "--validate-only" => config.validate_only = true,
+                "--dump-schema" => config.dump_schema = true,
+                "--headers" => config.headers = parse_headers(&value()?),
+                "--headers-file" => {
+                    let path = value()?;
+                    let text = std::fs::read_to_string(&path)
+                        .map_err(|err| format!("cannot read headers file {}: {}", path, err))?;
+                    config.headers = parse_headers(&text);
+                }
+                "--cell-cache-size" => config.cell_cache_size = parse_number(&flag, &value()?)?,
+                "--stress-rate" => config.stress_rate = parse_number(&flag, &value()?)?,
+                "--stress-rows" => config.stress_rows = parse_number(&flag, &value()?)?,
+                "--stress-cols" => config.stress_cols = parse_number(&flag, &value()?)?,
+                "--max-memory-mb" => config.max_memory_mb = Some(parse_number(&flag, &value()?)?),
+                "--max-cell-length" => config.max_cell_length = parse_number(&flag, &value()?)?,
+                "--max-export-cells" => config.max_export_cells = parse_number(&flag, &value()?)?,
+                "--admin-token" => config.admin_token = Some(value()?),
+                "--export-dir" => config.export_dir = Some(value()?),
+                "--formula" => config.formulas.push(Formula::parse(&value()?)?),
+                "--demo-script" => config.demo_script = demo::load(&value()?)?,
+                "--max-filters" => config.max_filters = parse_number(&flag, &value()?)?,
+                "--max-sort-keys" => config.max_sort_keys = parse_number(&flag, &value()?)?,
+                "--max-inflight" => config.max_inflight = parse_number(&flag, &value()?)?,
+                "--distinct-values-cap" => {
+                    config.distinct_values_cap = parse_number(&flag, &value()?)?
+                }
+                "--scan-budget" => config.scan_budget = parse_number(&flag, &value()?)?,
+                "--seed" => config.seed = Some(parse_number(&flag, &value()?)?),
+                "--sparsity" => config.sparsity = parse_number(&flag, &value()?)?,
+                "--synthetic-columns" => {
+                    config.synthetic_columns = value()?
+                        .split(',')
+                        .map(SyntheticColumnSpec::parse)
+                        .collect::<Result<_, _>>()?
+                }
+                "--missing-value" => config.missing_value = value()?,
+                "--flush-policy" => config.flush_policy = parse_flush_policy(&flag, &value()?)?,
+                "--compress-min-bytes" => {
+                    config.compress_min_bytes = Some(parse_number(&flag, &value()?)?)
+                }
+                "--welcome" => config.welcome = true,
+                "--listen-backlog" => config.listen_backlog = parse_number(&flag, &value()?)?,
+                "--max-connections" => {
+                    config.max_connections = Some(parse_number(&flag, &value()?)?)
+                }
+                "--reconnect-delay-ms" => {
+                    config.reconnect_delay = Duration::from_millis(parse_number(&flag, &value()?)?)
+                }
+                "--worker-threads" => {
+                    config.worker_threads = Some(parse_worker_threads(&flag, &value()?)?)
+                }
+                _ => return Err(format!("unknown flag {}", flag)),
+            }
+        }
+        let files = [
+            &config.inline_data,
+            &config.csv_path,
+            &config.xlsx_path,
+            &config.parquet_path,
+        ];
+        if files.iter().filter(|file| file.is_some()).count() > 1 {
+            return Err(
+                "only one of --inline-data, --csv, --xlsx and --parquet can be given".to_string(),
+            );
+        }
+        if config.sheet.is_some() && config.xlsx_path.is_none() {
+            return Err("--sheet needs an --xlsx workbook".to_string());
+        }
+        if config.validate_only && config.csv_path.is_none() {
+            return Err("--validate-only needs a --csv file to check".to_string());
+        }
+        if config.csv_background && config.csv_path.is_none() {
+            return Err("--csv-background needs a --csv file to load".to_string());
+        }
+        for (i, formula) in config.formulas.iter().enumerate() {
+            let label = crate::col_index_to_letters(formula.col);
+            if config.formulas[..i].iter().any(|other| other.col == formula.col) {
+                return Err(format!("column {} has more than one --formula", label));
+            }
+            let reads_formula = formula.inputs().into_iter().any(|input| {
+                config.formulas.iter().any(|other| other.col == input)
+            });
+            if reads_formula {
+                return Err(format!(
+                    "the formula for column {} reads another formula column",
+                    label
+                ));
+            }
+        }
+        let synthetic = files.iter().all(|file| file.is_none());
+        if config.seed.is_some() && !synthetic {
+            return Err("--seed only applies to the synthetic table".to_string());
+        }
+        if config.sparsity != 0.0 && !synthetic {
+            return Err("--sparsity only applies to the synthetic table".to_string());
+        }
+        if !(0.0..=1.0).contains(&config.sparsity) {
+            return Err(format!("--sparsity must be from 0 to 1, got {}", config.sparsity));
+        }
+        if !config.synthetic_columns.is_empty() && config.seed.is_none() {
+            return Err("--synthetic-columns needs --seed".to_string());
+        }
+        Ok(config)
+    }
+
+    /// The runtime's worker count: the flag, else `TOKIO_WORKER_THREADS`, else
+    /// one per available core.
+    pub fn resolve_worker_threads(&self) -> Result<usize, String> {
+        if let Some(threads) = self.worker_threads {
+            return Ok(threads);
+        }
+        match std::env::var("TOKIO_WORKER_THREADS") {
+            Ok(value) => parse_worker_threads("TOKIO_WORKER_THREADS", &value),
+            Err(_) => Ok(std::thread::available_parallelism().map_or(1, |n| n.get())),
+        }
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a number, got {:?}", flag, value))
+}
+
+/// A single ASCII character, with `tab` or `\t` accepted for a tab.
+fn parse_byte(flag: &str, value: &str) -> Result<u8, String> {
+    match value {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if value.len() == 1 && value.is_ascii() => Ok(value.as_bytes()[0]),
+        _ => Err(format!("{} expects a single ASCII character, got {:?}", flag, value)),
+    }
+}
+
+fn parse_worker_threads(name: &str, value: &str) -> Result<usize, String> {
+    match parse_number(name, value)? {
+        0 => Err(format!("{} must be at least 1", name)),
+        threads => Ok(threads),
+    }
+}
+
+/// Default coalescing window for a bare `--flush-policy coalesce`.
+const DEFAULT_COALESCE_MS: u64 = 2;
+
+/// `immediate`, `coalesce`, or `coalesce:<ms>` for a window other than the
+/// default.
+fn parse_flush_policy(flag: &str, value: &str) -> Result<FlushPolicy, String> {
+    match value.split_once(':') {
+        None if value == "immediate" => Ok(FlushPolicy::Immediate),
+        None if value == "coalesce" => Ok(FlushPolicy::Coalesce(Duration::from_millis(
+            DEFAULT_COALESCE_MS,
+        ))),
+        Some(("coalesce", ms)) => Ok(FlushPolicy::Coalesce(Duration::from_millis(
+            parse_number(flag, ms)?,
+        ))),
+        _ => Err(format!(
+            "{} expects immediate, coalesce or coalesce:<ms>, got {:?}",
+            flag, value
+        )),
+    }
+}
+
+/// Splits header names on commas or newlines, so a file can hold either a
+/// single CSV-style line or one name per line.
+fn parse_headers(text: &str) -> Vec<String> {
+    text.split([',', '\n'])
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
@@ -0,0 +1,47 @@
+//! `--demo-script`: timed edits, sorts and filters the server applies on its
+//! own, so a projected screen animates without anyone at the keyboard.
+//!
+//! The script is a JSON array of steps such as
+//! `{"atMs": 2000, "action": {"type": "edit", "row": 0, "col": 1, "value": "hi"}}`,
+//! each run once, `atMs` after the server starts.
+
+use crate::view::{Filter, SortKey};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DemoStep {
+    pub at_ms: u64,
+    pub action: DemoAction,
+}
+
+/// What a step does. Edits are broadcast like a client's; sorts and filters
+/// change every open session's view.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DemoAction {
+    Edit { row: u64, col: u32, value: String },
+    Sort { keys: Vec<SortKey> },
+    /// Fields as in a `filter_request`.
+    Filter(Filter),
+    /// Drops the filter on `col`, or every filter without one.
+    ClearFilters {
+        #[serde(default)]
+        col: Option<u32>,
+    },
+}
+
+/// Reads a script, in the order its steps run.
+pub fn load(path: &str) -> Result<Vec<DemoStep>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("cannot read demo script {}: {}", path, err))?;
+    let mut steps: Vec<DemoStep> =
+        serde_json::from_str(&text).map_err(|err| format!("demo script {}: {}", path, err))?;
+    for step in &mut steps {
+        if let DemoAction::Filter(filter) = &mut step.action {
+            filter.compile().map_err(|err| format!("demo script {}: bad regex: {}", path, err))?;
+        }
+    }
+    steps.sort_by_key(|step| step.at_ms);
+    Ok(steps)
+}
@@ -0,0 +1,104 @@
+//! Text encodings for exported ranges, so clients paste what the server
+//! quoted rather than reimplementing CSV rules.
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Rows encoded between progress reports in [`write_file`].
+pub const FILE_ROWS_PER_CHUNK: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Tsv,
+    /// One array of strings per row, all inside a single outer array.
+    Json,
+}
+
+/// Encodes `rows` as one chunk of a longer export. Concatenating every chunk
+/// in order gives the whole document; `first` and `last` mark the ends, which
+/// JSON needs for its brackets.
+pub fn encode_chunk(format: ExportFormat, rows: &[Vec<String>], first: bool, last: bool) -> String {
+    let mut out = String::new();
+    match format {
+        ExportFormat::Csv => write_delimited(&mut out, rows, ','),
+        ExportFormat::Tsv => write_delimited(&mut out, rows, '\t'),
+        ExportFormat::Json => {
+            if first {
+                out.push('[');
+            }
+            for (i, row) in rows.iter().enumerate() {
+                if !(first && i == 0) {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(row).unwrap());
+            }
+            if last {
+                out.push(']');
+            }
+        }
+    }
+    out
+}
+
+/// Writes `header` and then rows `0..row_count`, read through `row`, to a new
+/// file at `path`, calling `progress` with the rows written so far after each
+/// chunk. A cancelled export removes the partial file and returns `false`.
+pub fn write_file(
+    path: &Path,
+    format: ExportFormat,
+    header: Vec<String>,
+    row_count: u64,
+    canceled: &AtomicBool,
+    mut row: impl FnMut(u64) -> Vec<String>,
+    mut progress: impl FnMut(u64),
+) -> io::Result<bool> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut rows = vec![header];
+    let mut start = 0u64;
+    loop {
+        if canceled.load(Ordering::Relaxed) {
+            drop(out);
+            std::fs::remove_file(path)?;
+            return Ok(false);
+        }
+        let end = start.saturating_add(FILE_ROWS_PER_CHUNK).min(row_count);
+        rows.extend((start..end).map(&mut row));
+        let text = encode_chunk(format, &rows, start == 0, end == row_count);
+        out.write_all(text.as_bytes())?;
+        rows.clear();
+        progress(end);
+        if end == row_count {
+            break;
+        }
+        start = end;
+    }
+    out.flush()?;
+    Ok(true)
+}
+
+/// RFC 4180 style: fields holding the delimiter, a quote or a line break are
+/// quoted, with quotes doubled. Every row ends in `\r\n`.
+fn write_delimited(out: &mut String, rows: &[Vec<String>], delimiter: char) {
+    for row in rows {
+        for (i, field) in row.iter().enumerate() {
+            if i > 0 {
+                out.push(delimiter);
+            }
+            if field.contains([delimiter, '"', '\r', '\n']) {
+                out.push('"');
+                out.push_str(&field.replace('"', "\"\""));
+                out.push('"');
+            } else {
+                out.push_str(field);
+            }
+        }
+        out.push_str("\r\n");
+    }
+}
@@ -0,0 +1,367 @@
+//! Filter expressions from `filter_expr_request`, e.g.
+//! `age > 30 AND city = "NYC"`.
+//!
+//! A comparison is a column, an operator (`= != < <= > >=` or `contains`) and
+//! a literal: a number, or a string in double quotes with `\"` and `\\`
+//! escapes. Comparisons combine with `AND`, `OR` and `NOT` in any case, where
+//! `OR` binds loosest and `NOT` tightest, and with parentheses.
+//! A column is a header name, or its letters when no header matches; names
+//! with spaces or punctuation go in backticks. Each comparison behaves like
+//! the matching [`Filter`], so the ordering operators only pass numbers.
+//! An expression may nest [`MAX_DEPTH`] deep and hold [`MAX_TERMS`]
+//! comparisons, which keeps parsing and matching off a deep stack.
+
+use crate::view::{Filter, FilterOp};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Most parentheses and `NOT`s an expression may nest.
+pub const MAX_DEPTH: usize = 32;
+/// Most comparisons an expression may hold.
+pub const MAX_TERMS: usize = 256;
+
+/// A parsed expression, with column names already resolved to indices.
+#[derive(Clone, Debug)]
+pub enum FilterExpr {
+    Cond(Filter),
+    Not(Box<FilterExpr>),
+    And(Box<FilterExpr>, Box<FilterExpr>),
+    Or(Box<FilterExpr>, Box<FilterExpr>),
+}
+
+/// Why an expression was rejected, and the character offset (from 0) where.
+#[derive(Debug)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl FilterExpr {
+    /// Parses `text`, looking column names up through `column`.
+    pub fn parse(text: &str, column: impl Fn(&str) -> Option<u32>) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+            depth: 0,
+            terms: 0,
+            column: &column,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(c) => Err(parser.error(format!("unexpected {:?}", c))),
+        }
+    }
+
+    /// Adds every column the expression reads to `cols`.
+    pub fn collect_cols(&self, cols: &mut HashSet<u32>) {
+        match self {
+            FilterExpr::Cond(filter) => {
+                cols.insert(filter.col);
+            }
+            FilterExpr::Not(inner) => inner.collect_cols(cols),
+            FilterExpr::And(lhs, rhs) | FilterExpr::Or(lhs, rhs) => {
+                lhs.collect_cols(cols);
+                rhs.collect_cols(cols);
+            }
+        }
+    }
+
+    /// Whether a row passes, reading its cells through `cell`.
+    pub fn matches(&self, cell: &mut impl FnMut(u32) -> String) -> bool {
+        match self {
+            FilterExpr::Cond(filter) => filter.matches(&cell(filter.col)),
+            FilterExpr::Not(inner) => !inner.matches(cell),
+            FilterExpr::And(lhs, rhs) => lhs.matches(cell) && rhs.matches(cell),
+            FilterExpr::Or(lhs, rhs) => lhs.matches(cell) || rhs.matches(cell),
+        }
+    }
+}
+
+/// Recursive descent over `or := and (OR and)*`, `and := not (AND not)*`,
+/// `not := NOT not | ( or ) | column op literal`.
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    /// Parentheses and `NOT`s open around `pos`.
+    depth: usize,
+    /// Comparisons parsed so far.
+    terms: usize,
+    column: &'a dyn Fn(&str) -> Option<u32>,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            position: self.pos,
+            message,
+        }
+    }
+
+    /// Consumes `word` if it comes next as a whole word, in any case.
+    fn keyword(&mut self, word: &str) -> bool {
+        self.peek();
+        let end = self.pos + word.len();
+        let matches = self.chars.get(self.pos..end).is_some_and(|chars| {
+            chars.iter().zip(word.chars()).all(|(a, b)| a.eq_ignore_ascii_case(&b))
+        });
+        if matches && !self.chars.get(end).is_some_and(|&c| is_word_char(c)) {
+            self.pos = end;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<FilterExpr, ParseError> {
+        let mut lhs = self.and()?;
+        while self.keyword("or") {
+            lhs = FilterExpr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<FilterExpr, ParseError> {
+        let mut lhs = self.not()?;
+        while self.keyword("and") {
+            lhs = FilterExpr::And(Box::new(lhs), Box::new(self.not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn not(&mut self) -> Result<FilterExpr, ParseError> {
+        if self.keyword("not") {
+            self.nest()?;
+            let inner = self.not()?;
+            self.depth -= 1;
+            return Ok(FilterExpr::Not(Box::new(inner)));
+        }
+        if self.peek() == Some('(') {
+            self.nest()?;
+            self.pos += 1;
+            let inner = self.or()?;
+            if self.peek() != Some(')') {
+                return Err(self.error("missing )".to_string()));
+            }
+            self.pos += 1;
+            self.depth -= 1;
+            return Ok(inner);
+        }
+        self.comparison()
+    }
+
+    /// Enters a `NOT` or parenthesis, unless that nests too deep.
+    fn nest(&mut self) -> Result<(), ParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(format!("nested more than {} deep", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn comparison(&mut self) -> Result<FilterExpr, ParseError> {
+        if self.terms == MAX_TERMS {
+            self.peek();
+            return Err(self.error(format!("more than {} comparisons", MAX_TERMS)));
+        }
+        self.terms += 1;
+        let col = self.column()?;
+        let (op, negate) = self.operator()?;
+        let value = self.literal()?;
+        let filter = FilterExpr::Cond(Filter {
+            col,
+            op,
+            value,
+            values: HashSet::new(),
+            case_insensitive: false,
+            regex: None,
+        });
+        Ok(match negate {
+            true => FilterExpr::Not(Box::new(filter)),
+            false => filter,
+        })
+    }
+
+    fn column(&mut self) -> Result<u32, ParseError> {
+        let start = self.pos;
+        let name = match self.peek() {
+            Some('`') => {
+                self.pos += 1;
+                let name = self.take_while(|c| c != '`');
+                if self.chars.get(self.pos) != Some(&'`') {
+                    return Err(self.error("missing closing `".to_string()));
+                }
+                self.pos += 1;
+                name
+            }
+            Some(c) if is_word_char(c) => self.take_while(is_word_char),
+            Some(c) => return Err(self.error(format!("expected a column, found {:?}", c))),
+            None => return Err(self.error("expected a column".to_string())),
+        };
+        (self.column)(&name).ok_or(ParseError {
+            position: start,
+            message: format!("unknown column {:?}", name),
+        })
+    }
+
+    /// The operator, and whether the comparison is negated (`!=`).
+    fn operator(&mut self) -> Result<(FilterOp, bool), ParseError> {
+        if self.keyword("contains") {
+            return Ok((FilterOp::Contains, false));
+        }
+        let next = self.chars.get(self.pos + 1).copied();
+        let (op, negate, len) = match (self.peek(), next) {
+            (Some('!'), Some('=')) => (FilterOp::Equals, true, 2),
+            (Some('<'), Some('=')) => (FilterOp::Lte, false, 2),
+            (Some('>'), Some('=')) => (FilterOp::Gte, false, 2),
+            (Some('='), _) => (FilterOp::Equals, false, 1),
+            (Some('<'), _) => (FilterOp::Lt, false, 1),
+            (Some('>'), _) => (FilterOp::Gt, false, 1),
+            _ => return Err(self.error("expected an operator".to_string())),
+        };
+        self.pos += len;
+        Ok((op, negate))
+    }
+
+    fn literal(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') => {
+                let start = self.pos;
+                self.pos += 1;
+                let mut value = String::new();
+                loop {
+                    match self.chars.get(self.pos).copied() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            self.pos += 1;
+                            match self.chars.get(self.pos).copied() {
+                                Some(c @ ('"' | '\\')) => value.push(c),
+                                _ => return Err(self.error("invalid escape".to_string())),
+                            }
+                        }
+                        Some(c) => value.push(c),
+                        None => {
+                            return Err(ParseError {
+                                position: start,
+                                message: "unterminated string".to_string(),
+                            })
+                        }
+                    }
+                    self.pos += 1;
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(c) if c == '-' || c == '.' || c.is_ascii_digit() => {
+                let start = self.pos;
+                self.pos += 1;
+                let rest = self.take_while(|c| c.is_ascii_alphanumeric() || c == '.');
+                let number = format!("{}{}", c, rest);
+                match number.parse::<f64>() {
+                    Ok(_) => Ok(number),
+                    Err(_) => Err(ParseError {
+                        position: start,
+                        message: format!("{:?} is not a number", number),
+                    }),
+                }
+            }
+            Some(c) => Err(self.error(format!("expected a value, found {:?}", c))),
+            None => Err(self.error("expected a value".to_string())),
+        }
+    }
+
+    fn take_while(&mut self, keep: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|&c| keep(c)) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEOPLE: &[[&str; 3]] = &[
+        ["Ada", "36", "NYC"],
+        ["Bo", "25", "NYC"],
+        ["Cy", "41", "LA"],
+        ["Di", "52", "New York"],
+        ["Ed", "31", "NYC"],
+    ];
+
+    fn parse(text: &str) -> Result<FilterExpr, ParseError> {
+        let names = ["name", "age", "city"];
+        FilterExpr::parse(text, |name| names.iter().position(|&n| n == name).map(|i| i as u32))
+    }
+
+    /// Names of the people `text` keeps.
+    fn kept(text: &str) -> Vec<&'static str> {
+        let expr = parse(text).unwrap();
+        PEOPLE
+            .iter()
+            .filter(|person| expr.matches(&mut |col| person[col as usize].to_string()))
+            .map(|person| person[0])
+            .collect()
+    }
+
+    #[test]
+    fn compound_expressions_keep_the_matching_rows() {
+        assert_eq!(kept(r#"age > 30 AND city = "NYC""#), ["Ada", "Ed"]);
+        assert_eq!(kept(r#"age > 50 or city = "LA""#), ["Cy", "Di"]);
+        assert_eq!(kept(r#"NOT city = "NYC" AND age < 45"#), ["Cy"]);
+        assert_eq!(kept(r#"NOT (city = "NYC" AND age < 45)"#), ["Cy", "Di"]);
+        assert_eq!(kept(r#"city contains "New" OR age <= 25"#), ["Bo", "Di"]);
+        assert_eq!(kept(r#"`city` != "NYC" AND age >= 50"#), ["Di"]);
+    }
+
+    #[test]
+    fn malformed_expressions_say_where_they_went_wrong() {
+        let err = parse(r#"age > 30 AND"#).unwrap_err();
+        assert_eq!(err.position, 12);
+        let err = parse(r#"age > 30 city = "NYC""#).unwrap_err();
+        assert_eq!(err.position, 9);
+        let err = parse(r#"height > 2"#).unwrap_err();
+        assert_eq!(err.position, 0);
+        let err = parse(r#"city = "NYC"#).unwrap_err();
+        assert!(err.to_string().ends_with("at position 7"), "{}", err);
+        assert!(parse("(age > 30").is_err());
+    }
+
+    #[test]
+    fn nesting_and_length_are_limited() {
+        let nested = |depth: usize| {
+            format!("{}age > 30{}", "(".repeat(depth), ")".repeat(depth))
+        };
+        assert_eq!(kept(&nested(MAX_DEPTH)), ["Ada", "Cy", "Di", "Ed"]);
+        let err = parse(&nested(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(err.message, format!("nested more than {} deep", MAX_DEPTH));
+        assert_eq!(kept(&format!("{}age > 30", "NOT ".repeat(MAX_DEPTH - 1))), ["Bo"]);
+        assert!(parse(&format!("{}age > 30", "NOT ".repeat(MAX_DEPTH + 1))).is_err());
+        let err = parse(&"(".repeat(100_000)).unwrap_err();
+        assert_eq!(err.position, MAX_DEPTH);
+
+        let terms = |count: usize| vec!["age > 30"; count].join(" OR ");
+        assert_eq!(kept(&terms(MAX_TERMS)), ["Ada", "Cy", "Di", "Ed"]);
+        let err = parse(&terms(MAX_TERMS + 1)).unwrap_err();
+        assert_eq!(err.message, format!("more than {} comparisons", MAX_TERMS));
+        assert_eq!(err.position, MAX_TERMS * "age > 30 OR ".len());
+    }
+}
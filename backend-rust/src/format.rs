@@ -0,0 +1,36 @@
+//! Conditional formatting: per-session rules that style cells by value.
+
+use crate::view::Filter;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// How a cell should be drawn. Unset fields keep the client's default.
+#[derive(Clone, Debug, Default, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CellStyle {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bold: bool,
+}
+
+/// Applies `style` to the cells of `col` that pass the condition, using the
+/// same operators as filters.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FormatRule {
+    #[serde(flatten)]
+    pub condition: Filter,
+    pub style: CellStyle,
+}
+
+/// The style for a cell of `col` holding `value`: the first matching rule
+/// wins.
+pub fn style_for<'a>(rules: &'a [FormatRule], col: u32, value: &str) -> Option<&'a CellStyle> {
+    rules
+        .iter()
+        .find(|rule| rule.condition.col == col && rule.condition.matches(value))
+        .map(|rule| &rule.style)
+}
@@ -0,0 +1,219 @@
+//! Computed columns from `--formula "C=A+B"`.
+//!
+//! A formula is arithmetic over column references in the same row: letters
+//! name columns, with `+ - * /`, parentheses, unary minus and numeric
+//! literals. It is evaluated every time the cell is read, so it always
+//! reflects the current values, edits included. Blank cells count as 0;
+//! anything else that is not a number makes the result `#VALUE!`, and
+//! dividing by zero gives `#DIV/0!`.
+
+/// The column a formula fills and how to compute it.
+#[derive(Clone, Debug)]
+pub struct Formula {
+    pub col: u32,
+    expr: Expr,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(f64),
+    Col(u32),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+enum EvalError {
+    Value,
+    DivZero,
+}
+
+impl Formula {
+    /// Parses `TARGET=EXPR`, e.g. `C=A+B` or `D = (A - B) / 2`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (target, expr) = text
+            .split_once('=')
+            .ok_or_else(|| format!("formula {:?} should look like C=A+B", text))?;
+        let col = letters_to_col(target.trim())
+            .ok_or_else(|| format!("formula {:?} must start with a column name", text))?;
+        let mut parser = Parser {
+            chars: expr.chars().collect(),
+            pos: 0,
+        };
+        let expr = parser
+            .expr()
+            .and_then(|expr| match parser.peek() {
+                None => Ok(expr),
+                Some(c) => Err(format!("unexpected {:?}", c)),
+            })
+            .map_err(|err| format!("formula {:?}: {}", text, err))?;
+        Ok(Self { col, expr })
+    }
+
+    /// Every column the formula reads.
+    pub fn inputs(&self) -> Vec<u32> {
+        let mut cols = Vec::new();
+        self.expr.collect_cols(&mut cols);
+        cols
+    }
+
+    /// The formula's value for a row, reading its inputs through `cell`.
+    pub fn eval(&self, mut cell: impl FnMut(u32) -> String) -> String {
+        match self.expr.eval(&mut cell) {
+            Ok(value) => format_number(value),
+            Err(EvalError::Value) => "#VALUE!".to_string(),
+            Err(EvalError::DivZero) => "#DIV/0!".to_string(),
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, cell: &mut impl FnMut(u32) -> String) -> Result<f64, EvalError> {
+        match self {
+            Expr::Number(n) => Ok(*n),
+            Expr::Col(col) => {
+                let text = cell(*col);
+                let text = text.trim();
+                if text.is_empty() {
+                    return Ok(0.0);
+                }
+                text.parse::<f64>().map_err(|_| EvalError::Value)
+            }
+            Expr::Neg(inner) => Ok(-inner.eval(cell)?),
+            Expr::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.eval(cell)?, rhs.eval(cell)?);
+                match op {
+                    Op::Add => Ok(lhs + rhs),
+                    Op::Sub => Ok(lhs - rhs),
+                    Op::Mul => Ok(lhs * rhs),
+                    Op::Div if rhs == 0.0 => Err(EvalError::DivZero),
+                    Op::Div => Ok(lhs / rhs),
+                }
+            }
+        }
+    }
+
+    fn collect_cols(&self, cols: &mut Vec<u32>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Col(col) => cols.push(*col),
+            Expr::Neg(inner) => inner.collect_cols(cols),
+            Expr::Binary(lhs, _, rhs) => {
+                lhs.collect_cols(cols);
+                rhs.collect_cols(cols);
+            }
+        }
+    }
+}
+
+/// Whole numbers print without a decimal point.
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        value.to_string()
+    }
+}
+
+/// `A` -> 0, `Z` -> 25, `AA` -> 26; the inverse of the header letters.
+pub(crate) fn letters_to_col(letters: &str) -> Option<u32> {
+    if letters.is_empty() {
+        return None;
+    }
+    let mut col: u32 = 0;
+    for c in letters.chars() {
+        if !c.is_ascii_alphabetic() {
+            return None;
+        }
+        let digit = c.to_ascii_uppercase() as u32 - 'A' as u32 + 1;
+        col = col.checked_mul(26)?.checked_add(digit)?;
+    }
+    Some(col - 1)
+}
+
+/// Recursive descent over `expr := term (+|- term)*`,
+/// `term := factor (*|/ factor)*` and
+/// `factor := number | column | ( expr ) | - factor`.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.pos += 1;
+            let op = if op == '+' { Op::Add } else { Op::Sub };
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.term()?));
+        }
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.factor()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.pos += 1;
+            let op = if op == '*' { Op::Mul } else { Op::Div };
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.factor()?));
+        }
+        Ok(lhs)
+    }
+
+    fn factor(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.factor()?)))
+            }
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.expr()?;
+                match self.peek() {
+                    Some(')') => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => Err("missing )".to_string()),
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let word = self.take_while(|c| c.is_ascii_alphanumeric());
+                letters_to_col(&word)
+                    .map(Expr::Col)
+                    .ok_or_else(|| format!("{:?} is not a column name", word))
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+                number
+                    .parse()
+                    .map(Expr::Number)
+                    .map_err(|_| format!("{:?} is not a number", number))
+            }
+            Some(c) => Err(format!("unexpected {:?}", c)),
+            None => Err("unexpected end".to_string()),
+        }
+    }
+
+    fn take_while(&mut self, keep: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|&c| keep(c)) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
@@ -0,0 +1,166 @@
+//! Inbound frames, read ahead so superseded slice requests can be skipped.
+//!
+//! A slice request may carry a `coalesceKey`. Before handing out each frame
+//! the inbox takes whatever else the client has already sent, up to
+//! [`READ_AHEAD`] frames, and a keyed slice request with a later one of the
+//! same key waiting behind it is dropped unanswered: only the last viewport of
+//! a burst of scrolling gets built.
+
+use axum::extract::ws::Message;
+use futures_util::{FutureExt, Stream, StreamExt};
+use serde::Deserialize;
+use std::collections::VecDeque;
+
+/// Frames held beyond the one being handled.
+pub const READ_AHEAD: usize = 64;
+
+pub enum Inbound {
+    Frame(Result<Message, axum::Error>),
+    /// A slice request dropped for a later one with this key, with whether
+    /// the client asked to hear about it.
+    Superseded { coalesce_key: String, ack: bool },
+}
+
+pub struct Inbox<S> {
+    stream: S,
+    queued: VecDeque<Queued>,
+    ended: bool,
+}
+
+struct Queued {
+    frame: Result<Message, axum::Error>,
+    /// The key and `ackSuperseded` of a keyed slice request.
+    coalesce: Option<(String, bool)>,
+}
+
+/// The few fields of a frame needed to coalesce it.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Peek {
+    #[serde(rename = "type")]
+    kind: String,
+    coalesce_key: Option<String>,
+    #[serde(default)]
+    ack_superseded: bool,
+}
+
+impl<S> Inbox<S>
+where
+    S: Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            queued: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    /// The next frame to handle, or `None` once the client has gone. Only
+    /// waits while nothing is queued, so it is safe to cancel.
+    pub async fn next(&mut self) -> Option<Inbound> {
+        self.read_ready();
+        if self.queued.is_empty() && !self.ended {
+            match self.stream.next().await {
+                Some(frame) => self.enqueue(frame),
+                None => self.ended = true,
+            }
+            self.read_ready();
+        }
+        let Queued { frame, coalesce } = self.queued.pop_front()?;
+        let Some((coalesce_key, ack)) = coalesce else {
+            return Some(Inbound::Frame(frame));
+        };
+        let superseded = self.queued.iter().any(|later| {
+            later.coalesce.as_ref().is_some_and(|(key, _)| *key == coalesce_key)
+        });
+        Some(match superseded {
+            true => Inbound::Superseded { coalesce_key, ack },
+            false => Inbound::Frame(frame),
+        })
+    }
+
+    /// Queues frames that have already arrived, without waiting for more.
+    fn read_ready(&mut self) {
+        while self.queued.len() < READ_AHEAD && !self.ended {
+            match self.stream.next().now_or_never() {
+                Some(Some(frame)) => self.enqueue(frame),
+                Some(None) => self.ended = true,
+                None => break,
+            }
+        }
+    }
+
+    fn enqueue(&mut self, frame: Result<Message, axum::Error>) {
+        let coalesce = frame.as_ref().ok().and_then(coalesce_key);
+        self.queued.push_back(Queued { frame, coalesce });
+    }
+}
+
+/// The key of a keyed slice request, and its `ackSuperseded`.
+fn coalesce_key(msg: &Message) -> Option<(String, bool)> {
+    let bytes = match msg {
+        Message::Text(text) => text.as_bytes(),
+        Message::Binary(bytes) => bytes.as_slice(),
+        _ => return None,
+    };
+    // Keeps the extra parse off frames that cannot have a key.
+    if !bytes.windows(b"coalesceKey".len()).any(|window| window == b"coalesceKey") {
+        return None;
+    }
+    let peek: Peek = serde_json::from_slice(bytes).ok()?;
+    match peek.kind == "slice_request" {
+        true => Some((peek.coalesce_key?, peek.ack_superseded)),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slice(scroll_top: u64, key: &str, ack: bool) -> Result<Message, axum::Error> {
+        let msg = serde_json::json!({
+            "type": "slice_request",
+            "scrollTop": scroll_top,
+            "coalesceKey": key,
+            "ackSuperseded": ack,
+        });
+        Ok(Message::Text(msg.to_string()))
+    }
+
+    fn scroll_top(inbound: Inbound) -> u64 {
+        let Inbound::Frame(Ok(Message::Text(text))) = inbound else {
+            panic!("expected a text frame");
+        };
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        msg["scrollTop"].as_u64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_burst_of_one_key_is_answered_only_for_its_last_request() {
+        let burst = [slice(0, "grid", false), slice(20, "grid", false), slice(40, "grid", false)];
+        let mut inbox = Inbox::new(futures_util::stream::iter(burst));
+        for _ in 0..2 {
+            let next = inbox.next().await.unwrap();
+            assert!(matches!(next, Inbound::Superseded { ack: false, .. }));
+        }
+        assert_eq!(scroll_top(inbox.next().await.unwrap()), 40);
+        assert!(inbox.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_skipped_request_with_ack_superseded_says_so_and_other_keys_are_kept() {
+        let burst = [slice(0, "grid", true), slice(0, "minimap", false), slice(20, "grid", false)];
+        let mut inbox = Inbox::new(futures_util::stream::iter(burst));
+        match inbox.next().await.unwrap() {
+            Inbound::Superseded { coalesce_key, ack } => {
+                assert_eq!(coalesce_key, "grid");
+                assert!(ack);
+            }
+            Inbound::Frame(_) => panic!("expected the first grid request to be superseded"),
+        }
+        assert_eq!(scroll_top(inbox.next().await.unwrap()), 0);
+        assert_eq!(scroll_top(inbox.next().await.unwrap()), 20);
+    }
+}
@@ -0,0 +1,2977 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use futures_util::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpSocket};
+
+pub mod binary;
+pub mod cache;
+pub mod compress;
+pub mod config;
+pub mod demo;
+pub mod export;
+pub mod expr;
+pub mod format;
+pub mod formula;
+pub mod inbox;
+pub mod outbound;
+pub mod parquet_source;
+pub mod protocol;
+pub mod session;
+pub mod sizes;
+pub mod source;
+pub mod stats;
+pub mod stress;
+pub mod validate;
+pub mod view;
+pub mod xlsx;
+
+use cache::CellCache;
+use config::{Config, ConfigFlag, Settings};
+use expr::FilterExpr;
+use format::FormatRule;
+use formula::Formula;
+use inbox::{Inbound, Inbox};
+use demo::DemoAction;
+use outbound::{CloseReason, Outbound};
+use protocol::{
+    error_json, parse_client_message, AdminReset, CancelRequest, CancelResponse, Capability,
+    CellOriginal, CellRequest, CellResponse, CellSortKey, CellUpdate, CellValue, Cells, CellsMerged,
+    CellsUpdated, ClearFiltersRequest, ClientMessage, ColumnStatsRequest, ColumnStatsResponse,
+    ConditionalFormatSet, CurrentCell, Direction, DistinctValuesRequest, DistinctValuesResponse,
+    EchoRequest, EchoResponse, Encoding, ExportChunk, ExportFileDone, ExportProgress, ExportRequest,
+    ExportToFileRequest, ExtentRequest, ExtentResponse, FilterExprRequest, FilterInRequest,
+    HistoryResponse, JumpToCell, Limits, MergeCells, MetadataRequest, MetadataResponse,
+    MetadataUpdate, NeighborHint, NotModified, OnViewChange, Orientation, OverviewRequest,
+    OverviewResponse, PageRequest, PageResponse, PinColumnRequest, PinColumnResponse, PinnedCol,
+    ProtocolError, RangeRequest, RangeResponse, RangeSubscribed, RangeUnsubscribed, RevertCell,
+    RowsRequest, RowsResponse, Schema, ScrollTo, SelectColRequest, SelectColResponse,
+    SelectRowRequest, SelectRowResponse, ServerMessage, SetConditionalFormat, SliceEnd, SliceField,
+    SliceRequest, SliceResponse, SliceRow, SortRequest, StyledCell, SubscribeRange, Superseded,
+    TailRequest, TailResponse, TailRows, UnsubscribeRange, UpdateBatch, ViewChanged, ViewResponse,
+    Welcome,
+};
+use session::{CellRange, Edit, History, SessionState, Subscriptions, Tail};
+use sizes::Sizes;
+use source::DataSource;
+use stats::{ColumnStats, DistinctValues};
+use stress::StressConfig;
+use view::{BuiltRows, Filter, FilterOp, View, ViewSpec};
+
+/// State shared by every connection.
+pub struct AppState {
+    config: Config,
+    /// The reloadable part of `config`, as last loaded.
+    settings: RwLock<Settings>,
+    /// The `--config-file` flags as last loaded, to tell what a reload changed.
+    file_flags: Mutex<Vec<ConfigFlag>>,
+    source: Box<dyn DataSource>,
+    /// Recently read source values, when `--cell-cache-size` is set.
+    cell_cache: Option<CellCache>,
+    /// Cell values written by clients, layered over the source data.
+    overrides: RwLock<HashMap<(u64, u32), String>>,
+    /// Estimated size of `overrides`, updated under its write lock.
+    override_bytes: AtomicU64,
+    /// Version of every cell written since the last reset, also updated under
+    /// the overrides write lock. Cells never written are at version 0.
+    versions: Mutex<HashMap<(u64, u32), u64>>,
+    /// Source of versions; never reset, so a version is never handed out twice.
+    next_version: AtomicU64,
+    /// `--max-memory-mb` in bytes.
+    memory_budget: Option<u64>,
+    /// Merged cell ranges, none overlapping.
+    merges: RwLock<Vec<CellRange>>,
+    /// Bumped on every edit so cached results over the data can tell they are stale.
+    generation: AtomicU64,
+    /// Column stats per column and view generation, with the generation they
+    /// were computed at.
+    stats_cache: Mutex<HashMap<(u32, u64), (u64, ColumnStats)>>,
+    /// Distinct values per column, with the generation they were computed at.
+    distinct_cache: Mutex<HashMap<u32, (u64, DistinctValues)>>,
+    /// Open connections, for pushing broadcasts.
+    connections: Mutex<HashMap<u64, Connection>>,
+    next_connection_id: AtomicU64,
+}
+
+/// What a broadcast needs to know about a connection.
+struct Connection {
+    outbound: Outbound,
+    subscriptions: Subscriptions,
+    view: View,
+    history: History,
+    tail: Tail,
+}
+
+impl AppState {
+    pub fn new(config: Config, source: Box<dyn DataSource>) -> Self {
+        let cell_cache = match config.cell_cache_size {
+            0 => None,
+            size => Some(CellCache::new(size)),
+        };
+        let memory_budget = config.max_memory_mb.map(|mb| mb.saturating_mul(1024 * 1024));
+        if let (Some(cache), Some(budget)) = (&cell_cache, memory_budget) {
+            cache.set_byte_limit(budget);
+        }
+        Self {
+            settings: RwLock::new(Settings::from_config(&config)),
+            file_flags: Mutex::new(config.file_flags.clone()),
+            config,
+            source,
+            cell_cache,
+            overrides: RwLock::default(),
+            override_bytes: AtomicU64::default(),
+            versions: Mutex::default(),
+            next_version: AtomicU64::default(),
+            memory_budget,
+            merges: RwLock::default(),
+            generation: AtomicU64::default(),
+            stats_cache: Mutex::default(),
+            distinct_cache: Mutex::default(),
+            connections: Mutex::default(),
+            next_connection_id: AtomicU64::default(),
+        }
+    }
+
+    /// The current reloadable settings.
+    pub fn settings(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Reads `--config-file` again and applies its [`Settings`], logging
+    /// each change, and returns the changes. Other flags that changed in the
+    /// file are only logged, as they take a restart. A file that no longer
+    /// parses changes nothing.
+    pub fn reload_config(&self) -> Result<Vec<String>, String> {
+        let Some(path) = &self.config.config_file else {
+            return Err("there is no --config-file to reload".to_string());
+        };
+        let fresh = Config::from_args(self.config.args.clone())?;
+        let mut file_flags = self.file_flags.lock().unwrap();
+        for flag in config::restart_only_changes(&file_flags, &fresh.file_flags) {
+            tracing::warn!("{} changed in {}; it takes a restart to apply", flag, path);
+        }
+        *file_flags = fresh.file_flags.clone();
+        let settings = Settings::from_config(&fresh);
+        let changes = {
+            let mut current = self.settings.write().unwrap();
+            let changes = current.changes(&settings);
+            *current = settings;
+            changes
+        };
+        if !changes.is_empty() {
+            // Scans cached under the old caps and budgets are stale.
+            self.generation.fetch_add(1, Ordering::AcqRel);
+        }
+        for change in &changes {
+            tracing::info!("config reload: {}", change);
+        }
+        Ok(changes)
+    }
+
+    pub fn max_rows(&self) -> u64 {
+        self.source.row_count()
+    }
+
+    /// The source's width, widened to fit any formula column past its end.
+    pub fn max_cols(&self) -> u32 {
+        let formulas = self.config.formulas.iter().map(|formula| formula.col + 1);
+        formulas.fold(self.source.col_count(), u32::max)
+    }
+
+    fn formula(&self, col: u32) -> Option<&Formula> {
+        self.config.formulas.iter().find(|formula| formula.col == col)
+    }
+
+    /// The header label for `col`: its configured name, else its letters.
+    fn col_label(&self, col: u32) -> String {
+        match self.config.headers.get(col as usize) {
+            Some(name) => name.clone(),
+            None => col_index_to_letters(col),
+        }
+    }
+
+    /// The column a filter expression names: a header, compared without
+    /// case, else column letters.
+    fn col_by_name(&self, name: &str) -> Option<u32> {
+        let header = self.config.headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+        let col = header.map(|col| col as u32).or_else(|| formula::letters_to_col(name))?;
+        (col < self.max_cols()).then_some(col)
+    }
+
+    /// Adds a connection, or returns `None` when `--max-connections` are
+    /// already open.
+    fn register(&self, outbound: Outbound) -> Option<SessionState> {
+        let mut connections = self.connections.lock().unwrap();
+        if self.config.max_connections.is_some_and(|max| connections.len() >= max) {
+            return None;
+        }
+        let id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+        let session = SessionState::new(id, outbound);
+        let connection = Connection {
+            outbound: session.outbound.clone(),
+            subscriptions: session.subscriptions.clone(),
+            view: session.view.clone(),
+            history: session.history.clone(),
+            tail: session.tail.clone(),
+        };
+        connections.insert(id, connection);
+        Some(session)
+    }
+
+    fn unregister(&self, id: u64) {
+        self.connections.lock().unwrap().remove(&id);
+    }
+
+    /// Sends every open connection a close frame for `reason`.
+    fn close_all(&self, reason: CloseReason) {
+        for connection in self.connections.lock().unwrap().values() {
+            connection.outbound.close(reason);
+        }
+    }
+
+    /// Pushes changed cells to every connection except `from`, limited to
+    /// the ranges each has subscribed to. Connections that are behind drop the
+    /// push instead of holding up the sender.
+    fn broadcast(&self, from: u64, cells: &[CellValue]) {
+        for (id, connection) in self.connections.lock().unwrap().iter() {
+            if *id == from {
+                continue;
+            }
+            let cells = connection.subscriptions.select(cells);
+            if cells.is_empty() {
+                continue;
+            }
+            let text = ServerMessage::CellsUpdated(CellsUpdated { cells }).to_json();
+            connection.outbound.push(Message::Text(text));
+        }
+    }
+
+    /// Pushes rows `from..to`, just appended, to every connection following
+    /// the tail from the bottom of the table. Pins are ignored, as in streams.
+    fn push_tail(&self, from: u64, to: u64) {
+        let connections = self.connections.lock().unwrap();
+        let following: Vec<_> = connections
+            .values()
+            .filter(|connection| connection.view.rows().is_none())
+            .map(|connection| (connection.tail.get(), connection.outbound.clone()))
+            .filter(|(tail, _)| tail.enabled && tail.at_bottom)
+            .collect();
+        drop(connections);
+        let max_cells = self.settings().max_cells_per_slice;
+        for (tail, outbound) in following {
+            let new_rows = (to - from).min(MAX_ROWS_PER_RESPONSE as u64) as u32;
+            let col_count = tail.col_count.min(self.max_cols().saturating_sub(tail.start_col));
+            let (row_count, col_count, _) = clamp_to_cell_cap(new_rows, col_count, max_cells);
+            let start_row = to - row_count as u64;
+            let overrides = self.overrides.read().unwrap();
+            let cells_by_row = (start_row..to)
+                .map(|row| {
+                    let cols = tail.start_col..tail.start_col + col_count;
+                    cols.map(|col| self.cell_in(&overrides, row, col)).collect()
+                })
+                .collect();
+            drop(overrides);
+            let added = to.saturating_sub(tail.rows);
+            let msg = ServerMessage::TailRows(TailRows {
+                start_row,
+                start_col: tail.start_col,
+                cells_by_row,
+                max_rows: to,
+                height: tail.height + added * tail.row_height as u64,
+            });
+            outbound.push(Message::Text(msg.to_json()));
+        }
+    }
+
+    /// Every open connection's view, with where to tell it about changes.
+    fn views(&self) -> Vec<(View, Outbound)> {
+        let connections = self.connections.lock().unwrap();
+        let views = connections.values().map(|connection| {
+            (connection.view.clone(), connection.outbound.clone())
+        });
+        views.collect()
+    }
+
+    /// Pushes `text` to every connection except `from`, dropping it where a
+    /// connection is behind.
+    fn push_to_others(&self, from: u64, text: &str) {
+        for (id, connection) in self.connections.lock().unwrap().iter() {
+            if *id != from {
+                connection.outbound.push(Message::Text(text.to_string()));
+            }
+        }
+    }
+
+    /// Discards all edits, merges and every connection's filters and sorts, then tells
+    /// the other connections to start over.
+    fn reset(&self, from: u64) {
+        let mut overrides = self.overrides.write().unwrap();
+        overrides.clear();
+        self.override_bytes.store(0, Ordering::Relaxed);
+        self.versions.lock().unwrap().clear();
+        drop(overrides);
+        self.merges.write().unwrap().clear();
+        self.stats_cache.lock().unwrap().clear();
+        self.distinct_cache.lock().unwrap().clear();
+        if let Some(cache) = &self.cell_cache {
+            cache.clear();
+            cache.set_byte_limit(self.memory_budget.unwrap_or(u64::MAX));
+        }
+        // Moved on rather than back to 0, which results cached before the
+        // reset may still carry.
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        // Cleared with the registry unlocked, so no view or history lock is
+        // ever taken while holding it.
+        let connections: Vec<(View, History)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|connection| (connection.view.clone(), connection.history.clone()))
+            .collect();
+        for (view, history) in connections {
+            view.reset();
+            history.clear();
+        }
+        self.push_to_others(from, &ServerMessage::Reset.to_json());
+    }
+
+    /// Stats for `col` over the rows of a view's mapping, as read by
+    /// `View::current`, reusing the cached result while neither the mapping
+    /// nor any cell has changed since. `None` if the scan was cancelled.
+    fn column_stats(
+        &self,
+        col: u32,
+        (view_generation, view_rows): (u64, Option<Arc<Vec<u64>>>),
+        canceled: &AtomicBool,
+    ) -> Option<ColumnStats> {
+        let generation = self.generation.load(Ordering::Acquire);
+        let key = (col, view_generation);
+        if let Some((cached_at, stats)) = self.stats_cache.lock().unwrap().get(&key) {
+            if *cached_at == generation {
+                return Some(stats.clone());
+            }
+        }
+        let edits = self.edits_in(&self.with_formula_inputs(HashSet::from([col])));
+        let rows = view_rows.as_ref().map_or(self.max_rows(), |rows| rows.len() as u64);
+        let budget = self.settings().scan_budget;
+        let stats = stats::compute(rows, budget, canceled, |position| {
+            let row = view_rows.as_ref().map_or(position, |rows| rows[position as usize]);
+            self.cell_in(&edits, row, col)
+        })?;
+        let mut cache = self.stats_cache.lock().unwrap();
+        // Every filter change makes new keys; forget them all now and then.
+        if cache.len() >= STATS_CACHE_ENTRIES {
+            cache.clear();
+        }
+        cache.insert(key, (generation, stats.clone()));
+        Some(stats)
+    }
+
+    /// Distinct values of `col`, cached like the stats. `None` if the scan was
+    /// cancelled.
+    fn distinct_values(&self, col: u32, canceled: &AtomicBool) -> Option<DistinctValues> {
+        let generation = self.generation.load(Ordering::Acquire);
+        if let Some((cached_at, distinct)) = self.distinct_cache.lock().unwrap().get(&col) {
+            if *cached_at == generation {
+                return Some(distinct.clone());
+            }
+        }
+        let edits = self.edits_in(&self.with_formula_inputs(HashSet::from([col])));
+        let Settings { distinct_values_cap: cap, scan_budget: budget, .. } = self.settings();
+        let distinct = stats::distinct_values(self.max_rows(), cap, budget, canceled, |row| {
+            self.cell_in(&edits, row, col)
+        })?;
+        self.distinct_cache
+            .lock()
+            .unwrap()
+            .insert(col, (generation, distinct.clone()));
+        Some(distinct)
+    }
+
+    /// Physical ids of the rows passing every filter, in sort order, within
+    /// the scan budget. `None` if cancelled.
+    ///
+    /// The edits the scan can read are copied out first, so edits made
+    /// meanwhile are not held up behind it.
+    fn view_rows(&self, spec: &ViewSpec, canceled: &AtomicBool) -> Option<BuiltRows> {
+        let edits = self.edits_in(&self.with_formula_inputs(spec.cols()));
+        let budget = self.settings().scan_budget;
+        view::build_rows(spec, self.max_rows(), budget, canceled, |row, col| {
+            self.cell_in(&edits, row, col)
+        })
+    }
+
+    /// A copy of the edits in `cols`, for scans that should not hold up
+    /// writers while they run.
+    fn edits_in(&self, cols: &HashSet<u32>) -> HashMap<(u64, u32), String> {
+        self.overrides
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|((_, col), _)| cols.contains(col))
+            .map(|(key, value)| (*key, value.clone()))
+            .collect()
+    }
+
+    /// `cols` and every column their formulas read, directly or not.
+    fn with_formula_inputs(&self, mut cols: HashSet<u32>) -> HashSet<u32> {
+        let mut pending: Vec<u32> = cols.iter().copied().collect();
+        while let Some(col) = pending.pop() {
+            let inputs = self.formula(col).map(Formula::inputs).unwrap_or_default();
+            pending.extend(inputs.into_iter().filter(|&input| cols.insert(input)));
+        }
+        cols
+    }
+
+    /// Stores an edit over the source value, or drops it for `None`, and
+    /// returns the override it replaced. With an `expected_version` the write
+    /// only happens if the cell is still at that version; otherwise it fails
+    /// with `conflict`, carrying what the cell holds now. Values longer than
+    /// `--max-cell-length` are refused with `cell_too_long`.
+    ///
+    /// Under `--max-memory-mb` edits and the cell cache share the budget: the
+    /// cache gives up entries to make room first, and only an edit that would
+    /// not fit even with the cache empty is refused. The small per-column
+    /// stats cache is not counted.
+    fn set_override(
+        &self,
+        key: (u64, u32),
+        value: Option<String>,
+        expected_version: Option<u64>,
+    ) -> Result<Option<String>, ProtocolError> {
+        let max = self.config.max_cell_length;
+        if let Some(len) = value.as_ref().map(String::len).filter(|&len| len > max) {
+            return Err(ProtocolError::new(
+                "cell_too_long",
+                format!("the value is {} bytes, over the {} byte limit", len, max),
+            ));
+        }
+        let mut overrides = self.overrides.write().unwrap();
+        self.store_override(&mut overrides, key, value, expected_version)
+    }
+
+    /// [`AppState::set_override`] past the length check, for callers already
+    /// holding the overrides for writing.
+    fn store_override(
+        &self,
+        overrides: &mut HashMap<(u64, u32), String>,
+        key: (u64, u32),
+        value: Option<String>,
+        expected_version: Option<u64>,
+    ) -> Result<Option<String>, ProtocolError> {
+        let version = self.versions.lock().unwrap().get(&key).copied().unwrap_or(0);
+        if let Some(expected) = expected_version.filter(|&expected| expected != version) {
+            let message = format!("the cell is at version {}, not {}", version, expected);
+            return Err(ProtocolError::new("conflict", message).with_current(CurrentCell {
+                value: self.cell_in(overrides, key.0, key.1),
+                version,
+            }));
+        }
+        let bytes = self.override_bytes_with(overrides, key, value.as_deref());
+        if let Some(budget) = self.memory_budget.filter(|&budget| bytes > budget) {
+            return Err(memory_limit(bytes, budget));
+        }
+        Ok(self.put_override(overrides, key, value))
+    }
+
+    /// What the overrides would take in bytes with `value` stored at `key`.
+    fn override_bytes_with(
+        &self,
+        overrides: &HashMap<(u64, u32), String>,
+        key: (u64, u32),
+        value: Option<&str>,
+    ) -> u64 {
+        let old = overrides.get(&key).map_or(0, |old| override_bytes(old));
+        let new = value.map_or(0, override_bytes);
+        self.override_bytes.load(Ordering::Relaxed) - old + new
+    }
+
+    /// Stores or drops an edit that has already passed the version and
+    /// budget checks, keeping the byte count, versions and cell cache in step.
+    /// Versions only change under the overrides' write lock, which the caller
+    /// holds, so the check and the store cannot be split by another writer.
+    fn put_override(
+        &self,
+        overrides: &mut HashMap<(u64, u32), String>,
+        key: (u64, u32),
+        value: Option<String>,
+    ) -> Option<String> {
+        let bytes = self.override_bytes_with(overrides, key, value.as_deref());
+        if let (Some(budget), Some(cache)) = (self.memory_budget, &self.cell_cache) {
+            cache.set_byte_limit(budget.saturating_sub(bytes));
+        }
+        let replaced = match value {
+            Some(value) => overrides.insert(key, value),
+            None => overrides.remove(&key),
+        };
+        self.override_bytes.store(bytes, Ordering::Relaxed);
+        let version = self.next_version.fetch_add(1, Ordering::Relaxed) + 1;
+        self.versions.lock().unwrap().insert(key, version);
+        if let Some(cache) = &self.cell_cache {
+            cache.invalidate(key);
+        }
+        replaced
+    }
+
+    /// Refuses a batch of edits with `memory_limit` unless all of them fit the
+    /// budget together, so that none is stored when the last would not be.
+    /// Each cell may appear only once.
+    fn check_batch_budget<'a>(
+        &self,
+        overrides: &HashMap<(u64, u32), String>,
+        edits: impl Iterator<Item = ((u64, u32), &'a str)>,
+    ) -> Result<(), ProtocolError> {
+        let Some(budget) = self.memory_budget else {
+            return Ok(());
+        };
+        let mut bytes = self.override_bytes.load(Ordering::Relaxed);
+        for (key, value) in edits {
+            bytes -= overrides.get(&key).map_or(0, |old| override_bytes(old));
+            bytes += override_bytes(value);
+        }
+        match bytes > budget {
+            true => Err(memory_limit(bytes, budget)),
+            false => Ok(()),
+        }
+    }
+
+    /// The source's value, with `--missing-value` standing in where it has none.
+    fn source_cell(&self, row: u64, col: u32) -> String {
+        self.source_value(row, col).unwrap_or_else(|| self.config.missing_value.clone())
+    }
+
+    /// The source's value, or `None` where it has none.
+    fn source_value(&self, row: u64, col: u32) -> Option<String> {
+        // Formula columns can reach past the source.
+        if col >= self.source.col_count() {
+            return None;
+        }
+        match &self.cell_cache {
+            Some(cache) => cache.get_or_load((row, col), || self.source.cell(row, col)),
+            None => self.source.cell(row, col),
+        }
+    }
+
+    fn version(&self, row: u64, col: u32) -> u64 {
+        self.versions.lock().unwrap().get(&(row, col)).copied().unwrap_or(0)
+    }
+
+    fn cell(&self, row: u64, col: u32) -> String {
+        self.cell_in(&self.overrides.read().unwrap(), row, col)
+    }
+
+    /// A cell as clients see it, for callers already holding the overrides:
+    /// a formula's result, else the edit, else the source value.
+    fn cell_in(&self, overrides: &HashMap<(u64, u32), String>, row: u64, col: u32) -> String {
+        let value = self.value_in(overrides, row, col);
+        value.unwrap_or_else(|| self.config.missing_value.clone())
+    }
+
+    /// Like [`cell_in`](Self::cell_in), but `None` for a cell with no
+    /// formula, edit or source value rather than `--missing-value`.
+    fn value_in(
+        &self,
+        overrides: &HashMap<(u64, u32), String>,
+        row: u64,
+        col: u32,
+    ) -> Option<String> {
+        if let Some(formula) = self.formula(col) {
+            return Some(formula.eval(|input| self.cell_in(overrides, row, input)));
+        }
+        match overrides.get(&(row, col)) {
+            Some(value) => Some(value.clone()),
+            None => self.source_value(row, col),
+        }
+    }
+}
+
+// Size of the synthetic table served when no data is supplied
+pub const SERVER_MAX_ROWS: u64 = 10_000_000;
+pub const SERVER_MAX_COLS: u32 = 1_000;
+
+// Safety caps for PoC
+const MAX_ROWS_PER_RESPONSE: u32 = 1000;
+const MAX_COLS_PER_RESPONSE: u32 = 200;
+/// Column stats kept before the cache is emptied.
+const STATS_CACHE_ENTRIES: usize = 1024;
+/// Rows a `select_col_request` returns before it is cut short.
+const MAX_SELECTED_COL_ROWS: u64 = 10_000;
+
+/// Slice offsets up to this many times the table's pixel extent are clamped;
+/// beyond that they are treated as a client bug.
+const SCROLL_RANGE_FACTOR: u64 = 4;
+
+/// Like `TcpListener::bind`, but with our own accept backlog so a burst of
+/// clients connecting at once is queued rather than refused. Accept errors
+/// are logged by `axum::serve`, which then keeps going.
+pub fn bind_listener(addr: SocketAddr, backlog: u32) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// The HTTP app serving the spreadsheet socket at `/ws`, plus `/ws-stress`
+/// when `--stress-rate` is set.
+pub fn router(state: Arc<AppState>) -> Router {
+    let mut router = Router::new().route("/ws", get(ws_handler));
+    if state.config.stress_rate > 0 {
+        router = router.route("/ws-stress", get(ws_stress_handler));
+    }
+    router.with_state(state)
+}
+
+/// How often a still-loading table's row count is checked.
+const ROW_COUNT_WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// While the source is still loading, pushes `metadata_update` to every
+/// connection each time its row count has grown, and once more when it is
+/// complete. Rows appended from the moment it is called are pushed to tails.
+pub fn watch_row_count(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    let mut tailed = state.max_rows();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(ROW_COUNT_WATCH_INTERVAL);
+        let mut last = None;
+        loop {
+            ticker.tick().await;
+            // Checked first so the count read after it is final when complete.
+            let complete = state.source.is_complete();
+            let max_rows = state.max_rows();
+            if complete || last != Some(max_rows) {
+                // Cached stats and distinct values no longer cover every row.
+                state.generation.fetch_add(1, Ordering::AcqRel);
+                let msg = ServerMessage::MetadataUpdate(MetadataUpdate {
+                    max_rows,
+                    approximate: !complete,
+                });
+                // Ids count up from 0, so this skips nobody.
+                state.push_to_others(u64::MAX, &msg.to_json());
+                last = Some(max_rows);
+            }
+            if tailed < max_rows {
+                state.push_tail(tailed, max_rows);
+                tailed = max_rows;
+            }
+            if complete {
+                return;
+            }
+        }
+    })
+}
+
+/// Reloads `--config-file` on every `SIGHUP`. A file that fails to parse is
+/// logged and the running settings are kept.
+#[cfg(unix)]
+pub fn reload_on_hangup(state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = signal(SignalKind::hangup()).expect("install SIGHUP handler");
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match state.reload_config() {
+                Ok(changes) if changes.is_empty() => tracing::info!("config reload: no changes"),
+                Ok(_) => {}
+                Err(err) => tracing::error!("config reload failed: {}", err),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_hangup(_state: Arc<AppState>) {
+    tracing::warn!("--config-file is only reloaded on SIGHUP, which this platform lacks");
+}
+
+/// Plays `--demo-script`, running each step `atMs` after this is called.
+/// A step that cannot be applied is logged and skipped.
+pub fn run_demo_script(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now();
+        for step in &state.config.demo_script {
+            tokio::time::sleep_until(start + Duration::from_millis(step.at_ms)).await;
+            if let Err(err) = apply_demo_action(&state, &step.action) {
+                tracing::warn!("demo script step at {}ms: {}", step.at_ms, err.message);
+            }
+        }
+    })
+}
+
+/// Applies one step: an edit as if from a client every connection hears
+/// from, or a view change to every open session.
+fn apply_demo_action(state: &Arc<AppState>, action: &DemoAction) -> Result<(), ProtocolError> {
+    let (max_rows, max_cols) = (state.max_rows(), state.max_cols());
+    match action {
+        DemoAction::Edit { row, col, value } => {
+            check_writable(state)?;
+            validate_coord(*row, *col, max_rows, max_cols)?;
+            if state.formula(*col).is_some() {
+                return Err(ProtocolError::new(
+                    "bad_request",
+                    format!("column {} is computed by a formula", state.col_label(*col)),
+                ));
+            }
+            let formulas = formula_values(state, *row, *col);
+            state.set_override((*row, *col), Some(value.clone()), None)?;
+            // Ids count up from 0, so every connection hears of it.
+            publish_edit(state, u64::MAX, *row, *col, formulas);
+        }
+        DemoAction::Sort { keys } => {
+            for key in keys {
+                validate_coord(0, key.col, max_rows, max_cols)?;
+            }
+            change_every_view(state, |view| Some(view.set_sort(keys.clone())));
+        }
+        DemoAction::Filter(filter) => {
+            validate_coord(0, filter.col, max_rows, max_cols)?;
+            let limit = state.config.max_filters;
+            change_every_view(state, |view| view.set_filter(filter.clone(), limit));
+        }
+        DemoAction::ClearFilters { col } => {
+            change_every_view(state, |view| Some(view.clear_filters(*col)));
+        }
+    }
+    Ok(())
+}
+
+/// Applies `change` to every open session's view and rebuilds each, answering
+/// with a `view_response` as a client's own change would be. Views `change`
+/// leaves alone, returning `None`, are skipped.
+fn change_every_view(state: &Arc<AppState>, change: impl Fn(&View) -> Option<(u64, ViewSpec)>) {
+    for (view, outbound) in state.views() {
+        let Some((revision, spec)) = change(&view) else {
+            continue;
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let built = rebuild_view(state.clone(), &view, revision, spec, Arc::default()).await;
+            let resp = view_response(&state, &view, None, !built);
+            outbound.send(Message::Text(resp.to_json())).await;
+        });
+    }
+}
+
+/// Who is on the other end of a socket, for the connection log.
+struct Peer {
+    addr: SocketAddr,
+    user_agent: String,
+}
+
+impl Peer {
+    fn new(addr: SocketAddr, headers: &HeaderMap) -> Self {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("-")
+            .to_string();
+        Self { addr, user_agent }
+    }
+}
+
+/// Largest frame or message accepted from a client.
+const MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Needs the router served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let peer = Peer::new(addr, &headers);
+    // Axum 0.7 does not expose a direct API to select permessage-deflate here.
+    // However, most browsers will negotiate permessage-deflate automatically if
+    // the server's tungstenite backend is built with compression (Axum enables it internally).
+    // We also raise frame/message limits.
+    ws.max_message_size(MAX_MESSAGE_BYTES)
+        .max_frame_size(MAX_MESSAGE_BYTES)
+        .on_upgrade(move |socket| handle_socket(socket, state, peer, None))
+}
+
+/// A normal spreadsheet socket that also receives a stream of made-up edits.
+async fn ws_stress_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let peer = Peer::new(addr, &headers);
+    let stress = StressConfig {
+        rate: state.config.stress_rate,
+        rows: state.config.stress_rows.min(state.max_rows()),
+        cols: state.config.stress_cols.min(state.max_cols()),
+    };
+    ws.max_message_size(MAX_MESSAGE_BYTES)
+        .max_frame_size(MAX_MESSAGE_BYTES)
+        .on_upgrade(move |socket| handle_socket(socket, state, peer, Some(stress)))
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: Arc<AppState>,
+    peer: Peer,
+    stress: Option<StressConfig>,
+) {
+    let subprotocol = socket.protocol().and_then(|protocol| protocol.to_str().ok());
+    let welcome = state.config.welcome.then(|| welcome(&state, subprotocol.map(String::from)));
+    let (sink, stream) = socket.split();
+    let mut inbox = Inbox::new(stream);
+    let (outbound, mut writer) =
+        outbound::spawn_with(sink, state.config.flush_policy, state.config.reconnect_delay);
+    let Some(mut session) = state.register(outbound.clone()) else {
+        outbound.close(CloseReason::Overloaded);
+        let _ = writer.await;
+        return;
+    };
+    tracing::info!(
+        "connection {} opened from {} ({})",
+        session.id,
+        peer.addr,
+        peer.user_agent
+    );
+    if let Some(welcome) = welcome {
+        outbound.send(Message::Text(welcome.to_json())).await;
+    }
+    let stress = stress.map(|stress| stress::spawn(outbound.clone(), stress, session.id));
+    loop {
+        let msg_result = tokio::select! {
+            inbound = inbox.next() => match inbound {
+                Some(Inbound::Frame(frame)) => frame,
+                Some(Inbound::Superseded { coalesce_key, ack }) => {
+                    let msg = ServerMessage::Superseded(Superseded { coalesce_key });
+                    if ack && !outbound.send(Message::Text(msg.to_json())).await {
+                        break;
+                    }
+                    continue;
+                }
+                None => break,
+            },
+            // The writer stops when the client goes away or is closed as a slow consumer.
+            _ = &mut writer => break,
+        };
+        let reply = match msg_result {
+            Ok(Message::Text(txt)) => handle_frame(&state, &mut session, txt.as_bytes()),
+            // Some clients ship JSON in binary frames; decode them the same way.
+            Ok(Message::Binary(bytes)) => handle_frame(&state, &mut session, &bytes),
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        if let Some(reply) = reply {
+            if !outbound.send(reply).await {
+                break;
+            }
+        }
+    }
+    state.unregister(session.id);
+    tracing::info!("connection {} from {} closed", session.id, peer.addr);
+    if let Some(stress) = stress {
+        stress.abort();
+    }
+    writer.abort();
+}
+
+/// How long [`close_all_connections`] waits for clients to go.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Tells every connection the server is shutting down, with a reconnect
+/// delay, and waits up to [`SHUTDOWN_GRACE`] for them all to close.
+pub async fn close_all_connections(state: &AppState) {
+    state.close_all(CloseReason::ShuttingDown);
+    let deadline = tokio::time::Instant::now() + SHUTDOWN_GRACE;
+    while !state.connections.lock().unwrap().is_empty() {
+        if tokio::time::Instant::now() >= deadline {
+            tracing::warn!("shutting down with connections still open");
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Whether a `metadata_request` may negotiate `cap`.
+fn supports(state: &AppState, cap: Capability) -> bool {
+    match cap {
+        Capability::Binary => true,
+        Capability::Gzip => state.config.compress_min_bytes.is_some(),
+        Capability::Unknown => false,
+    }
+}
+
+fn welcome(state: &AppState, subprotocol: Option<String>) -> ServerMessage {
+    ServerMessage::Welcome(Welcome {
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        subprotocol,
+        max_rows: state.max_rows(),
+        max_cols: state.max_cols(),
+        capabilities: [Capability::Binary, Capability::Gzip]
+            .into_iter()
+            .filter(|&cap| supports(state, cap))
+            .collect(),
+        read_only: state.settings().read_only,
+        limits: limits(state),
+    })
+}
+
+fn limits(state: &AppState) -> Limits {
+    let settings = state.settings();
+    Limits {
+        max_rows_per_response: MAX_ROWS_PER_RESPONSE,
+        max_cols_per_response: MAX_COLS_PER_RESPONSE,
+        max_cells_per_slice: settings.max_cells_per_slice,
+        max_buffer: settings.max_buffer,
+        max_filters: state.config.max_filters,
+        max_sort_keys: state.config.max_sort_keys,
+        max_inflight: state.config.max_inflight,
+        max_pinned_cols: sizes::MAX_PINNED_COLS,
+        max_cell_length: state.config.max_cell_length,
+        max_export_cells: state.config.max_export_cells,
+        max_selected_col_rows: MAX_SELECTED_COL_ROWS,
+        distinct_values_cap: settings.distinct_values_cap,
+        scan_budget: settings.scan_budget,
+        source_timeout_ms: settings.source_timeout.map(|timeout| timeout.as_millis() as u64),
+        max_message_bytes: MAX_MESSAGE_BYTES,
+        queue_capacity: outbound::QUEUE_CAPACITY,
+        slow_consumer_drop_limit: outbound::SLOW_CONSUMER_DROP_LIMIT,
+        compress_min_bytes: state.config.compress_min_bytes,
+        max_connections: state.config.max_connections,
+    }
+}
+
+/// Parses one inbound frame and returns the reply to send.
+///
+/// Bytes that are not UTF-8 get `invalid_utf8`; everything else is sorted by
+/// [`parse_client_message`]. Replies are JSON text frames, except slices for
+/// a client that negotiated `binary`.
+///
+/// Long operations run in the background and send their own reply later, in
+/// which case this returns `None`.
+pub fn handle_frame(state: &Arc<AppState>, session: &mut SessionState, bytes: &[u8]) -> Option<Message> {
+    let msg = if bytes.starts_with(binary::UPDATE_BATCH_MAGIC) {
+        match binary::decode_update_batch(bytes) {
+            Ok(batch) => ClientMessage::CellUpdateBatch(batch),
+            Err(err) => return Some(Message::Text(error_json("bad_request", &err))),
+        }
+    } else {
+        let txt = match std::str::from_utf8(bytes) {
+            Ok(txt) => txt,
+            Err(_) => return Some(Message::Text(error_json("invalid_utf8", "invalid utf-8"))),
+        };
+        match parse_client_message(txt) {
+            Ok(msg) => msg,
+            Err(err) => return Some(Message::Text(err.to_json())),
+        }
+    };
+    if msg.runs_in_background() && session.inflight.count() >= state.config.max_inflight {
+        let err = ProtocolError::new(
+            "too_many_inflight",
+            format!("at most {} operations may run at once", state.config.max_inflight),
+        );
+        return Some(Message::Text(err.to_json()));
+    }
+    let result = match msg {
+        ClientMessage::MetadataRequest(req) => handle_metadata(state, session, req),
+        ClientMessage::EchoRequest(req) => Ok(handle_echo(req)),
+        ClientMessage::LimitsRequest => Ok(ServerMessage::LimitsResponse(limits(state))),
+        ClientMessage::SliceRequest(req) => {
+            return handle_slice(state, session, req)
+                .unwrap_or_else(|err| Some(Message::Text(err.to_json())))
+        }
+        ClientMessage::ExtentRequest(req) => extent_response(state, session, &req),
+        ClientMessage::SetRowHeight(req) => {
+            set_size(state, session, Some(req.row), None, req.height)
+        }
+        ClientMessage::SetColWidth(req) => {
+            set_size(state, session, None, Some(req.col), req.width)
+        }
+        ClientMessage::PinColumnRequest(req) => pin_column(state, session, &req),
+        ClientMessage::TailRequest(req) => Ok(tail(session, &req)),
+        ClientMessage::CellRequest(req) => handle_cell(state, req),
+        ClientMessage::RangeRequest(req) => {
+            make_range_response(state, &req).map(ServerMessage::RangeResponse)
+        }
+        ClientMessage::ColumnStatsRequest(req) => {
+            return in_background(handle_column_stats(state, session, req))
+        }
+        ClientMessage::DistinctValuesRequest(req) => {
+            return in_background(handle_distinct_values(state, session, req))
+        }
+        ClientMessage::OverviewRequest(req) => {
+            return in_background(handle_overview(state, session, req))
+        }
+        ClientMessage::ExportRequest(req) => {
+            return in_background(handle_export(state, session, req))
+        }
+        ClientMessage::ExportToFileRequest(req) => {
+            return in_background(handle_export_to_file(state, session, req))
+        }
+        ClientMessage::CancelRequest(req) => Ok(handle_cancel(session, req)),
+        ClientMessage::CellUpdate(req) => apply_cell_update(state, session, req),
+        ClientMessage::RevertCell(req) => revert_cell(state, session, &req),
+        ClientMessage::CellUpdateBatch(req) => apply_update_batch(state, session, req),
+        ClientMessage::Undo => step_history(state, session, true),
+        ClientMessage::Redo => step_history(state, session, false),
+        ClientMessage::SubscribeRange(req) => handle_subscribe_range(state, session, req),
+        ClientMessage::FilterRequest(req) => {
+            return in_background(set_filter(state, session, req.filter, req.request_id))
+        }
+        ClientMessage::FilterInRequest(req) => {
+            return in_background(handle_filter_in(state, session, req))
+        }
+        ClientMessage::FilterExprRequest(req) => {
+            return in_background(handle_filter_expr(state, session, req))
+        }
+        ClientMessage::ClearFiltersRequest(req) => {
+            handle_clear_filters(state, session, req);
+            return None;
+        }
+        ClientMessage::SortRequest(req) => return in_background(handle_sort(state, session, req)),
+        ClientMessage::RowsRequest(req) => {
+            make_rows_response(state, session, &req).map(ServerMessage::RowsResponse)
+        }
+        ClientMessage::PageRequest(req) => {
+            make_page_response(state, session, &req).map(ServerMessage::PageResponse)
+        }
+        ClientMessage::JumpToCell(req) => {
+            jump_to_cell(state, session, &req).map(ServerMessage::ScrollTo)
+        }
+        ClientMessage::SelectRowRequest(req) => {
+            select_row(state, session, &req).map(ServerMessage::SelectRowResponse)
+        }
+        ClientMessage::SelectColRequest(req) => {
+            select_col(state, session, &req).map(ServerMessage::SelectColResponse)
+        }
+        ClientMessage::SetConditionalFormat(req) => Ok(handle_set_conditional_format(session, req)),
+        ClientMessage::MergeCells(req) => merge_cells(state, session, req),
+        ClientMessage::AdminReset(req) => handle_admin_reset(state, session, req),
+        ClientMessage::UnsubscribeRange(req) => Ok(handle_unsubscribe_range(session, req)),
+    };
+    Some(Message::Text(match result {
+        Ok(msg) => msg.to_json(),
+        Err(err) => err.to_json(),
+    }))
+}
+
+/// The reply for a handler that started background work: nothing now if it
+/// did, since the work replies when it finishes, or the error if it did not.
+fn in_background(started: Result<(), ProtocolError>) -> Option<Message> {
+    started.err().map(|err| Message::Text(err.to_json()))
+}
+
+/// Negotiates capabilities and describes the table.
+fn handle_metadata(
+    state: &AppState,
+    session: &mut SessionState,
+    req: MetadataRequest,
+) -> Result<ServerMessage, ProtocolError> {
+    // Refused before the capabilities change, so they stay as they were.
+    if let Some(width) = req.char_width {
+        if !(width.is_finite() && width > 0.0) {
+            return Err(ProtocolError::new("bad_request", "charWidth must be positive"));
+        }
+    }
+    session.capabilities.clear();
+    for cap in req.capabilities {
+        if supports(state, cap) && !session.capabilities.contains(&cap) {
+            session.capabilities.push(cap);
+        }
+    }
+    let gzip = session.capabilities.contains(&Capability::Gzip);
+    session
+        .outbound
+        .set_compression(state.config.compress_min_bytes.filter(|_| gzip));
+    Ok(ServerMessage::MetadataResponse(MetadataResponse {
+        max_rows: state.max_rows(),
+        approximate: !state.source.is_complete(),
+        max_cols: state.max_cols(),
+        col_names: state.config.headers.clone(),
+        sheets: state.config.sheets.clone(),
+        cell_cache: state.cell_cache.as_ref().map(CellCache::counters),
+        capabilities: session.capabilities.clone(),
+        suggested_col_widths: req
+            .char_width
+            .map_or_else(Vec::new, |width| suggest_col_widths(state, width)),
+        ragged_rows: Some(state.source.ragged_rows()).filter(|&rows| rows > 0),
+        col_types: state.source.col_types(),
+        sort: session.view.sorted_by(),
+    }))
+}
+
+fn handle_echo(req: EchoRequest) -> ServerMessage {
+    ServerMessage::EchoResponse(EchoResponse {
+        payload: req.payload,
+        server_time_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64),
+    })
+}
+
+/// The slice frame for a viewport: JSON or binary, or `None` when a
+/// `--source-timeout-ms` sends it from the background instead.
+fn handle_slice(
+    state: &Arc<AppState>,
+    session: &mut SessionState,
+    mut req: SliceRequest,
+) -> Result<Option<Message>, ProtocolError> {
+    // Clamped before anything is sized from them.
+    let settings = state.settings();
+    req.vertical_buffer = req.vertical_buffer.min(settings.max_buffer);
+    req.horizontal_buffer = req.horizontal_buffer.min(settings.max_buffer);
+    validate_slice_request(state, session, &req)?;
+    session.sizes.defaults = Some((req.default_row_height, req.default_column_width));
+    if session.tail.get().enabled {
+        track_tail(state, session, &req);
+    }
+    if req.stream_viewport {
+        spawn_slice_stream(state.clone(), session, req);
+        return Ok(None);
+    }
+    let (generation, view_rows) = session.view.current();
+    if req.on_view_change == OnViewChange::Reject
+        && req.view_generation.is_some_and(|expected| expected != generation)
+    {
+        let msg = ServerMessage::ViewChanged(ViewChanged {
+            view_generation: generation,
+            visible_rows: view_rows.map_or(state.max_rows(), |rows| rows.len() as u64),
+        });
+        return Ok(Some(Message::Text(msg.to_json())));
+    }
+    let binary = wants_binary(session, &req);
+    if let Some(timeout) = settings.source_timeout {
+        spawn_slice(state.clone(), session, req, (generation, view_rows), binary, timeout);
+        return Ok(None);
+    }
+    let view_rows = view_rows.as_deref().map(Vec::as_slice);
+    let (rules, sizes) = (&session.format_rules, &session.sizes);
+    let slice = make_slice_response(state, view_rows, rules, sizes, &req);
+    Ok(Some(slice_message(slice, &req, generation, binary)))
+}
+
+fn handle_cell(state: &AppState, req: CellRequest) -> Result<ServerMessage, ProtocolError> {
+    validate_coord(req.row, req.col, state.max_rows(), state.max_cols())?;
+    Ok(ServerMessage::CellResponse(CellResponse {
+        row: req.row,
+        col: req.col,
+        value: state.cell(req.row, req.col),
+        version: state.version(req.row, req.col),
+    }))
+}
+
+fn handle_column_stats(
+    state: &Arc<AppState>,
+    session: &SessionState,
+    req: ColumnStatsRequest,
+) -> Result<(), ProtocolError> {
+    validate_coord(0, req.col, state.max_rows(), state.max_cols())?;
+    spawn_column_stats(state.clone(), session, req);
+    Ok(())
+}
+
+fn handle_distinct_values(
+    state: &Arc<AppState>,
+    session: &SessionState,
+    req: DistinctValuesRequest,
+) -> Result<(), ProtocolError> {
+    validate_coord(0, req.col, state.max_rows(), state.max_cols())?;
+    spawn_distinct_values(state.clone(), session, req);
+    Ok(())
+}
+
+fn handle_overview(
+    state: &Arc<AppState>,
+    session: &SessionState,
+    req: OverviewRequest,
+) -> Result<(), ProtocolError> {
+    if !(1..=MAX_OVERVIEW_BUCKETS).contains(&req.buckets) {
+        return Err(ProtocolError::new(
+            "bad_request",
+            format!("buckets must be between 1 and {}", MAX_OVERVIEW_BUCKETS),
+        ));
+    }
+    validate_coord(0, req.start_col, state.max_rows(), state.max_cols())?;
+    let col_count = req.col_count.unwrap_or(state.max_cols() - req.start_col);
+    let last_col = (req.start_col as u64 + col_count as u64).saturating_sub(1);
+    validate_coord(0, last_col.min(u32::MAX as u64) as u32, state.max_rows(), state.max_cols())?;
+    spawn_overview(state.clone(), session, req, col_count);
+    Ok(())
+}
+
+fn handle_export(
+    state: &Arc<AppState>,
+    session: &SessionState,
+    req: ExportRequest,
+) -> Result<(), ProtocolError> {
+    validate_export(state, &req)?;
+    spawn_export(state.clone(), session, req);
+    Ok(())
+}
+
+fn handle_export_to_file(
+    state: &Arc<AppState>,
+    session: &SessionState,
+    req: ExportToFileRequest,
+) -> Result<(), ProtocolError> {
+    let path = export_file_path(state, &req)?;
+    spawn_file_export(state.clone(), session, req, path);
+    Ok(())
+}
+
+fn handle_cancel(session: &SessionState, req: CancelRequest) -> ServerMessage {
+    ServerMessage::CancelResponse(CancelResponse {
+        found: session.inflight.cancel(&req.request_id),
+        request_id: req.request_id,
+    })
+}
+
+fn handle_subscribe_range(
+    state: &AppState,
+    session: &SessionState,
+    req: SubscribeRange,
+) -> Result<ServerMessage, ProtocolError> {
+    validate_coord(req.start_row, req.start_col, state.max_rows(), state.max_cols())?;
+    let range = CellRange {
+        start_row: req.start_row,
+        start_col: req.start_col,
+        row_count: req.row_count,
+        col_count: req.col_count,
+    };
+    session.subscriptions.subscribe(req.subscription_id.clone(), range);
+    Ok(ServerMessage::RangeSubscribed(RangeSubscribed {
+        subscription_id: req.subscription_id,
+    }))
+}
+
+fn handle_unsubscribe_range(session: &SessionState, req: UnsubscribeRange) -> ServerMessage {
+    ServerMessage::RangeUnsubscribed(RangeUnsubscribed {
+        found: session.subscriptions.unsubscribe(&req.subscription_id),
+        subscription_id: req.subscription_id,
+    })
+}
+
+fn handle_filter_in(
+    state: &Arc<AppState>,
+    session: &SessionState,
+    req: FilterInRequest,
+) -> Result<(), ProtocolError> {
+    let filter = Filter {
+        col: req.col,
+        op: FilterOp::In,
+        value: String::new(),
+        values: req.values,
+        case_insensitive: false,
+        regex: None,
+    };
+    set_filter(state, session, filter, req.request_id)
+}
+
+/// Parses the expression against the column names and rebuilds the view;
+/// a blank one drops it.
+fn handle_filter_expr(
+    state: &Arc<AppState>,
+    session: &SessionState,
+    req: FilterExprRequest,
+) -> Result<(), ProtocolError> {
+    let expr = match req.expr.trim().is_empty() {
+        true => None,
+        false => Some(
+            FilterExpr::parse(&req.expr, |name| state.col_by_name(name))
+                .map_err(|err| ProtocolError::new("bad_request", err.to_string()))?,
+        ),
+    };
+    let (revision, spec) = session.view.set_expr(expr);
+    spawn_view_rebuild(state.clone(), session, req.request_id, revision, spec, None);
+    Ok(())
+}
+
+fn handle_clear_filters(state: &Arc<AppState>, session: &SessionState, req: ClearFiltersRequest) {
+    let (revision, spec) = session.view.clear_filters(req.col);
+    spawn_view_rebuild(state.clone(), session, req.request_id, revision, spec, None);
+}
+
+/// Replaces the sort, within `--max-sort-keys`, and rebuilds the view.
+fn handle_sort(
+    state: &Arc<AppState>,
+    session: &SessionState,
+    req: SortRequest,
+) -> Result<(), ProtocolError> {
+    if req.keys.len() > state.config.max_sort_keys {
+        return Err(ProtocolError::new(
+            "too_many_sort_keys",
+            format!("at most {} sort keys are allowed", state.config.max_sort_keys),
+        ));
+    }
+    for key in &req.keys {
+        validate_coord(0, key.col, state.max_rows(), state.max_cols())?;
+    }
+    let (revision, spec) = session.view.set_sort(req.keys);
+    spawn_view_rebuild(state.clone(), session, req.request_id, revision, spec, req.follow_row);
+    Ok(())
+}
+
+fn handle_set_conditional_format(
+    session: &mut SessionState,
+    req: SetConditionalFormat,
+) -> ServerMessage {
+    session.format_rules = req.rules;
+    ServerMessage::ConditionalFormatSet(ConditionalFormatSet {
+        rule_count: session.format_rules.len(),
+    })
+}
+
+fn handle_admin_reset(
+    state: &AppState,
+    session: &SessionState,
+    req: AdminReset,
+) -> Result<ServerMessage, ProtocolError> {
+    check_admin(state, &req.token)?;
+    state.reset(session.id);
+    Ok(ServerMessage::Reset)
+}
+
+/// Scans the column on the blocking pool so the connection keeps reading
+/// (and can receive a `cancel_request`) while it runs.
+fn spawn_column_stats(state: Arc<AppState>, session: &SessionState, req: ColumnStatsRequest) {
+    let view = session.view.current();
+    let inflight = session.inflight.clone();
+    let outbound = session.outbound.clone();
+    let canceled = inflight.start(req.request_id.as_deref());
+    tokio::spawn(async move {
+        let col = req.col;
+        let stats = tokio::task::spawn_blocking(move || state.column_stats(col, view, &canceled))
+            .await
+            .unwrap_or(None);
+        inflight.finish(req.request_id.as_deref());
+        let resp = ServerMessage::ColumnStatsResponse(ColumnStatsResponse {
+            col,
+            request_id: req.request_id,
+            canceled: stats.is_none(),
+            stats,
+        });
+        outbound.send(Message::Text(resp.to_json())).await;
+    });
+}
+
+/// Scans for distinct values on the blocking pool, like column stats.
+fn spawn_distinct_values(state: Arc<AppState>, session: &SessionState, req: DistinctValuesRequest) {
+    let inflight = session.inflight.clone();
+    let outbound = session.outbound.clone();
+    let canceled = inflight.start(req.request_id.as_deref());
+    tokio::spawn(async move {
+        let col = req.col;
+        let distinct = tokio::task::spawn_blocking(move || state.distinct_values(col, &canceled))
+            .await
+            .unwrap_or(None);
+        inflight.finish(req.request_id.as_deref());
+        let resp = ServerMessage::DistinctValuesResponse(DistinctValuesResponse {
+            col,
+            request_id: req.request_id,
+            canceled: distinct.is_none(),
+            distinct,
+        });
+        outbound.send(Message::Text(resp.to_json())).await;
+    });
+}
+
+/// Most buckets an `overview_request` may ask for.
+const MAX_OVERVIEW_BUCKETS: u32 = 10_000;
+
+/// Samples the session's view on the blocking pool, reading about
+/// `--scan-budget` cells whatever the table's size. The edits in the sampled
+/// columns are copied out first, so writers are not held up meanwhile.
+fn spawn_overview(
+    state: Arc<AppState>,
+    session: &SessionState,
+    req: OverviewRequest,
+    col_count: u32,
+) {
+    let inflight = session.inflight.clone();
+    let outbound = session.outbound.clone();
+    let canceled = inflight.start(req.request_id.as_deref());
+    let view_rows = session.view.rows();
+    let total_rows = view_rows.as_ref().map_or(state.max_rows(), |rows| rows.len() as u64);
+    tokio::spawn(async move {
+        let (start_col, buckets) = (req.start_col, req.buckets);
+        let overview = tokio::task::spawn_blocking(move || {
+            let cols = (start_col..start_col + col_count).collect();
+            let edits = state.edits_in(&state.with_formula_inputs(cols));
+            let budget = state.settings().scan_budget;
+            stats::overview(total_rows, col_count, buckets, budget, &canceled, |row, c| {
+                let row = view_rows.as_ref().map_or(row, |rows| rows[row as usize]);
+                let value = state.cell_in(&edits, row, start_col + c);
+                !value.trim().is_empty() && value != state.config.missing_value
+            })
+        })
+        .await
+        .unwrap_or(None);
+        inflight.finish(req.request_id.as_deref());
+        let resp = ServerMessage::OverviewResponse(OverviewResponse {
+            request_id: req.request_id,
+            total_rows,
+            canceled: overview.is_none(),
+            overview,
+        });
+        outbound.send(Message::Text(resp.to_json())).await;
+    });
+}
+
+/// Rows encoded per `export_chunk`.
+const EXPORT_ROWS_PER_CHUNK: u64 = 1000;
+
+/// The start must lie inside the table, as must the last column. Rows past
+/// the end are not counted against `--max-export-cells`.
+fn validate_export(state: &AppState, req: &ExportRequest) -> Result<(), ProtocolError> {
+    validate_coord(req.start_row, req.start_col, state.max_rows(), state.max_cols())?;
+    if req.col_count > 0 {
+        validate_coord(
+            req.start_row,
+            req.start_col.saturating_add(req.col_count - 1),
+            state.max_rows(),
+            state.max_cols(),
+        )?;
+    }
+    let rows = req.row_count.min(state.max_rows() - req.start_row);
+    check_export_cells(state, rows.saturating_mul(req.col_count as u64))
+}
+
+/// Refuses a range or export of more than `--max-export-cells` cells.
+fn check_export_cells(state: &AppState, requested: u64) -> Result<(), ProtocolError> {
+    let allowed = state.config.max_export_cells;
+    if requested <= allowed {
+        return Ok(());
+    }
+    let message = format!("{} cells requested, at most {} allowed", requested, allowed);
+    Err(ProtocolError::new("range_too_large", message).with_counts(requested, allowed))
+}
+
+/// Streams the export a chunk at a time, each read on the blocking pool.
+/// Sends wait on the outbound queue, so a slow client slows the export
+/// rather than piling chunks up in memory.
+fn spawn_export(state: Arc<AppState>, session: &SessionState, req: ExportRequest) {
+    let inflight = session.inflight.clone();
+    let outbound = session.outbound.clone();
+    let canceled = inflight.start(req.request_id.as_deref());
+    tokio::spawn(async move {
+        let end = req.start_row.saturating_add(req.row_count).min(state.max_rows());
+        let mut seq = 0;
+        let mut start = req.start_row;
+        loop {
+            let chunk_end = start.saturating_add(EXPORT_ROWS_PER_CHUNK).min(end);
+            let stop = canceled.load(Ordering::Relaxed);
+            let data = match stop {
+                true => String::new(),
+                false => {
+                    let state = state.clone();
+                    let (start_col, col_count) = (req.start_col, req.col_count);
+                    let (first, last) = (start == req.start_row, chunk_end == end);
+                    let format = req.format;
+                    tokio::task::spawn_blocking(move || {
+                        let rows: Vec<Vec<String>> = (start..chunk_end)
+                            .map(|row| {
+                                (0..col_count)
+                                    .map(|c| state.cell(row, start_col + c))
+                                    .collect()
+                            })
+                            .collect();
+                        export::encode_chunk(format, &rows, first, last)
+                    })
+                    .await
+                    .unwrap_or_default()
+                }
+            };
+            let done = stop || chunk_end == end;
+            if done {
+                // Before the last chunk goes out, so the client can start
+                // another operation as soon as it arrives.
+                inflight.finish(req.request_id.as_deref());
+            }
+            let chunk = ServerMessage::ExportChunk(ExportChunk {
+                request_id: req.request_id.clone(),
+                seq,
+                data,
+                done,
+                canceled: stop,
+            });
+            let sent = outbound.send(Message::Text(chunk.to_json())).await;
+            if done {
+                break;
+            }
+            if !sent {
+                inflight.finish(req.request_id.as_deref());
+                break;
+            }
+            seq += 1;
+            start = chunk_end;
+        }
+    });
+}
+
+/// Where an `export_to_file_request` may write: admins only, and only a
+/// plain file name inside `--export-dir`.
+fn export_file_path(
+    state: &AppState,
+    req: &ExportToFileRequest,
+) -> Result<PathBuf, ProtocolError> {
+    check_admin(state, &req.token)?;
+    let Some(dir) = &state.config.export_dir else {
+        return Err(ProtocolError::new("forbidden", "file exports need --export-dir"));
+    };
+    let name = Path::new(&req.file_name);
+    if name.file_name() != Some(name.as_os_str()) {
+        return Err(ProtocolError::new("bad_request", "fileName must be a plain file name"));
+    }
+    Ok(Path::new(dir).join(name))
+}
+
+/// Writes the view as it stands now on the blocking pool. Progress pushes
+/// are dropped rather than queued when the client is behind; the final
+/// `export_file_done` always waits for room.
+fn spawn_file_export(
+    state: Arc<AppState>,
+    session: &SessionState,
+    req: ExportToFileRequest,
+    path: PathBuf,
+) {
+    let inflight = session.inflight.clone();
+    let outbound = session.outbound.clone();
+    let view_rows = session.view.rows();
+    let canceled = inflight.start(req.request_id.as_deref());
+    tokio::spawn(async move {
+        let request_id = req.request_id.clone();
+        let total_rows = view_rows.as_ref().map_or(state.max_rows(), |rows| rows.len() as u64);
+        let progress = outbound.clone();
+        let file = path.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            let cols = state.max_cols();
+            let header = (0..cols).map(|col| state.col_label(col)).collect();
+            export::write_file(
+                &file,
+                req.format,
+                header,
+                total_rows,
+                &canceled,
+                |row| {
+                    let row = view_rows.as_ref().map_or(row, |rows| rows[row as usize]);
+                    (0..cols).map(|col| state.cell(row, col)).collect()
+                },
+                |rows_written| {
+                    let msg = ServerMessage::ExportProgress(ExportProgress {
+                        request_id: req.request_id.clone(),
+                        rows_written,
+                        total_rows,
+                    });
+                    progress.push(Message::Text(msg.to_json()));
+                },
+            )
+        })
+        .await;
+        inflight.finish(request_id.as_deref());
+        let text = match written {
+            Ok(Ok(finished)) => ServerMessage::ExportFileDone(ExportFileDone {
+                request_id,
+                path: path.display().to_string(),
+                row_count: if finished { total_rows } else { 0 },
+                canceled: !finished,
+            })
+            .to_json(),
+            Ok(Err(err)) => {
+                tracing::warn!("export to {} failed: {}", path.display(), err);
+                error_json("export_failed", &format!("cannot write {}: {}", path.display(), err))
+            }
+            Err(_) => error_json("export_failed", "export stopped unexpectedly"),
+        };
+        outbound.send(Message::Text(text)).await;
+    });
+}
+
+/// Sets a column filter on the session's view and starts rebuilding it,
+/// within `--max-filters`.
+fn set_filter(
+    state: &Arc<AppState>,
+    session: &SessionState,
+    mut filter: Filter,
+    request_id: Option<String>,
+) -> Result<(), ProtocolError> {
+    validate_coord(0, filter.col, state.max_rows(), state.max_cols())?;
+    filter
+        .compile()
+        .map_err(|err| ProtocolError::new("bad_regex", format!("bad regex: {}", err)))?;
+    let Some((revision, spec)) = session.view.set_filter(filter, state.config.max_filters) else {
+        return Err(ProtocolError::new(
+            "too_many_filters",
+            format!("at most {} filters are allowed", state.config.max_filters),
+        ));
+    };
+    spawn_view_rebuild(state.clone(), session, request_id, revision, spec, None);
+    Ok(())
+}
+
+/// Rebuilds the session's row mapping on the blocking pool, like column
+/// stats. A cancelled rebuild leaves the previous rows in place.
+fn spawn_view_rebuild(
+    state: Arc<AppState>,
+    session: &SessionState,
+    request_id: Option<String>,
+    revision: u64,
+    spec: ViewSpec,
+    follow_row: Option<u64>,
+) {
+    let inflight = session.inflight.clone();
+    let outbound = session.outbound.clone();
+    let view = session.view.clone();
+    let sizes = session.sizes.clone();
+    let canceled = inflight.start(request_id.as_deref());
+    tokio::spawn(async move {
+        let built = rebuild_view(state.clone(), &view, revision, spec, canceled).await;
+        inflight.finish(request_id.as_deref());
+        let resp = view_response(&state, &view, request_id, !built);
+        outbound.send(Message::Text(resp.to_json())).await;
+        let Some(row) = follow_row.filter(|_| built) else {
+            return;
+        };
+        let view_rows = view.rows();
+        if let Some(msg) = scroll_to(&sizes, view_rows.as_deref().map(Vec::as_slice), row, None) {
+            outbound.send(Message::Text(ServerMessage::ScrollTo(msg).to_json())).await;
+        }
+    });
+}
+
+/// Builds the rows for `spec` on the blocking pool and installs them in
+/// `view` unless a later change superseded them; `false` if cancelled.
+async fn rebuild_view(
+    state: Arc<AppState>,
+    view: &View,
+    revision: u64,
+    spec: ViewSpec,
+    canceled: Arc<AtomicBool>,
+) -> bool {
+    let sort = spec.sort.clone();
+    let rows = tokio::task::spawn_blocking(move || match spec.is_identity() {
+        true => Some(None),
+        false => state.view_rows(&spec, &canceled).map(Some),
+    })
+    .await
+    .unwrap_or(None);
+    let Some(rows) = rows else {
+        return false;
+    };
+    view.install(revision, rows, sort);
+    true
+}
+
+fn view_response(
+    state: &AppState,
+    view: &View,
+    request_id: Option<String>,
+    canceled: bool,
+) -> ServerMessage {
+    ServerMessage::ViewResponse(ViewResponse {
+        request_id,
+        canceled,
+        visible_rows: view.rows().map_or(state.max_rows(), |rows| rows.len() as u64),
+        sort: view.sorted_by(),
+        view_generation: view.generation(),
+        budget_exhausted: view.budget_exhausted(),
+    })
+}
+
+/// Stores a client edit and broadcasts it, with any formula cells it
+/// changed, to the other connections.
+fn apply_cell_update(
+    state: &AppState,
+    session: &SessionState,
+    req: CellUpdate,
+) -> Result<ServerMessage, ProtocolError> {
+    check_writable(state)?;
+    validate_coord(req.row, req.col, state.max_rows(), state.max_cols())?;
+    let mut req = req;
+    (req.row, req.col) = merge_anchor(state, req.row, req.col);
+    if state.formula(req.col).is_some() {
+        return Err(ProtocolError::new(
+            "bad_request",
+            format!("column {} is computed by a formula", state.col_label(req.col)),
+        ));
+    }
+    let formulas = formula_values(state, req.row, req.col);
+    let before =
+        state.set_override((req.row, req.col), Some(req.value.clone()), req.expected_version)?;
+    session.history.record(Edit {
+        row: req.row,
+        col: req.col,
+        before,
+        after: Some(req.value),
+    });
+    let cells = publish_edit(state, session.id, req.row, req.col, formulas);
+    Ok(ServerMessage::CellsUpdated(CellsUpdated { cells }))
+}
+
+/// Drops a cell's edit and broadcasts the source value it falls back to. A
+/// cell without an edit is left alone, keeping its version.
+fn revert_cell(
+    state: &AppState,
+    session: &SessionState,
+    req: &RevertCell,
+) -> Result<ServerMessage, ProtocolError> {
+    check_writable(state)?;
+    validate_coord(req.row, req.col, state.max_rows(), state.max_cols())?;
+    let (row, col) = merge_anchor(state, req.row, req.col);
+    let formulas = formula_values(state, row, col);
+    // Checked and dropped under one lock, so two reverts of one edit cannot
+    // both get past the check and record it twice.
+    let mut overrides = state.overrides.write().unwrap();
+    if !overrides.contains_key(&(row, col)) {
+        return Ok(ServerMessage::CellsUpdated(CellsUpdated { cells: Vec::new() }));
+    }
+    let before = state.store_override(&mut overrides, (row, col), None, None)?;
+    drop(overrides);
+    session.history.record(Edit {
+        row,
+        col,
+        before,
+        after: None,
+    });
+    let cells = publish_edit(state, session.id, row, col, formulas);
+    Ok(ServerMessage::CellsUpdated(CellsUpdated { cells }))
+}
+
+/// Stores a block of edits and broadcasts them as one `cells_updated`. The
+/// whole block is checked first, so a rejected batch changes nothing; edits
+/// are not recorded for `undo`, whose history holds too few to revert a paste.
+/// A batch may carry no more cells than a slice.
+fn apply_update_batch(
+    state: &AppState,
+    session: &SessionState,
+    req: UpdateBatch,
+) -> Result<ServerMessage, ProtocolError> {
+    check_writable(state)?;
+    let width = req.cells.first().map_or(0, Vec::len);
+    if req.cells.iter().any(|row| row.len() != width) {
+        let message = "the rows of a batch must all be the same length";
+        return Err(ProtocolError::new("bad_request", message));
+    }
+    if width == 0 {
+        return Ok(ServerMessage::CellsUpdated(CellsUpdated { cells: Vec::new() }));
+    }
+    let max_cells = state.settings().max_cells_per_slice;
+    if req.cells.len() as u64 * width as u64 > max_cells {
+        return Err(ProtocolError::new(
+            "bad_request",
+            format!("a batch may hold at most {} cells", max_cells),
+        ));
+    }
+    validate_coord(req.row, req.col, state.max_rows(), state.max_cols())?;
+    let last_row = req.row.saturating_add(req.cells.len() as u64 - 1);
+    let last_col = req.col.saturating_add(width as u32 - 1);
+    validate_coord(last_row, last_col, state.max_rows(), state.max_cols())?;
+    let cols = req.col..=last_col;
+    let formula = state.config.formulas.iter().find(|formula| cols.contains(&formula.col));
+    if let Some(formula) = formula {
+        return Err(ProtocolError::new(
+            "bad_request",
+            format!("column {} is computed by a formula", state.col_label(formula.col)),
+        ));
+    }
+    let max = state.config.max_cell_length;
+    if let Some(len) = req.cells.iter().flatten().map(String::len).find(|&len| len > max) {
+        return Err(ProtocolError::new(
+            "cell_too_long",
+            format!("a value is {} bytes, over the {} byte limit", len, max),
+        ));
+    }
+    // Cells of one merge all land on its anchor, where the last value wins,
+    // so each cell is stored once and exactly as budgeted.
+    let (mut keys, mut last) = (Vec::new(), HashMap::new());
+    for (row, values) in (req.row..).zip(req.cells) {
+        for (col, value) in (req.col..).zip(values) {
+            let key = merge_anchor(state, row, col);
+            if last.insert(key, value).is_none() {
+                keys.push(key);
+            }
+        }
+    }
+    let edits: Vec<_> = keys
+        .into_iter()
+        .filter_map(|key| Some((key, last.remove(&key)?, formula_values(state, key.0, key.1))))
+        .collect();
+    // Held across the whole batch, so it is budgeted and stored as one.
+    let mut overrides = state.overrides.write().unwrap();
+    let batch = edits.iter().map(|(key, value, _)| (*key, value.as_str()));
+    state.check_batch_budget(&overrides, batch)?;
+    for (key, value, _) in &edits {
+        state.put_override(&mut overrides, *key, Some(value.clone()));
+    }
+    drop(overrides);
+    let mut cells = Vec::new();
+    for ((row, col), _, formulas) in edits {
+        cells.extend(edited_cells(state, row, col, formulas));
+    }
+    state.generation.fetch_add(1, Ordering::AcqRel);
+    state.broadcast(session.id, &cells);
+    Ok(ServerMessage::CellsUpdated(CellsUpdated { cells }))
+}
+
+/// The refusal of an edit that would take the overrides to `bytes`.
+fn memory_limit(bytes: u64, budget: u64) -> ProtocolError {
+    tracing::warn!(
+        "refusing an edit: edits alone would need {} of the {} byte budget",
+        bytes,
+        budget
+    );
+    ProtocolError::new("memory_limit", "edits have used up the server's memory budget")
+}
+
+/// The top-left cell of the merge holding a cell, where its edits are kept,
+/// or the cell itself outside any merge.
+fn merge_anchor(state: &AppState, row: u64, col: u32) -> (u64, u32) {
+    let merges = state.merges.read().unwrap();
+    match merges.iter().find(|merge| merge.contains(row, col)) {
+        Some(merge) => (merge.start_row, merge.start_col),
+        None => (row, col),
+    }
+}
+
+/// Undoes or redoes one of this connection's edits. Either way the cell goes
+/// back to exactly what the edit found or left, even if another connection
+/// has changed it since.
+fn step_history(
+    state: &AppState,
+    session: &SessionState,
+    undo: bool,
+) -> Result<ServerMessage, ProtocolError> {
+    check_writable(state)?;
+    let apply = |edit: &Edit| {
+        let value = if undo { &edit.before } else { &edit.after };
+        let formulas = formula_values(state, edit.row, edit.col);
+        state.set_override((edit.row, edit.col), value.clone(), None)?;
+        Ok(publish_edit(state, session.id, edit.row, edit.col, formulas))
+    };
+    let stepped = if undo {
+        session.history.undo(apply)
+    } else {
+        session.history.redo(apply)
+    };
+    let response = match stepped.transpose()? {
+        Some(cells) => HistoryResponse { noop: false, cells },
+        None => HistoryResponse {
+            noop: true,
+            cells: Vec::new(),
+        },
+    };
+    Ok(if undo {
+        ServerMessage::UndoResponse(response)
+    } else {
+        ServerMessage::RedoResponse(response)
+    })
+}
+
+/// The formula columns reading `col`, with their values in `row`, taken
+/// before an edit so [`publish_edit`] can tell which of them it changed.
+fn formula_values(state: &AppState, row: u64, col: u32) -> Vec<(u32, String)> {
+    state
+        .config
+        .formulas
+        .iter()
+        .filter(|formula| formula.inputs().contains(&col))
+        .map(|formula| (formula.col, state.cell(row, formula.col)))
+        .collect()
+}
+
+/// Bumps the generation after a cell changed and pushes its new value to the
+/// other connections, along with the formula cells whose value it changed
+/// from `formulas`. Returns the pushed cells.
+fn publish_edit(
+    state: &AppState,
+    from: u64,
+    row: u64,
+    col: u32,
+    formulas: Vec<(u32, String)>,
+) -> Vec<CellValue> {
+    state.generation.fetch_add(1, Ordering::AcqRel);
+    let cells = edited_cells(state, row, col, formulas);
+    state.broadcast(from, &cells);
+    cells
+}
+
+/// A changed cell's new value, then the formula cells whose value it changed
+/// from `formulas`.
+fn edited_cells(
+    state: &AppState,
+    row: u64,
+    col: u32,
+    formulas: Vec<(u32, String)>,
+) -> Vec<CellValue> {
+    let mut cells = vec![CellValue {
+        row,
+        col,
+        value: state.cell(row, col),
+        version: Some(state.version(row, col)),
+    }];
+    for (formula_col, before) in formulas {
+        let value = state.cell(row, formula_col);
+        if value != before {
+            cells.push(CellValue {
+                row,
+                col: formula_col,
+                value,
+                version: None,
+            });
+        }
+    }
+    cells
+}
+
+/// Records a merge and tells the other connections about it. Merges must
+/// cover more than one cell, fit in the table and not overlap each other.
+fn merge_cells(
+    state: &AppState,
+    session: &SessionState,
+    req: MergeCells,
+) -> Result<ServerMessage, ProtocolError> {
+    check_writable(state)?;
+    if req.row_count == 0 || req.col_count == 0 || (req.row_count == 1 && req.col_count == 1) {
+        return Err(ProtocolError::new("bad_request", "a merge must span more than one cell"));
+    }
+    validate_coord(req.start_row, req.start_col, state.max_rows(), state.max_cols())?;
+    validate_coord(
+        req.start_row.saturating_add(req.row_count as u64 - 1),
+        req.start_col.saturating_add(req.col_count - 1),
+        state.max_rows(),
+        state.max_cols(),
+    )?;
+    let range = CellRange {
+        start_row: req.start_row,
+        start_col: req.start_col,
+        row_count: req.row_count,
+        col_count: req.col_count,
+    };
+    {
+        let mut merges = state.merges.write().unwrap();
+        if merges.iter().any(|merge| merge.overlaps(&range)) {
+            return Err(ProtocolError::new("bad_request", "merge overlaps an existing merge"));
+        }
+        merges.push(range);
+    }
+    state.generation.fetch_add(1, Ordering::AcqRel);
+    let msg = ServerMessage::CellsMerged(CellsMerged {
+        start_row: req.start_row,
+        start_col: req.start_col,
+        row_count: req.row_count,
+        col_count: req.col_count,
+    });
+    state.push_to_others(session.id, &msg.to_json());
+    Ok(msg)
+}
+
+/// Admin messages need the `--admin-token` secret; without one configured
+/// they are always refused.
+fn check_admin(state: &AppState, token: &str) -> Result<(), ProtocolError> {
+    match &state.config.admin_token {
+        Some(expected) if expected == token => Ok(()),
+        _ => Err(ProtocolError::new("forbidden", "invalid admin token")),
+    }
+}
+
+/// Rejects mutations while the server is `--read-only`.
+fn check_writable(state: &AppState) -> Result<(), ProtocolError> {
+    if state.settings().read_only {
+        return Err(ProtocolError::new("read_only", "server is read-only"));
+    }
+    Ok(())
+}
+
+/// Rejects coordinates outside the table so nothing is generated or stored for
+/// cells that cannot exist.
+fn validate_coord(row: u64, col: u32, max_rows: u64, max_cols: u32) -> Result<(), ProtocolError> {
+    if row >= max_rows || col >= max_cols {
+        return Err(ProtocolError::new(
+            "out_of_bounds",
+            format!(
+                "cell ({}, {}) is outside the {}x{} table",
+                row, col, max_rows, max_cols
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Rough bytes an override costs: its text plus the map entry around it.
+fn override_bytes(value: &str) -> u64 {
+    64 + value.len() as u64
+}
+
+/// Reads an explicit rectangle of cells. Both corners must lie inside the
+/// table; the size is held to the same caps as slices, and to
+/// `--max-export-cells`.
+fn make_range_response(state: &AppState, req: &RangeRequest) -> Result<RangeResponse, ProtocolError> {
+    validate_coord(req.start_row, req.start_col, state.max_rows(), state.max_cols())?;
+    // Counted as asked for, before the per-response caps cut it down.
+    check_export_cells(state, req.row_count as u64 * req.col_count as u64)?;
+    let row_count = req.row_count.min(MAX_ROWS_PER_RESPONSE);
+    let col_count = req.col_count.min(MAX_COLS_PER_RESPONSE);
+    if row_count > 0 && col_count > 0 {
+        validate_coord(
+            req.start_row.saturating_add(row_count as u64 - 1),
+            req.start_col.saturating_add(col_count - 1),
+            state.max_rows(),
+            state.max_cols(),
+        )?;
+    }
+
+    let mut cells_by_row = Vec::with_capacity(row_count as usize);
+    for r in 0..row_count as u64 {
+        let mut row = Vec::with_capacity(col_count as usize);
+        for c in 0..col_count {
+            row.push(state.cell(req.start_row + r, req.start_col + c));
+        }
+        cells_by_row.push(row);
+    }
+
+    Ok(RangeResponse {
+        start_row: req.start_row,
+        start_col: req.start_col,
+        row_count,
+        col_count,
+        cells_by_row,
+    })
+}
+
+/// Reads rows by visual position in the session's view, mapped to physical
+/// rows through its filters and sort. Columns are checked like a range;
+/// rows past the end of the view are simply not returned.
+fn make_rows_response(
+    state: &AppState,
+    session: &SessionState,
+    req: &RowsRequest,
+) -> Result<RowsResponse, ProtocolError> {
+    let col_count = req.col_count.min(MAX_COLS_PER_RESPONSE);
+    validate_coord(0, req.start_col, state.max_rows(), state.max_cols())?;
+    if col_count > 0 {
+        validate_coord(
+            0,
+            req.start_col.saturating_add(col_count - 1),
+            state.max_rows(),
+            state.max_cols(),
+        )?;
+    }
+
+    let view_rows = session.view.rows();
+    let total_rows = view_rows.as_ref().map_or(state.max_rows(), |rows| rows.len() as u64);
+    let start = req.start.min(total_rows);
+    let end = start
+        .saturating_add(req.count.min(MAX_ROWS_PER_RESPONSE) as u64)
+        .min(total_rows);
+    let row_ids: Vec<u64> = (start..end)
+        .map(|row| view_rows.as_ref().map_or(row, |rows| rows[row as usize]))
+        .collect();
+    let cells_by_row = row_ids
+        .iter()
+        .map(|&row| {
+            (0..col_count)
+                .map(|c| state.cell(row, req.start_col + c))
+                .collect()
+        })
+        .collect();
+
+    Ok(RowsResponse {
+        start,
+        row_count: row_ids.len() as u32,
+        start_col: req.start_col,
+        col_count,
+        row_ids,
+        cells_by_row,
+    })
+}
+
+/// Serves a page as a rows request starting at the cursor's visual row. The
+/// cursor is that row written as `r<row>`; it is not guaranteed to keep this
+/// form, so clients only hand it back.
+fn make_page_response(
+    state: &AppState,
+    session: &SessionState,
+    req: &PageRequest,
+) -> Result<PageResponse, ProtocolError> {
+    if req.page_size == 0 {
+        return Err(ProtocolError::new("bad_request", "pageSize must be positive"));
+    }
+    let start = match &req.cursor {
+        None => 0,
+        Some(cursor) => cursor
+            .strip_prefix('r')
+            .and_then(|row| row.parse().ok())
+            .ok_or_else(|| ProtocolError::new("bad_request", "invalid cursor"))?,
+    };
+    let col_count = req.col_count.unwrap_or(state.max_cols().saturating_sub(req.start_col));
+    let rows = make_rows_response(
+        state,
+        session,
+        &RowsRequest {
+            start,
+            count: req.page_size,
+            start_col: req.start_col,
+            col_count,
+        },
+    )?;
+    let end = rows.start + rows.row_count as u64;
+    let total_rows = session.view.rows().map_or(state.max_rows(), |rows| rows.len() as u64);
+    Ok(PageResponse {
+        next_cursor: (end < total_rows).then(|| format!("r{}", end)),
+        rows,
+    })
+}
+
+/// A whole row of the view, read as a one-row rows request. That request
+/// reads the view once, so a row past its end comes back as no rows, even
+/// if a rebuild shrank the view meanwhile; their `start` is the view length.
+fn select_row(
+    state: &AppState,
+    session: &SessionState,
+    req: &SelectRowRequest,
+) -> Result<SelectRowResponse, ProtocolError> {
+    let mut rows = make_rows_response(
+        state,
+        session,
+        &RowsRequest {
+            start: req.row,
+            count: 1,
+            start_col: 0,
+            col_count: state.max_cols(),
+        },
+    )?;
+    if rows.row_ids.is_empty() {
+        validate_coord(req.row, 0, rows.start, state.max_cols())?;
+    }
+    Ok(SelectRowResponse {
+        row: req.row,
+        row_id: rows.row_ids[0],
+        cells: rows.cells_by_row.swap_remove(0),
+        truncated: state.max_cols() > rows.col_count,
+    })
+}
+
+/// A whole column of the view, up to [`MAX_SELECTED_COL_ROWS`] rows.
+fn select_col(
+    state: &AppState,
+    session: &SessionState,
+    req: &SelectColRequest,
+) -> Result<SelectColResponse, ProtocolError> {
+    validate_coord(0, req.col, state.max_rows(), state.max_cols())?;
+    let view_rows = session.view.rows();
+    let total_rows = view_rows.as_ref().map_or(state.max_rows(), |rows| rows.len() as u64);
+    let row_ids: Vec<u64> = (0..total_rows.min(MAX_SELECTED_COL_ROWS))
+        .map(|row| view_rows.as_ref().map_or(row, |rows| rows[row as usize]))
+        .collect();
+    Ok(SelectColResponse {
+        col: req.col,
+        cells: row_ids.iter().map(|&row| state.cell(row, req.col)).collect(),
+        row_ids,
+        total_rows,
+        truncated: total_rows > MAX_SELECTED_COL_ROWS,
+    })
+}
+
+/// The pixel height and width of the session's view at these defaults, with
+/// its custom sizes.
+fn scroll_extent(
+    state: &AppState,
+    session: &SessionState,
+    row_height: u32,
+    col_width: u32,
+) -> (u64, u64) {
+    let rows = session.view.rows().map_or(state.max_rows(), |rows| rows.len() as u64);
+    (
+        session.sizes.rows.offset(rows, row_height),
+        session.sizes.cols.offset(state.max_cols() as u64, col_width),
+    )
+}
+
+/// Answers an `extent_request`, remembering its defaults for later size
+/// changes.
+fn extent_response(
+    state: &AppState,
+    session: &mut SessionState,
+    req: &ExtentRequest,
+) -> Result<ServerMessage, ProtocolError> {
+    if req.default_row_height == 0 || req.default_column_width == 0 {
+        return Err(ProtocolError::new(
+            "bad_request",
+            "defaultRowHeight and defaultColumnWidth must be positive",
+        ));
+    }
+    session.sizes.defaults = Some((req.default_row_height, req.default_column_width));
+    let (height, width) =
+        scroll_extent(state, session, req.default_row_height, req.default_column_width);
+    Ok(ServerMessage::ExtentResponse(ExtentResponse { height, width }))
+}
+
+/// Applies a `set_row_height` or `set_col_width` and replies with the new
+/// extent. Needs the defaults from an earlier slice or extent request.
+fn set_size(
+    state: &AppState,
+    session: &mut SessionState,
+    row: Option<u64>,
+    col: Option<u32>,
+    size: Option<u32>,
+) -> Result<ServerMessage, ProtocolError> {
+    let Some((row_height, col_width)) = session.sizes.defaults else {
+        return Err(ProtocolError::new(
+            "bad_request",
+            "send an extent_request or slice_request with the default sizes first",
+        ));
+    };
+    if size == Some(0) {
+        return Err(ProtocolError::new("bad_request", "sizes must be positive"));
+    }
+    let rows = session.view.rows().map_or(state.max_rows(), |rows| rows.len() as u64);
+    validate_coord(row.unwrap_or(0), col.unwrap_or(0), rows, state.max_cols())?;
+    match (row, col) {
+        (Some(row), _) => session.sizes.rows.set(row, size),
+        (_, Some(col)) => session.sizes.cols.set(col as u64, size),
+        (None, None) => {}
+    }
+    let (height, width) = scroll_extent(state, session, row_height, col_width);
+    Ok(ServerMessage::ExtentResponse(ExtentResponse { height, width }))
+}
+
+/// Where to scroll to bring physical `row`, and `col` if given, to the top
+/// left of the viewport at the session's default sizes. `None` if the view
+/// leaves the row out or no defaults have been seen yet.
+fn scroll_to(
+    sizes: &Sizes,
+    view_rows: Option<&[u64]>,
+    row: u64,
+    col: Option<u32>,
+) -> Option<ScrollTo> {
+    let (row_height, col_width) = sizes.defaults?;
+    let position = match view_rows {
+        Some(rows) => rows.iter().position(|&id| id == row)? as u64,
+        None => row,
+    };
+    Some(ScrollTo {
+        scroll_top: sizes.rows.offset(position, row_height),
+        scroll_left: col.map(|col| sizes.cols.offset(col as u64, col_width)),
+        row,
+        position,
+        col,
+    })
+}
+
+/// Answers a `jump_to_cell` with the offsets that show the cell.
+fn jump_to_cell(
+    state: &AppState,
+    session: &SessionState,
+    req: &JumpToCell,
+) -> Result<ScrollTo, ProtocolError> {
+    validate_coord(req.row, req.col, state.max_rows(), state.max_cols())?;
+    if session.sizes.defaults.is_none() {
+        return Err(ProtocolError::new(
+            "bad_request",
+            "send an extent_request or slice_request with the default sizes first",
+        ));
+    }
+    let view_rows = session.view.rows();
+    let view_rows = view_rows.as_deref().map(Vec::as_slice);
+    scroll_to(&session.sizes, view_rows, req.row, Some(req.col)).ok_or_else(|| {
+        ProtocolError::new("not_in_view", format!("row {} is filtered out", req.row))
+    })
+}
+
+/// Pins or unpins a column for this session's later slices, within
+/// [`sizes::MAX_PINNED_COLS`].
+fn pin_column(
+    state: &AppState,
+    session: &mut SessionState,
+    req: &PinColumnRequest,
+) -> Result<ServerMessage, ProtocolError> {
+    validate_coord(0, req.col, state.max_rows(), state.max_cols())?;
+    let pins = &mut session.sizes.pins;
+    if req.side.is_some() && !pins.contains(req.col) && pins.len() >= sizes::MAX_PINNED_COLS {
+        return Err(ProtocolError::new(
+            "bad_request",
+            format!("at most {} columns can be pinned", sizes::MAX_PINNED_COLS),
+        ));
+    }
+    pins.set(req.col, req.side);
+    Ok(ServerMessage::PinColumnResponse(PinColumnResponse {
+        pinned_left: pins.left.clone(),
+        pinned_right: pins.right.clone(),
+    }))
+}
+
+fn tail(session: &SessionState, req: &TailRequest) -> ServerMessage {
+    session.tail.update(|tail| tail.enabled = req.enabled);
+    ServerMessage::TailResponse(TailResponse {
+        enabled: req.enabled,
+    })
+}
+
+/// Records where a slice leaves a tail-following session: whether its screen
+/// reaches the last row, and its columns, for the rows pushed after it.
+fn track_tail(state: &AppState, session: &SessionState, req: &SliceRequest) {
+    let rows = state.max_rows();
+    let sizes = &session.sizes;
+    let start_row = sizes.rows.index_at(req.scroll_top, req.default_row_height, rows);
+    let visible_rows =
+        sizes.rows.count_covering(start_row, req.screen_height as u64, req.default_row_height);
+    let window = slice_window(state, sizes, req, rows);
+    session.tail.update(|tail| {
+        tail.at_bottom = session.view.rows().is_none() && start_row + visible_rows >= rows;
+        tail.start_col = window.start_col;
+        tail.col_count = window.col_count;
+        tail.rows = rows;
+        tail.height = sizes.rows.offset(rows, req.default_row_height);
+        tail.row_height = req.default_row_height;
+    });
+}
+
+/// Rejects viewports the slice arithmetic cannot work with.
+///
+/// Offsets far past the end of the table are refused as `scroll_out_of_range`
+/// rather than clamped: they usually mean a client sent a negative value that
+/// wrapped, and an empty slice would hide the bug.
+fn validate_slice_request(
+    state: &AppState,
+    session: &SessionState,
+    req: &SliceRequest,
+) -> Result<(), ProtocolError> {
+    if req.default_row_height == 0 || req.default_column_width == 0 {
+        return Err(ProtocolError::new(
+            "bad_request",
+            "defaultRowHeight and defaultColumnWidth must be positive",
+        ));
+    }
+    let (height, width) = scroll_extent(
+        state,
+        session,
+        req.default_row_height,
+        req.default_column_width,
+    );
+    let axes = [("scrollTop", req.scroll_top, height), ("scrollLeft", req.scroll_left, width)];
+    for (name, offset, extent) in axes {
+        if offset > extent.saturating_mul(SCROLL_RANGE_FACTOR) {
+            return Err(ProtocolError::new(
+                "scroll_out_of_range",
+                format!("{} {} is far past the {}px table extent", name, offset, extent),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Rows sampled per column for suggested widths.
+const AUTO_WIDTH_SAMPLE_ROWS: u64 = 100;
+/// Room for cell padding and borders on top of the text.
+const AUTO_WIDTH_PADDING: u32 = 16;
+const AUTO_WIDTH_MIN: u32 = 40;
+const AUTO_WIDTH_MAX: u32 = 400;
+
+/// Fits each column to its longest header or sampled cell, at `char_width`
+/// pixels per character, within sane bounds.
+fn suggest_col_widths(state: &AppState, char_width: f64) -> Vec<u32> {
+    let rows = state.max_rows().min(AUTO_WIDTH_SAMPLE_ROWS);
+    let overrides = state.overrides.read().unwrap();
+    (0..state.max_cols())
+        .map(|col| {
+            let chars = (0..rows)
+                .map(|row| state.cell_in(&overrides, row, col).chars().count())
+                .chain([state.col_label(col).chars().count()])
+                .max()
+                .unwrap_or(0);
+            let width = (chars as f64 * char_width).ceil().min(u32::MAX as f64) as u32;
+            width
+                .saturating_add(AUTO_WIDTH_PADDING)
+                .clamp(AUTO_WIDTH_MIN, AUTO_WIDTH_MAX)
+        })
+        .collect()
+}
+
+/// Binary frames carry only the plain cells, so anything extra means JSON.
+fn wants_binary(session: &SessionState, req: &SliceRequest) -> bool {
+    req.encoding == Encoding::Binary
+        && !req.styled
+        && !req.sort_keys
+        && !req.include_neighbors
+        && !req.dirty
+        && !req.originals
+        && !req.distinguish_null
+        && !req.line_counts
+        && req.fields.is_none()
+        && req.view_generation.is_none()
+        && req.orientation == Orientation::Row
+        && session.sizes.pins.is_empty()
+        && session.capabilities.contains(&Capability::Binary)
+}
+
+/// The reply to a slice request: `not_modified` if the client already holds
+/// it, else the slice in the requested encoding and schema, with only the
+/// requested fields, stamped with the view `generation` it was cut from.
+fn slice_message(
+    mut slice: SliceResponse,
+    req: &SliceRequest,
+    generation: u64,
+    binary: bool,
+) -> Message {
+    slice.view_generation = generation;
+    slice.view_changed = req.view_generation.is_some_and(|expected| expected != generation);
+    if let Some(fields) = &req.fields {
+        keep_fields(&mut slice, fields);
+    }
+    let msg = match &req.if_none_match {
+        Some(etag) if *etag == slice.etag => {
+            ServerMessage::NotModified(NotModified { etag: slice.etag })
+        }
+        _ if binary => return Message::Binary(binary::encode_slice(&slice)),
+        _ if req.schema == Schema::Min => ServerMessage::SliceMin(slice.into()),
+        _ => ServerMessage::SliceResponse(slice),
+    };
+    Message::Text(msg.to_json())
+}
+
+/// Drops the fields a `fields` list leaves out. The etag was taken first, so
+/// it still matches the whole slice.
+fn keep_fields(slice: &mut SliceResponse, fields: &[SliceField]) {
+    if !fields.contains(&SliceField::ColLetters) {
+        slice.col_letters = None;
+    }
+    if !fields.contains(&SliceField::CellsByRow) {
+        slice.cells_by_row = None;
+    }
+    if !fields.contains(&SliceField::CellsByCol) {
+        slice.cells_by_col = None;
+    }
+    if !fields.contains(&SliceField::Merges) {
+        slice.merges.clear();
+    }
+    if !fields.contains(&SliceField::RowIds) {
+        slice.row_ids = None;
+    }
+}
+
+/// Builds the slice on the blocking pool and answers `source_timeout` if the
+/// source takes longer than `timeout`, so a stuck source costs the client one
+/// error rather than the whole connection. The abandoned build is left to
+/// finish on its own.
+fn spawn_slice(
+    state: Arc<AppState>,
+    session: &SessionState,
+    req: SliceRequest,
+    (generation, view_rows): (u64, Option<Arc<Vec<u64>>>),
+    binary: bool,
+    timeout: Duration,
+) {
+    let outbound = session.outbound.clone();
+    let format_rules = session.format_rules.clone();
+    let sizes = session.sizes.clone();
+    tokio::spawn(async move {
+        let build = tokio::task::spawn_blocking(move || {
+            let view_rows = view_rows.as_deref().map(Vec::as_slice);
+            let slice = make_slice_response(&state, view_rows, &format_rules, &sizes, &req);
+            slice_message(slice, &req, generation, binary)
+        });
+        let msg = match tokio::time::timeout(timeout, build).await {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(_)) => {
+                Message::Text(error_json("internal_error", "the slice could not be built"))
+            }
+            Err(_) => {
+                tracing::warn!("a slice took longer than the {:?} source timeout", timeout);
+                Message::Text(error_json(
+                    "source_timeout",
+                    &format!("the data source did not answer within {:?}", timeout),
+                ))
+            }
+        };
+        outbound.send(msg).await;
+    });
+}
+
+/// Sends the slice for `req` a row at a time, each read on the blocking pool
+/// and sent as soon as it is ready, then `slice_end`.
+fn spawn_slice_stream(state: Arc<AppState>, session: &SessionState, req: SliceRequest) {
+    let outbound = session.outbound.clone();
+    let view_rows = session.view.rows();
+    let sizes = session.sizes.clone();
+    tokio::spawn(async move {
+        let total_rows = view_rows.as_ref().map_or(state.max_rows(), |rows| rows.len() as u64);
+        let window = slice_window(&state, &sizes, &req, total_rows);
+        let (start_col, col_count) = (window.start_col, window.col_count);
+        for row in window.start_row..window.start_row + window.row_count as u64 {
+            let row_id = view_rows.as_ref().map_or(row, |rows| rows[row as usize]);
+            let state = state.clone();
+            let cells = tokio::task::spawn_blocking(move || {
+                (start_col..start_col + col_count)
+                    .map(|col| state.cell(row_id, col))
+                    .collect()
+            })
+            .await
+            .unwrap_or_default();
+            let msg = ServerMessage::SliceRow(SliceRow {
+                row,
+                row_id,
+                start_col,
+                cells,
+            });
+            if !outbound.send(Message::Text(msg.to_json())).await {
+                return;
+            }
+        }
+        let end = ServerMessage::SliceEnd(SliceEnd {
+            start_row: window.start_row,
+            row_count: window.row_count,
+            start_col,
+            col_count,
+            col_letters: (start_col..start_col + col_count)
+                .map(|col| state.col_label(col))
+                .collect(),
+            clamped: window.clamped,
+        });
+        outbound.send(Message::Text(end.to_json())).await;
+    });
+}
+
+/// Describes the slices a screen up, down, left and right of `req` by
+/// building each one, which makes the request roughly five times the work.
+/// Directions that would leave the table are skipped.
+fn neighbor_hints(
+    state: &AppState,
+    view_rows: Option<&[u64]>,
+    format_rules: &[FormatRule],
+    sizes: &Sizes,
+    req: &SliceRequest,
+    total_rows: u64,
+) -> Vec<NeighborHint> {
+    let (top, left) = (req.scroll_top, req.scroll_left);
+    let (height, width) = (req.screen_height as u64, req.screen_width as u64);
+    let row_extent = sizes.rows.offset(total_rows, req.default_row_height);
+    let col_extent = sizes.cols.offset(state.max_cols() as u64, req.default_column_width);
+    let candidates = [
+        (Direction::Up, (top > 0).then(|| (top.saturating_sub(height), left))),
+        (Direction::Down, (top + height < row_extent).then(|| (top + height, left))),
+        (Direction::Left, (left > 0).then(|| (top, left.saturating_sub(width)))),
+        (Direction::Right, (left + width < col_extent).then(|| (top, left + width))),
+    ];
+    candidates
+        .into_iter()
+        .filter_map(|(direction, scroll)| {
+            let (scroll_top, scroll_left) = scroll?;
+            let neighbor_req = SliceRequest {
+                scroll_top,
+                scroll_left,
+                include_neighbors: false,
+                ..req.clone()
+            };
+            let neighbor =
+                make_slice_response(state, view_rows, format_rules, sizes, &neighbor_req);
+            Some(NeighborHint {
+                direction,
+                scroll_top,
+                scroll_left,
+                start_row: neighbor.start_row,
+                row_count: neighbor.row_count,
+                start_col: neighbor.start_col,
+                col_count: neighbor.col_count,
+                estimated_bytes: serde_json::to_vec(&neighbor).map_or(0, |json| json.len() as u64),
+                etag: neighbor.etag,
+            })
+        })
+        .collect()
+}
+
+/// Creates a slice response containing a window of spreadsheet data based on the client's viewport.
+/// 
+/// This function calculates which rows and columns should be visible based on the scroll position
+/// and screen dimensions, then reads the cells for that window from the data source, layering in
+/// any values clients have written. It applies buffer zones around the visible area for smooth
+/// scrolling and enforces safety limits on the response size.
+///
+/// Rows are visual positions in the session's view, given by `view_rows`
+/// when a filter or sort is active. Styled requests also get the session's
+/// conditional formatting.
+fn make_slice_response(
+    state: &AppState,
+    view_rows: Option<&[u64]>,
+    format_rules: &[FormatRule],
+    sizes: &Sizes,
+    req: &SliceRequest,
+) -> SliceResponse {
+    let total_rows = view_rows.map_or(state.max_rows(), |rows| rows.len() as u64);
+    let SliceWindow {
+        start_row,
+        row_count,
+        start_col,
+        col_count,
+        clamped,
+    } = slice_window(state, sizes, req, total_rows);
+    // The body skips pinned columns, running on past them to keep its width.
+    let pins = &sizes.pins;
+    let cols: Vec<u32> = (start_col..state.max_cols())
+        .filter(|&col| !pins.contains(col))
+        .take(col_count as usize)
+        .collect();
+    let col_count = cols.len() as u32;
+
+    let col_letters: Vec<String> = cols.iter().map(|&col| state.col_label(col)).collect();
+
+    let row_ids: Vec<u64> = (start_row..start_row + row_count as u64)
+        .map(|row| view_rows.map_or(row, |rows| rows[row as usize]))
+        .collect();
+
+    let overrides = state.overrides.read().unwrap();
+    let mut cells_by_row: Vec<Vec<String>> = Vec::with_capacity(row_count as usize);
+    let mut dirty = Vec::new();
+    let mut originals = Vec::new();
+    let mut nulls = Vec::new();
+    for (r, &row_idx) in row_ids.iter().enumerate() {
+        let mut row: Vec<String> = Vec::with_capacity(col_count as usize);
+        for (c, &col_idx) in cols.iter().enumerate() {
+            if req.dirty {
+                let edited = overrides.get(&(row_idx, col_idx));
+                if edited.is_some_and(|value| *value != state.source_cell(row_idx, col_idx)) {
+                    dirty.push((r as u32, c as u32));
+                }
+            }
+            if req.originals && overrides.contains_key(&(row_idx, col_idx)) {
+                originals.push(CellOriginal {
+                    row: r as u32,
+                    col: c as u32,
+                    original: state.source_cell(row_idx, col_idx),
+                });
+            }
+            match state.value_in(&overrides, row_idx, col_idx) {
+                Some(value) => row.push(value),
+                None => {
+                    nulls.push((r, c));
+                    row.push(state.config.missing_value.clone());
+                }
+            }
+        }
+        cells_by_row.push(row);
+    }
+
+    let mut styles = Vec::new();
+    if req.styled && !format_rules.is_empty() {
+        for (r, row) in cells_by_row.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                if let Some(style) = format::style_for(format_rules, cols[c], value) {
+                    styles.push(StyledCell {
+                        row: r as u32,
+                        col: c as u32,
+                        style: style.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    let mut sort_keys = Vec::new();
+    if req.sort_keys {
+        for (r, row) in cells_by_row.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                // Plain numbers already read as what they sort by.
+                if value.trim().parse::<f64>().is_ok() {
+                    continue;
+                }
+                if let Some(key) = view::numeric_value(value) {
+                    sort_keys.push(CellSortKey {
+                        row: r as u32,
+                        col: c as u32,
+                        value: key,
+                    });
+                }
+            }
+        }
+    }
+
+    // Merge corners were validated against the table, so their ends fit in u32.
+    let in_cols = |merge: &CellRange| {
+        cols.iter().any(|&col| merge.start_col <= col && col < merge.start_col + merge.col_count)
+    };
+    let in_rows =
+        |merge: &CellRange| row_ids.iter().any(|&row| merge.contains(row, merge.start_col));
+    let merges: Vec<(u64, u32, u32, u32)> = state
+        .merges
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|merge| in_cols(merge) && in_rows(merge))
+        .map(|merge| (merge.start_row, merge.start_col, merge.row_count, merge.col_count))
+        .collect();
+
+    let pinned = |pinned: &[u32]| -> Vec<PinnedCol> {
+        pinned
+            .iter()
+            .map(|&col| PinnedCol {
+                col,
+                label: state.col_label(col),
+                cells: row_ids.iter().map(|&row| state.cell_in(&overrides, row, col)).collect(),
+            })
+            .collect()
+    };
+    let (pinned_left_cols, pinned_right_cols) = (pinned(&pins.left), pinned(&pins.right));
+    let pinned_width = |pinned: &[u32]| -> Option<u64> {
+        let width = |&col: &u32| sizes.cols.size(col as u64, req.default_column_width) as u64;
+        (!pinned.is_empty()).then(|| pinned.iter().map(width).sum())
+    };
+    let (pinned_left_width, pinned_right_width) =
+        (pinned_width(&pins.left), pinned_width(&pins.right));
+    let body_cols = if pins.is_empty() { Vec::new() } else { cols };
+
+    let mut row_line_counts = Vec::new();
+    if req.line_counts {
+        let pinned = pinned_left_cols.iter().chain(&pinned_right_cols);
+        row_line_counts = cells_by_row
+            .iter()
+            .enumerate()
+            .map(|(r, row)| {
+                let pinned_cells = pinned.clone().map(|pinned| &pinned.cells[r]);
+                row.iter().chain(pinned_cells).map(|cell| line_count(cell)).max().unwrap_or(1)
+            })
+            .collect();
+    }
+
+    let (cells_by_row, cells_by_col) = match (req.orientation, req.distinguish_null) {
+        (Orientation::Row, false) => (Some(Cells::new(cells_by_row, req.sparse)), None),
+        (Orientation::Row, true) => (Some(Cells::nullable(cells_by_row, &nulls)), None),
+        (Orientation::Column, false) => {
+            let cells_by_col = transpose(cells_by_row, col_count);
+            (None, Some(Cells::new(cells_by_col, req.sparse)))
+        }
+        (Orientation::Column, true) => {
+            let cells_by_col = transpose(cells_by_row, col_count);
+            let nulls: Vec<(usize, usize)> = nulls.iter().map(|&(r, c)| (c, r)).collect();
+            (None, Some(Cells::nullable(cells_by_col, &nulls)))
+        }
+    };
+    let mut slice = SliceResponse {
+        start_row,
+        row_count,
+        start_col,
+        col_count,
+        col_letters: Some(col_letters),
+        cells_by_row,
+        cells_by_col,
+        clamped,
+        merges,
+        row_ids: Some(row_ids),
+        styles,
+        sort_keys,
+        neighbor_hints: Vec::new(),
+        dirty,
+        originals,
+        body_cols,
+        pinned_left_cols,
+        pinned_right_cols,
+        pinned_left_width,
+        pinned_right_width,
+        row_line_counts,
+        // Stamped by `slice_message`, after the etag is taken.
+        view_generation: 0,
+        view_changed: false,
+        etag: String::new(),
+    };
+    debug_assert_eq!(slice.check_shape(), Ok(()));
+    slice.etag = slice_etag(&slice);
+    // Added after hashing so the etag covers only the slice's own window.
+    if req.include_neighbors {
+        slice.neighbor_hints =
+            neighbor_hints(state, view_rows, format_rules, sizes, req, total_rows);
+    }
+    slice
+}
+
+/// The rows and columns a slice request covers, before any cells are read.
+struct SliceWindow {
+    start_row: u64,
+    row_count: u32,
+    start_col: u32,
+    col_count: u32,
+    /// Cut down to `--max-cells-per-slice`.
+    clamped: bool,
+}
+
+fn slice_window(
+    state: &AppState,
+    sizes: &Sizes,
+    req: &SliceRequest,
+    total_rows: u64,
+) -> SliceWindow {
+    // Counts are worked out in u64 and clamped to the table before narrowing,
+    // so extreme scroll offsets and buffers give an empty or short slice
+    // rather than wrapping. Every `start + offset` below stays inside the table.
+    let start_row = sizes.rows.index_at(req.scroll_top, req.default_row_height, total_rows);
+    let visible_rows =
+        sizes.rows.count_covering(start_row, req.screen_height as u64, req.default_row_height);
+    let row_count = visible_rows
+        .saturating_add(req.vertical_buffer as u64 * 2)
+        .min(total_rows - start_row)
+        .min(u32::MAX as u64) as u32;
+
+    let max_cols = state.max_cols() as u64;
+    let start_col = sizes.cols.index_at(req.scroll_left, req.default_column_width, max_cols);
+    let visible_cols =
+        sizes.cols.count_covering(start_col, req.screen_width as u64, req.default_column_width);
+    let start_col = start_col as u32;
+    let col_count = visible_cols
+        .saturating_add(req.horizontal_buffer as u64 * 2)
+        .min((state.max_cols() - start_col) as u64) as u32;
+
+    let row_count = row_count.min(MAX_ROWS_PER_RESPONSE);
+    let col_count = col_count.min(MAX_COLS_PER_RESPONSE);
+    let (row_count, col_count, clamped) =
+        clamp_to_cell_cap(row_count, col_count, state.settings().max_cells_per_slice);
+    SliceWindow {
+        start_row,
+        row_count,
+        start_col,
+        col_count,
+        clamped,
+    }
+}
+
+/// Display lines in `value`: one more than its line breaks, with `\r\n`
+/// counting once.
+fn line_count(value: &str) -> u32 {
+    let breaks = value.matches(['\n', '\r']).count() - value.matches("\r\n").count();
+    1 + breaks as u32
+}
+
+/// Turns rows of `cols` cells into `cols` columns.
+fn transpose(rows: Vec<Vec<String>>, cols: u32) -> Vec<Vec<String>> {
+    let mut by_col: Vec<Vec<String>> = (0..cols).map(|_| Vec::with_capacity(rows.len())).collect();
+    for row in rows {
+        for (col, cell) in by_col.iter_mut().zip(row) {
+            col.push(cell);
+        }
+    }
+    by_col
+}
+
+/// Hashes everything a slice response carries (its empty etag, neighbor hints
+/// and view generation aside), so two slices share an etag exactly when their
+/// own contents would serialize the same.
+fn slice_etag(slice: &SliceResponse) -> String {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    slice.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Shrinks a `rows` x `cols` window to at most `max_cells` cells. Rows go first
+/// so the full width stays visible; columns only shrink when a single row is
+/// already over the cap. The flag reports whether anything was cut.
+fn clamp_to_cell_cap(rows: u32, cols: u32, max_cells: u64) -> (u32, u32, bool) {
+    if rows as u64 * cols as u64 <= max_cells {
+        return (rows, cols, false);
+    }
+    let cols = cols.min(max_cells.min(u32::MAX as u64) as u32);
+    let rows = match cols {
+        0 => 0,
+        cols => (max_cells / cols as u64).min(rows as u64) as u32,
+    };
+    (rows, cols, true)
+}
+
+fn col_index_to_letters(mut index: u32) -> String {
+    // 0 -> A, 25 -> Z, 26 -> AA, 27 -> AB, ...
+    let mut chars: Vec<char> = Vec::new();
+    loop {
+        let rem = index % 26;
+        chars.push((b'A' + (rem as u8)) as char);
+        index /= 26;
+        if index == 0 {
+            break;
+        }
+        index -= 1; // carry adjustment for 1-based alphabetic sequence
+    }
+    chars.iter().rev().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use source::SyntheticSource;
+
+    /// The coordinate-labelled table, 100 rows by 10 columns.
+    fn synthetic_state(config: Config) -> Arc<AppState> {
+        let source = SyntheticSource {
+            rows: 100,
+            cols: 10,
+            seed: None,
+            columns: Vec::new(),
+            sparsity: 0.0,
+        };
+        Arc::new(AppState::new(config, Box::new(source)))
+    }
+
+    fn session_on(state: &Arc<AppState>) -> SessionState {
+        let (outbound, _) = outbound::spawn(futures_util::sink::drain());
+        state.register(outbound).unwrap()
+    }
+
+    fn slice_request(scroll_top: u64) -> SliceRequest {
+        let json = format!(
+            r#"{{"type":"slice_request","screenWidth":300,"screenHeight":40,
+                "horizontalBuffer":0,"verticalBuffer":0,"defaultColumnWidth":100,
+                "defaultRowHeight":20,"scrollLeft":100,"scrollTop":{}}}"#,
+            scroll_top
+        );
+        match protocol::parse_client_message(&json) {
+            Ok(ClientMessage::SliceRequest(req)) => req,
+            other => panic!("not a slice request: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_slice_answers_a_viewport_on_its_own() {
+        let state = synthetic_state(Config::from_args(Vec::new()).unwrap());
+        let mut session = session_on(&state);
+        let Ok(Some(Message::Text(text))) = handle_slice(&state, &mut session, slice_request(60))
+        else {
+            panic!("no slice frame");
+        };
+        let slice: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(slice["type"], "slice_response");
+        let cells = serde_json::json!([["R4C B", "R4C C", "R4C D"], ["R5C B", "R5C C", "R5C D"]]);
+        assert_eq!(slice["cellsByRow"], cells);
+
+        let far = handle_slice(&state, &mut session, slice_request(100 * 20 * 5));
+        assert_eq!(far.err().map(|err| err.code), Some("scroll_out_of_range"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_demo_script_applies_each_edit_at_its_time() {
+        let mut config = Config::from_args(Vec::new()).unwrap();
+        let edit = |at_ms: u64, value: &str| {
+            let action = serde_json::json!({"type": "edit", "row": 0, "col": 1, "value": value});
+            serde_json::from_value(serde_json::json!({"atMs": at_ms, "action": action})).unwrap()
+        };
+        config.demo_script = vec![edit(1000, "first"), edit(3000, "second")];
+        let state = synthetic_state(config);
+        let cell = || state.cell_in(&state.overrides.read().unwrap(), 0, 1);
+        let demo = run_demo_script(state.clone());
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(cell(), "R1C B");
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert_eq!(cell(), "first");
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert_eq!(cell(), "first");
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert_eq!(cell(), "second");
+        demo.await.unwrap();
+    }
+}
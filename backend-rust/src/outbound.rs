@@ -0,0 +1,413 @@
+//! Per-connection outbound queue.
+//!
+//! Every connection gets a bounded queue drained by its own writer task, so a
+//! client that stops reading only ever stalls itself. Replies wait for room in
+//! the queue, while pushes the client did not ask for (broadcasts) are dropped
+//! when it is full. A connection that drops too many before its queue next
+//! empties is closed as a slow consumer; one that catches up starts over.
+//!
+//! The writer either flushes every message as it goes or, under
+//! [`FlushPolicy::Coalesce`], writes whatever else arrives within a short
+//! window and flushes them together. It also does the framing and gzipping
+//! of [`crate::compress`] for connections that negotiated it, so that work
+//! stays off the task handling requests.
+
+use crate::compress;
+use axum::extract::ws::{CloseFrame, Message};
+use futures_util::{Sink, SinkExt};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+/// Messages buffered per connection before pushes start being dropped.
+pub const QUEUE_CAPACITY: usize = 256;
+
+/// Pushes dropped while the queue stays backed up before the connection is
+/// closed as a slow consumer.
+pub const SLOW_CONSUMER_DROP_LIMIT: u32 = 1024;
+
+/// Why the server closed a connection, carried in the close frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    SlowConsumer,
+    /// Over `--max-connections`.
+    Overloaded,
+    /// The server is stopping, typically to restart.
+    ShuttingDown,
+}
+
+impl CloseReason {
+    pub fn code(self) -> u16 {
+        match self {
+            // Policy violation: the client is not keeping up with its stream.
+            CloseReason::SlowConsumer => 1008,
+            // Try again later.
+            CloseReason::Overloaded => 1013,
+            // Going away.
+            CloseReason::ShuttingDown => 1001,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseReason::SlowConsumer => "slow consumer",
+            CloseReason::Overloaded => "too many connections",
+            CloseReason::ShuttingDown => "server shutting down",
+        }
+    }
+
+    /// The machine-readable name in the close payload.
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::SlowConsumer => "slow_consumer",
+            CloseReason::Overloaded => "overloaded",
+            CloseReason::ShuttingDown => "shutting_down",
+        }
+    }
+
+    /// How long a client should wait before reconnecting, as a multiple of
+    /// `base`. A restarting server is back soonest, while an overloaded one
+    /// would refuse an eager reconnect all over again.
+    pub fn reconnect_delay(self, base: Duration) -> Duration {
+        match self {
+            CloseReason::ShuttingDown => base,
+            CloseReason::SlowConsumer => base * 2,
+            CloseReason::Overloaded => base * 5,
+        }
+    }
+
+    /// The close frame's reason string: JSON such as
+    /// `{"reason":"overloaded","retryAfterMs":5000}` for clients to back off by.
+    pub fn payload(self, base: Duration) -> String {
+        serde_json::json!({
+            "reason": self.name(),
+            "retryAfterMs": self.reconnect_delay(base).as_millis() as u64,
+        })
+        .to_string()
+    }
+}
+
+/// When the writer flushes the socket.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// After every message: lowest latency.
+    #[default]
+    Immediate,
+    /// Once per batch of messages arriving within the window after the
+    /// first: fewer writes at the cost of up to that much delay.
+    Coalesce(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushOutcome {
+    Queued,
+    Dropped,
+    Closed,
+}
+
+/// Handle for queueing messages to one connection. Cheap to clone.
+#[derive(Clone)]
+pub struct Outbound {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    tx: mpsc::Sender<Message>,
+    dropped: AtomicU32,
+    close: Notify,
+    close_reason: OnceLock<CloseReason>,
+    /// `compress::wrap`'s threshold, or `usize::MAX` when not compressing.
+    compress_min_bytes: AtomicUsize,
+    /// Scaled by the close reason into the suggested reconnect delay.
+    reconnect_delay: Duration,
+}
+
+impl Inner {
+    fn encode(&self, msg: Message) -> Message {
+        let min_bytes = self.compress_min_bytes.load(Ordering::Relaxed);
+        if min_bytes == usize::MAX {
+            return msg;
+        }
+        match msg {
+            Message::Text(text) => Message::Binary(compress::wrap(text.as_bytes(), min_bytes)),
+            Message::Binary(bytes) => Message::Binary(compress::wrap(&bytes, min_bytes)),
+            other => other,
+        }
+    }
+}
+
+/// Reconnect delay [`spawn`] bases its close frames on.
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Starts the writer task for `sink` and returns the handle used to feed it.
+/// The task finishes when the sink errors, the connection is closed, or every
+/// handle is gone.
+pub fn spawn<S>(sink: S) -> (Outbound, JoinHandle<()>)
+where
+    S: Sink<Message> + Unpin + Send + 'static,
+{
+    spawn_with(sink, FlushPolicy::Immediate, DEFAULT_RECONNECT_DELAY)
+}
+
+/// [`spawn`] with a choice of flush policy and of the delay close frames
+/// suggest reconnecting after.
+pub fn spawn_with<S>(
+    sink: S,
+    policy: FlushPolicy,
+    reconnect_delay: Duration,
+) -> (Outbound, JoinHandle<()>)
+where
+    S: Sink<Message> + Unpin + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+    let inner = Arc::new(Inner {
+        tx,
+        dropped: AtomicU32::new(0),
+        close: Notify::new(),
+        close_reason: OnceLock::new(),
+        compress_min_bytes: AtomicUsize::new(usize::MAX),
+        reconnect_delay,
+    });
+    let writer = tokio::spawn(run_writer(sink, rx, inner.clone(), policy));
+    (Outbound { inner }, writer)
+}
+
+async fn run_writer<S>(
+    mut sink: S,
+    mut rx: mpsc::Receiver<Message>,
+    inner: Arc<Inner>,
+    policy: FlushPolicy,
+) where
+    S: Sink<Message> + Unpin,
+{
+    loop {
+        tokio::select! {
+            msg = rx.recv() => {
+                let Some(msg) = msg else { break };
+                tokio::select! {
+                    res = write(&mut sink, msg, &mut rx, &inner, policy) => {
+                        if res.is_err() {
+                            break;
+                        }
+                        if rx.is_empty() {
+                            inner.dropped.store(0, Ordering::Relaxed);
+                        }
+                    }
+                    // The client stopped reading mid-send; there is no point
+                    // queueing a close frame behind it.
+                    _ = inner.close.notified() => break,
+                }
+            }
+            _ = inner.close.notified() => {
+                if let Some(reason) = inner.close_reason.get() {
+                    let frame = CloseFrame {
+                        code: reason.code(),
+                        reason: reason.payload(inner.reconnect_delay).into(),
+                    };
+                    let _ = sink.send(Message::Close(Some(frame))).await;
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// Writes `first`, plus under coalescing anything that follows within the
+/// window, then flushes.
+async fn write<S>(
+    sink: &mut S,
+    first: Message,
+    rx: &mut mpsc::Receiver<Message>,
+    inner: &Inner,
+    policy: FlushPolicy,
+) -> Result<(), S::Error>
+where
+    S: Sink<Message> + Unpin,
+{
+    let window = match policy {
+        FlushPolicy::Immediate => return sink.send(inner.encode(first)).await,
+        FlushPolicy::Coalesce(window) => window,
+    };
+    sink.feed(inner.encode(first)).await?;
+    let deadline = tokio::time::Instant::now() + window;
+    while let Ok(Some(msg)) = tokio::time::timeout_at(deadline, rx.recv()).await {
+        sink.feed(inner.encode(msg)).await?;
+    }
+    sink.flush().await
+}
+
+impl Outbound {
+    /// Queues a reply, waiting for room if the client is behind. Returns
+    /// `false` once the connection is gone.
+    pub async fn send(&self, msg: Message) -> bool {
+        self.inner.tx.send(msg).await.is_ok()
+    }
+
+    /// Queues a push without waiting. When the queue is full the push is
+    /// dropped, and after too many drops the connection is closed.
+    pub fn push(&self, msg: Message) -> PushOutcome {
+        match self.inner.tx.try_send(msg) {
+            Ok(()) => PushOutcome::Queued,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                let dropped = self.inner.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped >= SLOW_CONSUMER_DROP_LIMIT {
+                    self.close(CloseReason::SlowConsumer);
+                    PushOutcome::Closed
+                } else {
+                    tracing::debug!("outbound queue full, dropped push ({} so far)", dropped);
+                    PushOutcome::Dropped
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => PushOutcome::Closed,
+        }
+    }
+
+    /// Frames, and gzips from `min_bytes` on, every message written after
+    /// this; `None` goes back to sending them as they are.
+    pub fn set_compression(&self, min_bytes: Option<usize>) {
+        let min_bytes = min_bytes.unwrap_or(usize::MAX);
+        self.inner.compress_min_bytes.store(min_bytes, Ordering::Relaxed);
+    }
+
+    /// Asks the writer to send a close frame and stop.
+    pub fn close(&self, reason: CloseReason) {
+        if self.inner.close_reason.set(reason).is_ok() {
+            tracing::warn!("closing connection: {}", reason.reason());
+            self.inner.close.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::sync::Mutex;
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+
+    /// A sink that takes nothing until opened, like a client that stopped
+    /// reading, and keeps count of what it was sent and how often flushed.
+    #[derive(Clone, Default)]
+    struct Gate(Arc<GateState>);
+
+    #[derive(Default)]
+    struct GateState {
+        open: AtomicBool,
+        waker: Mutex<Option<Waker>>,
+        received: AtomicUsize,
+        flushes: AtomicUsize,
+    }
+
+    impl Gate {
+        fn open(&self, open: bool) {
+            self.0.open.store(open, Ordering::SeqCst);
+            if let Some(waker) = self.0.waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+
+        fn received(&self) -> usize {
+            self.0.received.load(Ordering::SeqCst)
+        }
+
+        fn flushes(&self) -> usize {
+            self.0.flushes.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Sink<Message> for Gate {
+        type Error = ();
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            if self.0.open.load(Ordering::SeqCst) {
+                return Poll::Ready(Ok(()));
+            }
+            *self.0.waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+
+        fn start_send(self: Pin<&mut Self>, _: Message) -> Result<(), ()> {
+            self.0.received.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            self.0.flushes.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn push_many(outbound: &Outbound, count: usize) -> Vec<PushOutcome> {
+        (0..count).map(|_| outbound.push(Message::Text("{}".to_string()))).collect()
+    }
+
+    async fn wait_for(gate: &Gate, received: usize) {
+        while gate.received() < received {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn a_stalled_client_loses_pushes_without_holding_up_others() {
+        let stalled = Gate::default();
+        let (slow, _writer) = spawn(stalled.clone());
+        let reading = Gate::default();
+        reading.open(true);
+        let (fast, _writer) = spawn(reading.clone());
+        // Let the stalled writer take its first message and block on it.
+        slow.push(Message::Text("{}".to_string()));
+        tokio::task::yield_now().await;
+
+        let outcomes = push_many(&slow, QUEUE_CAPACITY + 10);
+        let dropped = outcomes.iter().filter(|&&outcome| outcome == PushOutcome::Dropped).count();
+        assert!(dropped >= 10, "only {} pushes dropped", dropped);
+        assert!(push_many(&fast, 10).iter().all(|&outcome| outcome == PushOutcome::Queued));
+        wait_for(&reading, 10).await;
+        assert_eq!(stalled.received(), 0);
+    }
+
+    #[tokio::test]
+    async fn catching_up_forgives_earlier_drops() {
+        let gate = Gate::default();
+        let (outbound, _writer) = spawn(gate.clone());
+        let backlog = QUEUE_CAPACITY + SLOW_CONSUMER_DROP_LIMIT as usize - 1;
+        let mut received = 0;
+        for _ in 0..2 {
+            // Each backlog drops all but one of the limit.
+            let outcomes = push_many(&outbound, backlog);
+            assert!(!outcomes.contains(&PushOutcome::Closed));
+            received += outcomes.iter().filter(|&&outcome| outcome == PushOutcome::Queued).count();
+            gate.open(true);
+            wait_for(&gate, received).await;
+            tokio::task::yield_now().await;
+            gate.open(false);
+        }
+        assert_eq!(push_many(&outbound, 1), vec![PushOutcome::Queued]);
+    }
+
+    /// Flushes taken to write five messages pushed back to back.
+    async fn flushes_for_five(policy: FlushPolicy) -> usize {
+        let gate = Gate::default();
+        gate.open(true);
+        let (outbound, _writer) = spawn_with(gate.clone(), policy, DEFAULT_RECONNECT_DELAY);
+        push_many(&outbound, 5);
+        wait_for(&gate, 5).await;
+        // Let a coalescing window run out before counting.
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        gate.flushes()
+    }
+
+    #[tokio::test]
+    async fn coalescing_flushes_rapid_messages_together() {
+        assert_eq!(flushes_for_five(FlushPolicy::Immediate).await, 5);
+        assert_eq!(flushes_for_five(FlushPolicy::Coalesce(Duration::from_millis(10))).await, 1);
+    }
+}
@@ -0,0 +1,192 @@
+//! Parquet files, read a row group at a time.
+//!
+//! Opening reads only the footer: the schema, and the row count of every row
+//! group, which is enough to find the group holding any row. Reading a cell
+//! decodes just that group, and the last [`CACHED_ROW_GROUPS`] decoded groups
+//! are kept, so the rest of a slice in the same group costs nothing more.
+//! Cells are the typed Arrow values formatted as text; nulls have no value.
+
+use crate::source::DataSource;
+use arrow_array::{Array, RecordBatch};
+use arrow_cast::display::{ArrayFormatter, FormatOptions};
+use bytes::Bytes;
+use parquet::arrow::arrow_reader::{
+    ArrowReaderMetadata, ArrowReaderOptions, ParquetRecordBatchReaderBuilder,
+};
+use parquet::file::reader::{ChunkReader, Length};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+/// Decoded row groups kept for later reads.
+pub const CACHED_ROW_GROUPS: usize = 4;
+
+pub struct ParquetSource<R = File> {
+    /// Column names from the file's schema.
+    pub headers: Vec<String>,
+    /// Each column's Arrow type, such as `Int64` or `Utf8`.
+    pub col_types: Vec<String>,
+    reader: Arc<R>,
+    metadata: ArrowReaderMetadata,
+    /// The first row of each row group, then the total row count.
+    group_starts: Vec<u64>,
+    /// Recently decoded groups, oldest first.
+    decoded: Mutex<VecDeque<(usize, RecordBatch)>>,
+}
+
+impl ParquetSource {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| format!("cannot open parquet {}: {}", path, err))?;
+        Self::from_reader(file).map_err(|err| format!("{}: {}", path, err))
+    }
+}
+
+impl<R: ChunkReader + 'static> ParquetSource<R> {
+    /// Reads the footer of the file in `reader`, and nothing else yet.
+    pub fn from_reader(reader: R) -> Result<Self, String> {
+        let metadata = ArrowReaderMetadata::load(&reader, ArrowReaderOptions::new())
+            .map_err(|err| format!("cannot read parquet footer: {}", err))?;
+        let fields = metadata.schema().fields();
+        let headers = fields.iter().map(|field| field.name().clone()).collect();
+        let col_types = fields.iter().map(|field| field.data_type().to_string()).collect();
+        let mut group_starts = vec![0u64];
+        for group in metadata.metadata().row_groups() {
+            group_starts.push(group_starts.last().unwrap() + group.num_rows().max(0) as u64);
+        }
+        Ok(Self {
+            headers,
+            col_types,
+            reader: Arc::new(reader),
+            metadata,
+            group_starts,
+            decoded: Mutex::default(),
+        })
+    }
+
+    /// Row group `group`, decoded now unless it already was.
+    fn row_group(&self, group: usize) -> Option<RecordBatch> {
+        // Held while decoding too, as readers of one file share its cursor.
+        let mut decoded = self.decoded.lock().unwrap();
+        if let Some((_, batch)) = decoded.iter().find(|(cached, _)| *cached == group) {
+            return Some(batch.clone());
+        }
+        let batch = match self.decode(group) {
+            Ok(batch) => batch,
+            Err(err) => {
+                tracing::warn!("cannot read parquet row group {}: {}", group, err);
+                return None;
+            }
+        };
+        if decoded.len() == CACHED_ROW_GROUPS {
+            decoded.pop_front();
+        }
+        decoded.push_back((group, batch.clone()));
+        Some(batch)
+    }
+
+    fn decode(&self, group: usize) -> Result<RecordBatch, String> {
+        let rows = self.group_starts[group + 1] - self.group_starts[group];
+        let reader = Shared(self.reader.clone());
+        let mut batches =
+            ParquetRecordBatchReaderBuilder::new_with_metadata(reader, self.metadata.clone())
+                .with_row_groups(vec![group])
+                .with_batch_size(rows.max(1) as usize)
+                .build()
+                .map_err(|err| err.to_string())?;
+        match batches.next() {
+            Some(batch) => batch.map_err(|err| err.to_string()),
+            None => Ok(RecordBatch::new_empty(self.metadata.schema().clone())),
+        }
+    }
+}
+
+impl<R: ChunkReader + 'static> DataSource for ParquetSource<R> {
+    fn row_count(&self) -> u64 {
+        *self.group_starts.last().unwrap()
+    }
+
+    fn col_count(&self) -> u32 {
+        self.headers.len() as u32
+    }
+
+    fn cell(&self, row: u64, col: u32) -> Option<String> {
+        let group = self.group_starts.partition_point(|&start| start <= row) - 1;
+        let batch = self.row_group(group)?;
+        let index = (row - self.group_starts[group]) as usize;
+        let array = batch.column(col as usize);
+        if array.is_null(index) {
+            return None;
+        }
+        let formatter = ArrayFormatter::try_new(array.as_ref(), &FormatOptions::default()).ok()?;
+        Some(formatter.value(index).to_string())
+    }
+
+    fn col_types(&self) -> Vec<String> {
+        self.col_types.clone()
+    }
+}
+
+/// One reader handed to every row group's decoder.
+struct Shared<R>(Arc<R>);
+
+impl<R: ChunkReader> Length for Shared<R> {
+    fn len(&self) -> u64 {
+        self.0.len()
+    }
+}
+
+impl<R: ChunkReader> ChunkReader for Shared<R> {
+    type T = R::T;
+
+    fn get_read(&self, start: u64) -> parquet::errors::Result<R::T> {
+        self.0.get_read(start)
+    }
+
+    fn get_bytes(&self, start: u64, length: usize) -> parquet::errors::Result<Bytes> {
+        self.0.get_bytes(start, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Int64Array, StringArray};
+    use parquet::arrow::ArrowWriter;
+    use parquet::file::properties::WriterProperties;
+
+    /// `rows` rows of an id and a name, `group_rows` to a row group.
+    fn parquet(rows: i64, group_rows: usize) -> Bytes {
+        let ids = Int64Array::from_iter_values(0..rows);
+        let names = StringArray::from_iter_values((0..rows).map(|id| format!("row {}", id)));
+        let batch = RecordBatch::try_from_iter([
+            ("id", Arc::new(ids) as Arc<dyn Array>),
+            ("name", Arc::new(names) as Arc<dyn Array>),
+        ])
+        .unwrap();
+        let props = WriterProperties::builder()
+            .set_max_row_group_row_count(Some(group_rows))
+            .build();
+        let mut file = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut file, batch.schema(), Some(props)).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        Bytes::from(file)
+    }
+
+    fn decoded_groups<R>(source: &ParquetSource<R>) -> Vec<usize> {
+        source.decoded.lock().unwrap().iter().map(|(group, _)| *group).collect()
+    }
+
+    #[test]
+    fn a_cell_in_a_later_row_group_decodes_only_that_group() {
+        let source = ParquetSource::from_reader(parquet(1000, 100)).unwrap();
+        assert_eq!(source.row_count(), 1000);
+        assert_eq!(source.headers, ["id", "name"]);
+        assert_eq!(source.col_types, ["Int64", "Utf8"]);
+        assert!(decoded_groups(&source).is_empty());
+
+        assert_eq!(source.cell(734, 1).as_deref(), Some("row 734"));
+        assert_eq!(source.cell(799, 0).as_deref(), Some("799"));
+        assert_eq!(decoded_groups(&source), [7]);
+    }
+}
@@ -0,0 +1,2128 @@
+//! Wire messages exchanged over the socket.
+
+use crate::cache::CacheCounters;
+use crate::export::ExportFormat;
+use crate::format::{CellStyle, FormatRule};
+use crate::sizes::PinSide;
+use crate::stats::{ColumnStats, DistinctValues, Overview};
+use crate::view::{Filter, SortKey};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{InstanceType, SchemaObject, StringValidation, SubschemaValidation};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+
+/// Every message a client may send, dispatched on its `type` field.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    MetadataRequest(MetadataRequest),
+    EchoRequest(EchoRequest),
+    /// Answered with `limits_response`.
+    LimitsRequest,
+    SliceRequest(SliceRequest),
+    CellRequest(CellRequest),
+    RangeRequest(RangeRequest),
+    ColumnStatsRequest(ColumnStatsRequest),
+    CancelRequest(CancelRequest),
+    CellUpdate(CellUpdate),
+    RevertCell(RevertCell),
+    CellUpdateBatch(UpdateBatch),
+    /// Reverts this connection's latest edit.
+    Undo,
+    /// Reapplies the edit the latest `undo` reverted.
+    Redo,
+    SubscribeRange(SubscribeRange),
+    UnsubscribeRange(UnsubscribeRange),
+    FilterRequest(FilterRequest),
+    FilterInRequest(FilterInRequest),
+    FilterExprRequest(FilterExprRequest),
+    ClearFiltersRequest(ClearFiltersRequest),
+    SortRequest(SortRequest),
+    RowsRequest(RowsRequest),
+    PageRequest(PageRequest),
+    ExtentRequest(ExtentRequest),
+    SetRowHeight(SetRowHeight),
+    SetColWidth(SetColWidth),
+    PinColumnRequest(PinColumnRequest),
+    TailRequest(TailRequest),
+    JumpToCell(JumpToCell),
+    SelectRowRequest(SelectRowRequest),
+    SelectColRequest(SelectColRequest),
+    ExportRequest(ExportRequest),
+    ExportToFileRequest(ExportToFileRequest),
+    DistinctValuesRequest(DistinctValuesRequest),
+    OverviewRequest(OverviewRequest),
+    AdminReset(AdminReset),
+    MergeCells(MergeCells),
+    SetConditionalFormat(SetConditionalFormat),
+}
+
+impl ClientMessage {
+    /// Whether the reply comes from a background operation, which counts
+    /// toward `--max-inflight`.
+    pub fn runs_in_background(&self) -> bool {
+        matches!(
+            self,
+            ClientMessage::ColumnStatsRequest(_)
+                | ClientMessage::DistinctValuesRequest(_)
+                | ClientMessage::OverviewRequest(_)
+                | ClientMessage::ExportRequest(_)
+                | ClientMessage::ExportToFileRequest(_)
+                | ClientMessage::FilterRequest(_)
+                | ClientMessage::FilterInRequest(_)
+                | ClientMessage::FilterExprRequest(_)
+                | ClientMessage::ClearFiltersRequest(_)
+                | ClientMessage::SortRequest(_)
+        )
+    }
+}
+
+/// The `type` tag of every [`ClientMessage`] variant, so a message that fails
+/// to parse can be told apart as an unknown type or a known one with bad fields.
+pub const CLIENT_MESSAGE_TYPES: &[&str] = &[
+    "metadata_request",
+    "slice_request",
+    "cell_request",
+    "range_request",
+    "column_stats_request",
+    "cancel_request",
+    "cell_update",
+    "subscribe_range",
+    "unsubscribe_range",
+    "filter_request",
+    "clear_filters_request",
+    "admin_reset",
+    "merge_cells",
+    "set_conditional_format",
+    "sort_request",
+    "rows_request",
+    "export_request",
+    "distinct_values_request",
+    "filter_in_request",
+    "undo",
+    "redo",
+    "export_to_file_request",
+    "echo_request",
+    "filter_expr_request",
+    "page_request",
+    "select_row_request",
+    "select_col_request",
+    "extent_request",
+    "set_row_height",
+    "set_col_width",
+    "limits_request",
+    "overview_request",
+    "pin_column_request",
+    "tail_request",
+    "jump_to_cell",
+    "revert_cell",
+    "cell_update_batch",
+];
+
+/// Also negotiates the connection's capabilities: the server only uses
+/// features listed here, and forgets earlier ones on each request.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataRequest {
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// Average pixel width of one character in the client's cell font. When
+    /// set, the response suggests a width for every column.
+    #[serde(default)]
+    pub char_width: Option<f64>,
+}
+
+/// Answered at once with `echo_response`, for measuring round trips.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EchoRequest {
+    /// Anything, typically the client's send time; returned untouched.
+    #[serde(default)]
+    pub payload: serde_json::Value,
+}
+
+/// Optional protocol features. Names this server does not know are ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Slices may be sent as binary frames; see [`crate::binary`].
+    Binary,
+    /// Messages come framed and, when large, gzipped; see [`crate::compress`].
+    /// Only offered under `--compress-min-bytes`.
+    Gzip,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SliceRequest {
+    pub screen_width: u32,
+    pub screen_height: u32,
+    pub horizontal_buffer: u32,
+    pub vertical_buffer: u32,
+    pub default_column_width: u32,
+    pub default_row_height: u32,
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub scroll_left: u64,
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub scroll_top: u64,
+    /// Send blank cells as `null` and fully blank rows, or columns in column
+    /// orientation, as a single `null`.
+    #[serde(default)]
+    pub sparse: bool,
+    /// Etag of a slice the client already holds; an identical result is
+    /// answered with `not_modified` instead of the cells.
+    #[serde(default)]
+    pub if_none_match: Option<String>,
+    #[serde(default)]
+    pub schema: Schema,
+    /// Apply the session's conditional formatting and return `styles`.
+    #[serde(default)]
+    pub styled: bool,
+    /// Return `sortKeys` for cells whose text is a formatted number.
+    #[serde(default)]
+    pub sort_keys: bool,
+    /// Return `neighborHints` describing the slices one screen away.
+    #[serde(default)]
+    pub include_neighbors: bool,
+    /// Return `dirty`, the cells whose edits differ from the source.
+    #[serde(default)]
+    pub dirty: bool,
+    /// Return `originals`, the source value behind every edited cell.
+    #[serde(default)]
+    pub originals: bool,
+    /// Send cells the source holds no value for as `null`, keeping `""` for
+    /// empty ones. Takes precedence over `sparse`, whose `null` means blank.
+    #[serde(default)]
+    pub distinguish_null: bool,
+    /// Return `rowLineCounts`, for sizing rows to cells with line breaks.
+    #[serde(default)]
+    pub line_counts: bool,
+    /// Only honoured when negotiated; otherwise the reply is JSON.
+    #[serde(default)]
+    pub encoding: Encoding,
+    #[serde(default)]
+    pub orientation: Orientation,
+    /// Only these of the slice's always-sent fields, for clients that already
+    /// hold the rest; all of them when absent. The window's position, counts,
+    /// `clamped` and `etag` are always sent, and opt-in fields follow their
+    /// own options. Always sent as JSON.
+    #[serde(default)]
+    pub fields: Option<Vec<SliceField>>,
+    /// The view generation the client expects, from an earlier slice or
+    /// `view_response`. If the session's filters or sort have changed since,
+    /// `onViewChange` says what to do. Always sent as JSON.
+    #[serde(default)]
+    pub view_generation: Option<u64>,
+    #[serde(default)]
+    pub on_view_change: OnViewChange,
+    /// Send the cells a row at a time, as each is read, in `slice_row`
+    /// messages followed by a `slice_end`, for sources slow enough that the
+    /// first rows are worth showing early. Only the cells are streamed, and
+    /// densely: every option above, and any pinned columns, are ignored.
+    #[serde(default)]
+    pub stream_viewport: bool,
+    /// Skip this request if a later one with the same key is already
+    /// waiting by the time it is reached, so a burst of scrolling only builds
+    /// the last viewport.
+    #[serde(default)]
+    pub coalesce_key: Option<String>,
+    /// Answer a request skipped that way with `superseded` rather than
+    /// nothing.
+    #[serde(default)]
+    pub ack_superseded: bool,
+}
+
+/// How a slice lays out its cells.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Orientation {
+    /// `cellsByRow`, one array per row.
+    #[default]
+    Row,
+    /// `cellsByCol`, one array per column, for clients that draw or upload
+    /// a column at a time. Always sent as JSON.
+    Column,
+}
+
+/// What a slice request with an outdated `viewGeneration` gets.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OnViewChange {
+    /// The slice of the current view, with `viewChanged` set.
+    #[default]
+    Flag,
+    /// `view_changed` instead of a slice.
+    Reject,
+}
+
+/// A field of a slice response that `fields` can leave out.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SliceField {
+    ColLetters,
+    CellsByRow,
+    CellsByCol,
+    Merges,
+    RowIds,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// Needs the `binary` capability.
+    Binary,
+}
+
+/// Key names used for the reply.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Schema {
+    /// `slice_response` with its usual camelCase keys.
+    #[default]
+    Full,
+    /// `slice_min`, the same data under the single-letter keys of [`MinSliceResponse`].
+    Min,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CellRequest {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    pub col: u32,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RangeRequest {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub start_col: u32,
+    pub row_count: u32,
+    pub col_count: u32,
+}
+
+/// Stats over the rows of the session's view, filters applied.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnStatsRequest {
+    pub col: u32,
+    /// Lets the client cancel the scan with a `cancel_request`.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Asks for a coarse picture of the session's view, as many buckets of rows
+/// as the minimap has room for, each summarised by how full it is.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OverviewRequest {
+    pub buckets: u32,
+    #[serde(default)]
+    pub start_col: u32,
+    /// Columns to summarise; every one from `startCol` on when absent.
+    #[serde(default)]
+    pub col_count: Option<u32>,
+    /// Lets the client cancel the scan with a `cancel_request`.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Asks for a column's distinct values over the whole table, ignoring the
+/// session's filters.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DistinctValuesRequest {
+    pub col: u32,
+    /// Lets the client cancel the scan with a `cancel_request`.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelRequest {
+    pub request_id: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CellUpdate {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    pub col: u32,
+    pub value: String,
+    /// Only write if the cell is still at this version, as last seen in a
+    /// `cell_response` or `cells_updated`. Without it the last write wins.
+    #[serde(default)]
+    pub expected_version: Option<u64>,
+}
+
+/// Drops a cell's edit so it shows the source value again, unlike writing an
+/// empty string. Answered with `cells_updated`, empty if the cell had no edit.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RevertCell {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    pub col: u32,
+}
+
+/// Writes a block of cells at once, such as a paste, with `row` and `col` its
+/// top-left cell and `cells` one equally long array per row. Also accepted as
+/// a binary frame, laid out as described in the `binary` module. Answered
+/// with one `cells_updated`; batches are not undone by `undo`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateBatch {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    pub col: u32,
+    pub cells: Vec<Vec<String>>,
+}
+
+/// Asks for `cells_updated` pushes limited to a rectangle. Once a connection
+/// holds any subscription, changes outside all of them are no longer pushed.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscribeRange {
+    pub subscription_id: String,
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub start_col: u32,
+    pub row_count: u32,
+    pub col_count: u32,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UnsubscribeRange {
+    pub subscription_id: String,
+}
+
+/// Sets this session's filter on a column; slices then address only the rows
+/// that pass every filter.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterRequest {
+    #[serde(flatten)]
+    pub filter: Filter,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Shorthand for a `filter_request` with op `in`: keeps the rows whose `col`
+/// cell is one of `values`, as a filter dropdown's checkboxes would.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterInRequest {
+    pub col: u32,
+    pub values: HashSet<String>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Filters this session's view by an expression such as
+/// `age > 30 AND city = "NYC"`, alongside any column filters; see
+/// [`crate::expr`] for the syntax. A blank expression removes it.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterExprRequest {
+    pub expr: String,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Removes the filter on `col`, or all of them when `col` is absent.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ClearFiltersRequest {
+    #[serde(default)]
+    pub col: Option<u32>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Sorts this session's view by `keys`; an empty list restores physical
+/// order. Filters stay in place.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SortRequest {
+    pub keys: Vec<SortKey>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// A physical row to keep in sight: once sorted, `scroll_to` follows the
+    /// `view_response` with where it moved, if it is still in the view.
+    #[serde(default)]
+    pub follow_row: Option<u64>,
+}
+
+/// Reads the rows at visual positions `start..start + count` of the session's
+/// view, independent of any viewport.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RowsRequest {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start: u64,
+    pub count: u32,
+    pub start_col: u32,
+    pub col_count: u32,
+}
+
+/// Reads the session's view a page at a time: up to `pageSize` rows from
+/// `cursor`, or from the top without one. Each response carries the cursor
+/// for the next page, so a client can walk every row in view order without
+/// doing any position arithmetic. Columns default to the whole width.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PageRequest {
+    #[serde(default)]
+    pub cursor: Option<String>,
+    pub page_size: u32,
+    #[serde(default)]
+    pub start_col: u32,
+    #[serde(default)]
+    pub col_count: Option<u32>,
+}
+
+/// The pixel size of the session's whole view, for sizing the scroll
+/// container, counting custom row heights and column widths.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtentRequest {
+    pub default_row_height: u32,
+    pub default_column_width: u32,
+}
+
+/// Sizes one visual row of this session's view; without `height` it goes
+/// back to the default. Slices and the extent then account for it.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SetRowHeight {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    #[serde(default)]
+    pub height: Option<u32>,
+}
+
+/// Like [`SetRowHeight`], for a column.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SetColWidth {
+    pub col: u32,
+    #[serde(default)]
+    pub width: Option<u32>,
+}
+
+/// Scrolls to a cell by its physical row, as the row-number gutter shows it,
+/// wherever the session's view puts that row. Answered with `scroll_to`, and
+/// needs the defaults from an earlier slice or extent request.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct JumpToCell {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    pub col: u32,
+}
+
+/// Pins a column to the left or right edge of this session's slices, or
+/// unpins it without `side`. Answered with `pin_column_response`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PinColumnRequest {
+    pub col: u32,
+    #[serde(default)]
+    pub side: Option<PinSide>,
+}
+
+/// Follows rows appended while the table loads: while the session's last
+/// slice reached the bottom of an unfiltered, unsorted table, each new batch is
+/// pushed as `tail_rows`. Answered with `tail_response`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TailRequest {
+    pub enabled: bool,
+}
+
+/// The cells of a whole row of the session's view, for a click on its header.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectRowRequest {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+}
+
+/// The cells of a whole column, in view order, for a click on its header.
+/// Long columns are cut short; `export_request` reads the rest.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectColRequest {
+    pub col: u32,
+}
+
+/// Streams a rectangle of physical rows as text in `export_chunk` messages.
+/// Unlike `range_request` it is not cut to the per-response caps: rows past
+/// the end of the table are dropped, and the rest are held to
+/// `--max-export-cells` as a range is, or refused with `range_too_large`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportRequest {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub start_col: u32,
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row_count: u64,
+    pub col_count: u32,
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Lets the client cancel the export with a `cancel_request`.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Writes the session's current view, filters and sort applied, every column
+/// and a header row, to `fileName` inside `--export-dir`. Needs
+/// `--admin-token`. Progress arrives as `export_progress`, then one
+/// `export_file_done`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportToFileRequest {
+    pub token: String,
+    /// A bare file name; any existing file is replaced.
+    pub file_name: String,
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Lets the client cancel the export with a `cancel_request`.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Joins a rectangle into one displayed cell. Edits anywhere inside it land
+/// on the top-left cell.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeCells {
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub start_col: u32,
+    pub row_count: u32,
+    pub col_count: u32,
+}
+
+/// Replaces this session's conditional formatting rules.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SetConditionalFormat {
+    pub rules: Vec<FormatRule>,
+}
+
+/// Discards every edit and every session's filters. Needs `--admin-token`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminReset {
+    pub token: String,
+}
+
+/// Writes a row position as a JSON number while JavaScript can hold it
+/// exactly, and as a string of digits past 2^53, the form [`u64_or_string`]
+/// reads back.
+fn u64_as_safe_number<S: serde::Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+    match *value {
+        value if value <= MAX_SAFE_INTEGER => serializer.serialize_u64(value),
+        value => serializer.collect_str(&value),
+    }
+}
+
+/// One row position, written by [`u64_as_safe_number`] and read by
+/// [`u64_or_string`], for positions inside lists and tuples.
+struct SafeU64(u64);
+
+impl Serialize for SafeU64 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        u64_as_safe_number(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SafeU64 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64_or_string(deserializer).map(SafeU64)
+    }
+}
+
+impl JsonSchema for SafeU64 {
+    fn schema_name() -> String {
+        "SafeU64".to_string()
+    }
+
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> schemars::schema::Schema {
+        u64_or_string_schema(gen)
+    }
+}
+
+/// A list of row positions, each written like a [`SafeU64`].
+struct SafeU64s<'a>(&'a [u64]);
+
+impl Serialize for SafeU64s<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|&value| SafeU64(value)))
+    }
+}
+
+/// [`u64_as_safe_number`] for every entry of a list of rows.
+fn u64s_as_safe_numbers<S: serde::Serializer>(
+    values: &[u64],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    SafeU64s(values).serialize(serializer)
+}
+
+/// [`u64s_as_safe_numbers`] for row ids that may be left out.
+fn row_ids_as_safe_numbers<S: serde::Serializer>(
+    ids: &Option<Vec<u64>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    ids.as_deref().map(SafeU64s).serialize(serializer)
+}
+
+/// A merge as `[top, left, rows, cols]`.
+type Merge = (u64, u32, u32, u32);
+
+/// [`u64_as_safe_number`] for the top row of each merge.
+fn merges_as_safe_numbers<S: serde::Serializer>(
+    merges: &[Merge],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let merges = merges.iter().map(|&(row, col, rows, cols)| (SafeU64(row), col, rows, cols));
+    serializer.collect_seq(merges)
+}
+
+/// Reads what [`row_ids_as_safe_numbers`] writes.
+fn row_ids_or_strings<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u64>>, D::Error> {
+    let ids = Option::<Vec<SafeU64>>::deserialize(deserializer)?;
+    Ok(ids.map(|ids| ids.into_iter().map(|id| id.0).collect()))
+}
+
+/// Reads what [`merges_as_safe_numbers`] writes.
+fn merges_or_strings<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Merge>, D::Error> {
+    let merges = Vec::<(SafeU64, u32, u32, u32)>::deserialize(deserializer)?;
+    Ok(merges.into_iter().map(|(row, col, rows, cols)| (row.0, col, rows, cols)).collect())
+}
+
+fn u64s_or_strings_schema(gen: &mut SchemaGenerator) -> schemars::schema::Schema {
+    gen.subschema_for::<Vec<SafeU64>>()
+}
+
+fn merges_schema(gen: &mut SchemaGenerator) -> schemars::schema::Schema {
+    gen.subschema_for::<Vec<(SafeU64, u32, u32, u32)>>()
+}
+
+/// What [`u64_or_string`] accepts, for [`json_schema`].
+fn u64_or_string_schema(gen: &mut SchemaGenerator) -> schemars::schema::Schema {
+    let string = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        string: Some(Box::new(StringValidation {
+            pattern: Some("^[0-9]+$".to_string()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![gen.subschema_for::<u64>(), string.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+/// JSON Schema for every message, for `--dump-schema`: `ClientMessage` and
+/// `ServerMessage` under `definitions`, with everything they refer to.
+pub fn json_schema() -> serde_json::Value {
+    let mut gen = SchemaSettings::draft07().into_generator();
+    let messages = vec![gen.subschema_for::<ClientMessage>(), gen.subschema_for::<ServerMessage>()];
+    serde_json::json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "sheets_ws_server messages",
+        "anyOf": messages,
+        "definitions": gen.definitions(),
+    })
+}
+
+/// Accepts a row position as a JSON number or as a string of digits, since
+/// JavaScript clients lose precision on numbers past 2^53 and often send
+/// those as strings instead.
+fn u64_or_string<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    struct Visitor;
+
+    impl serde::de::Visitor<'_> for Visitor {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a non-negative integer or a string of digits")
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<u64, E> {
+            Ok(value)
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<u64, E> {
+            u64::try_from(value).map_err(|_| E::custom("expected a non-negative integer"))
+        }
+
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<u64, E> {
+            value.parse().map_err(|_| E::custom(format!("{:?} is not a row position", value)))
+        }
+    }
+
+    deserializer.deserialize_any(Visitor)
+}
+
+/// Parses one text frame into a [`ClientMessage`].
+///
+/// Failures are sorted into error codes: an empty or whitespace-only frame,
+/// as some keepalive libraries send (`empty_message`), text that is not JSON
+/// (`invalid_json`), JSON that is not a typed message object
+/// (`invalid_message`), an unrecognised `type` (`unknown_type`) and a known
+/// type with bad fields (`bad_request`).
+pub fn parse_client_message(txt: &str) -> Result<ClientMessage, ProtocolError> {
+    use serde_json::error::Category;
+
+    if txt.trim().is_empty() {
+        return Err(ProtocolError::new("empty_message", "empty message"));
+    }
+    let err = match serde_json::from_str::<ClientMessage>(txt) {
+        Ok(msg) => return Ok(msg),
+        Err(err) => err,
+    };
+    if !matches!(err.classify(), Category::Data) {
+        return Err(ProtocolError::new("invalid_json", "invalid json"));
+    }
+
+    // Only the error path pays for a second look at the tag.
+    #[derive(Deserialize)]
+    struct Tag {
+        #[serde(rename = "type")]
+        kind: String,
+    }
+    match serde_json::from_str::<Tag>(txt) {
+        Err(_) => Err(ProtocolError::new("invalid_message", "missing message type")),
+        Ok(tag) if !CLIENT_MESSAGE_TYPES.contains(&tag.kind.as_str()) => {
+            Err(ProtocolError::new("unknown_type", "unknown message type"))
+        }
+        Ok(_) => Err(ProtocolError::new("bad_request", format!("bad request: {}", err))),
+    }
+}
+
+/// Every message the server sends. The `type` field comes from the variant name.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    MetadataResponse(MetadataResponse),
+    MetadataUpdate(MetadataUpdate),
+    EchoResponse(EchoResponse),
+    LimitsResponse(Limits),
+    SliceResponse(SliceResponse),
+    SliceMin(MinSliceResponse),
+    SliceRow(SliceRow),
+    SliceEnd(SliceEnd),
+    CellResponse(CellResponse),
+    RangeResponse(RangeResponse),
+    ColumnStatsResponse(ColumnStatsResponse),
+    DistinctValuesResponse(DistinctValuesResponse),
+    OverviewResponse(OverviewResponse),
+    CancelResponse(CancelResponse),
+    CellsUpdated(CellsUpdated),
+    UndoResponse(HistoryResponse),
+    RedoResponse(HistoryResponse),
+    RangeSubscribed(RangeSubscribed),
+    RangeUnsubscribed(RangeUnsubscribed),
+    ViewResponse(ViewResponse),
+    RowsResponse(RowsResponse),
+    PageResponse(PageResponse),
+    /// Also the reply to a size change, with the extent it leaves.
+    ExtentResponse(ExtentResponse),
+    PinColumnResponse(PinColumnResponse),
+    TailResponse(TailResponse),
+    TailRows(TailRows),
+    ScrollTo(ScrollTo),
+    SelectRowResponse(SelectRowResponse),
+    SelectColResponse(SelectColResponse),
+    ExportChunk(ExportChunk),
+    ExportProgress(ExportProgress),
+    ExportFileDone(ExportFileDone),
+    CellsMerged(CellsMerged),
+    ConditionalFormatSet(ConditionalFormatSet),
+    /// All edits and filters were discarded; clients should re-request what they show.
+    Reset,
+    NotModified(NotModified),
+    ViewChanged(ViewChanged),
+    Superseded(Superseded),
+    Welcome(Welcome),
+    Error(ErrorResponse),
+}
+
+impl ServerMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Debug, Hash, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SliceResponse {
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub row_count: u32,
+    pub start_col: u32,
+    pub col_count: u32,
+    /// Header label per column: the configured name, or its letters. Only
+    /// absent when left out of `fields`, like the cells, merges and row ids.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub col_letters: Option<Vec<String>>,
+    /// Absent in column orientation, where `cells_by_col` holds the cells.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cells_by_row: Option<Cells>,
+    /// The same cells transposed, one entry per column, in column orientation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cells_by_col: Option<Cells>,
+    /// Rows were dropped to stay under the cells-per-slice cap.
+    pub clamped: bool,
+    /// Merges touching the slice as `[top, left, rows, cols]`, in full even
+    /// where they extend past its edges.
+    #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "merges_as_safe_numbers")]
+    #[schemars(schema_with = "merges_schema")]
+    pub merges: Vec<(u64, u32, u32, u32)>,
+    /// Physical row id of each returned row, for the row-number gutter.
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "row_ids_as_safe_numbers")]
+    #[schemars(schema_with = "u64s_or_strings_schema")]
+    pub row_ids: Option<Vec<u64>>,
+    /// Styled cells in styled mode; cells left out keep the default look.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub styles: Vec<StyledCell>,
+    /// The numeric value the server sorts by, for cells where it differs from
+    /// the display text; cells left out sort by their text as shown.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sort_keys: Vec<CellSortKey>,
+    /// With `includeNeighbors`, one hint per direction that is still inside
+    /// the table.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub neighbor_hints: Vec<NeighborHint>,
+    /// With `dirty`, `[row, col]` within the slice of every cell holding an
+    /// edit that differs from the source value.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dirty: Vec<(u32, u32)>,
+    /// With `originals`, what the source holds under each edited cell of the
+    /// body, whose edited value is in the cells as usual.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub originals: Vec<CellOriginal>,
+    /// With pinned columns, the table column of each body column: the body
+    /// skips pinned columns, so it need not be one contiguous run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub body_cols: Vec<u32>,
+    /// Columns pinned to the left edge, with their cells for the slice's rows
+    /// however far it is scrolled.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pinned_left_cols: Vec<PinnedCol>,
+    /// Like `pinned_left_cols`, for the right edge.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pinned_right_cols: Vec<PinnedCol>,
+    /// With columns pinned left, their summed width at the session's sizes:
+    /// the freeze boundary's offset from the left edge, where its shadow goes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_left_width: Option<u64>,
+    /// Like `pinned_left_width`, measured in from the right edge.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned_right_width: Option<u64>,
+    /// With `lineCounts`, the most lines any cell of each row spans, pinned
+    /// cells included: one more than its line breaks.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub row_line_counts: Vec<u32>,
+    /// The generation of the session's view the rows were taken from, which
+    /// goes up each time a filter or sort change takes effect.
+    pub view_generation: u64,
+    /// The request's `viewGeneration` was outdated, so these may not be the
+    /// rows it expected.
+    pub view_changed: bool,
+    /// Hash of the window and its contents, for `ifNoneMatch` on a later request.
+    pub etag: String,
+}
+
+/// A pinned column of a slice: one cell per row in `row_ids`, never elided.
+#[derive(Debug, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PinnedCol {
+    pub col: u32,
+    pub label: String,
+    pub cells: Vec<String>,
+}
+
+impl SliceResponse {
+    /// Checks that the arrays sent agree with the counts: `colCount` letters,
+    /// `rowCount` rows and row ids, and `colCount` cells in every row that is
+    /// present, or `rowCount` in every column.
+    pub fn check_shape(&self) -> Result<(), String> {
+        let (rows, cols) = (self.row_count as usize, self.col_count as usize);
+        if let Some(letters) = self.col_letters.as_ref().filter(|letters| letters.len() != cols) {
+            return Err(format!("{} col letters for {} columns", letters.len(), cols));
+        }
+        if let Some(row_ids) = self.row_ids.as_ref().filter(|row_ids| row_ids.len() != rows) {
+            return Err(format!("{} row ids for {} rows", row_ids.len(), rows));
+        }
+        let (cells, lines, len, line, across) = match (&self.cells_by_row, &self.cells_by_col) {
+            (Some(cells), None) => (cells, rows, cols, "row", "wide"),
+            (None, Some(cells)) => (cells, cols, rows, "column", "long"),
+            (None, None) => return Ok(()),
+            _ => return Err("cellsByRow and cellsByCol cannot both be set".to_string()),
+        };
+        let lens: Vec<Option<usize>> = match cells {
+            Cells::Dense(cells) => cells.iter().map(|line| Some(line.len())).collect(),
+            Cells::Sparse(cells) => cells.iter().map(|line| line.as_ref().map(Vec::len)).collect(),
+            Cells::Nullable(cells) => cells.iter().map(|line| Some(line.len())).collect(),
+        };
+        if lens.len() != lines {
+            return Err(format!("{} cell {}s for {} {}s", lens.len(), line, lines, line));
+        }
+        match lens.iter().position(|n| n.is_some_and(|n| n != len)) {
+            Some(i) => Err(format!("cell {} {} is not {} {}", line, i, len, across)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// One row of a streamed slice.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SliceRow {
+    /// Position in the session's view.
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row_id: u64,
+    pub start_col: u32,
+    pub cells: Vec<String>,
+}
+
+/// Ends a streamed slice once its last `slice_row` is sent, describing the
+/// window as a `slice_response` would.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SliceEnd {
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub row_count: u32,
+    pub start_col: u32,
+    pub col_count: u32,
+    pub col_letters: Vec<String>,
+    pub clamped: bool,
+}
+
+/// Sent unprompted as soon as a connection opens, under `--welcome`, so a
+/// client can size its first slice without a `metadata_request` round trip.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Welcome {
+    pub server_version: String,
+    /// The `Sec-WebSocket-Protocol` agreed on for this connection, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subprotocol: Option<String>,
+    pub max_rows: u64,
+    pub max_cols: u32,
+    /// Every capability a `metadata_request` could negotiate.
+    pub capabilities: Vec<Capability>,
+    pub read_only: bool,
+    pub limits: Limits,
+}
+
+/// The caps the server holds requests to, as currently configured. There
+/// are no rate limits; a client that stops reading is instead closed once
+/// `slowConsumerDropLimit` pushes to it have been dropped.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    pub max_rows_per_response: u32,
+    pub max_cols_per_response: u32,
+    pub max_cells_per_slice: u64,
+    pub max_buffer: u32,
+    pub max_filters: usize,
+    pub max_sort_keys: usize,
+    pub max_inflight: usize,
+    /// Columns a session may pin, on both sides together.
+    pub max_pinned_cols: usize,
+    /// Longest value, in UTF-8 bytes, an edit may store.
+    pub max_cell_length: usize,
+    /// Cells a `range_request` or `export_request` may cover.
+    pub max_export_cells: u64,
+    /// Rows a `select_col_request` returns cells for.
+    pub max_selected_col_rows: u64,
+    pub distinct_values_cap: usize,
+    /// Rows a column scan (stats, distinct values) reads before answering
+    /// from what it has.
+    pub scan_budget: u64,
+    /// Slices not built within this long are answered from the background.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_timeout_ms: Option<u64>,
+    /// Largest frame or message the server accepts, in bytes.
+    pub max_message_bytes: usize,
+    /// Messages queued for the client before pushes start being dropped.
+    pub queue_capacity: usize,
+    pub slow_consumer_drop_limit: u32,
+    /// Replies from this many bytes on are gzipped, under the `gzip`
+    /// capability.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compress_min_bytes: Option<usize>,
+    /// Connections served at once, past which new ones are closed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+}
+
+/// A slice request with `ackSuperseded` was skipped for a later one with
+/// the same `coalesceKey`.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Superseded {
+    pub coalesce_key: String,
+}
+
+/// Sent instead of a slice whose etag matches the request's `ifNoneMatch`.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NotModified {
+    pub etag: String,
+}
+
+/// The source value of an edited cell, by position within the slice.
+#[derive(Debug, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CellOriginal {
+    pub row: u32,
+    pub col: u32,
+    pub original: String,
+}
+
+/// A conditional formatting match, by position within the slice.
+#[derive(Debug, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StyledCell {
+    pub row: u32,
+    pub col: u32,
+    pub style: CellStyle,
+}
+
+/// What the slice one screen away in `direction` would hold, without its
+/// cells, so the client can decide whether to prefetch it.
+#[derive(Debug, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NeighborHint {
+    pub direction: Direction,
+    /// Scroll offsets to send to fetch it.
+    pub scroll_top: u64,
+    pub scroll_left: u64,
+    #[serde(serialize_with = "u64_as_safe_number", deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub row_count: u32,
+    pub start_col: u32,
+    pub col_count: u32,
+    /// Usable as `ifNoneMatch` once fetched.
+    pub etag: String,
+    /// Size of its `slice_response` JSON.
+    pub estimated_bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// A formatted number's value, by position within the slice.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CellSortKey {
+    pub row: u32,
+    pub col: u32,
+    pub value: f64,
+}
+
+impl Hash for CellSortKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.row, self.col, self.value.to_bits()).hash(state);
+    }
+}
+
+/// A [`SliceResponse`] with one-letter keys to save bandwidth:
+///
+/// | key | field            |   | key | field           |
+/// |-----|------------------|---|-----|-----------------|
+/// | `r` | startRow         |   | `d` | cellsByRow      |
+/// | `n` | rowCount         |   | `k` | clamped         |
+/// | `c` | startCol         |   | `m` | merges          |
+/// | `w` | colCount         |   | `i` | rowIds          |
+/// | `l` | colLetters       |   | `e` | etag            |
+/// | `s` | styles           |   | `o` | sortKeys        |
+/// | `h` | neighborHints    |   | `x` | dirty           |
+/// | `y` | cellsByCol       |   | `b` | bodyCols        |
+/// | `p` | pinnedLeftCols   |   | `q` | pinnedRightCols |
+/// | `t` | rowLineCounts    |   | `g` | viewGeneration  |
+/// | `v` | viewChanged      |   | `a` | pinnedLeftWidth |
+/// | `f` | pinnedRightWidth |   | `u` | originals       |
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct MinSliceResponse {
+    #[serde(rename = "r", serialize_with = "u64_as_safe_number")]
+    #[serde(deserialize_with = "u64_or_string")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    #[serde(rename = "n")]
+    pub row_count: u32,
+    #[serde(rename = "c")]
+    pub start_col: u32,
+    #[serde(rename = "w")]
+    pub col_count: u32,
+    #[serde(rename = "l", default, skip_serializing_if = "Option::is_none")]
+    pub col_letters: Option<Vec<String>>,
+    #[serde(rename = "d", default, skip_serializing_if = "Option::is_none")]
+    pub cells_by_row: Option<Cells>,
+    #[serde(rename = "y", default, skip_serializing_if = "Option::is_none")]
+    pub cells_by_col: Option<Cells>,
+    #[serde(rename = "k")]
+    pub clamped: bool,
+    #[serde(rename = "m", default, skip_serializing_if = "Vec::is_empty")]
+    #[serde(serialize_with = "merges_as_safe_numbers", deserialize_with = "merges_or_strings")]
+    #[schemars(schema_with = "merges_schema")]
+    pub merges: Vec<(u64, u32, u32, u32)>,
+    #[serde(rename = "i", default, skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "row_ids_as_safe_numbers", deserialize_with = "row_ids_or_strings")]
+    #[schemars(schema_with = "u64s_or_strings_schema")]
+    pub row_ids: Option<Vec<u64>>,
+    #[serde(rename = "s", default, skip_serializing_if = "Vec::is_empty")]
+    pub styles: Vec<StyledCell>,
+    #[serde(rename = "o", default, skip_serializing_if = "Vec::is_empty")]
+    pub sort_keys: Vec<CellSortKey>,
+    #[serde(rename = "h", default, skip_serializing_if = "Vec::is_empty")]
+    pub neighbor_hints: Vec<NeighborHint>,
+    #[serde(rename = "x", default, skip_serializing_if = "Vec::is_empty")]
+    pub dirty: Vec<(u32, u32)>,
+    #[serde(rename = "u", default, skip_serializing_if = "Vec::is_empty")]
+    pub originals: Vec<CellOriginal>,
+    #[serde(rename = "b", default, skip_serializing_if = "Vec::is_empty")]
+    pub body_cols: Vec<u32>,
+    #[serde(rename = "p", default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_left_cols: Vec<PinnedCol>,
+    #[serde(rename = "q", default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_right_cols: Vec<PinnedCol>,
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    pub pinned_left_width: Option<u64>,
+    #[serde(rename = "f", default, skip_serializing_if = "Option::is_none")]
+    pub pinned_right_width: Option<u64>,
+    #[serde(rename = "t", default, skip_serializing_if = "Vec::is_empty")]
+    pub row_line_counts: Vec<u32>,
+    #[serde(rename = "g")]
+    pub view_generation: u64,
+    #[serde(rename = "v")]
+    pub view_changed: bool,
+    #[serde(rename = "e")]
+    pub etag: String,
+}
+
+impl From<SliceResponse> for MinSliceResponse {
+    fn from(slice: SliceResponse) -> Self {
+        Self {
+            start_row: slice.start_row,
+            row_count: slice.row_count,
+            start_col: slice.start_col,
+            col_count: slice.col_count,
+            col_letters: slice.col_letters,
+            cells_by_row: slice.cells_by_row,
+            cells_by_col: slice.cells_by_col,
+            clamped: slice.clamped,
+            merges: slice.merges,
+            row_ids: slice.row_ids,
+            styles: slice.styles,
+            sort_keys: slice.sort_keys,
+            neighbor_hints: slice.neighbor_hints,
+            dirty: slice.dirty,
+            originals: slice.originals,
+            body_cols: slice.body_cols,
+            pinned_left_cols: slice.pinned_left_cols,
+            pinned_right_cols: slice.pinned_right_cols,
+            pinned_left_width: slice.pinned_left_width,
+            pinned_right_width: slice.pinned_right_width,
+            row_line_counts: slice.row_line_counts,
+            view_generation: slice.view_generation,
+            view_changed: slice.view_changed,
+            etag: slice.etag,
+        }
+    }
+}
+
+/// Slice cells: every value as a string, with blanks elided, or with
+/// missing values as `null`.
+#[derive(Debug, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Cells {
+    Dense(Vec<Vec<String>>),
+    Sparse(Vec<Option<Vec<Option<String>>>>),
+    Nullable(Vec<Vec<Option<String>>>),
+}
+
+impl Cells {
+    /// `lines` with the cells at `nulls`, as `(line, index)` pairs, sent as
+    /// `null`.
+    pub fn nullable(lines: Vec<Vec<String>>, nulls: &[(usize, usize)]) -> Self {
+        let mut lines: Vec<Vec<Option<String>>> =
+            lines.into_iter().map(|line| line.into_iter().map(Some).collect()).collect();
+        for &(line, index) in nulls {
+            lines[line][index] = None;
+        }
+        Cells::Nullable(lines)
+    }
+
+    pub fn new(cells_by_row: Vec<Vec<String>>, sparse: bool) -> Self {
+        if !sparse {
+            return Cells::Dense(cells_by_row);
+        }
+        Cells::Sparse(
+            cells_by_row
+                .into_iter()
+                .map(|row| {
+                    if row.iter().all(String::is_empty) {
+                        return None;
+                    }
+                    let row = row
+                        .into_iter()
+                        .map(|cell| (!cell.is_empty()).then_some(cell))
+                        .collect();
+                    Some(row)
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CellResponse {
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    pub col: u32,
+    pub value: String,
+    /// Bumped by every write to the cell; 0 if it was never written.
+    pub version: u64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RangeResponse {
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub start_col: u32,
+    pub row_count: u32,
+    pub col_count: u32,
+    pub cells_by_row: Vec<Vec<String>>,
+}
+
+/// Rows past the end of the view are left out, so `row_count` can be short.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RowsResponse {
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start: u64,
+    pub row_count: u32,
+    pub start_col: u32,
+    pub col_count: u32,
+    /// Physical row of each returned row.
+    #[serde(serialize_with = "u64s_as_safe_numbers")]
+    #[schemars(schema_with = "u64s_or_strings_schema")]
+    pub row_ids: Vec<u64>,
+    pub cells_by_row: Vec<Vec<String>>,
+}
+
+/// A `rows_response` for the page, plus where the next one starts: pass
+/// `nextCursor` back as-is, and stop once it is absent.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PageResponse {
+    #[serde(flatten)]
+    pub rows: RowsResponse,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Every pinned column of the session after a `pin_column_request`, in the
+/// order they are shown from each edge inward.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PinColumnResponse {
+    pub pinned_left: Vec<u32>,
+    pub pinned_right: Vec<u32>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TailResponse {
+    pub enabled: bool,
+}
+
+/// Rows just appended, in the columns of the session's last slice, with the
+/// new row count and scroll height. Only the newest rows are sent when a batch
+/// is over the slice limits.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TailRows {
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub start_col: u32,
+    pub cells_by_row: Vec<Vec<String>>,
+    pub max_rows: u64,
+    pub height: u64,
+}
+
+/// Total scroll height and width in pixels.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtentResponse {
+    pub height: u64,
+    pub width: u64,
+}
+
+/// Scroll offsets the client should move to, putting `row` at the top edge
+/// and `col`, when given, at the left one. Without `scrollLeft` the client
+/// keeps its horizontal position.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScrollTo {
+    pub scroll_top: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scroll_left: Option<u64>,
+    /// The physical row, and its position in the session's view.
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub position: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub col: Option<u32>,
+}
+
+/// Every column of the row, from the first, up to the per-response column
+/// cap; `truncated` says the table is wider.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectRowResponse {
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    /// The physical row behind the visual one.
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row_id: u64,
+    pub cells: Vec<String>,
+    pub truncated: bool,
+}
+
+/// The column's cells from the top of the view; `truncated` says the view
+/// has more than `cells.len()` rows of the `totalRows`.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectColResponse {
+    pub col: u32,
+    #[serde(serialize_with = "u64s_as_safe_numbers")]
+    #[schemars(schema_with = "u64s_or_strings_schema")]
+    pub row_ids: Vec<u64>,
+    pub cells: Vec<String>,
+    pub total_rows: u64,
+    pub truncated: bool,
+}
+
+/// One piece of an export; the client joins `data` in `seq` order. The last
+/// chunk has `done` set, or `canceled` if the export was stopped early.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportChunk {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub seq: u64,
+    pub data: String,
+    pub done: bool,
+    pub canceled: bool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportProgress {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub rows_written: u64,
+    pub total_rows: u64,
+}
+
+/// The file export finished. A cancelled export leaves no file behind.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportFileDone {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub path: String,
+    /// Data rows written, not counting the header.
+    pub row_count: u64,
+    pub canceled: bool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnStatsResponse {
+    pub col: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub canceled: bool,
+    #[serde(flatten)]
+    pub stats: Option<ColumnStats>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DistinctValuesResponse {
+    pub col: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    pub canceled: bool,
+    #[serde(flatten)]
+    pub distinct: Option<DistinctValues>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OverviewResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Rows in the view the buckets divide.
+    pub total_rows: u64,
+    pub canceled: bool,
+    #[serde(flatten)]
+    pub overview: Option<Overview>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelResponse {
+    pub request_id: String,
+    /// An operation with that id was still running and has been told to stop.
+    pub found: bool,
+}
+
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CellValue {
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub row: u64,
+    pub col: u32,
+    pub value: String,
+    /// The cell's version after an edit; absent for cells that changed
+    /// because something they depend on did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+
+/// Only the cells whose value an edit changed, to apply in place: the edited
+/// cell and any formula cells that now read differently.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CellsUpdated {
+    pub cells: Vec<CellValue>,
+}
+
+/// The cells an `undo` or `redo` changed, which the other connections get as
+/// `cells_updated`.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryResponse {
+    /// There was nothing to undo or redo, and `cells` is empty.
+    pub noop: bool,
+    pub cells: Vec<CellValue>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RangeSubscribed {
+    pub subscription_id: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RangeUnsubscribed {
+    pub subscription_id: String,
+    /// A subscription with that id existed and has been removed.
+    pub found: bool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConditionalFormatSet {
+    pub rule_count: usize,
+}
+
+/// A merge was added; also pushed to the other connections.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CellsMerged {
+    #[serde(serialize_with = "u64_as_safe_number")]
+    #[schemars(schema_with = "u64_or_string_schema")]
+    pub start_row: u64,
+    pub start_col: u32,
+    pub row_count: u32,
+    pub col_count: u32,
+}
+
+/// Sent once a filter change has been applied to the session's view.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ViewResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// The rebuild was cancelled and the previous rows are still in use.
+    pub canceled: bool,
+    /// Rows a slice can now reach.
+    pub visible_rows: u64,
+    /// The rebuild stopped after `--scan-budget` rows, so rows past them are
+    /// left out of the view.
+    pub budget_exhausted: bool,
+    /// The sort those rows are in, key by key: the request's when it went
+    /// through, the previous one when cancelled.
+    pub sort: Vec<SortKey>,
+    /// The view's generation now, for `viewGeneration` on later slices.
+    pub view_generation: u64,
+}
+
+/// Answers a slice request with `onViewChange: reject` whose
+/// `viewGeneration` is outdated.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ViewChanged {
+    pub view_generation: u64,
+    pub visible_rows: u64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataResponse {
+    pub max_rows: u64,
+    /// The table is still loading and `maxRows` will grow; `metadata_update`
+    /// pushes follow until it is final.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub approximate: bool,
+    pub max_cols: u32,
+    /// Configured header names; columns past the end use letters.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub col_names: Vec<String>,
+    /// The worksheets of the `--xlsx` workbook, of which one is served.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sheets: Vec<String>,
+    /// Hit and miss counts, when the cell cache is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cell_cache: Option<CacheCounters>,
+    /// The requested capabilities this server supports, now in effect.
+    pub capabilities: Vec<Capability>,
+    /// Pixel widths fitting each column's header and first rows, when the
+    /// request gave a `charWidth`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub suggested_col_widths: Vec<u32>,
+    /// Data rows with more or fewer fields than the header, when there are any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ragged_rows: Option<u64>,
+    /// Each source column's type, for sources that have a schema.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub col_types: Vec<String>,
+    /// The session's sort keys, in precedence order, when its rows are sorted.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sort: Vec<SortKey>,
+}
+
+/// The row count changed while the table loads. The last one has
+/// `approximate` unset.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataUpdate {
+    pub max_rows: u64,
+    pub approximate: bool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EchoResponse {
+    pub payload: serde_json::Value,
+    /// When the server handled the request, in milliseconds since the Unix
+    /// epoch, for estimating clock offset.
+    pub server_time_ms: u64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<CurrentCell>,
+    /// With `range_too_large`, the cells asked for and the most allowed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed: Option<u64>,
+}
+
+/// What a cell holds now, sent with a `conflict` so the client can reconcile.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrentCell {
+    pub value: String,
+    pub version: u64,
+}
+
+/// A request that could not be served, reported to the client as an `error` message.
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub code: &'static str,
+    pub message: String,
+    pub current: Option<CurrentCell>,
+    /// Cells requested and allowed, for `range_too_large`.
+    pub counts: Option<(u64, u64)>,
+}
+
+impl ProtocolError {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            current: None,
+            counts: None,
+        }
+    }
+
+    pub fn with_current(mut self, current: CurrentCell) -> Self {
+        self.current = Some(current);
+        self
+    }
+
+    pub fn with_counts(mut self, requested: u64, allowed: u64) -> Self {
+        self.counts = Some((requested, allowed));
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        ServerMessage::Error(ErrorResponse {
+            code: self.code,
+            message: self.message.clone(),
+            current: self.current.clone(),
+            requested: self.counts.map(|(requested, _)| requested),
+            allowed: self.counts.map(|(_, allowed)| allowed),
+        })
+        .to_json()
+    }
+}
+
+pub fn error_json(code: &'static str, message: &str) -> String {
+    ProtocolError::new(code, message).to_json()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One message of every type a client may send.
+    const CLIENT_SAMPLES: &[&str] = &[
+        r#"{"type":"metadata_request"}"#,
+        r#"{"type":"slice_request","screenWidth":800,"screenHeight":600,"horizontalBuffer":1,
+            "verticalBuffer":1,"defaultColumnWidth":100,"defaultRowHeight":20,"scrollLeft":0,
+            "scrollTop":40}"#,
+        r#"{"type":"cell_request","row":12,"col":3}"#,
+        r#"{"type":"range_request","startRow":1,"startCol":2,"rowCount":3,"colCount":4}"#,
+        r#"{"type":"column_stats_request","col":1,"requestId":"s"}"#,
+        r#"{"type":"cancel_request","requestId":"s"}"#,
+        r#"{"type":"cell_update","row":4,"col":2,"value":"x"}"#,
+        r#"{"type":"subscribe_range","subscriptionId":"s","startRow":0,"startCol":0,
+            "rowCount":5,"colCount":3}"#,
+        r#"{"type":"unsubscribe_range","subscriptionId":"s"}"#,
+        r#"{"type":"filter_request","col":1,"op":"gt","value":"10"}"#,
+        r#"{"type":"clear_filters_request"}"#,
+        r#"{"type":"admin_reset","token":"t"}"#,
+        r#"{"type":"merge_cells","startRow":3,"startCol":1,"rowCount":4,"colCount":3}"#,
+        r#"{"type":"set_conditional_format","rules":[{"col":1,"op":"gt","value":"100",
+            "style":{"bold":true}}]}"#,
+        r#"{"type":"sort_request","keys":[{"col":1,"descending":true}]}"#,
+        r#"{"type":"rows_request","start":0,"count":3,"startCol":0,"colCount":2}"#,
+        r#"{"type":"export_request","startRow":0,"startCol":0,"rowCount":2,"colCount":1,
+            "format":"csv"}"#,
+        r#"{"type":"distinct_values_request","col":2,"requestId":"d1"}"#,
+        r#"{"type":"filter_in_request","col":2,"values":["apple","pear"]}"#,
+        r#"{"type":"undo"}"#,
+        r#"{"type":"redo"}"#,
+        r#"{"type":"export_to_file_request","token":"t","fileName":"view.csv"}"#,
+        r#"{"type":"echo_request","payload":{"sentAt":1}}"#,
+        r#"{"type":"filter_expr_request","expr":"age > 30 AND city = \"NYC\""}"#,
+        r#"{"type":"page_request","cursor":"0.5","pageSize":100}"#,
+        r#"{"type":"select_row_request","row":7}"#,
+        r#"{"type":"select_col_request","col":2}"#,
+        r#"{"type":"extent_request","defaultRowHeight":24,"defaultColumnWidth":100}"#,
+        r#"{"type":"set_row_height","row":3,"height":60}"#,
+        r#"{"type":"set_col_width","col":3,"width":150}"#,
+        r#"{"type":"limits_request"}"#,
+        r#"{"type":"overview_request","buckets":20}"#,
+        r#"{"type":"pin_column_request","col":3,"side":"left"}"#,
+        r#"{"type":"tail_request","enabled":true}"#,
+        r#"{"type":"jump_to_cell","row":"500","col":3}"#,
+        r#"{"type":"revert_cell","row":4,"col":2}"#,
+        r#"{"type":"cell_update_batch","row":0,"col":0,"cells":[["a"]]}"#,
+    ];
+
+    /// `slice_request` to `SliceRequest`, the name of its variant.
+    fn camel_case(snake: &str) -> String {
+        snake.split('_').map(|word| word[..1].to_uppercase() + &word[1..]).collect()
+    }
+
+    #[test]
+    fn every_client_message_type_parses_to_its_own_variant() {
+        let mut seen = Vec::new();
+        for sample in CLIENT_SAMPLES {
+            let msg = parse_client_message(sample).unwrap_or_else(|err| {
+                panic!("{} did not parse: {}", sample, err.message);
+            });
+            let kind: serde_json::Value = serde_json::from_str(sample).unwrap();
+            let kind = kind["type"].as_str().unwrap().to_string();
+            let variant = format!("{:?}", msg);
+            assert!(variant.starts_with(&camel_case(&kind)), "{} parsed as {}", kind, variant);
+            seen.push(kind);
+        }
+        let mut types = CLIENT_MESSAGE_TYPES.to_vec();
+        types.sort();
+        seen.sort();
+        assert_eq!(seen, types, "samples and CLIENT_MESSAGE_TYPES differ");
+    }
+
+    fn type_of(msg: ServerMessage) -> String {
+        let json: serde_json::Value = serde_json::from_str(&msg.to_json()).unwrap();
+        json["type"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn server_messages_are_tagged_with_their_type() {
+        let cell = CellResponse { row: 0, col: 0, value: String::new(), version: 0 };
+        let range = RangeResponse {
+            start_row: 0,
+            start_col: 0,
+            row_count: 0,
+            col_count: 0,
+            cells_by_row: Vec::new(),
+        };
+        let stats = ColumnStatsResponse { col: 0, request_id: None, canceled: true, stats: None };
+        let cancel = CancelResponse { request_id: "r".into(), found: true };
+        let subscribed = RangeSubscribed { subscription_id: "s".into() };
+        let unsubscribed = RangeUnsubscribed { subscription_id: "s".into(), found: true };
+        let view = ViewResponse {
+            request_id: None,
+            canceled: false,
+            visible_rows: 3,
+            budget_exhausted: false,
+            sort: Vec::new(),
+            view_generation: 0,
+        };
+        let merged = CellsMerged { start_row: 3, start_col: 1, row_count: 4, col_count: 3 };
+        let tagged = [
+            (ServerMessage::CellResponse(cell), "cell_response"),
+            (ServerMessage::RangeResponse(range), "range_response"),
+            (ServerMessage::ColumnStatsResponse(stats), "column_stats_response"),
+            (ServerMessage::CancelResponse(cancel), "cancel_response"),
+            (ServerMessage::CellsUpdated(CellsUpdated { cells: Vec::new() }), "cells_updated"),
+            (ServerMessage::RangeSubscribed(subscribed), "range_subscribed"),
+            (ServerMessage::RangeUnsubscribed(unsubscribed), "range_unsubscribed"),
+            (ServerMessage::ViewResponse(view), "view_response"),
+            (ServerMessage::Reset, "reset"),
+            (ServerMessage::CellsMerged(merged), "cells_merged"),
+            (
+                ServerMessage::ConditionalFormatSet(ConditionalFormatSet { rule_count: 1 }),
+                "conditional_format_set",
+            ),
+            (ServerMessage::NotModified(NotModified { etag: "e".into() }), "not_modified"),
+            (
+                ServerMessage::ExportChunk(ExportChunk {
+                    request_id: None,
+                    seq: 0,
+                    data: String::new(),
+                    done: true,
+                    canceled: false,
+                }),
+                "export_chunk",
+            ),
+            (
+                ServerMessage::EchoResponse(EchoResponse {
+                    payload: serde_json::Value::Null,
+                    server_time_ms: 0,
+                }),
+                "echo_response",
+            ),
+            (
+                ServerMessage::ExtentResponse(ExtentResponse { height: 24, width: 100 }),
+                "extent_response",
+            ),
+            (
+                ServerMessage::DistinctValuesResponse(DistinctValuesResponse {
+                    col: 0,
+                    request_id: None,
+                    canceled: true,
+                    distinct: None,
+                }),
+                "distinct_values_response",
+            ),
+            (
+                ServerMessage::UndoResponse(HistoryResponse { noop: true, cells: Vec::new() }),
+                "undo_response",
+            ),
+            (
+                ServerMessage::RedoResponse(HistoryResponse { noop: true, cells: Vec::new() }),
+                "redo_response",
+            ),
+            (
+                ServerMessage::ExportProgress(ExportProgress {
+                    request_id: None,
+                    rows_written: 0,
+                    total_rows: 1,
+                }),
+                "export_progress",
+            ),
+            (
+                ServerMessage::ExportFileDone(ExportFileDone {
+                    request_id: None,
+                    path: "view.csv".into(),
+                    row_count: 0,
+                    canceled: true,
+                }),
+                "export_file_done",
+            ),
+        ];
+        for (msg, kind) in tagged {
+            assert_eq!(type_of(msg), kind);
+        }
+        assert!(error_json("bad_request", "no").starts_with(r#"{"type":"error","#));
+        // Metadata and slices are too big to build here; every request the
+        // integration tests make checks the type of its reply.
+    }
+
+    /// A slice of `rows` by `cols` holding `cells`, with letters and row ids
+    /// to match and every optional part empty.
+    fn slice(rows: u32, cols: u32, cells: Cells) -> SliceResponse {
+        SliceResponse {
+            start_row: 10,
+            row_count: rows,
+            start_col: 2,
+            col_count: cols,
+            col_letters: Some((0..cols).map(|col| format!("C{}", col)).collect()),
+            cells_by_row: Some(cells),
+            cells_by_col: None,
+            clamped: false,
+            merges: Vec::new(),
+            row_ids: Some((10..10 + rows as u64).collect()),
+            styles: Vec::new(),
+            sort_keys: Vec::new(),
+            neighbor_hints: Vec::new(),
+            dirty: Vec::new(),
+            originals: Vec::new(),
+            body_cols: Vec::new(),
+            pinned_left_cols: Vec::new(),
+            pinned_right_cols: Vec::new(),
+            pinned_left_width: None,
+            pinned_right_width: None,
+            row_line_counts: Vec::new(),
+            view_generation: 0,
+            view_changed: false,
+            etag: String::new(),
+        }
+    }
+
+    fn text(cells: &[&[&str]]) -> Vec<Vec<String>> {
+        cells.iter().map(|line| line.iter().map(|cell| cell.to_string()).collect()).collect()
+    }
+
+    #[test]
+    fn dense_and_sparse_cells_pass_the_shape_check_when_they_fit() {
+        let dense = Cells::Dense(text(&[&["a", "b"], &["c", "d"], &["e", "f"]]));
+        assert_eq!(slice(3, 2, dense).check_shape(), Ok(()));
+        let sparse = Cells::Sparse(vec![Some(vec![None, Some("b".into())]), None, None]);
+        assert_eq!(slice(3, 2, sparse).check_shape(), Ok(()));
+        assert_eq!(slice(0, 0, Cells::Dense(Vec::new())).check_shape(), Ok(()));
+
+        let mut by_col = slice(3, 2, Cells::Dense(Vec::new()));
+        by_col.cells_by_row = None;
+        by_col.cells_by_col = Some(Cells::Dense(text(&[&["a", "c", "e"], &["b", "d", "f"]])));
+        assert_eq!(by_col.check_shape(), Ok(()));
+    }
+
+    #[test]
+    fn the_shape_check_names_whatever_disagrees_with_the_counts() {
+        let short_row = Cells::Dense(text(&[&["a", "b"], &["c"]]));
+        assert_eq!(slice(2, 2, short_row).check_shape(), Err("cell row 1 is not 2 wide".into()));
+        let long_row = Cells::Sparse(vec![None, Some(vec![None, None, None])]);
+        assert_eq!(slice(2, 2, long_row).check_shape(), Err("cell row 1 is not 2 wide".into()));
+        let missing_row = Cells::Dense(text(&[&["a", "b"]]));
+        let expected = "1 cell rows for 2 rows".to_string();
+        assert_eq!(slice(2, 2, missing_row).check_shape(), Err(expected));
+
+        let mut letters = slice(1, 2, Cells::Dense(text(&[&["a", "b"]])));
+        letters.col_letters = Some(vec!["A".into()]);
+        assert_eq!(letters.check_shape(), Err("1 col letters for 2 columns".into()));
+        let mut row_ids = slice(1, 2, Cells::Dense(text(&[&["a", "b"]])));
+        row_ids.row_ids = Some(Vec::new());
+        assert_eq!(row_ids.check_shape(), Err("0 row ids for 1 rows".into()));
+    }
+
+    #[test]
+    fn the_tag_is_serialized_as_type_ahead_of_the_fields() {
+        let msg = ServerMessage::SliceResponse(slice(1, 1, Cells::Dense(text(&[&["a"]]))));
+        let json = msg.to_json();
+        assert!(json.starts_with(r#"{"type":"slice_response","startRow":10,"#), "{}", json);
+        let min = ServerMessage::SliceMin(slice(1, 1, Cells::Dense(text(&[&["a"]]))).into());
+        assert!(min.to_json().starts_with(r#"{"type":"slice_min","r":10,"#));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(value.get("kind").is_none() && value.get("r#type").is_none());
+    }
+
+    fn slice_request(scroll_top: &str) -> Result<ClientMessage, ProtocolError> {
+        parse_client_message(&format!(
+            r#"{{"type":"slice_request","screenWidth":800,"screenHeight":600,
+                "horizontalBuffer":0,"verticalBuffer":0,"defaultColumnWidth":100,
+                "defaultRowHeight":20,"scrollLeft":0,"scrollTop":{}}}"#,
+            scroll_top
+        ))
+    }
+
+    #[test]
+    fn row_positions_past_2_pow_53_survive_as_strings() {
+        let Ok(ClientMessage::SliceRequest(req)) = slice_request(r#""9007199254740993""#) else {
+            panic!("string scrollTop refused");
+        };
+        assert_eq!(req.scroll_top, 9_007_199_254_740_993);
+        let Ok(ClientMessage::SliceRequest(req)) = slice_request("40") else {
+            panic!("number scrollTop refused");
+        };
+        assert_eq!(req.scroll_top, 40);
+        let max = r#"{"type":"cell_request","row":"18446744073709551615","col":0}"#;
+        let cell = parse_client_message(max);
+        assert!(matches!(cell, Ok(ClientMessage::CellRequest(req)) if req.row == u64::MAX));
+        for bad in [r#""-1""#, r#""1.5""#, r#""12abc""#, r#""18446744073709551616""#, "-1"] {
+            assert!(slice_request(bad).is_err(), "{} parsed", bad);
+        }
+    }
+
+    /// The top-level keys of `msg` as sent.
+    fn keys(msg: ServerMessage) -> Vec<String> {
+        let json: serde_json::Value = serde_json::from_str(&msg.to_json()).unwrap();
+        json.as_object().unwrap().keys().cloned().collect()
+    }
+
+    #[test]
+    fn fields_go_out_in_camel_case() {
+        let metadata = keys(ServerMessage::MetadataResponse(MetadataResponse {
+            max_rows: 1_000,
+            approximate: true,
+            max_cols: 26,
+            col_names: vec!["id".into()],
+            sheets: vec!["Sheet1".into()],
+            cell_cache: None,
+            capabilities: Vec::new(),
+            suggested_col_widths: vec![80],
+            ragged_rows: Some(2),
+            col_types: Vec::new(),
+            sort: Vec::new(),
+        }));
+        for key in ["maxRows", "maxCols", "colNames", "suggestedColWidths", "raggedRows"] {
+            assert!(metadata.contains(&key.to_string()), "{} missing from {:?}", key, metadata);
+        }
+        let slice = keys(ServerMessage::SliceResponse(slice(1, 1, Cells::Dense(text(&[&["a"]])))));
+        for key in ["startRow", "rowCount", "colLetters", "cellsByRow", "rowIds"] {
+            assert!(slice.contains(&key.to_string()), "{} missing from {:?}", key, slice);
+        }
+        for key in metadata.iter().chain(&slice) {
+            assert!(!key.contains('_'), "{} is not camelCase", key);
+        }
+    }
+
+    #[test]
+    fn row_positions_past_2_pow_53_go_out_as_strings() {
+        const PAST: u64 = (1 << 53) + 1;
+        let far = || {
+            let mut far = slice(1, 1, Cells::Dense(text(&[&["a"]])));
+            far.start_row = PAST;
+            far.row_ids = Some(vec![PAST]);
+            far.merges = vec![(PAST, 2, 1, 1)];
+            far
+        };
+        let sent = |msg: ServerMessage| -> serde_json::Value {
+            serde_json::from_str(&msg.to_json()).unwrap()
+        };
+        let json = sent(ServerMessage::SliceResponse(far()));
+        let past = serde_json::json!(PAST.to_string());
+        assert_eq!((&json["startRow"], &json["rowIds"][0]), (&past, &past));
+        assert_eq!(json["merges"][0], serde_json::json!([PAST.to_string(), 2, 1, 1]));
+        let near = sent(ServerMessage::SliceResponse(slice(1, 1, Cells::Dense(text(&[&["a"]])))));
+        assert_eq!((&near["startRow"], &near["rowIds"][0]), (&10.into(), &10.into()));
+
+        let rows = ServerMessage::RowsResponse(RowsResponse {
+            start: PAST,
+            row_count: 1,
+            start_col: 0,
+            col_count: 0,
+            row_ids: vec![PAST, 3],
+            cells_by_row: vec![Vec::new()],
+        });
+        let json = sent(rows);
+        assert_eq!((&json["start"], &json["rowIds"]), (&past, &serde_json::json!([past, 3])));
+
+        // The short-key form reads its own strings back.
+        let min = sent(ServerMessage::SliceMin(far().into()));
+        assert_eq!((&min["r"], &min["i"][0], &min["m"][0][0]), (&past, &past, &past));
+        let min: MinSliceResponse = serde_json::from_value(min).unwrap();
+        assert_eq!((min.start_row, min.row_ids), (PAST, Some(vec![PAST])));
+        assert_eq!(min.merges, [(PAST, 2, 1, 1)]);
+    }
+}
@@ -0,0 +1,317 @@
+//! Per-connection state.
+
+use crate::format::FormatRule;
+use crate::outbound::Outbound;
+use crate::protocol::{Capability, CellValue};
+use crate::sizes::Sizes;
+use crate::view::View;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub struct SessionState {
+    pub id: u64,
+    pub outbound: Outbound,
+    pub inflight: Inflight,
+    pub subscriptions: Subscriptions,
+    pub view: View,
+    pub history: History,
+    /// Conditional formatting applied to styled slices.
+    pub format_rules: Vec<FormatRule>,
+    /// Negotiated by the last `metadata_request`; none until then.
+    pub capabilities: Vec<Capability>,
+    /// Custom row heights and column widths in the session's view.
+    pub sizes: Sizes,
+    pub tail: Tail,
+}
+
+impl SessionState {
+    pub fn new(id: u64, outbound: Outbound) -> Self {
+        Self {
+            id,
+            outbound,
+            inflight: Inflight::default(),
+            subscriptions: Subscriptions::default(),
+            view: View::default(),
+            history: History::default(),
+            format_rules: Vec::new(),
+            capabilities: Vec::new(),
+            sizes: Sizes::default(),
+            tail: Tail::default(),
+        }
+    }
+}
+
+/// Long operations still running: how many, and the cancellation flags of
+/// those with a `request_id`. Long loops poll their flag and stop early once
+/// set.
+#[derive(Clone, Default)]
+pub struct Inflight(Arc<Mutex<Running>>);
+
+#[derive(Default)]
+struct Running {
+    flags: HashMap<String, Arc<AtomicBool>>,
+    count: usize,
+}
+
+impl Inflight {
+    /// Returns the flag for a new operation. Operations without a request id
+    /// cannot be cancelled, so their flag is never registered.
+    pub fn start(&self, request_id: Option<&str>) -> Arc<AtomicBool> {
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut running = self.0.lock().unwrap();
+        running.count += 1;
+        if let Some(request_id) = request_id {
+            running.flags.insert(request_id.to_string(), flag.clone());
+        }
+        flag
+    }
+
+    pub fn finish(&self, request_id: Option<&str>) {
+        let mut running = self.0.lock().unwrap();
+        running.count = running.count.saturating_sub(1);
+        if let Some(request_id) = request_id {
+            running.flags.remove(request_id);
+        }
+    }
+
+    /// Operations started and not yet finished.
+    pub fn count(&self) -> usize {
+        self.0.lock().unwrap().count
+    }
+
+    /// Flags the operation as cancelled; `false` if nothing is running under that id.
+    pub fn cancel(&self, request_id: &str) -> bool {
+        match self.0.lock().unwrap().flags.get(request_id) {
+            Some(flag) => {
+                flag.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A rectangle of cells, `row_count` x `col_count` from its top-left corner.
+#[derive(Clone, Copy, Debug, Hash)]
+pub struct CellRange {
+    pub start_row: u64,
+    pub start_col: u32,
+    pub row_count: u32,
+    pub col_count: u32,
+}
+
+impl CellRange {
+    pub fn contains(&self, row: u64, col: u32) -> bool {
+        row >= self.start_row
+            && row - self.start_row < self.row_count as u64
+            && col >= self.start_col
+            && col - self.start_col < self.col_count
+    }
+
+    /// Whether the ranges share a cell.
+    pub fn overlaps(&self, other: &CellRange) -> bool {
+        let end_row = |range: &CellRange| range.start_row.saturating_add(range.row_count as u64);
+        let end_col = |range: &CellRange| range.start_col as u64 + range.col_count as u64;
+        self.start_row < end_row(other)
+            && other.start_row < end_row(self)
+            && (self.start_col as u64) < end_col(other)
+            && (other.start_col as u64) < end_col(self)
+    }
+}
+
+/// Ranges the client asked to hear about, keyed by its `subscription_id`.
+/// Shared with the connection registry so broadcasts can be filtered per
+/// connection.
+#[derive(Clone, Default)]
+pub struct Subscriptions(Arc<Mutex<HashMap<String, CellRange>>>);
+
+impl Subscriptions {
+    /// Adds a range, replacing any earlier one under the same id.
+    pub fn subscribe(&self, subscription_id: String, range: CellRange) {
+        self.0.lock().unwrap().insert(subscription_id, range);
+    }
+
+    /// Drops a range; `false` if no subscription has that id.
+    pub fn unsubscribe(&self, subscription_id: &str) -> bool {
+        self.0.lock().unwrap().remove(subscription_id).is_some()
+    }
+
+    /// The cells this connection should be told about. A connection with no
+    /// subscriptions hears about every change.
+    pub fn select(&self, cells: &[CellValue]) -> Vec<CellValue> {
+        let ranges = self.0.lock().unwrap();
+        cells
+            .iter()
+            .filter(|cell| {
+                ranges.is_empty() || ranges.values().any(|range| range.contains(cell.row, cell.col))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Whether the client follows appended rows, and what its last slice showed.
+/// Shared with the connection registry so new rows can be pushed to it.
+#[derive(Clone, Default)]
+pub struct Tail(Arc<Mutex<TailState>>);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TailState {
+    pub enabled: bool,
+    /// The last slice reached the last row of an unfiltered, unsorted view.
+    pub at_bottom: bool,
+    pub start_col: u32,
+    pub col_count: u32,
+    /// The row count and scroll height when that slice was made, and the
+    /// height of rows added since.
+    pub rows: u64,
+    pub height: u64,
+    pub row_height: u32,
+}
+
+impl Tail {
+    pub fn get(&self) -> TailState {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn update(&self, change: impl FnOnce(&mut TailState)) {
+        change(&mut self.0.lock().unwrap());
+    }
+}
+
+/// How many edits `undo` can step back through.
+pub const HISTORY_LIMIT: usize = 100;
+
+/// One applied edit. `None` means the cell had no override and showed the
+/// source value.
+#[derive(Clone, Debug)]
+pub struct Edit {
+    pub row: u64,
+    pub col: u32,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Default)]
+struct Stacks {
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    /// Bumped by [`History::clear`], so an edit taken out before a reset is
+    /// not put back after it.
+    clears: u64,
+}
+
+/// This connection's own edits, newest last, for `undo` and `redo`. Shared
+/// with the connection registry so a reset can forget them.
+#[derive(Clone, Default)]
+pub struct History(Arc<Mutex<Stacks>>);
+
+impl History {
+    /// Adds a fresh edit, dropping the oldest past [`HISTORY_LIMIT`]. Anything
+    /// undone before it can no longer be redone.
+    pub fn record(&self, edit: Edit) {
+        let mut stacks = self.0.lock().unwrap();
+        stacks.redo.clear();
+        if stacks.undo.len() == HISTORY_LIMIT {
+            stacks.undo.pop_front();
+        }
+        stacks.undo.push_back(edit);
+    }
+
+    /// Hands the newest edit to `apply`, and moves it to the redo stack if
+    /// `apply` succeeds. `None` when there is nothing to undo.
+    pub fn undo<T, E>(&self, apply: impl FnOnce(&Edit) -> Result<T, E>) -> Option<Result<T, E>> {
+        self.step(true, apply)
+    }
+
+    /// The mirror of [`History::undo`] for the most recently undone edit.
+    pub fn redo<T, E>(&self, apply: impl FnOnce(&Edit) -> Result<T, E>) -> Option<Result<T, E>> {
+        self.step(false, apply)
+    }
+
+    /// Takes the edit off the top of one stack and, once `apply` is done with
+    /// it, puts it on the other if that worked or back where it was if not.
+    ///
+    /// `apply` runs without the lock: publishing an edit takes the connection
+    /// registry's lock, which a reset holds while it clears histories.
+    fn step<T, E>(
+        &self,
+        undo: bool,
+        apply: impl FnOnce(&Edit) -> Result<T, E>,
+    ) -> Option<Result<T, E>> {
+        let (edit, clears) = {
+            let mut stacks = self.0.lock().unwrap();
+            let edit = match undo {
+                true => stacks.undo.pop_back(),
+                false => stacks.redo.pop(),
+            };
+            (edit?, stacks.clears)
+        };
+        let result = apply(&edit);
+        let mut stacks = self.0.lock().unwrap();
+        if stacks.clears == clears {
+            match (undo, result.is_ok()) {
+                (true, true) | (false, false) => stacks.redo.push(edit),
+                (false, true) | (true, false) => stacks.undo.push_back(edit),
+            }
+        }
+        Some(result)
+    }
+
+    pub fn clear(&self) {
+        let mut stacks = self.0.lock().unwrap();
+        stacks.undo.clear();
+        stacks.redo.clear();
+        stacks.clears += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(row: u64) -> Edit {
+        Edit {
+            row,
+            col: 0,
+            before: None,
+            after: Some(row.to_string()),
+        }
+    }
+
+    fn top(history: &History, undo: bool) -> Option<u64> {
+        let peek = |edit: &Edit| Err::<(), u64>(edit.row);
+        let stepped = match undo {
+            true => history.undo(peek),
+            false => history.redo(peek),
+        };
+        stepped.map(|result| result.unwrap_err())
+    }
+
+    #[test]
+    fn a_failed_step_leaves_the_edit_where_it_was() {
+        let history = History::default();
+        history.record(edit(1));
+        history.record(edit(2));
+        assert_eq!(top(&history, true), Some(2));
+        assert_eq!(top(&history, true), Some(2));
+        assert_eq!(history.undo(|edit| Ok::<u64, ()>(edit.row)), Some(Ok(2)));
+        assert_eq!(top(&history, false), Some(2));
+        assert_eq!(top(&history, true), Some(1));
+    }
+
+    #[test]
+    fn an_edit_applied_across_a_clear_is_not_put_back() {
+        let history = History::default();
+        history.record(edit(1));
+        // `apply` may take other locks; a clear landing meanwhile wins.
+        let undone = history.undo(|edit| {
+            history.clear();
+            Ok::<u64, ()>(edit.row)
+        });
+        assert_eq!(undone, Some(Ok(1)));
+        assert_eq!(top(&history, false), None);
+        assert_eq!(top(&history, true), None);
+    }
+}
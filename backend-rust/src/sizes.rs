@@ -0,0 +1,131 @@
+//! Per-session row heights and column widths that differ from the default,
+//! and the columns pinned to either edge.
+//!
+//! Sizes belong to positions in the session's view rather than to physical
+//! rows, so a filter or sort changes what is shown at a position but not how
+//! tall it is. Everything not sized here uses the default the client sends
+//! with each slice.
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// How many columns one session may pin to its edges, counting both sides.
+pub const MAX_PINNED_COLS: usize = 16;
+
+#[derive(Clone, Debug, Default)]
+pub struct Sizes {
+    pub rows: Axis,
+    pub cols: Axis,
+    /// Default row height and column width from the client's last slice or
+    /// extent request, which size changes are measured with.
+    pub defaults: Option<(u32, u32)>,
+    pub pins: Pins,
+}
+
+/// Which edge a `pin_column_request` pins its column to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PinSide {
+    Left,
+    Right,
+}
+
+/// Columns shown at the left and right edges of every slice whatever the
+/// horizontal scroll, in the order they were pinned.
+#[derive(Clone, Debug, Default)]
+pub struct Pins {
+    pub left: Vec<u32>,
+    pub right: Vec<u32>,
+}
+
+impl Pins {
+    /// Pins `col` to `side`, moving it if it was on the other one, or unpins
+    /// it for `None`.
+    pub fn set(&mut self, col: u32, side: Option<PinSide>) {
+        self.left.retain(|&pinned| pinned != col);
+        self.right.retain(|&pinned| pinned != col);
+        match side {
+            Some(PinSide::Left) => self.left.push(col),
+            Some(PinSide::Right) => self.right.push(col),
+            None => {}
+        }
+    }
+
+    pub fn contains(&self, col: u32) -> bool {
+        self.left.contains(&col) || self.right.contains(&col)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left.is_empty() && self.right.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.left.len() + self.right.len()
+    }
+}
+
+/// Custom sizes along one axis, by index.
+#[derive(Clone, Debug, Default)]
+pub struct Axis(BTreeMap<u64, u32>);
+
+impl Axis {
+    /// Sizes `index` explicitly, or returns it to the default for `None`.
+    pub fn set(&mut self, index: u64, size: Option<u32>) {
+        match size {
+            Some(size) => self.0.insert(index, size),
+            None => self.0.remove(&index),
+        };
+    }
+
+    /// The size of `index`, custom or `default`.
+    pub fn size(&self, index: u64, default: u32) -> u32 {
+        self.0.get(&index).copied().unwrap_or(default)
+    }
+
+    /// Pixels before `index`: the extent of the first `index` items.
+    pub fn offset(&self, index: u64, default: u32) -> u64 {
+        let default = default as u64;
+        let mut offset = index.saturating_mul(default);
+        for &size in self.0.range(..index).map(|(_, size)| size) {
+            offset = offset.saturating_sub(default).saturating_add(size as u64);
+        }
+        offset
+    }
+
+    /// The index whose span holds pixel `px`, or `len` if that is past the end.
+    pub fn index_at(&self, px: u64, default: u32, len: u64) -> u64 {
+        let default = default as u64;
+        let (mut index, mut start) = (0u64, 0u64);
+        for (&custom, &size) in self.0.range(..len) {
+            let gap_end = start.saturating_add((custom - index).saturating_mul(default));
+            if px < gap_end {
+                return index + (px - start) / default;
+            }
+            let end = gap_end.saturating_add(size as u64);
+            if px < end {
+                return custom;
+            }
+            (index, start) = (custom + 1, end);
+        }
+        index.saturating_add((px - start) / default).min(len)
+    }
+
+    /// How many items from `first` on it takes to cover `px` pixels.
+    pub fn count_covering(&self, first: u64, px: u64, default: u32) -> u64 {
+        let default = default as u64;
+        let (mut index, mut covered) = (first, 0u64);
+        for (&custom, &size) in self.0.range(first..) {
+            if covered >= px {
+                break;
+            }
+            let needed = (px - covered).div_ceil(default);
+            if needed <= custom - index {
+                return index + needed - first;
+            }
+            covered += (custom - index) * default + size as u64;
+            index = custom + 1;
+        }
+        index + (px.saturating_sub(covered)).div_ceil(default) - first
+    }
+}
@@ -0,0 +1,722 @@
+//! Where cell values come from.
+
+use crate::col_index_to_letters;
+use crate::config::CsvOptions;
+use flate2::read::MultiGzDecoder;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// A read-only table of cells. Client edits are layered on top by `AppState`.
+pub trait DataSource: Send + Sync {
+    fn row_count(&self) -> u64;
+    fn col_count(&self) -> u32;
+    /// The value at (`row`, `col`); only called for coordinates inside the table.
+    /// `None` means the source holds no value there, as opposed to an empty one.
+    fn cell(&self, row: u64, col: u32) -> Option<String>;
+    /// `false` while rows are still arriving, in which case `row_count` is
+    /// only what has arrived so far.
+    fn is_complete(&self) -> bool {
+        true
+    }
+    /// Rows read with more or fewer fields than the header row, so far.
+    fn ragged_rows(&self) -> u64 {
+        0
+    }
+    /// Each column's type, when the source has a schema saying so.
+    fn col_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl<T: DataSource + ?Sized> DataSource for Arc<T> {
+    fn row_count(&self) -> u64 {
+        (**self).row_count()
+    }
+
+    fn col_count(&self) -> u32 {
+        (**self).col_count()
+    }
+
+    fn cell(&self, row: u64, col: u32) -> Option<String> {
+        (**self).cell(row, col)
+    }
+
+    fn is_complete(&self) -> bool {
+        (**self).is_complete()
+    }
+
+    fn ragged_rows(&self) -> u64 {
+        (**self).ragged_rows()
+    }
+
+    fn col_types(&self) -> Vec<String> {
+        (**self).col_types()
+    }
+}
+
+/// The default mock table whose cells are labelled with their own coordinates,
+/// or, given a seed, filled with made-up but plausible values.
+pub struct SyntheticSource {
+    pub rows: u64,
+    pub cols: u32,
+    /// Each cell is derived from `(seed, row, col)` alone, so the same seed
+    /// always gives the same table.
+    pub seed: Option<u64>,
+    /// What each seeded column holds, repeated across the table's width;
+    /// empty means [`SyntheticColumnSpec::DEFAULT_CYCLE`].
+    pub columns: Vec<SyntheticColumnSpec>,
+    /// Fraction of cells, from 0 to 1, that are blank. Which ones depends
+    /// only on the seed, row and column.
+    pub sparsity: f64,
+}
+
+/// The kind of value a seeded synthetic column is filled with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyntheticColumnSpec {
+    /// `0` to `9999`.
+    Integer,
+    /// Dollars with thousands separators, e.g. `$1,234.56`.
+    Currency,
+    Word,
+    /// ISO 8601, e.g. `2013-07-21`.
+    Date,
+    /// `true` or `false`.
+    Boolean,
+}
+
+impl SyntheticColumnSpec {
+    pub const DEFAULT_CYCLE: &'static [Self] =
+        &[Self::Integer, Self::Currency, Self::Word, Self::Date, Self::Boolean];
+
+    /// The `--synthetic-columns` name: `integer`, `currency`, `word`, `date`
+    /// or `boolean`.
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.trim() {
+            "integer" => Ok(Self::Integer),
+            "currency" => Ok(Self::Currency),
+            "word" => Ok(Self::Word),
+            "date" => Ok(Self::Date),
+            "boolean" => Ok(Self::Boolean),
+            other => Err(format!("unknown synthetic column type {:?}", other)),
+        }
+    }
+}
+
+impl DataSource for SyntheticSource {
+    fn row_count(&self) -> u64 {
+        self.rows
+    }
+
+    fn col_count(&self) -> u32 {
+        self.cols
+    }
+
+    fn cell(&self, row: u64, col: u32) -> Option<String> {
+        if is_blank(self.seed.unwrap_or(0), row, col, self.sparsity) {
+            return Some(String::new());
+        }
+        Some(match self.seed {
+            Some(seed) => {
+                let cycle = match self.columns.is_empty() {
+                    true => SyntheticColumnSpec::DEFAULT_CYCLE,
+                    false => &self.columns,
+                };
+                seeded_cell(seed, row, col, cycle[col as usize % cycle.len()])
+            }
+            None => synthetic_cell(row, col),
+        })
+    }
+}
+
+/// Whether the cell falls in the `sparsity` fraction left blank. Hashed
+/// apart from the cell's value, which would otherwise decide it.
+fn is_blank(seed: u64, row: u64, col: u32, sparsity: f64) -> bool {
+    if sparsity >= 1.0 {
+        return true;
+    }
+    let n = mix(mix(!seed ^ row) ^ col as u64);
+    // The top 53 bits as a fraction in [0, 1).
+    ((n >> 11) as f64 / (1u64 << 53) as f64) < sparsity
+}
+
+fn synthetic_cell(row: u64, col: u32) -> String {
+    format!("R{}C {}", row + 1, col_index_to_letters(col))
+}
+
+const WORDS: &[&str] = &[
+    "alpha", "bravo", "charlie", "delta", "echo", "foxtrot", "golf", "hotel", "india", "juliet",
+    "kilo", "lima", "mike", "november", "oscar", "papa",
+];
+
+fn seeded_cell(seed: u64, row: u64, col: u32, spec: SyntheticColumnSpec) -> String {
+    let n = mix(mix(seed ^ row) ^ col as u64);
+    match spec {
+        SyntheticColumnSpec::Integer => (n % 10_000).to_string(),
+        SyntheticColumnSpec::Currency => {
+            format!("${}.{:02}", thousands(n % 100_000), (n >> 32) % 100)
+        }
+        SyntheticColumnSpec::Word => WORDS[(n % WORDS.len() as u64) as usize].to_string(),
+        SyntheticColumnSpec::Date => format!(
+            "{}-{:02}-{:02}",
+            2000 + n % 30,
+            1 + (n >> 8) % 12,
+            1 + (n >> 16) % 28
+        ),
+        SyntheticColumnSpec::Boolean => (n & 1 == 1).to_string(),
+    }
+}
+
+/// `1234567` -> `1,234,567`.
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+/// splitmix64's finalizer: a cheap, well-spread hash of one word.
+fn mix(mut n: u64) -> u64 {
+    n = n.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    n = (n ^ (n >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    n = (n ^ (n >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    n ^ (n >> 31)
+}
+
+/// A small table held entirely in memory, e.g. from `--inline-data`.
+pub struct InlineSource {
+    rows: Vec<Vec<String>>,
+    cols: u32,
+}
+
+impl InlineSource {
+    /// Parses a JSON array of rows, each an array of strings. Rows may be
+    /// ragged; the table is as wide as the longest row and the gaps read as
+    /// missing.
+    pub fn from_json(text: &str) -> Result<Self, String> {
+        let rows: Vec<Vec<String>> = serde_json::from_str(text)
+            .map_err(|err| format!("inline data must be a JSON array of string arrays: {}", err))?;
+        Self::from_rows(rows)
+    }
+
+    pub fn from_rows(rows: Vec<Vec<String>>) -> Result<Self, String> {
+        let cols = rows.iter().map(Vec::len).max().unwrap_or(0);
+        let cols = u32::try_from(cols).map_err(|_| "inline data has too many columns".to_string())?;
+        Ok(Self { rows, cols })
+    }
+}
+
+impl DataSource for InlineSource {
+    fn row_count(&self) -> u64 {
+        self.rows.len() as u64
+    }
+
+    fn col_count(&self) -> u32 {
+        self.cols
+    }
+
+    fn cell(&self, row: u64, col: u32) -> Option<String> {
+        self.rows
+            .get(row as usize)
+            .and_then(|cells| cells.get(col as usize))
+            .cloned()
+    }
+}
+
+/// A CSV file loaded into memory. The first record is the header row and
+/// supplies the column names. Rows may be ragged: gaps in short rows read as
+/// missing, and long rows widen the table unless `--csv-truncate-long-rows`
+/// cuts them back to the header. Either way they are counted.
+///
+/// Repeated header names are told apart with a suffix, so a file headed
+/// `id,id,id` has columns `id`, `id (2)` and `id (3)`. Blank names are left
+/// alone and cannot be looked up.
+pub struct CsvSource {
+    pub headers: Vec<String>,
+    pub ragged_rows: u64,
+    table: InlineSource,
+}
+
+impl CsvSource {
+    pub fn open(path: &str, options: &CsvOptions) -> Result<Self, String> {
+        let file = open_csv_file(path).map_err(|err| format!("cannot open csv {}: {}", path, err))?;
+        Self::from_reader(file, options).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    pub fn from_reader(mut reader: impl Read, options: &CsvOptions) -> Result<Self, String> {
+        let mut text = Vec::new();
+        reader
+            .read_to_end(&mut text)
+            .map_err(|err| format!("cannot read csv: {}", err))?;
+        let delimiter = options
+            .delimiter
+            .unwrap_or_else(|| detect_delimiter(&text, options.quote));
+
+        let mut records = csv_records(text.as_slice(), options, delimiter);
+        let headers = records.next().ok_or_else(|| "csv is empty".to_string())??;
+        let headers = disambiguate_headers(headers);
+        let mut rows = records.collect::<Result<Vec<Vec<String>>, String>>()?;
+        let ragged_rows = rows
+            .iter_mut()
+            .map(|row| fit_to_header(row, headers.len(), options.truncate_long_rows))
+            .filter(|&ragged| ragged)
+            .count() as u64;
+        Ok(Self {
+            headers,
+            ragged_rows,
+            table: InlineSource::from_rows(rows)?,
+        })
+    }
+}
+
+/// Splits CSV text into records, refusing any wider than `--csv-max-cols`.
+fn csv_records(
+    reader: impl Read,
+    options: &CsvOptions,
+    delimiter: u8,
+) -> impl Iterator<Item = Result<Vec<String>, String>> {
+    let max_cols = options.max_cols;
+    let csv = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .quote(options.quote)
+        .escape(options.escape)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+    csv.into_records().map(move |record| {
+        let record = record.map_err(|err| format!("bad csv: {}", err))?;
+        if record.len() > max_cols as usize {
+            let line = record.position().map_or(0, |pos| pos.line());
+            return Err(format!(
+                "csv line {} has {} columns, more than the limit of {} (see --csv-max-cols)",
+                line,
+                record.len(),
+                max_cols
+            ));
+        }
+        Ok(record.iter().map(str::to_string).collect::<Vec<String>>())
+    })
+}
+
+/// Whether `row` has other than `width` fields, first cutting it down to
+/// `width` if it is longer and `truncate` is set.
+fn fit_to_header(row: &mut Vec<String>, width: usize, truncate: bool) -> bool {
+    if row.len() > width && truncate {
+        row.truncate(width);
+        return true;
+    }
+    row.len() != width
+}
+
+/// A table whose rows arrive over time, such as a CSV file still loading.
+/// Rows are appended in batches; reads see whatever has arrived.
+#[derive(Default)]
+pub struct GrowingSource {
+    rows: RwLock<Vec<Vec<String>>>,
+    cols: AtomicU32,
+    complete: AtomicBool,
+    ragged_rows: AtomicU64,
+}
+
+impl GrowingSource {
+    pub fn append(&self, batch: Vec<Vec<String>>) {
+        let width = batch.iter().map(Vec::len).max().unwrap_or(0) as u32;
+        self.rows.write().unwrap().extend(batch);
+        self.cols.fetch_max(width, Ordering::Release);
+    }
+
+    /// No more rows will come.
+    pub fn finish(&self) {
+        self.complete.store(true, Ordering::Release);
+    }
+}
+
+impl DataSource for GrowingSource {
+    fn row_count(&self) -> u64 {
+        self.rows.read().unwrap().len() as u64
+    }
+
+    fn col_count(&self) -> u32 {
+        self.cols.load(Ordering::Acquire)
+    }
+
+    fn cell(&self, row: u64, col: u32) -> Option<String> {
+        self.rows
+            .read()
+            .unwrap()
+            .get(row as usize)
+            .and_then(|cells| cells.get(col as usize))
+            .cloned()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.complete.load(Ordering::Acquire)
+    }
+
+    fn ragged_rows(&self) -> u64 {
+        self.ragged_rows.load(Ordering::Relaxed)
+    }
+}
+
+/// Rows read between appends when loading in the background.
+const BACKGROUND_BATCH_ROWS: usize = 10_000;
+
+/// Reads the header row of the CSV file at `path`, then loads the rest on a
+/// thread of its own, so serving can start at once. Returns the disambiguated
+/// headers and the source the rows land in. A bad record stops the load
+/// there, with a warning, keeping the rows before it.
+pub fn load_csv_in_background(
+    path: &str,
+    options: &CsvOptions,
+) -> Result<(Vec<String>, Arc<GrowingSource>), String> {
+    let mut reader = BufReader::new(
+        open_csv_file(path).map_err(|err| format!("cannot open csv {}: {}", path, err))?,
+    );
+    let delimiter = match options.delimiter {
+        Some(delimiter) => delimiter,
+        None => {
+            let start = reader.fill_buf().map_err(|err| format!("cannot read csv: {}", err))?;
+            detect_delimiter(start, options.quote)
+        }
+    };
+    let mut records = csv_records(reader, options, delimiter);
+    let headers = records
+        .next()
+        .ok_or_else(|| format!("{}: csv is empty", path))?
+        .map_err(|err| format!("{}: {}", path, err))?;
+    let source = Arc::new(GrowingSource::default());
+    source.cols.store(headers.len() as u32, Ordering::Release);
+    let loading = source.clone();
+    let path = path.to_string();
+    let (width, truncate) = (headers.len(), options.truncate_long_rows);
+    std::thread::spawn(move || {
+        let mut batch = Vec::with_capacity(BACKGROUND_BATCH_ROWS);
+        for record in records {
+            match record {
+                Ok(mut row) => {
+                    if fit_to_header(&mut row, width, truncate) {
+                        loading.ragged_rows.fetch_add(1, Ordering::Relaxed);
+                    }
+                    batch.push(row)
+                }
+                Err(err) => {
+                    tracing::warn!("{}: stopped loading: {}", path, err);
+                    break;
+                }
+            }
+            if batch.len() == BACKGROUND_BATCH_ROWS {
+                loading.append(std::mem::take(&mut batch));
+            }
+        }
+        loading.append(batch);
+        loading.finish();
+        tracing::info!("{}: loaded {} rows", path, loading.row_count());
+        warn_ragged(&path, loading.ragged_rows(), width);
+    });
+    Ok((disambiguate_headers(headers), source))
+}
+
+/// Logs how many rows of the file at `path` did not match its header.
+pub fn warn_ragged(path: &str, ragged_rows: u64, width: usize) {
+    if ragged_rows > 0 {
+        tracing::warn!(
+            "{}: {} rows do not have the header's {} fields",
+            path,
+            ragged_rows,
+            width
+        );
+    }
+}
+
+/// The first bytes of every gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Opens a CSV file for reading, decompressing it on the fly if it is
+/// gzipped: named `.gz` or starting with the gzip magic bytes. The whole
+/// decompressed text is loaded either way, so random access is unaffected.
+pub fn open_csv_file(path: &str) -> std::io::Result<Box<dyn Read + Send>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let gzipped = path.ends_with(".gz") || file.fill_buf()?.starts_with(&GZIP_MAGIC);
+    Ok(match gzipped {
+        true => Box::new(MultiGzDecoder::new(file)),
+        false => Box::new(file),
+    })
+}
+
+impl CsvSource {
+    /// The column headed `name`, after disambiguation.
+    pub fn col_index(&self, name: &str) -> Option<u32> {
+        if name.is_empty() {
+            return None;
+        }
+        self.headers.iter().position(|header| header == name).map(|col| col as u32)
+    }
+}
+
+/// Renames the second and later uses of a header to `name (2)`, `name (3)`
+/// and so on, skipping any suffix another column already has.
+pub(crate) fn disambiguate_headers(headers: Vec<String>) -> Vec<String> {
+    let mut taken: HashSet<String> = headers.iter().cloned().collect();
+    let mut seen = HashSet::new();
+    headers
+        .into_iter()
+        .map(|name| {
+            if name.is_empty() || seen.insert(name.clone()) {
+                return name;
+            }
+            let renamed = (2..)
+                .map(|n| format!("{} ({})", name, n))
+                .find(|candidate| !taken.contains(candidate))
+                .unwrap();
+            taken.insert(renamed.clone());
+            seen.insert(renamed.clone());
+            renamed
+        })
+        .collect()
+}
+
+impl DataSource for CsvSource {
+    fn row_count(&self) -> u64 {
+        self.table.row_count()
+    }
+
+    fn col_count(&self) -> u32 {
+        self.table.col_count()
+    }
+
+    fn cell(&self, row: u64, col: u32) -> Option<String> {
+        self.table.cell(row, col)
+    }
+
+    fn ragged_rows(&self) -> u64 {
+        self.ragged_rows
+    }
+}
+
+/// Picks whichever of comma, semicolon, tab or pipe occurs most often
+/// outside quotes on the first line, preferring comma on a tie.
+fn detect_delimiter(text: &[u8], quote: u8) -> u8 {
+    const CANDIDATES: [u8; 4] = [b',', b';', b'\t', b'|'];
+    let mut counts = [0usize; CANDIDATES.len()];
+    let mut quoted = false;
+    for &byte in text {
+        if byte == quote {
+            quoted = !quoted;
+        } else if !quoted && (byte == b'\n' || byte == b'\r') {
+            break;
+        } else if !quoted {
+            if let Some(i) = CANDIDATES.iter().position(|&c| c == byte) {
+                counts[i] += 1;
+            }
+        }
+    }
+    let mut best = 0;
+    for i in 1..CANDIDATES.len() {
+        if counts[i] > counts[best] {
+            best = i;
+        }
+    }
+    CANDIDATES[best]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(text: &str, options: &CsvOptions) -> Result<CsvSource, String> {
+        CsvSource::from_reader(text.as_bytes(), options)
+    }
+
+    fn rows(source: &CsvSource) -> Vec<Vec<String>> {
+        (0..source.row_count())
+            .map(|row| {
+                let cells = 0..source.col_count();
+                cells.map(|col| source.cell(row, col).unwrap_or_default()).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn semicolons_are_detected_and_split_fields() {
+        let text = "name;price\nApfel;\"1,50\"\nBirne;2,00\n";
+        let source = load(text, &CsvOptions::default()).unwrap();
+        assert_eq!(source.headers, ["name", "price"]);
+        assert_eq!(rows(&source), [["Apfel", "1,50"], ["Birne", "2,00"]]);
+    }
+
+    #[test]
+    fn short_and_long_rows_are_counted_and_fitted_to_the_header() {
+        let text = "a,b,c\n1,2\n3,4,5,6\n7,8,9\n";
+        let source = load(text, &CsvOptions::default()).unwrap();
+        assert_eq!(source.ragged_rows, 2);
+        assert_eq!(source.col_count(), 4);
+        assert_eq!(source.cell(0, 2), None);
+        assert_eq!(source.cell(1, 3).as_deref(), Some("6"));
+        assert_eq!(source.cell(2, 2).as_deref(), Some("9"));
+
+        let options = CsvOptions {
+            truncate_long_rows: true,
+            ..CsvOptions::default()
+        };
+        let source = load(text, &options).unwrap();
+        assert_eq!(source.ragged_rows, 2);
+        assert_eq!(source.col_count(), 3);
+        assert_eq!(rows(&source), [["1", "2", ""], ["3", "4", "5"], ["7", "8", "9"]]);
+    }
+
+    #[test]
+    fn tabs_split_fields_and_quotes_keep_delimiters_inside_them() {
+        let text = "id\tnote\n1\t\"a\ttab\"\n2\t'it''s'\n";
+        for delimiter in [None, Some(b'\t')] {
+            let options = CsvOptions {
+                delimiter,
+                ..CsvOptions::default()
+            };
+            let source = load(text, &options).unwrap();
+            assert_eq!(rows(&source), [["1", "a\ttab"], ["2", "'it''s'"]]);
+        }
+
+        let options = CsvOptions {
+            quote: b'\'',
+            ..CsvOptions::default()
+        };
+        let source = load(text, &options).unwrap();
+        assert_eq!(source.cell(1, 1).as_deref(), Some("it's"));
+    }
+
+    #[test]
+    fn an_escape_character_stands_in_for_doubled_quotes() {
+        let options = CsvOptions {
+            escape: Some(b'\\'),
+            ..CsvOptions::default()
+        };
+        let source = load("a,b\n\"say \\\"hi\\\"\",x\n", &options).unwrap();
+        assert_eq!(rows(&source), [["say \"hi\"", "x"]]);
+    }
+
+    #[test]
+    fn a_file_wider_than_the_column_ceiling_is_refused() {
+        let options = CsvOptions {
+            max_cols: 3,
+            ..CsvOptions::default()
+        };
+        assert_eq!(load("a,b,c\n1,2,3\n", &options).unwrap().col_count(), 3);
+        let err = load("a,b,c\n1,2,3,4\n", &options).err().unwrap();
+        assert_eq!(
+            err,
+            "csv line 2 has 4 columns, more than the limit of 3 (see --csv-max-cols)"
+        );
+        assert!(load("a,b,c,d,e\n", &options).is_err());
+    }
+
+    fn seeded(rows: u64, cols: u32, seed: u64) -> SyntheticSource {
+        SyntheticSource {
+            rows,
+            cols,
+            seed: Some(seed),
+            columns: Vec::new(),
+            sparsity: 0.0,
+        }
+    }
+
+    #[test]
+    fn the_same_seed_makes_the_same_table_and_another_seed_a_different_one() {
+        let cells = |source: &SyntheticSource| -> Vec<Option<String>> {
+            let coords = (0..50).flat_map(|row| (0..8).map(move |col| (row, col)));
+            coords.map(|(row, col)| source.cell(row, col)).collect()
+        };
+        let first = cells(&seeded(50, 8, 7));
+        assert_eq!(cells(&seeded(50, 8, 7)), first);
+        let other = cells(&seeded(50, 8, 8));
+        assert!(first.iter().zip(&other).filter(|(a, b)| a != b).count() > first.len() / 2);
+    }
+
+    /// Whether `cell` is in the format `spec` promises.
+    fn is_formatted(cell: &str, spec: SyntheticColumnSpec) -> bool {
+        let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        match spec {
+            SyntheticColumnSpec::Integer => cell.parse::<u64>().is_ok_and(|n| n < 10_000),
+            SyntheticColumnSpec::Currency => cell.strip_prefix('$').is_some_and(|amount| {
+                let (whole, cents) = amount.split_once('.').unwrap_or((amount, ""));
+                let mut groups = whole.split(',');
+                let lead = groups.next().unwrap();
+                let cents_ok = digits(cents) && cents.len() == 2;
+                cents_ok
+                    && digits(lead)
+                    && lead.len() <= 3
+                    && groups.all(|group| digits(group) && group.len() == 3)
+            }),
+            SyntheticColumnSpec::Word => WORDS.contains(&cell),
+            SyntheticColumnSpec::Date => {
+                let parts: Vec<&str> = cell.split('-').collect();
+                let widths: Vec<usize> = parts.iter().map(|part| part.len()).collect();
+                parts.iter().all(|part| digits(part)) && widths == [4, 2, 2]
+            }
+            SyntheticColumnSpec::Boolean => cell == "true" || cell == "false",
+        }
+    }
+
+    #[test]
+    fn every_seeded_column_keeps_its_format_down_the_table() {
+        let source = seeded(200, 10, 3);
+        for col in 0..10 {
+            let spec = SyntheticColumnSpec::DEFAULT_CYCLE[col as usize % 5];
+            for row in 0..200 {
+                let cell = source.cell(row, col).unwrap();
+                assert!(is_formatted(&cell, spec), "{:?} at ({}, {}): {:?}", spec, row, col, cell);
+            }
+        }
+        assert_eq!(source.cell(0, 1).unwrap().chars().next(), Some('$'));
+
+        let columns = vec![SyntheticColumnSpec::Boolean, SyntheticColumnSpec::Date];
+        let source = SyntheticSource { columns, ..seeded(50, 3, 3) };
+        let specs = [SyntheticColumnSpec::Boolean, SyntheticColumnSpec::Date];
+        for (col, spec) in [0, 1, 2].into_iter().zip(specs.into_iter().cycle()) {
+            assert!((0..50).all(|row| is_formatted(&source.cell(row, col).unwrap(), spec)));
+        }
+        assert_eq!(SyntheticColumnSpec::parse(" currency"), Ok(SyntheticColumnSpec::Currency));
+        assert!(SyntheticColumnSpec::parse("money").is_err());
+    }
+
+    #[test]
+    fn repeated_headers_get_distinct_names_that_look_up_their_own_columns() {
+        let source = load("id,id,id (2),name,id\n1,2,3,Ada,5\n", &CsvOptions::default()).unwrap();
+        assert_eq!(source.headers, ["id", "id (3)", "id (2)", "name", "id (4)"]);
+        for (col, name) in source.headers.iter().enumerate() {
+            assert_eq!(source.col_index(name), Some(col as u32));
+        }
+        assert_eq!(source.cell(0, source.col_index("id (4)").unwrap()).as_deref(), Some("5"));
+        assert_eq!(source.col_index(""), None);
+    }
+
+    fn sparse(sparsity: f64) -> SyntheticSource {
+        SyntheticSource {
+            sparsity,
+            ..seeded(100, 10, 3)
+        }
+    }
+
+    fn blanks(source: &SyntheticSource) -> Vec<(u64, u32)> {
+        let coords = (0..100).flat_map(|row| (0..10).map(move |col| (row, col)));
+        coords.filter(|&(row, col)| source.cell(row, col).unwrap().is_empty()).collect()
+    }
+
+    #[test]
+    fn sparsity_blanks_that_fraction_of_cells_and_the_same_ones_every_time() {
+        assert!(blanks(&sparse(0.0)).is_empty());
+        assert_eq!(blanks(&sparse(1.0)).len(), 1000);
+        let half = blanks(&sparse(0.5));
+        assert!((400..600).contains(&half.len()), "{} of 1000 blank", half.len());
+        assert_eq!(blanks(&sparse(0.5)), half);
+        let (full, half_blank) = (sparse(0.0), sparse(0.5));
+        let (row, col) = (0..100).map(|row| (row, 0)).find(|cell| !half.contains(cell)).unwrap();
+        assert_eq!(half_blank.cell(row, col), full.cell(row, col));
+    }
+}
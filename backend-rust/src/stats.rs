@@ -0,0 +1,183 @@
+//! Quick per-column statistics for header hover cards.
+
+use crate::view;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ColumnStats {
+    /// Non-empty cells.
+    pub count: u64,
+    /// Cells that parse as numbers; `min`, `max` and `sum` cover only these.
+    pub numeric_count: u64,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub sum: f64,
+    /// The column has more rows than were scanned; the same as
+    /// `budgetExhausted`, which newer clients should read instead.
+    pub truncated: bool,
+    #[serde(flatten)]
+    pub scan: Scan,
+}
+
+/// How much of the column a scan read. Scans stop after `--scan-budget` rows,
+/// so on a big table a result may cover only the top of the column.
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Scan {
+    pub scanned_rows: u64,
+    /// The scan stopped at the budget with rows left unread.
+    pub budget_exhausted: bool,
+}
+
+/// How often the scan checks whether it was cancelled.
+const CANCEL_CHECK_INTERVAL: u64 = 4096;
+
+/// Scans up to `budget` of the column's `rows`, reading each cell through
+/// `cell`. Returns `None` if `canceled` is set part way through.
+pub fn compute(
+    rows: u64,
+    budget: u64,
+    canceled: &AtomicBool,
+    mut cell: impl FnMut(u64) -> String,
+) -> Option<ColumnStats> {
+    let scanned_rows = rows.min(budget);
+    let mut stats = ColumnStats {
+        count: 0,
+        numeric_count: 0,
+        min: None,
+        max: None,
+        sum: 0.0,
+        truncated: scanned_rows < rows,
+        scan: Scan {
+            scanned_rows,
+            budget_exhausted: scanned_rows < rows,
+        },
+    };
+    for row in 0..scanned_rows {
+        if row % CANCEL_CHECK_INTERVAL == 0 && canceled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let value = cell(row);
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        stats.count += 1;
+        if let Some(n) = value.parse::<f64>().ok().filter(|n| n.is_finite()) {
+            stats.numeric_count += 1;
+            stats.sum += n;
+            stats.min = Some(stats.min.map_or(n, |m| m.min(n)));
+            stats.max = Some(stats.max.map_or(n, |m| m.max(n)));
+        }
+    }
+    Some(stats)
+}
+
+/// A column's distinct values, for filter dropdowns.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DistinctValues {
+    /// In sort order: numbers by value, then text.
+    pub values: Vec<String>,
+    /// The column has more distinct values than were returned.
+    pub truncated: bool,
+    #[serde(flatten)]
+    pub scan: Scan,
+}
+
+/// Collects up to `cap` distinct values over the first `budget` of the
+/// column's `rows`, stopping as soon as there are more. Returns `None` if
+/// `canceled` is set part way through.
+pub fn distinct_values(
+    rows: u64,
+    cap: usize,
+    budget: u64,
+    canceled: &AtomicBool,
+    mut cell: impl FnMut(u64) -> String,
+) -> Option<DistinctValues> {
+    let mut seen = HashSet::new();
+    let mut truncated = false;
+    let mut scanned_rows = 0;
+    for row in 0..rows.min(budget) {
+        if row % CANCEL_CHECK_INTERVAL == 0 && canceled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let value = cell(row);
+        if seen.len() == cap && !seen.contains(&value) {
+            truncated = true;
+            break;
+        }
+        seen.insert(value);
+        scanned_rows += 1;
+    }
+    let mut values: Vec<String> = seen.into_iter().collect();
+    values.sort_by(|a, b| view::compare_cells(a, b));
+    Some(DistinctValues {
+        values,
+        truncated,
+        scan: Scan {
+            scanned_rows,
+            budget_exhausted: !truncated && scanned_rows < rows,
+        },
+    })
+}
+
+/// How full the table is along its length, for a minimap or overview
+/// scrollbar.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Overview {
+    /// Per bucket, the share of non-empty cells among the rows read from it,
+    /// from 0 to 1. Bucket `i` of `n` covers rows `i * rows / n` (rounded
+    /// down) up to where the next one starts, so with fewer rows than
+    /// buckets some are empty and read as 0.
+    pub fill: Vec<f64>,
+    pub scanned_rows: u64,
+    /// Only some of a bucket's rows were read, evenly spaced.
+    pub sampled: bool,
+}
+
+/// Summarises `rows` in `buckets` buckets. Each reads at most its share of
+/// `budget` cells across all `cols` columns, so the whole scan stays within
+/// about `budget` whatever the table's size. Returns `None` if `canceled`
+/// is set part way through.
+pub fn overview(
+    rows: u64,
+    cols: u32,
+    buckets: u32,
+    budget: u64,
+    canceled: &AtomicBool,
+    mut filled: impl FnMut(u64, u32) -> bool,
+) -> Option<Overview> {
+    let rows_per_bucket = (budget / (buckets as u64 * cols as u64).max(1)).max(1);
+    let bucket_start = |i: u64| (i as u128 * rows as u128 / buckets as u128) as u64;
+    let mut overview = Overview {
+        fill: Vec::with_capacity(buckets as usize),
+        scanned_rows: 0,
+        sampled: false,
+    };
+    for i in 0..buckets as u64 {
+        if canceled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let (start, len) = (bucket_start(i), bucket_start(i + 1) - bucket_start(i));
+        let reads = len.min(rows_per_bucket);
+        let mut cells = 0u64;
+        for k in 0..reads {
+            let row = start + (k as u128 * len as u128 / reads as u128) as u64;
+            cells += (0..cols).filter(|&col| filled(row, col)).count() as u64;
+        }
+        let read_cells = reads * cols as u64;
+        overview.fill.push(match read_cells {
+            0 => 0.0,
+            read_cells => cells as f64 / read_cells as f64,
+        });
+        overview.scanned_rows += reads;
+        overview.sampled |= reads < len;
+    }
+    Some(overview)
+}
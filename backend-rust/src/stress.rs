@@ -0,0 +1,53 @@
+//! Synthetic `cells_updated` traffic for stress-testing clients on `/ws-stress`.
+
+use crate::outbound::{Outbound, PushOutcome};
+use crate::protocol::{CellValue, CellsUpdated, ServerMessage};
+use axum::extract::ws::Message;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Where and how fast the fake edits land.
+#[derive(Clone, Copy, Debug)]
+pub struct StressConfig {
+    /// Pushes per second.
+    pub rate: u32,
+    /// Edits fall in the top-left `rows` x `cols` of the table.
+    pub rows: u64,
+    pub cols: u32,
+}
+
+/// Pushes one random single-cell `cells_updated` to `outbound` every
+/// `1 / rate` seconds until the connection closes. The values are not stored,
+/// so a later slice shows the real data again.
+pub fn spawn(outbound: Outbound, config: StressConfig, seed: u64) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut rng = XorShift(seed | 1);
+        let mut ticker = tokio::time::interval(Duration::from_secs(1) / config.rate.max(1));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        for n in 0u64.. {
+            ticker.tick().await;
+            let cell = CellValue {
+                row: rng.next() % config.rows.max(1),
+                col: (rng.next() % config.cols.max(1) as u64) as u32,
+                value: format!("stress {}", n),
+                version: None,
+            };
+            let msg = ServerMessage::CellsUpdated(CellsUpdated { cells: vec![cell] });
+            if let PushOutcome::Closed = outbound.push(Message::Text(msg.to_json())) {
+                return;
+            }
+        }
+    })
+}
+
+/// Small, fast and plenty random for making up cell positions.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -0,0 +1,150 @@
+//! `--validate-only`: checks a data file and reports on it without serving.
+
+use crate::config::CsvOptions;
+use crate::source::{CsvSource, DataSource};
+use std::fmt;
+use std::io::Read;
+
+/// Most individual warnings listed before the rest are only counted.
+const MAX_LISTED_WARNINGS: usize = 20;
+
+/// The narrowest type that fits every non-empty cell of a column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    Empty,
+    Integer,
+    Number,
+    Boolean,
+    Text,
+}
+
+impl ColumnType {
+    fn of(cell: &str) -> Self {
+        let cell = cell.trim();
+        if cell.is_empty() {
+            ColumnType::Empty
+        } else if cell.parse::<i64>().is_ok() {
+            ColumnType::Integer
+        } else if cell.parse::<f64>().is_ok_and(f64::is_finite) {
+            ColumnType::Number
+        } else if cell.eq_ignore_ascii_case("true") || cell.eq_ignore_ascii_case("false") {
+            ColumnType::Boolean
+        } else {
+            ColumnType::Text
+        }
+    }
+
+    /// The narrowest type covering both.
+    fn widen(self, other: Self) -> Self {
+        use ColumnType::*;
+        match (self, other) {
+            (Empty, other) | (other, Empty) => other,
+            (a, b) if a == b => a,
+            (Integer, Number) | (Number, Integer) => Number,
+            _ => Text,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ColumnType::Empty => "empty",
+            ColumnType::Integer => "integer",
+            ColumnType::Number => "number",
+            ColumnType::Boolean => "boolean",
+            ColumnType::Text => "text",
+        }
+    }
+}
+
+/// What validation found. A file that failed to load has an `error` and
+/// nothing else.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub error: Option<String>,
+    pub rows: u64,
+    pub columns: Vec<(String, ColumnType)>,
+    pub warnings: Vec<String>,
+}
+
+impl Report {
+    /// Whether the file loaded without errors or warnings.
+    pub fn is_clean(&self) -> bool {
+        self.error.is_none() && self.warnings.is_empty()
+    }
+}
+
+/// Loads a CSV the way the server would and inspects every row.
+pub fn validate_csv(reader: impl Read, options: &CsvOptions) -> Report {
+    let source = match CsvSource::from_reader(reader, options) {
+        Ok(source) => source,
+        Err(err) => {
+            return Report {
+                error: Some(err),
+                ..Report::default()
+            }
+        }
+    };
+
+    let width = source.headers.len() as u32;
+    let mut types = vec![ColumnType::Empty; source.col_count() as usize];
+    let mut warnings = Vec::new();
+    for row in 0..source.row_count() {
+        let mut fields = 0;
+        for col in 0..source.col_count() {
+            if let Some(cell) = source.cell(row, col) {
+                fields = col + 1;
+                types[col as usize] = types[col as usize].widen(ColumnType::of(&cell));
+            }
+        }
+        if fields != width {
+            warnings.push(format!(
+                "data row {} has {} fields, the header has {}",
+                row + 1,
+                fields,
+                width
+            ));
+        }
+    }
+
+    let columns = types
+        .into_iter()
+        .enumerate()
+        .map(|(col, kind)| {
+            let name = source
+                .headers
+                .get(col)
+                .cloned()
+                .unwrap_or_else(|| format!("(unnamed column {})", col + 1));
+            (name, kind)
+        })
+        .collect();
+    Report {
+        error: None,
+        rows: source.row_count(),
+        columns,
+        warnings,
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(err) = &self.error {
+            return writeln!(f, "error: {}", err);
+        }
+        writeln!(f, "{} rows, {} columns", self.rows, self.columns.len())?;
+        for (name, kind) in &self.columns {
+            writeln!(f, "  {}: {}", name, kind.name())?;
+        }
+        for warning in self.warnings.iter().take(MAX_LISTED_WARNINGS) {
+            writeln!(f, "warning: {}", warning)?;
+        }
+        if self.warnings.len() > MAX_LISTED_WARNINGS {
+            writeln!(
+                f,
+                "warning: ... and {} more",
+                self.warnings.len() - MAX_LISTED_WARNINGS
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,537 @@
+//! Per-session filtered and sorted view of the table.
+//!
+//! Slices are addressed by visual row. With no filters or sort a visual row is
+//! the physical row; otherwise the view holds the physical ids of the rows
+//! that passed, in display order.
+
+use crate::expr::FilterExpr;
+use regex::{Regex, RegexBuilder};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterOp {
+    /// The cell contains the value as a substring.
+    Contains,
+    Equals,
+    // The comparisons only pass cells that parse as numbers.
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    /// The cell equals one of `values`.
+    In,
+    /// Some part of the cell matches `value` as a regular expression, in the
+    /// syntax of the `regex` crate; anchor it with `^` and `$` to match all.
+    Regex,
+}
+
+/// Keeps the rows whose `col` cell satisfies `op` against `value`, or for
+/// `in`, against `values`.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Filter {
+    pub col: u32,
+    pub op: FilterOp,
+    #[serde(default)]
+    pub value: String,
+    #[serde(default)]
+    pub values: HashSet<String>,
+    /// Ignore case for `contains`, `equals` and `regex`.
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// What [`Filter::compile`] made of `value`, for the ops matched by regex.
+    #[serde(skip)]
+    pub regex: Option<Regex>,
+}
+
+impl Filter {
+    /// Compiles the pattern a `regex` filter, or a case-insensitive
+    /// `contains` or `equals`, is matched with. Until then a `regex` filter
+    /// matches nothing and the others keep to case.
+    pub fn compile(&mut self) -> Result<(), regex::Error> {
+        let pattern = match self.op {
+            FilterOp::Regex => self.value.clone(),
+            FilterOp::Contains if self.case_insensitive => regex::escape(&self.value),
+            FilterOp::Equals if self.case_insensitive => {
+                format!("^(?:{})$", regex::escape(&self.value))
+            }
+            _ => return Ok(()),
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(self.case_insensitive)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .build()?;
+        self.regex = Some(regex);
+        Ok(())
+    }
+
+    pub fn matches(&self, cell: &str) -> bool {
+        if let Some(regex) = &self.regex {
+            return regex.is_match(cell);
+        }
+        let compare = |pass: fn(f64, f64) -> bool| {
+            match (cell.trim().parse::<f64>(), self.value.trim().parse::<f64>()) {
+                (Ok(cell), Ok(value)) => pass(cell, value),
+                _ => false,
+            }
+        };
+        match self.op {
+            FilterOp::Contains => cell.contains(self.value.as_str()),
+            FilterOp::Equals => cell == self.value,
+            FilterOp::Gt => compare(|cell, value| cell > value),
+            FilterOp::Gte => compare(|cell, value| cell >= value),
+            FilterOp::Lt => compare(|cell, value| cell < value),
+            FilterOp::Lte => compare(|cell, value| cell <= value),
+            FilterOp::In => self.values.contains(cell),
+            FilterOp::Regex => false,
+        }
+    }
+}
+
+/// Bytes a compiled filter pattern may take, so no client can make the
+/// server build a huge automaton.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Orders rows by `col`. Earlier keys take precedence; ties keep physical
+/// order.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SortKey {
+    pub col: u32,
+    #[serde(default)]
+    pub descending: bool,
+    /// Compare text by [`natural_cmp`], so `item2` sorts before `item10`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub natural: bool,
+}
+
+/// The number a cell sorts by, read through common display formatting:
+/// thousands separators, a leading currency sign and a trailing `%`.
+pub fn numeric_value(cell: &str) -> Option<f64> {
+    let cell = cell.trim();
+    let (negative, rest) = match cell.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, cell),
+    };
+    let rest = rest.trim_start_matches(['$', '€', '£', '¥']);
+    let (percent, rest) = match rest.strip_suffix('%') {
+        Some(rest) => (true, rest),
+        None => (false, rest),
+    };
+    let value: f64 = rest.replace(',', "").parse().ok().filter(|value: &f64| value.is_finite())?;
+    let value = if percent { value / 100.0 } else { value };
+    Some(if negative { -value } else { value })
+}
+
+/// Numbers sort before text and compare by value; text compares bytewise.
+pub fn compare_cells(a: &str, b: &str) -> CmpOrdering {
+    match (numeric_value(a), numeric_value(b)) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => CmpOrdering::Less,
+        (None, Some(_)) => CmpOrdering::Greater,
+        (None, None) => a.cmp(b),
+    }
+}
+
+/// Like [`compare_cells`], but text compares by [`natural_cmp`].
+pub fn compare_cells_natural(a: &str, b: &str) -> CmpOrdering {
+    match (numeric_value(a), numeric_value(b)) {
+        (None, None) => natural_cmp(a, b),
+        _ => compare_cells(a, b),
+    }
+}
+
+/// Compares text with each run of ASCII digits read as a whole number, so
+/// `a2` comes before `a10` and `v1.9` before `v1.10`. Other runs compare
+/// bytewise. Runs equal as numbers, like `007` and `7`, tie and the rest of
+/// the text decides; text equal throughout save for leading zeros falls back
+/// to bytewise, so the order stays total.
+pub fn natural_cmp(a: &str, b: &str) -> CmpOrdering {
+    let (mut a_runs, mut b_runs) = (runs(a), runs(b));
+    loop {
+        let (a_run, b_run) = match (a_runs.next(), b_runs.next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return CmpOrdering::Less,
+            (Some(_), None) => return CmpOrdering::Greater,
+            (Some(a_run), Some(b_run)) => (a_run, b_run),
+        };
+        let is_number = |run: &str| run.starts_with(|c: char| c.is_ascii_digit());
+        let order = match (is_number(a_run), is_number(b_run)) {
+            (true, true) => {
+                let (a_run, b_run) = (a_run.trim_start_matches('0'), b_run.trim_start_matches('0'));
+                a_run.len().cmp(&b_run.len()).then_with(|| a_run.cmp(b_run))
+            }
+            _ => a_run.cmp(b_run),
+        };
+        if order.is_ne() {
+            return order;
+        }
+    }
+}
+
+/// Splits text into alternating runs of ASCII digits and everything else.
+fn runs(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let digits = rest.chars().next()?.is_ascii_digit();
+        let end = rest.find(|c: char| c.is_ascii_digit() != digits).unwrap_or(rest.len());
+        let (run, tail) = rest.split_at(end);
+        rest = tail;
+        Some(run)
+    })
+}
+
+/// What a view is built from.
+#[derive(Clone, Debug, Default)]
+pub struct ViewSpec {
+    /// At most one filter per column.
+    pub filters: Vec<Filter>,
+    /// From `filter_expr_request`; rows must pass it as well as `filters`.
+    pub expr: Option<FilterExpr>,
+    pub sort: Vec<SortKey>,
+}
+
+impl ViewSpec {
+    /// Whether the view is every physical row in order.
+    pub fn is_identity(&self) -> bool {
+        self.filters.is_empty() && self.expr.is_none() && self.sort.is_empty()
+    }
+
+    /// Every column the filters, expression and sort read.
+    pub fn cols(&self) -> HashSet<u32> {
+        let mut cols: HashSet<u32> = self.filters.iter().map(|filter| filter.col).collect();
+        cols.extend(self.sort.iter().map(|key| key.col));
+        if let Some(expr) = &self.expr {
+            expr.collect_cols(&mut cols);
+        }
+        cols
+    }
+}
+
+/// How often the scan checks whether it was cancelled.
+const CANCEL_CHECK_INTERVAL: u64 = 4096;
+
+/// Rows sorted at a time before the runs are merged, so a long sort still
+/// sees a cancellation soon.
+const SORT_RUN: usize = 65_536;
+
+/// A mapping made by [`build_rows`].
+pub struct BuiltRows {
+    pub rows: Vec<u64>,
+    /// The scan stopped at its budget; rows past it are left out.
+    pub budget_exhausted: bool,
+}
+
+/// Physical ids of the rows among the first `budget` of `rows` that pass
+/// every filter, in sort order, reading cells through `cell`. Returns `None`
+/// if `canceled` is set part way through, sorting included.
+pub fn build_rows(
+    spec: &ViewSpec,
+    rows: u64,
+    budget: u64,
+    canceled: &AtomicBool,
+    mut cell: impl FnMut(u64, u32) -> String,
+) -> Option<BuiltRows> {
+    let scanned_rows = rows.min(budget);
+    let budget_exhausted = scanned_rows < rows;
+    let mut passed = Vec::new();
+    for row in 0..scanned_rows {
+        if row % CANCEL_CHECK_INTERVAL == 0 && canceled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let passes = spec.filters.iter().all(|filter| filter.matches(&cell(row, filter.col)))
+            && spec.expr.as_ref().is_none_or(|expr| expr.matches(&mut |col| cell(row, col)));
+        if passes {
+            passed.push(row);
+        }
+    }
+    if spec.sort.is_empty() {
+        return Some(BuiltRows {
+            rows: passed,
+            budget_exhausted,
+        });
+    }
+
+    // Read each sort cell once up front rather than on every comparison.
+    let mut keyed = Vec::with_capacity(passed.len());
+    for (i, row) in (0u64..).zip(passed) {
+        if i % CANCEL_CHECK_INTERVAL == 0 && canceled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let keys: Vec<String> = spec.sort.iter().map(|key| cell(row, key.col)).collect();
+        keyed.push((row, keys));
+    }
+    let compare_keys = |(_, a): &(u64, Vec<String>), (_, b): &(u64, Vec<String>)| {
+        spec.sort
+            .iter()
+            .zip(a.iter().zip(b))
+            .map(|(key, (a, b))| {
+                let compare = match key.natural {
+                    false => compare_cells,
+                    true => compare_cells_natural,
+                };
+                match key.descending {
+                    false => compare(a, b),
+                    true => compare(b, a),
+                }
+            })
+            .find(|order| order.is_ne())
+            .unwrap_or(CmpOrdering::Equal)
+    };
+    let sorted = sort_cancellable(keyed, compare_keys, canceled)?;
+    Some(BuiltRows {
+        rows: sorted.into_iter().map(|(row, _)| row).collect(),
+        budget_exhausted,
+    })
+}
+
+/// A stable sort of `items` that gives up with `None` once `canceled` is
+/// set: runs of [`SORT_RUN`] are sorted one at a time, then merged in pairs.
+fn sort_cancellable<T>(
+    items: Vec<T>,
+    compare: impl Fn(&T, &T) -> CmpOrdering,
+    canceled: &AtomicBool,
+) -> Option<Vec<T>> {
+    let mut items = items.into_iter();
+    let mut runs = Vec::new();
+    loop {
+        let mut run: Vec<T> = items.by_ref().take(SORT_RUN).collect();
+        if run.is_empty() {
+            break;
+        }
+        if canceled.load(Ordering::Relaxed) {
+            return None;
+        }
+        run.sort_by(&compare);
+        runs.push(run);
+    }
+    while runs.len() > 1 {
+        let mut pairs = runs.into_iter();
+        runs = Vec::new();
+        while let Some(left) = pairs.next() {
+            runs.push(match pairs.next() {
+                Some(right) => merge(left, right, &compare, canceled)?,
+                None => left,
+            });
+        }
+    }
+    Some(runs.pop().unwrap_or_default())
+}
+
+/// Merges two sorted runs, taking from `left` on ties to keep the sort
+/// stable.
+fn merge<T>(
+    left: Vec<T>,
+    right: Vec<T>,
+    compare: &impl Fn(&T, &T) -> CmpOrdering,
+    canceled: &AtomicBool,
+) -> Option<Vec<T>> {
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        let merged_rows = merged.len() as u64;
+        if merged_rows.is_multiple_of(CANCEL_CHECK_INTERVAL) && canceled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let next = match compare(b, a).is_lt() {
+            true => right.next(),
+            false => left.next(),
+        };
+        merged.extend(next);
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Some(merged)
+}
+
+/// The session's filters and sort, and the row mapping last built from them.
+///
+/// Rebuilding runs in the background, so each change takes a revision and a
+/// finished mapping is only installed if no later change has been made.
+#[derive(Clone, Default)]
+pub struct View(Arc<Mutex<ViewState>>);
+
+#[derive(Default)]
+struct ViewState {
+    spec: ViewSpec,
+    revision: u64,
+    /// Names the mapping installed, so slices can tell which one they were
+    /// cut from: 0 for the first, then a number no view has used before, so
+    /// caches over a mapping can key on it too.
+    generation: u64,
+    /// `None` while unfiltered and unsorted: every physical row in order.
+    rows: Option<Arc<Vec<u64>>>,
+    /// `rows` stopped at the scan budget; see [`BuiltRows`].
+    budget_exhausted: bool,
+    /// The sort `rows` was built with, which lags `spec.sort` while a
+    /// rebuild runs and stays put if it is cancelled.
+    sorted_by: Vec<SortKey>,
+}
+
+/// Hands out view generations, shared by every view.
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+fn next_generation() -> u64 {
+    NEXT_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+impl View {
+    /// Sets the filter for its column, replacing any earlier one there.
+    /// Returns the new revision and the spec to build it from, or `None`,
+    /// changing nothing, if that would make more than `limit` filters.
+    pub fn set_filter(&self, filter: Filter, limit: usize) -> Option<(u64, ViewSpec)> {
+        let mut view = self.0.lock().unwrap();
+        let replaces = view.spec.filters.iter().any(|existing| existing.col == filter.col);
+        if !replaces && view.spec.filters.len() >= limit {
+            return None;
+        }
+        view.spec.filters.retain(|existing| existing.col != filter.col);
+        view.spec.filters.push(filter);
+        view.revision += 1;
+        Some((view.revision, view.spec.clone()))
+    }
+
+    /// Replaces the filter expression; `None` drops it. Column filters stay.
+    pub fn set_expr(&self, expr: Option<FilterExpr>) -> (u64, ViewSpec) {
+        let mut view = self.0.lock().unwrap();
+        view.spec.expr = expr;
+        view.revision += 1;
+        (view.revision, view.spec.clone())
+    }
+
+    /// Drops the filter on `col`, or every filter and the filter expression
+    /// when `col` is `None`.
+    pub fn clear_filters(&self, col: Option<u32>) -> (u64, ViewSpec) {
+        let mut view = self.0.lock().unwrap();
+        match col {
+            Some(col) => view.spec.filters.retain(|existing| existing.col != col),
+            None => {
+                view.spec.filters.clear();
+                view.spec.expr = None;
+            }
+        }
+        view.revision += 1;
+        (view.revision, view.spec.clone())
+    }
+
+    /// Replaces the sort; no keys restores physical order.
+    pub fn set_sort(&self, sort: Vec<SortKey>) -> (u64, ViewSpec) {
+        let mut view = self.0.lock().unwrap();
+        view.spec.sort = sort;
+        view.revision += 1;
+        (view.revision, view.spec.clone())
+    }
+
+    /// Drops every filter and the sort at once. Rebuilds still running are
+    /// superseded.
+    pub fn reset(&self) {
+        let mut view = self.0.lock().unwrap();
+        view.spec = ViewSpec::default();
+        view.revision += 1;
+        view.generation = next_generation();
+        view.rows = None;
+        view.budget_exhausted = false;
+        view.sorted_by.clear();
+    }
+
+    /// Stores the mapping built for `revision` in the order of `sort`, or
+    /// the identity for `None`; `false` if it was superseded.
+    pub fn install(&self, revision: u64, rows: Option<BuiltRows>, sort: Vec<SortKey>) -> bool {
+        let mut view = self.0.lock().unwrap();
+        if view.revision != revision {
+            return false;
+        }
+        view.budget_exhausted = rows.as_ref().is_some_and(|rows| rows.budget_exhausted);
+        view.rows = rows.map(|rows| Arc::new(rows.rows));
+        view.sorted_by = sort;
+        view.generation = next_generation();
+        true
+    }
+
+    /// The sort keys the current mapping is in, for drawing header arrows.
+    pub fn sorted_by(&self) -> Vec<SortKey> {
+        self.0.lock().unwrap().sorted_by.clone()
+    }
+
+    /// The current mapping, or `None` while unfiltered and unsorted.
+    pub fn rows(&self) -> Option<Arc<Vec<u64>>> {
+        self.0.lock().unwrap().rows.clone()
+    }
+
+    /// Whether the current mapping stopped at the scan budget.
+    pub fn budget_exhausted(&self) -> bool {
+        self.0.lock().unwrap().budget_exhausted
+    }
+
+    /// The current generation, replaced by every change that takes effect.
+    pub fn generation(&self) -> u64 {
+        self.0.lock().unwrap().generation
+    }
+
+    /// The current mapping with its generation, read together.
+    pub fn current(&self) -> (u64, Option<Arc<Vec<u64>>>) {
+        let view = self.0.lock().unwrap();
+        (view.generation, view.rows.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatted_numbers_compare_by_value() {
+        let mut cells = vec!["10", "9", "$1,200", "100%", "-5", "€900", "abc", "1.5"];
+        cells.sort_by(|a, b| compare_cells(a, b));
+        assert_eq!(cells, ["-5", "100%", "1.5", "9", "10", "€900", "$1,200", "abc"]);
+        assert_eq!(numeric_value("$1,200"), Some(1200.0));
+        assert_eq!(numeric_value("12.5%"), Some(0.125));
+    }
+
+    #[test]
+    fn natural_order_reads_digit_runs_as_numbers() {
+        let mut cells = vec!["a2", "a10", "a1"];
+        cells.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(cells, ["a1", "a2", "a10"]);
+        let mut versions = vec!["v1.10", "v1.9", "v10.0", "v1.9.1", "v2"];
+        versions.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(versions, ["v1.9", "v1.9.1", "v1.10", "v2", "v10.0"]);
+        let mut zeros = vec!["item007", "item7", "item06", "item7b"];
+        zeros.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(zeros, ["item06", "item007", "item7", "item7b"]);
+        assert_eq!(natural_cmp("x", "x1"), CmpOrdering::Less);
+    }
+
+    #[test]
+    fn a_sort_over_several_runs_is_stable() {
+        let items: Vec<(u64, u64)> = (0..SORT_RUN as u64 * 3 + 7).map(|i| (i % 5, i)).collect();
+        let by_key = |a: &(u64, u64), b: &(u64, u64)| a.0.cmp(&b.0);
+        let sorted = sort_cancellable(items.clone(), by_key, &AtomicBool::new(false)).unwrap();
+        let mut expected = items;
+        expected.sort_by(by_key);
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn a_canceled_sort_gives_up() {
+        let items: Vec<u64> = (0..SORT_RUN as u64 * 2).rev().collect();
+        assert!(sort_cancellable(items, u64::cmp, &AtomicBool::new(true)).is_none());
+    }
+
+    #[test]
+    fn rows_past_the_budget_are_left_out() {
+        let spec = ViewSpec {
+            sort: vec![SortKey { col: 0, descending: false, natural: false }],
+            ..ViewSpec::default()
+        };
+        let cell = |row: u64, _| (9 - row).to_string();
+        let built = build_rows(&spec, 10, 5, &AtomicBool::new(false), cell).unwrap();
+        assert_eq!((built.rows, built.budget_exhausted), (vec![4, 3, 2, 1, 0], true));
+    }
+}
@@ -0,0 +1,602 @@
+//! `--xlsx`: serves a worksheet of an Excel workbook.
+//!
+//! An XLSX file is a zip archive of XML parts. Only the parts cell values
+//! need are read: the workbook's sheet list and its relationships, the shared
+//! strings, the number formats that mark dates, and the chosen sheet's cells.
+//! The archive and XML readers here cover what spreadsheet programs write,
+//! not every corner of either format.
+
+use crate::source::{disambiguate_headers, DataSource, InlineSource};
+use flate2::read::DeflateDecoder;
+use std::collections::HashMap;
+use std::io::Read;
+
+/// One worksheet of a workbook, loaded into memory. As with a CSV file, the
+/// first row supplies the column names and the rest are the table.
+///
+/// Numbers keep the text Excel stored, booleans read `true` or `false`, and
+/// numbers formatted as dates or times read as `2024-03-01`,
+/// `2024-03-01 09:30:00` or `09:30:00`.
+pub struct XlsxSource {
+    /// Every worksheet in the workbook, in tab order.
+    pub sheet_names: Vec<String>,
+    /// The worksheet being served.
+    pub sheet: String,
+    pub headers: Vec<String>,
+    table: InlineSource,
+}
+
+impl XlsxSource {
+    /// Opens `sheet`, or the first worksheet when `None`.
+    pub fn open(path: &str, sheet: Option<&str>) -> Result<Self, String> {
+        let bytes =
+            std::fs::read(path).map_err(|err| format!("cannot open xlsx {}: {}", path, err))?;
+        Self::from_bytes(&bytes, sheet).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    pub fn from_bytes(bytes: &[u8], sheet: Option<&str>) -> Result<Self, String> {
+        let zip = Zip::parse(bytes)?;
+        let workbook = zip.text("xl/workbook.xml")?;
+        let rels = zip.text("xl/_rels/workbook.xml.rels")?;
+        let sheets = workbook_sheets(&workbook, &rels)?;
+        let sheet_names: Vec<String> = sheets.iter().map(|(name, _)| name.clone()).collect();
+        let (name, part) = match sheet {
+            None => sheets.first().ok_or("the workbook has no sheets")?,
+            Some(wanted) => sheets.iter().find(|(name, _)| name == wanted).ok_or_else(|| {
+                format!("no sheet named {:?}; the workbook has {}", wanted, sheet_names.join(", "))
+            })?,
+        };
+        let shared = match zip.has("xl/sharedStrings.xml") {
+            true => shared_strings(&zip.text("xl/sharedStrings.xml")?),
+            false => Vec::new(),
+        };
+        let dates = DateFormats {
+            styles: match zip.has("xl/styles.xml") {
+                true => date_styles(&zip.text("xl/styles.xml")?),
+                false => Vec::new(),
+            },
+            from_1904: Events::new(&workbook).any(|event| match event {
+                Event::Open { name, attrs, .. } => {
+                    name == "workbookPr"
+                        && matches!(attr(attrs, "date1904").as_deref(), Some("1" | "true"))
+                }
+                _ => false,
+            }),
+        };
+        let mut rows = sheet_rows(&zip.text(part)?, &shared, &dates);
+        if rows.is_empty() {
+            return Err(format!("sheet {:?} is empty", name));
+        }
+        let headers = disambiguate_headers(rows.remove(0));
+        Ok(Self {
+            sheet_names,
+            sheet: name.clone(),
+            headers,
+            table: InlineSource::from_rows(rows)?,
+        })
+    }
+}
+
+impl DataSource for XlsxSource {
+    fn row_count(&self) -> u64 {
+        self.table.row_count()
+    }
+
+    fn col_count(&self) -> u32 {
+        self.table.col_count()
+    }
+
+    fn cell(&self, row: u64, col: u32) -> Option<String> {
+        self.table.cell(row, col)
+    }
+}
+
+/// The worksheets named in `workbook.xml`, in tab order, each with the path
+/// of its part as its relationship gives it.
+fn workbook_sheets(workbook: &str, rels: &str) -> Result<Vec<(String, String)>, String> {
+    let targets: HashMap<String, String> = Events::new(rels)
+        .filter_map(|event| match event {
+            Event::Open { name: "Relationship", attrs, .. } => {
+                Some((attr(attrs, "Id")?, attr(attrs, "Target")?))
+            }
+            _ => None,
+        })
+        .collect();
+    let mut sheets = Vec::new();
+    for event in Events::new(workbook) {
+        let Event::Open { name: "sheet", attrs, .. } = event else {
+            continue;
+        };
+        let (Some(name), Some(id)) = (attr(attrs, "name"), attr(attrs, "id")) else {
+            return Err("a sheet in workbook.xml has no name or id".to_string());
+        };
+        let target = targets
+            .get(&id)
+            .ok_or_else(|| format!("sheet {:?} has no part in the workbook", name))?;
+        let part = match target.strip_prefix('/') {
+            Some(absolute) => absolute.to_string(),
+            None => format!("xl/{}", target),
+        };
+        sheets.push((name, part));
+    }
+    Ok(sheets)
+}
+
+/// The shared string table, each entry joined from its runs. Phonetic
+/// readings (`rPh`) are left out.
+fn shared_strings(xml: &str) -> Vec<String> {
+    let mut strings = Vec::new();
+    let (mut text, mut in_text, mut phonetic) = (String::new(), false, 0);
+    for event in Events::new(xml) {
+        match event {
+            Event::Open { name: "si", empty, .. } => {
+                text.clear();
+                if empty {
+                    strings.push(String::new());
+                }
+            }
+            Event::Close("si") => strings.push(std::mem::take(&mut text)),
+            Event::Open { name: "rPh", empty: false, .. } => phonetic += 1,
+            Event::Close("rPh") => phonetic -= 1,
+            Event::Open { name: "t", empty, .. } => in_text = !empty,
+            Event::Close("t") => in_text = false,
+            Event::Text(raw) if in_text && phonetic == 0 => text.push_str(&unescape(raw)),
+            _ => {}
+        }
+    }
+    strings
+}
+
+/// For each cell style (`cellXfs` in `styles.xml`), whether its number format
+/// shows a date or time.
+fn date_styles(xml: &str) -> Vec<bool> {
+    let mut custom: HashMap<String, String> = HashMap::new();
+    let mut styles = Vec::new();
+    let mut in_cell_xfs = false;
+    for event in Events::new(xml) {
+        match event {
+            Event::Open { name: "numFmt", attrs, .. } => {
+                let (id, code) = (attr(attrs, "numFmtId"), attr(attrs, "formatCode"));
+                if let (Some(id), Some(code)) = (id, code) {
+                    custom.insert(id, code);
+                }
+            }
+            Event::Open { name: "cellXfs", empty, .. } => in_cell_xfs = !empty,
+            Event::Close("cellXfs") => in_cell_xfs = false,
+            Event::Open { name: "xf", attrs, .. } if in_cell_xfs => {
+                let id = attr(attrs, "numFmtId").unwrap_or_default();
+                styles.push(match custom.get(&id) {
+                    Some(code) => is_date_format(code),
+                    None => is_builtin_date_format(&id),
+                });
+            }
+            _ => {}
+        }
+    }
+    styles
+}
+
+/// The built-in number formats 14-22 and 45-47 are dates and times.
+fn is_builtin_date_format(id: &str) -> bool {
+    matches!(id.parse::<u32>(), Ok(14..=22 | 45..=47))
+}
+
+/// Whether a custom format code uses a date or time part, ignoring quoted
+/// text, escaped characters and bracketed colors or conditions.
+fn is_date_format(code: &str) -> bool {
+    let (mut quoted, mut bracketed, mut escaped) = (false, false, false);
+    for c in code.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '"' => quoted = !quoted,
+            _ if quoted => {}
+            '\\' => escaped = true,
+            '[' => bracketed = true,
+            ']' => bracketed = false,
+            _ if bracketed => {}
+            'd' | 'D' | 'm' | 'M' | 'y' | 'Y' | 'h' | 'H' | 's' | 'S' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+struct DateFormats {
+    styles: Vec<bool>,
+    /// The workbook counts days from 1904 rather than 1900.
+    from_1904: bool,
+}
+
+/// Excel's own sheet size.
+const MAX_SHEET_ROWS: usize = 1 << 20;
+const MAX_SHEET_COLS: usize = 1 << 14;
+
+/// A worksheet's cells as rows of text, placed by their `r` references so
+/// skipped cells read empty. Rows are as long as their last cell.
+fn sheet_rows(xml: &str, shared: &[String], dates: &DateFormats) -> Vec<Vec<String>> {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let (mut row, mut next_row, mut next_col) = (0usize, 0usize, 0usize);
+    let mut cell: Option<(usize, Option<String>, Option<usize>)> = None;
+    let (mut value, mut in_value) = (String::new(), false);
+    for event in Events::new(xml) {
+        match event {
+            Event::Open { name: "row", attrs, .. } => {
+                let number = attr(attrs, "r").and_then(|r| r.parse::<usize>().ok());
+                row = number.and_then(|r| r.checked_sub(1)).unwrap_or(next_row);
+                (next_row, next_col) = (row + 1, 0);
+            }
+            Event::Open { name: "c", attrs, empty } => {
+                let col = attr(attrs, "r").and_then(|r| cell_col(&r)).unwrap_or(next_col);
+                next_col = col + 1;
+                let style = attr(attrs, "s").and_then(|s| s.parse().ok());
+                value.clear();
+                // Past Excel's own limits the reference is bogus; skip the cell.
+                cell = match !empty && row < MAX_SHEET_ROWS && col < MAX_SHEET_COLS {
+                    true => Some((col, attr(attrs, "t"), style)),
+                    false => None,
+                };
+            }
+            Event::Open { name: "v" | "t", empty, .. } if cell.is_some() => in_value = !empty,
+            Event::Close("v" | "t") => in_value = false,
+            Event::Text(raw) if in_value => value.push_str(&unescape(raw)),
+            Event::Close("c") => {
+                let Some((col, kind, style)) = cell.take() else {
+                    continue;
+                };
+                let text = cell_text(&value, kind.as_deref(), style, shared, dates);
+                if rows.len() <= row {
+                    rows.resize(row + 1, Vec::new());
+                }
+                let cells = &mut rows[row];
+                if cells.len() <= col {
+                    cells.resize(col + 1, String::new());
+                }
+                cells[col] = text;
+            }
+            _ => {}
+        }
+    }
+    rows
+}
+
+/// The column of a cell reference such as `B3`.
+fn cell_col(reference: &str) -> Option<usize> {
+    let letters = reference.trim_end_matches(|c: char| c.is_ascii_digit());
+    crate::formula::letters_to_col(letters).map(|col| col as usize)
+}
+
+/// A cell's text from its stored value and its `t` type.
+fn cell_text(
+    value: &str,
+    kind: Option<&str>,
+    style: Option<usize>,
+    shared: &[String],
+    dates: &DateFormats,
+) -> String {
+    match kind {
+        Some("s") => value
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| shared.get(index).cloned())
+            .unwrap_or_default(),
+        Some("b") => (value.trim() == "1").to_string(),
+        Some("inlineStr" | "str" | "e" | "d") => value.to_string(),
+        _ => match style.is_some_and(|style| dates.styles.get(style) == Some(&true)) {
+            true => value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(|serial| date_text(serial, dates.from_1904))
+                .unwrap_or_else(|| value.to_string()),
+            false => value.to_string(),
+        },
+    }
+}
+
+/// An Excel date serial as ISO text: the date, the time of day if it has
+/// one, or only the time for a serial under one day.
+fn date_text(serial: f64, from_1904: bool) -> Option<String> {
+    if !(0.0..3_000_000.0).contains(&serial) {
+        return None;
+    }
+    let seconds = (serial.fract() * 86_400.0).round() as i64;
+    let (mut days, seconds) = (serial.trunc() as i64 + seconds / 86_400, seconds % 86_400);
+    let time = format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if days == 0 && !from_1904 {
+        return Some(time);
+    }
+    // Serials count from 1899-12-31 as day 1, and Excel keeps Lotus's
+    // made-up 1900-02-29 as day 60, so later serials are a day ahead.
+    if from_1904 {
+        days += 1462;
+    } else if days < 60 {
+        days += 1;
+    }
+    let (year, month, day) = civil_from_days(days - 25_569);
+    let date = format!("{:04}-{:02}-{:02}", year, month, day);
+    Some(match seconds {
+        0 => date,
+        _ => format!("{} {}", date, time),
+    })
+}
+
+/// The calendar date `days` after 1970-01-01, by Howard Hinnant's
+/// `civil_from_days`: years run from March so the leap day comes last.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Larger parts are refused, so a tiny archive cannot inflate without bound.
+const MAX_PART_BYTES: u64 = 1 << 30;
+
+/// The entries of a zip archive, read through its central directory.
+struct Zip<'a> {
+    bytes: &'a [u8],
+    entries: HashMap<String, ZipEntry>,
+}
+
+struct ZipEntry {
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    local_header: usize,
+}
+
+impl<'a> Zip<'a> {
+    fn parse(bytes: &'a [u8]) -> Result<Self, String> {
+        const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+        const DIRECTORY_ENTRY: u32 = 0x0201_4b50;
+        let not_a_zip = || "not an xlsx file (no zip directory)".to_string();
+        // The end record is the last 22 bytes plus a comment of up to 64 KiB.
+        let earliest = bytes.len().saturating_sub(22 + 0xffff);
+        let end = (earliest..=bytes.len().saturating_sub(22))
+            .rev()
+            .find(|&at| read_u32(bytes, at) == Some(END_OF_DIRECTORY))
+            .ok_or_else(not_a_zip)?;
+        let count = read_u16(bytes, end + 10).ok_or_else(not_a_zip)?;
+        let mut at = read_u32(bytes, end + 16).ok_or_else(not_a_zip)? as usize;
+        if count == 0xffff || at == 0xffff_ffff {
+            return Err("zip64 workbooks are not supported".to_string());
+        }
+        let mut entries = HashMap::new();
+        for _ in 0..count {
+            let field = |offset: usize| read_u32(bytes, at + offset).ok_or_else(not_a_zip);
+            let short = |offset: usize| read_u16(bytes, at + offset).ok_or_else(not_a_zip);
+            if field(0)? != DIRECTORY_ENTRY {
+                return Err(not_a_zip());
+            }
+            let name_len = short(28)? as usize;
+            let next = at + 46 + name_len + short(30)? as usize + short(32)? as usize;
+            let name = bytes.get(at + 46..at + 46 + name_len).ok_or_else(not_a_zip)?;
+            let entry = ZipEntry {
+                method: short(10)?,
+                crc: field(16)?,
+                compressed_size: u64::from(field(20)?),
+                size: u64::from(field(24)?),
+                local_header: field(42)? as usize,
+            };
+            entries.insert(String::from_utf8_lossy(name).into_owned(), entry);
+            at = next;
+        }
+        Ok(Self { bytes, entries })
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    /// An entry's contents, inflated and checked against its CRC.
+    fn read(&self, name: &str) -> Result<Vec<u8>, String> {
+        const LOCAL_HEADER: u32 = 0x0403_4b50;
+        let entry = self.entries.get(name).ok_or_else(|| format!("the workbook has no {}", name))?;
+        let damaged = || format!("{} is damaged", name);
+        let at = entry.local_header;
+        if read_u32(self.bytes, at) != Some(LOCAL_HEADER) {
+            return Err(damaged());
+        }
+        let name_len = read_u16(self.bytes, at + 26).ok_or_else(damaged)? as usize;
+        let extra_len = read_u16(self.bytes, at + 28).ok_or_else(damaged)? as usize;
+        let start = at + 30 + name_len + extra_len;
+        let end = start.checked_add(entry.compressed_size as usize).ok_or_else(damaged)?;
+        let stored = self.bytes.get(start..end).ok_or_else(damaged)?;
+        if entry.size > MAX_PART_BYTES {
+            return Err(format!("{} is over {} bytes", name, MAX_PART_BYTES));
+        }
+        let data = match entry.method {
+            0 => stored.to_vec(),
+            8 => {
+                let mut data = Vec::with_capacity(entry.size as usize);
+                DeflateDecoder::new(stored)
+                    .take(entry.size + 1)
+                    .read_to_end(&mut data)
+                    .map_err(|_| damaged())?;
+                data
+            }
+            method => return Err(format!("{} uses unsupported compression {}", name, method)),
+        };
+        let mut crc = flate2::Crc::new();
+        crc.update(&data);
+        if data.len() as u64 != entry.size || crc.sum() != entry.crc {
+            return Err(damaged());
+        }
+        Ok(data)
+    }
+
+    fn text(&self, name: &str) -> Result<String, String> {
+        String::from_utf8(self.read(name)?).map_err(|_| format!("{} is not UTF-8", name))
+    }
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(at..at.checked_add(2)?)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at.checked_add(4)?)?.try_into().ok()?))
+}
+
+/// A piece of XML. Names have any namespace prefix removed; text is still
+/// escaped.
+#[derive(Debug, PartialEq)]
+enum Event<'a> {
+    Open { name: &'a str, attrs: &'a str, empty: bool },
+    Close(&'a str),
+    Text(&'a str),
+}
+
+/// The tags and text of an XML document in order, skipping declarations,
+/// comments and doctypes.
+struct Events<'a> {
+    xml: &'a str,
+}
+
+impl<'a> Events<'a> {
+    fn new(xml: &'a str) -> Self {
+        Self { xml }
+    }
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        loop {
+            if self.xml.is_empty() {
+                return None;
+            }
+            let Some(rest) = self.xml.strip_prefix('<') else {
+                let end = self.xml.find('<').unwrap_or(self.xml.len());
+                let (text, rest) = self.xml.split_at(end);
+                self.xml = rest;
+                return Some(Event::Text(text));
+            };
+            let closer = match rest.as_bytes().first() {
+                Some(b'?') => "?>",
+                Some(b'!') if rest.starts_with("!--") => "-->",
+                Some(b'!') => ">",
+                _ => {
+                    let end = rest.find('>')?;
+                    let tag = &rest[..end];
+                    self.xml = &rest[end + 1..];
+                    if let Some(name) = tag.strip_prefix('/') {
+                        return Some(Event::Close(local_name(name.trim())));
+                    }
+                    let (tag, empty) = match tag.strip_suffix('/') {
+                        Some(tag) => (tag, true),
+                        None => (tag, false),
+                    };
+                    let (name, attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+                    return Some(Event::Open { name: local_name(name), attrs, empty });
+                }
+            };
+            let end = rest.find(closer)?;
+            self.xml = &rest[end + closer.len()..];
+        }
+    }
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit_once(':').map_or(name, |(_, local)| local)
+}
+
+/// The unescaped value of attribute `name` (without its prefix) in `attrs`.
+fn attr(attrs: &str, name: &str) -> Option<String> {
+    let mut rest = attrs;
+    while let Some(eq) = rest.find('=') {
+        let key = local_name(rest[..eq].trim());
+        let value = rest[eq + 1..].trim_start();
+        let quote = value.chars().next()?;
+        let end = value[1..].find(quote)? + 1;
+        if key == name {
+            return Some(unescape(&value[1..end]));
+        }
+        rest = &value[end + 1..];
+    }
+    None
+}
+
+/// Replaces the predefined and numeric character references.
+fn unescape(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(end) = rest.find(';') else { break };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_serials_read_as_iso_dates_and_times() {
+        assert_eq!(date_text(45_352.0, false).as_deref(), Some("2024-03-01"));
+        assert_eq!(date_text(45_352.395_833_333, false).as_deref(), Some("2024-03-01 09:30:00"));
+        assert_eq!(date_text(0.5, false).as_deref(), Some("12:00:00"));
+        assert_eq!(date_text(1.0, false).as_deref(), Some("1900-01-01"));
+        assert_eq!(date_text(61.0, false).as_deref(), Some("1900-03-01"));
+        assert_eq!(date_text(0.0, true).as_deref(), Some("1904-01-01"));
+    }
+
+    #[test]
+    fn date_formats_are_told_from_number_formats() {
+        assert!(is_date_format("yyyy-mm-dd"));
+        assert!(is_date_format("[$-409]h:mm AM/PM"));
+        assert!(!is_date_format("0.00"));
+        assert!(!is_date_format("[Red]#,##0"));
+        assert!(!is_date_format("\"days\" 0"));
+        assert!(is_builtin_date_format("14") && !is_builtin_date_format("2"));
+    }
+
+    #[test]
+    fn xml_events_skip_declarations_and_strip_prefixes() {
+        let xml = r#"<?xml version="1.0"?><!-- hi --><x:a k="1 &amp; 2"><b/>t&lt;</x:a>"#;
+        let events: Vec<Event> = Events::new(xml).collect();
+        let open = Event::Open { name: "a", attrs: r#"k="1 &amp; 2""#, empty: false };
+        let empty = Event::Open { name: "b", attrs: "", empty: true };
+        assert_eq!(events, [open, empty, Event::Text("t&lt;"), Event::Close("a")]);
+        assert_eq!(attr(r#"k="1 &amp; 2""#, "k").as_deref(), Some("1 & 2"));
+        assert_eq!(unescape("&#65;&#x42;&bogus;"), "AB&bogus;");
+    }
+}
@@ -0,0 +1,314 @@
+//! Reading and writing single cells and explicit ranges.
+
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+use sheets_ws_server::source::InlineSource;
+use std::time::Duration;
+
+fn range(row: u64, col: u32, rows: u32, cols: u32) -> Value {
+    json!({
+        "type": "range_request",
+        "startRow": row,
+        "startCol": col,
+        "rowCount": rows,
+        "colCount": cols,
+    })
+}
+
+fn update(row: u64, col: u32, value: &str) -> Value {
+    json!({"type": "cell_update", "row": row, "col": col, "value": value})
+}
+
+#[tokio::test]
+async fn coordinates_are_checked_against_the_table() {
+    let server = start(config(&[]), synthetic(10, 5)).await;
+    let mut client = server.connect().await;
+    let cell = client.request(json!({"type": "cell_request", "row": 9, "col": 4}), "cell_response");
+    assert_eq!(cell.await["value"], "R10C E");
+    for (row, col) in [(10, 4), (9, 5)] {
+        let err = client.request_error(json!({"type": "cell_request", "row": row, "col": col}));
+        assert_eq!(err.await["code"], "out_of_bounds");
+    }
+
+    client.request(range(9, 4, 1, 1), "range_response").await;
+    assert_eq!(client.request_error(range(10, 4, 1, 1)).await["code"], "out_of_bounds");
+    assert_eq!(client.request_error(range(0, 4, 1, 2)).await["code"], "out_of_bounds");
+
+    client.request(update(9, 4, "x"), "cells_updated").await;
+    assert_eq!(client.request_error(update(9, 5, "x")).await["code"], "out_of_bounds");
+    assert_eq!(client.request_error(update(10, 0, "x")).await["code"], "out_of_bounds");
+}
+
+#[tokio::test]
+async fn inline_data_serves_exactly_the_given_matrix() {
+    let matrix = [["a", "b", "c"], ["d", "", "f"]];
+    let config = config(&["--inline-data", &serde_json::to_string(&matrix).unwrap()]);
+    let source = InlineSource::from_json(config.inline_data.as_deref().unwrap()).unwrap();
+    let server = start(config, Box::new(source)).await;
+    let mut client = server.connect().await;
+    let metadata = client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+    assert_eq!((metadata["maxRows"].clone(), metadata["maxCols"].clone()), (json!(2), json!(3)));
+    for (row, cells) in matrix.iter().enumerate() {
+        for (col, value) in cells.iter().enumerate() {
+            let cell = json!({"type": "cell_request", "row": row, "col": col});
+            assert_eq!(client.request(cell, "cell_response").await["value"], *value);
+        }
+    }
+    let outside = json!({"type": "cell_request", "row": 2, "col": 0});
+    assert_eq!(client.request_error(outside).await["code"], "out_of_bounds");
+}
+
+#[tokio::test]
+async fn read_only_mode_refuses_edits_and_keeps_the_cell() {
+    let server = start(config(&["--read-only"]), synthetic(10, 5)).await;
+    let mut client = server.connect().await;
+    assert_eq!(client.request_error(update(1, 1, "x")).await["code"], "read_only");
+    let cell = client.request(json!({"type": "cell_request", "row": 1, "col": 1}), "cell_response");
+    assert_eq!(cell.await["value"], "R2C B");
+}
+
+#[tokio::test]
+async fn a_merge_crossing_the_viewport_edge_is_reported_whole() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    let merge = json!({
+        "type": "merge_cells",
+        "startRow": 8,
+        "startCol": 3,
+        "rowCount": 4,
+        "colCount": 3,
+    });
+    client.request(merge, "cells_merged").await;
+    let slice = client.request(slice_at(0, 0, 10, 4), "slice_response").await;
+    assert_eq!(slice["merges"], json!([[8, 3, 4, 3]]));
+
+    let reply = client.request(update(10, 5, "spanning"), "cells_updated").await;
+    assert_eq!(reply["cells"][0]["row"], 8);
+    assert_eq!(reply["cells"][0]["col"], 3);
+    let slice = client.request(slice_at(8, 3, 1, 1), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["spanning"]]));
+}
+
+#[tokio::test]
+async fn the_memory_budget_evicts_cached_cells_before_refusing_edits() {
+    let flags = ["--max-memory-mb", "1", "--cell-cache-size", "100000"];
+    let server = start(config(&flags), synthetic(10_000, 10)).await;
+    let mut client = server.connect().await;
+    for block in 0..10 {
+        client.request(slice_at(block * 100, 0, 100, 10), "slice_response").await;
+    }
+    let metadata = json!({"type": "metadata_request"});
+    let filled = client.request(metadata.clone(), "metadata_response").await["cellCache"].clone();
+    assert_eq!(filled["entries"], 10_000);
+    let filled_bytes = filled["bytes"].as_u64().unwrap();
+    assert!(filled_bytes > 600_000, "{}", filled);
+
+    client.request(update(0, 0, &"x".repeat(500_000)), "cells_updated").await;
+    let shrunk = client.request(metadata, "metadata_response").await["cellCache"].clone();
+    assert!(shrunk["bytes"].as_u64().unwrap() <= (1 << 20) - 500_064, "{}", shrunk);
+    assert!(shrunk["entries"].as_u64().unwrap() < 10_000, "{}", shrunk);
+
+    let error = client.request_error(update(0, 1, &"y".repeat(600_000))).await;
+    assert_eq!(error["code"], "memory_limit");
+}
+
+#[tokio::test]
+async fn editing_a_formula_input_changes_the_computed_column() {
+    let rows: &[&[&str]] = &[&["1", "2", ""], &["10", "x", ""]];
+    let server = start(config(&["--formula", "C=A+B"]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let slice = client.request(slice_at(0, 0, 2, 3), "slice_response").await;
+    assert_eq!(slice["cellsByRow"][0][2], "3");
+
+    let reply = client.request(update(0, 0, "40"), "cells_updated").await;
+    let cells = reply["cells"].as_array().unwrap();
+    assert!(cells.iter().any(|cell| cell["col"] == 2 && cell["value"] == "42"), "{}", reply);
+    let slice = client.request(slice_at(0, 0, 2, 3), "slice_response").await;
+    assert_eq!(slice["cellsByRow"][0][2], "42");
+    assert_eq!(client.request_error(update(0, 2, "7")).await["code"], "bad_request");
+}
+
+#[tokio::test]
+async fn undo_restores_an_edit_and_redo_puts_it_back() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    let cell = slice_at(4, 2, 1, 1);
+    client.request(update(4, 2, "edited"), "cells_updated").await;
+    assert_eq!(client.request(cell.clone(), "slice_response").await["cellsByRow"][0][0], "edited");
+
+    let undone = client.request(json!({"type": "undo"}), "undo_response").await;
+    assert_eq!(undone["noop"], false);
+    assert_eq!(client.request(cell.clone(), "slice_response").await["cellsByRow"][0][0], "R5C C");
+    let redone = client.request(json!({"type": "redo"}), "redo_response").await;
+    assert_eq!(redone["cells"][0]["value"], "edited");
+    assert_eq!(client.request(cell, "slice_response").await["cellsByRow"][0][0], "edited");
+
+    let nothing = client.request(json!({"type": "redo"}), "redo_response").await;
+    assert_eq!((nothing["noop"].clone(), nothing["cells"].clone()), (json!(true), json!([])));
+}
+
+#[tokio::test]
+async fn an_update_at_a_stale_version_is_rejected_with_the_current_cell() {
+    let server = start(config(&[]), synthetic(10, 5)).await;
+    let mut client = server.connect().await;
+    let first = client.request(update(2, 1, "first"), "cells_updated").await;
+    let seen = first["cells"][0]["version"].as_u64().unwrap();
+    let mut other = server.connect().await;
+    other.request(update(2, 1, "second"), "cells_updated").await;
+
+    let stale = with(update(2, 1, "mine"), json!({"expectedVersion": seen}));
+    let conflict = client.request_error(stale).await;
+    assert_eq!(conflict["code"], "conflict");
+    assert_eq!(conflict["current"]["value"], "second");
+    let current = conflict["current"]["version"].as_u64().unwrap();
+    assert!(current > seen);
+
+    let fresh = with(update(2, 1, "mine"), json!({"expectedVersion": current}));
+    let written = client.request(fresh, "cells_updated").await;
+    assert_eq!(written["cells"][0]["value"], "mine");
+    assert!(written["cells"][0]["version"].as_u64().unwrap() > current);
+    // Without an expected version the last write wins.
+    client.request(update(2, 1, "last"), "cells_updated").await;
+    let cell = client.request(slice_at(2, 1, 1, 1), "slice_response").await;
+    assert_eq!(cell["cellsByRow"][0][0], "last");
+}
+
+#[tokio::test]
+async fn selecting_a_row_or_column_returns_all_its_cells() {
+    let server = start(config(&[]), synthetic(20_000, 5)).await;
+    let mut client = server.connect().await;
+    let select = json!({"type": "select_row_request", "row": 7});
+    let row = client.request(select, "select_row_response").await;
+    assert_eq!(row["cells"], json!(["R8C A", "R8C B", "R8C C", "R8C D", "R8C E"]));
+    assert_eq!((&row["rowId"], &row["truncated"]), (&json!(7), &json!(false)));
+
+    let select = json!({"type": "select_col_request", "col": 2});
+    let col = client.request(select, "select_col_response").await;
+    let cells = col["cells"].as_array().unwrap();
+    assert_eq!((cells[0].clone(), cells[9_999].clone()), (json!("R1C C"), json!("R10000C C")));
+    assert_eq!((&col["totalRows"], &col["truncated"]), (&json!(20_000), &json!(true)));
+
+    let past = json!({"type": "select_row_request", "row": 20_000});
+    assert_eq!(client.request_error(past).await["code"], "out_of_bounds");
+    let none = json!({"type": "filter_request", "col": 0, "op": "equals", "value": "nobody"});
+    client.request(none, "view_response").await;
+    let first = json!({"type": "select_row_request", "row": 0});
+    assert_eq!(client.request_error(first).await["code"], "out_of_bounds");
+}
+
+#[tokio::test]
+async fn a_value_over_the_cell_length_limit_is_refused_and_stores_nothing() {
+    let server = start(config(&["--max-cell-length", "8"]), synthetic(10, 5)).await;
+    let mut client = server.connect().await;
+    let written = client.request(update(1, 1, "12345678"), "cells_updated").await;
+    assert_eq!(written["cells"][0]["value"], "12345678");
+
+    let err = client.request_error(update(2, 2, "123456789")).await;
+    assert_eq!(err["code"], "cell_too_long");
+    let cells = json!([["ok", "123456789"]]);
+    let batch = json!({"type": "cell_update_batch", "row": 3, "col": 0, "cells": cells});
+    assert_eq!(client.request_error(batch).await["code"], "cell_too_long");
+    let cells = client.request(slice_at(1, 0, 3, 3), "slice_response").await;
+    assert_eq!(cells["cellsByRow"][1][2], "R3C C");
+    assert_eq!(cells["cellsByRow"][2][0], "R4C A");
+}
+
+#[tokio::test]
+async fn originals_give_the_source_value_under_each_edit_until_it_is_reverted() {
+    let server = start(config(&[]), synthetic(10, 5)).await;
+    let mut client = server.connect().await;
+    client.request(update(2, 1, "mine"), "cells_updated").await;
+    let slice = with(slice_at(1, 0, 3, 3), json!({"originals": true}));
+    let edited = client.request(slice.clone(), "slice_response").await;
+    assert_eq!(edited["cellsByRow"][1][1], "mine");
+    assert_eq!(edited["originals"], json!([{"row": 1, "col": 1, "original": "R3C B"}]));
+
+    let revert = json!({"type": "revert_cell", "row": 2, "col": 1});
+    client.request(revert, "cells_updated").await;
+    let reverted = client.request(slice, "slice_response").await;
+    assert_eq!(reverted["cellsByRow"][1][1], "R3C B");
+    assert!(reverted.get("originals").is_none(), "{}", reverted);
+}
+
+#[tokio::test]
+async fn reverting_an_edit_brings_back_the_source_value_for_everyone() {
+    let server = start(config(&[]), synthetic(10, 5)).await;
+    let mut client = server.connect().await;
+    let mut other = server.connect().await;
+    client.request(update(4, 2, ""), "cells_updated").await;
+    other.recv_type("cells_updated").await;
+    let blank = client.request(slice_at(4, 2, 1, 1), "slice_response").await;
+    assert_eq!(blank["cellsByRow"], json!([[""]]));
+
+    let revert = json!({"type": "revert_cell", "row": 4, "col": 2});
+    let reverted = client.request(revert.clone(), "cells_updated").await;
+    assert_eq!(reverted["cells"][0]["value"], "R5C C");
+    assert_eq!(other.recv_type("cells_updated").await["cells"][0]["value"], "R5C C");
+    let slice = client.request(slice_at(4, 2, 1, 1), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["R5C C"]]));
+
+    let again = client.request(revert, "cells_updated").await;
+    assert_eq!(again["cells"], json!([]));
+    other.expect_silence(Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn a_batch_over_the_memory_budget_stores_none_of_its_cells() {
+    let server = start(config(&["--max-memory-mb", "1"]), synthetic(10, 5)).await;
+    let mut client = server.connect().await;
+    let half = "x".repeat(400_000);
+    let cells = json!([["first", half, half, half]]);
+    let batch = json!({"type": "cell_update_batch", "row": 1, "col": 0, "cells": cells});
+    assert_eq!(client.request_error(batch).await["code"], "memory_limit");
+    let slice = client.request(slice_at(1, 0, 1, 4), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["R2C A", "R2C B", "R2C C", "R2C D"]]));
+
+    let cells = json!([["first", half, half]]);
+    let batch = json!({"type": "cell_update_batch", "row": 1, "col": 0, "cells": cells});
+    client.request(batch, "cells_updated").await;
+    let slice = client.request(slice_at(1, 0, 1, 1), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["first"]]));
+}
+
+#[tokio::test]
+async fn a_batch_that_frees_the_room_it_needs_is_stored_whole() {
+    let server = start(config(&["--max-memory-mb", "1"]), synthetic(10, 5)).await;
+    let mut client = server.connect().await;
+    let big = "x".repeat(700_000);
+    client.request(update(1, 1, &big), "cells_updated").await;
+    // Growing the first cell before shrinking the second would pass the
+    // budget on the way, but only where the batch ends up counts.
+    let cells = json!([[big, "small"]]);
+    let batch = json!({"type": "cell_update_batch", "row": 1, "col": 0, "cells": cells});
+    client.request(batch, "cells_updated").await;
+    let slice = client.request(slice_at(1, 1, 1, 1), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["small"]]));
+
+    let merge = json!({
+        "type": "merge_cells",
+        "startRow": 3,
+        "startCol": 0,
+        "rowCount": 1,
+        "colCount": 3,
+    });
+    client.request(merge, "cells_merged").await;
+    let cells = json!([["a", "b", "c"]]);
+    let batch = json!({"type": "cell_update_batch", "row": 3, "col": 0, "cells": cells});
+    let written = client.request(batch, "cells_updated").await;
+    let cells = written["cells"].as_array().unwrap();
+    assert_eq!((cells.len(), &cells[0]["col"], &cells[0]["value"]), (1, &json!(0), &json!("c")));
+}
+
+#[tokio::test]
+async fn a_batch_past_the_slice_cell_cap_is_refused() {
+    let server = start(config(&["--max-cells-per-slice", "4"]), synthetic(10, 5)).await;
+    let mut client = server.connect().await;
+    let cells = json!([["a", "b"], ["c", "d"], ["e", "f"]]);
+    let batch = json!({"type": "cell_update_batch", "row": 0, "col": 0, "cells": cells});
+    let err = client.request_error(batch).await;
+    assert_eq!(err["code"], "bad_request");
+    let slice = client.request(slice_at(0, 0, 1, 1), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["R1C A"]]));
+}
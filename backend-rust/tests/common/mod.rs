@@ -0,0 +1,207 @@
+//! A server on a free local port, and a client that speaks its protocol.
+//!
+//! Each test starts its own server with the config and table it needs, so
+//! tests never share edits, views or connections.
+
+#![allow(dead_code)]
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use sheets_ws_server::{
+    config::Config,
+    router,
+    source::{DataSource, InlineSource, SyntheticSource},
+    AppState,
+};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// How long a test waits for any one frame before failing.
+pub const RECV_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Server {
+    pub state: Arc<AppState>,
+    pub addr: SocketAddr,
+}
+
+/// Parses `args` as if given on the command line.
+pub fn config(args: &[&str]) -> Config {
+    Config::from_args(args.iter().map(|arg| arg.to_string())).expect("test config")
+}
+
+/// The coordinate-labelled table, `R1C A` and so on.
+pub fn synthetic(rows: u64, cols: u32) -> Box<dyn DataSource> {
+    Box::new(SyntheticSource {
+        rows,
+        cols,
+        seed: None,
+        columns: Vec::new(),
+        sparsity: 0.0,
+    })
+}
+
+pub fn inline(rows: &[&[&str]]) -> Box<dyn DataSource> {
+    let json = serde_json::to_string(rows).unwrap();
+    Box::new(InlineSource::from_json(&json).expect("inline table"))
+}
+
+/// Wraps a table to count the cells read from it, taking `delay` over each
+/// like a slow backing store.
+pub struct Probe {
+    pub inner: Box<dyn DataSource>,
+    pub delay: Duration,
+    pub reads: Arc<AtomicU64>,
+}
+
+/// `inner` behind a [`Probe`], and the probe's read counter.
+pub fn probe(inner: Box<dyn DataSource>, delay: Duration) -> (Box<dyn DataSource>, Arc<AtomicU64>) {
+    let reads = Arc::new(AtomicU64::new(0));
+    let probe = Probe {
+        inner,
+        delay,
+        reads: reads.clone(),
+    };
+    (Box::new(probe), reads)
+}
+
+impl DataSource for Probe {
+    fn row_count(&self) -> u64 {
+        self.inner.row_count()
+    }
+
+    fn col_count(&self) -> u32 {
+        self.inner.col_count()
+    }
+
+    fn cell(&self, row: u64, col: u32) -> Option<String> {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if !self.delay.is_zero() {
+            std::thread::sleep(self.delay);
+        }
+        self.inner.cell(row, col)
+    }
+}
+
+pub async fn start(config: Config, source: Box<dyn DataSource>) -> Server {
+    start_with_state(Arc::new(AppState::new(config, source))).await
+}
+
+pub async fn start_with_state(state: Arc<AppState>) -> Server {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind test server");
+    let addr = listener.local_addr().unwrap();
+    let app = router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Server { state, addr }
+}
+
+impl Server {
+    pub async fn connect(&self) -> Client {
+        self.connect_to("/ws").await
+    }
+
+    pub async fn connect_to(&self, path: &str) -> Client {
+        let url = format!("ws://{}{}", self.addr, path);
+        let (ws, _) = tokio_tungstenite::connect_async(url).await.expect("connect to test server");
+        Client { ws }
+    }
+}
+
+pub struct Client {
+    pub ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Client {
+    pub async fn send(&mut self, msg: Value) {
+        self.send_text(&msg.to_string()).await;
+    }
+
+    pub async fn send_text(&mut self, text: &str) {
+        self.ws.send(Message::Text(text.to_string())).await.expect("send text frame");
+    }
+
+    pub async fn send_binary(&mut self, bytes: Vec<u8>) {
+        self.ws.send(Message::Binary(bytes)).await.expect("send binary frame");
+    }
+
+    /// The next frame of any kind, skipping pings and pongs.
+    pub async fn recv_frame(&mut self) -> Message {
+        loop {
+            let frame = tokio::time::timeout(RECV_TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for a frame")
+                .expect("connection ended")
+                .expect("read frame");
+            if !matches!(frame, Message::Ping(_) | Message::Pong(_)) {
+                return frame;
+            }
+        }
+    }
+
+    /// The next frame, which must be a JSON text frame.
+    pub async fn recv(&mut self) -> Value {
+        match self.recv_frame().await {
+            Message::Text(text) => serde_json::from_str(&text).expect("frame is JSON"),
+            other => panic!("expected a text frame, got {:?}", other),
+        }
+    }
+
+    /// The next message of type `kind`, skipping pushes of other types. An
+    /// unexpected `error` fails the test.
+    pub async fn recv_type(&mut self, kind: &str) -> Value {
+        loop {
+            let msg = self.recv().await;
+            if msg["type"] == kind {
+                return msg;
+            }
+            assert_ne!(msg["type"], "error", "expected {}, got {}", kind, msg);
+        }
+    }
+
+    /// Sends `msg` and waits for a reply of type `kind`.
+    pub async fn request(&mut self, msg: Value, kind: &str) -> Value {
+        self.send(msg).await;
+        self.recv_type(kind).await
+    }
+
+    /// Sends `msg` and waits for an `error`.
+    pub async fn request_error(&mut self, msg: Value) -> Value {
+        self.send(msg).await;
+        self.recv_type("error").await
+    }
+
+    /// Nothing arrives within `wait`.
+    pub async fn expect_silence(&mut self, wait: Duration) {
+        if let Ok(frame) = tokio::time::timeout(wait, self.ws.next()).await {
+            panic!("expected no frame, got {:?}", frame);
+        }
+    }
+}
+
+/// A `slice_request` whose viewport is `rows` by `cols` cells from cell
+/// (`row`, `col`), at 20px rows and 100px columns, with no buffer.
+pub fn slice_at(row: u64, col: u32, rows: u32, cols: u32) -> Value {
+    serde_json::json!({
+        "type": "slice_request",
+        "screenWidth": cols * 100,
+        "screenHeight": rows * 20,
+        "horizontalBuffer": 0,
+        "verticalBuffer": 0,
+        "defaultColumnWidth": 100,
+        "defaultRowHeight": 20,
+        "scrollLeft": col as u64 * 100,
+        "scrollTop": row * 20,
+    })
+}
+
+/// `slice` with `fields` merged over it.
+pub fn with(mut slice: Value, fields: Value) -> Value {
+    for (key, value) in fields.as_object().expect("fields object") {
+        slice[key] = value.clone();
+    }
+    slice
+}
@@ -0,0 +1,69 @@
+//! CSV files opened from disk the way `--csv` opens them, and served.
+
+mod common;
+
+use common::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sheets_ws_server::config::CsvOptions;
+use sheets_ws_server::source::{CsvSource, DataSource};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// `rows` data rows under an `id,name` header, each about 40 bytes.
+fn table(rows: u64) -> String {
+    let mut text = String::from("id,name\n");
+    for row in 0..rows {
+        text.push_str(&format!("{},\"row {} of the gzipped table\"\n", row, row));
+    }
+    text
+}
+
+fn gzip(text: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(text).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn write(name: &str, bytes: &[u8]) -> String {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, bytes).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn a_gzipped_csv_reads_cells_far_past_its_first_block() {
+    // Far more than one deflate block or read buffer of decompressed text.
+    let path = write("table.csv.gz", &gzip(table(50_000).as_bytes()));
+    let source = CsvSource::open(&path, &CsvOptions::default()).unwrap();
+    assert_eq!(source.headers, ["id", "name"]);
+    assert_eq!(source.row_count(), 50_000);
+    assert_eq!(source.cell(49_999, 1).as_deref(), Some("row 49999 of the gzipped table"));
+    assert_eq!(source.cell(31_337, 0).as_deref(), Some("31337"));
+}
+
+#[test]
+fn gzip_is_recognized_by_its_magic_bytes_and_across_members() {
+    let text = table(1_000);
+    let (head, tail) = text.split_at(text.len() / 2);
+    let mut members = gzip(head.as_bytes());
+    members.extend(gzip(tail.as_bytes()));
+    let path = write("members.csv", &members);
+    let source = CsvSource::open(&path, &CsvOptions::default()).unwrap();
+    assert_eq!(source.row_count(), 1_000);
+    assert_eq!(source.cell(999, 0).as_deref(), Some("999"));
+}
+
+#[tokio::test]
+async fn ragged_rows_are_counted_in_metadata_and_read_as_whole_rows() {
+    let path = write("ragged.csv", b"a,b,c\n1,2\n3,4,5,6\n7,8,9\n");
+    let source = CsvSource::open(&path, &CsvOptions::default()).unwrap();
+    let server = start(config(&["--missing-value", "?"]), Box::new(source)).await;
+    let mut client = server.connect().await;
+    let metadata = serde_json::json!({"type": "metadata_request"});
+    let metadata = client.request(metadata, "metadata_response").await;
+    assert_eq!((&metadata["raggedRows"], &metadata["maxCols"]), (&2.into(), &4.into()));
+    let slice = client.request(slice_at(0, 0, 3, 4), "slice_response").await;
+    assert_eq!(slice["cellsByRow"][0], serde_json::json!(["1", "2", "?", "?"]));
+    assert_eq!(slice["cellsByRow"][1], serde_json::json!(["3", "4", "5", "6"]));
+}
@@ -0,0 +1,86 @@
+//! `export_request`: ranges streamed back as text in `export_chunk`s.
+
+mod common;
+
+use common::*;
+use serde_json::{json, Value};
+
+fn export(rows: u64, cols: u32, format: &str) -> Value {
+    json!({
+        "type": "export_request",
+        "startRow": 0,
+        "startCol": 0,
+        "rowCount": rows,
+        "colCount": cols,
+        "format": format,
+    })
+}
+
+/// Every chunk's data, joined, once the last has arrived.
+async fn exported(client: &mut Client, request: Value) -> String {
+    client.send(request).await;
+    let mut text = String::new();
+    loop {
+        let chunk = client.recv_type("export_chunk").await;
+        text.push_str(chunk["data"].as_str().unwrap());
+        if chunk["done"] == true {
+            return text;
+        }
+    }
+}
+
+#[tokio::test]
+async fn csv_quotes_embedded_commas_and_tsv_separates_with_tabs() {
+    let rows: &[&[&str]] = &[&["Smith, Jo", "say \"hi\""], &["plain", "two\nlines"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let csv = exported(&mut client, export(2, 2, "csv")).await;
+    assert_eq!(csv, "\"Smith, Jo\",\"say \"\"hi\"\"\"\r\nplain,\"two\nlines\"\r\n");
+    let tsv = exported(&mut client, export(2, 2, "tsv")).await;
+    assert_eq!(tsv, "Smith, Jo\t\"say \"\"hi\"\"\"\r\nplain\t\"two\nlines\"\r\n");
+    let json = exported(&mut client, export(2, 2, "json")).await;
+    let parsed: Vec<Vec<String>> = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed, [["Smith, Jo", "say \"hi\""], ["plain", "two\nlines"]]);
+}
+
+#[tokio::test]
+async fn the_view_exports_to_a_file_with_a_header_row() {
+    let dir = std::path::PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("export-view");
+    std::fs::create_dir_all(&dir).unwrap();
+    let flags = ["--admin-token", "t", "--export-dir", dir.to_str().unwrap()];
+    let server = start(config(&flags), synthetic(20, 3)).await;
+    let mut client = server.connect().await;
+    let filter = json!({"type": "filter_request", "col": 0, "op": "contains", "value": "1C"});
+    client.request(filter, "view_response").await;
+
+    let request = json!({"type": "export_to_file_request", "token": "t", "fileName": "view.csv"});
+    let done = client.request(request, "export_file_done").await;
+    assert_eq!((done["rowCount"].clone(), done["canceled"].clone()), (json!(2), json!(false)));
+    let text = std::fs::read_to_string(done["path"].as_str().unwrap()).unwrap();
+    assert_eq!(text, "A,B,C\r\nR1C A,R1C B,R1C C\r\nR11C A,R11C B,R11C C\r\n");
+}
+
+#[tokio::test]
+async fn ranges_and_exports_past_the_cell_cap_are_refused_with_the_counts() {
+    let server = start(config(&["--max-export-cells", "10000"]), synthetic(3000, 10)).await;
+    let mut client = server.connect().await;
+    let range = |rows: u32| {
+        let counts = json!({"rowCount": rows, "colCount": 10});
+        with(json!({"type": "range_request", "startRow": 0, "startCol": 0}), counts)
+    };
+    let at_cap = client.request(range(1000), "range_response").await;
+    assert_eq!(at_cap["cellsByRow"].as_array().unwrap().len(), 1000);
+    // Counted before the 1000-row response cap would have cut it to the limit.
+    let over = client.request_error(range(1001)).await;
+    assert_eq!(over["code"], "range_too_large");
+    assert_eq!((&over["requested"], &over["allowed"]), (&json!(10_010), &json!(10_000)));
+
+    let csv = exported(&mut client, export(1000, 10, "csv")).await;
+    assert_eq!(csv.lines().count(), 1000);
+    let over = client.request_error(export(1001, 10, "csv")).await;
+    assert_eq!(over["code"], "range_too_large");
+    assert_eq!((&over["requested"], &over["allowed"]), (&json!(10_010), &json!(10_000)));
+    // Rows past the end of the table are not counted.
+    let last_rows = with(export(5000, 10, "csv"), json!({"startRow": 2999}));
+    assert_eq!(exported(&mut client, last_rows).await.lines().count(), 1);
+}
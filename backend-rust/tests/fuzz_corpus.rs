@@ -0,0 +1,40 @@
+//! Replays the `parse_frame` fuzz corpus through `handle_frame`, so the
+//! seeds are checked on every test run and not only under `cargo fuzz`.
+
+use sheets_ws_server::{
+    binary, config::Config, handle_frame, outbound, session::SessionState, source::SyntheticSource,
+    AppState,
+};
+use std::sync::Arc;
+
+#[tokio::test]
+async fn every_seed_gets_a_well_formed_reply() {
+    let source = SyntheticSource {
+        rows: 1_000,
+        cols: 50,
+        seed: None,
+        columns: Vec::new(),
+        sparsity: 0.0,
+    };
+    let state = Arc::new(AppState::new(Config::default(), Box::new(source)));
+    let (outbound, _writer) = outbound::spawn(futures_util::sink::drain());
+    let mut session = SessionState::new(0, outbound);
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/parse_frame");
+    let mut seeds = 0;
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let data = std::fs::read(&path).unwrap();
+        seeds += 1;
+        let Some(reply) = handle_frame(&state, &mut session, &data) else {
+            continue;
+        };
+        let reply = reply.into_data();
+        if reply.starts_with(binary::MAGIC) {
+            continue;
+        }
+        let reply: serde_json::Value = serde_json::from_slice(&reply)
+            .unwrap_or_else(|_| panic!("{} got a reply that is not JSON", path.display()));
+        assert!(reply["type"].is_string(), "{} got {}", path.display(), reply);
+    }
+    assert!(seeds > 0, "no seeds in {}", dir);
+}
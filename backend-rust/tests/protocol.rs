@@ -0,0 +1,204 @@
+//! Framing, parse errors, metadata and capability negotiation.
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use sheets_ws_server::compress;
+use std::io::Read;
+use tokio_tungstenite::tungstenite::Message;
+
+#[tokio::test]
+async fn malformed_json_and_unknown_shapes_get_distinct_codes() {
+    let server = start(config(&[]), synthetic(10, 10)).await;
+    let mut client = server.connect().await;
+    client.send_text("{\"type\": \"metadata_request\"").await;
+    assert_eq!(client.recv_type("error").await["code"], "invalid_json");
+    let err = client.request_error(json!({"kind": "metadata_request"})).await;
+    assert_eq!(err["code"], "invalid_message");
+    let err = client.request_error(json!({"type": "slice_request", "scrollTop": "x"})).await;
+    assert_eq!(err["code"], "bad_request");
+    let err = client.request_error(json!({"type": "no_such_message"})).await;
+    assert_eq!(err["code"], "unknown_type");
+}
+
+#[tokio::test]
+async fn an_unknown_type_is_answered_and_the_same_socket_keeps_working() {
+    let server = start(config(&[]), synthetic(10, 10)).await;
+    let mut client = server.connect().await;
+    let err = client.request_error(json!({"type": "no_such_message"})).await;
+    assert_eq!(err["code"], "unknown_type");
+    assert_eq!(err["message"], "unknown message type");
+    let metadata = client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+    assert_eq!((&metadata["maxRows"], &metadata["maxCols"]), (&json!(10), &json!(10)));
+}
+
+#[tokio::test]
+async fn empty_frames_get_empty_message_and_the_socket_stays_open() {
+    let server = start(config(&[]), synthetic(10, 10)).await;
+    let mut client = server.connect().await;
+    for frame in ["", "   ", "\n\t "] {
+        client.send_text(frame).await;
+        assert_eq!(client.recv_type("error").await["code"], "empty_message", "{:?}", frame);
+    }
+    client.send_binary(Vec::new()).await;
+    assert_eq!(client.recv_type("error").await["code"], "empty_message");
+    client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+}
+
+#[tokio::test]
+async fn binary_frames_that_are_not_utf8_are_rejected() {
+    let server = start(config(&[]), synthetic(10, 10)).await;
+    let mut client = server.connect().await;
+    client.send_binary(vec![0xff, 0xfe, 0xfd]).await;
+    assert_eq!(client.recv_type("error").await["code"], "invalid_utf8");
+    client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+}
+
+#[tokio::test]
+async fn binary_slices_need_the_binary_capability() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    let binary = with(slice_at(0, 0, 3, 3), json!({"encoding": "binary"}));
+    client.send(binary.clone()).await;
+    assert_eq!(client.recv_type("slice_response").await["rowCount"], 3);
+
+    let metadata = json!({"type": "metadata_request", "capabilities": ["binary"]});
+    let reply = client.request(metadata, "metadata_response").await;
+    assert_eq!(reply["capabilities"], json!(["binary"]));
+    client.send(binary).await;
+    assert!(matches!(client.recv_frame().await, Message::Binary(_)));
+}
+
+#[tokio::test]
+async fn a_refused_metadata_request_keeps_the_negotiated_capabilities() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    let metadata = json!({"type": "metadata_request", "capabilities": ["binary"]});
+    client.request(metadata, "metadata_response").await;
+
+    let bad = json!({"type": "metadata_request", "capabilities": [], "charWidth": -1});
+    assert_eq!(client.request_error(bad).await["code"], "bad_request");
+    client.send(with(slice_at(0, 0, 3, 3), json!({"encoding": "binary"}))).await;
+    assert!(matches!(client.recv_frame().await, Message::Binary(_)));
+}
+
+#[tokio::test]
+async fn suggested_widths_grow_with_the_sampled_cells() {
+    let long = "a considerably longer cell value";
+    let rows: &[&[&str]] = &[&["id", "description"], &["1", "short"], &["2", long]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let plain = client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+    assert!(plain.get("suggestedColWidths").is_none(), "{}", plain);
+
+    let sized = json!({"type": "metadata_request", "charWidth": 8.0});
+    let metadata = client.request(sized, "metadata_response").await;
+    let widths = metadata["suggestedColWidths"].as_array().unwrap();
+    assert_eq!(widths.len(), 2);
+    let (narrow, wide) = (widths[0].as_u64().unwrap(), widths[1].as_u64().unwrap());
+    assert!(narrow < wide, "{:?}", widths);
+    assert_eq!(wide, long.len() as u64 * 8 + 16);
+}
+
+#[tokio::test]
+async fn an_echo_returns_the_payload_untouched_with_the_server_time() {
+    let server = start(config(&[]), synthetic(10, 10)).await;
+    let mut client = server.connect().await;
+    let payload = json!({"sentAt": 1712345678901.25, "tags": ["a", null, {"n": -1}]});
+    let before = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    let echo = json!({"type": "echo_request", "payload": payload});
+    let reply = client.request(echo, "echo_response").await;
+    assert_eq!(reply["payload"], payload);
+    let server_time = reply["serverTimeMs"].as_u64().expect("serverTimeMs");
+    assert!(server_time >= before.as_millis() as u64, "{}", reply);
+}
+
+#[tokio::test]
+async fn a_welcome_arrives_before_the_client_says_anything() {
+    let server = start(config(&["--welcome", "--read-only"]), synthetic(500, 20)).await;
+    let mut client = server.connect().await;
+    let welcome = client.recv().await;
+    assert_eq!(welcome["type"], "welcome");
+    assert_eq!((&welcome["maxRows"], &welcome["maxCols"]), (&json!(500), &json!(20)));
+    assert_eq!(welcome["serverVersion"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(welcome["readOnly"], true);
+    assert!(welcome["capabilities"].as_array().unwrap().contains(&json!("binary")));
+    assert!(welcome["limits"]["maxCellsPerSlice"].is_u64());
+
+    let quiet = start(config(&[]), synthetic(500, 20)).await;
+    quiet.connect().await.expect_silence(std::time::Duration::from_millis(100)).await;
+}
+
+/// The codec byte and the message inside a `gzip`-capability frame.
+fn unwrap_compressed(frame: Message) -> (u8, serde_json::Value) {
+    let Message::Binary(bytes) = frame else {
+        panic!("expected a binary frame, got {:?}", frame);
+    };
+    assert_eq!(&bytes[..4], compress::MAGIC);
+    let mut text = String::new();
+    match bytes[4] {
+        compress::NONE => text = String::from_utf8(bytes[5..].to_vec()).unwrap(),
+        compress::GZIP => {
+            flate2::read::GzDecoder::new(&bytes[5..]).read_to_string(&mut text).unwrap();
+        }
+        codec => panic!("unknown codec {}", codec),
+    }
+    (bytes[4], serde_json::from_str(&text).unwrap())
+}
+
+#[tokio::test]
+async fn only_replies_past_the_threshold_are_gzipped() {
+    let server = start(config(&["--compress-min-bytes", "2000"]), synthetic(1000, 50)).await;
+    let mut client = server.connect().await;
+    let negotiate = json!({"type": "metadata_request", "capabilities": ["gzip"]});
+    client.send(negotiate).await;
+    let (_, metadata) = unwrap_compressed(client.recv_frame().await);
+    assert_eq!(metadata["capabilities"], json!(["gzip"]));
+
+    client.send(slice_at(0, 0, 1, 1)).await;
+    let (codec, small) = unwrap_compressed(client.recv_frame().await);
+    assert_eq!((codec, &small["cellsByRow"]), (compress::NONE, &json!([["R1C A"]])));
+    client.send(slice_at(0, 0, 30, 20)).await;
+    let (codec, large) = unwrap_compressed(client.recv_frame().await);
+    assert_eq!((codec, &large["cellsByRow"][29][19]), (compress::GZIP, &json!("R30C T")));
+}
+
+#[tokio::test]
+async fn the_reported_limits_are_the_configured_ones() {
+    let args = [
+        "--max-cells-per-slice", "1234", "--max-buffer", "7", "--max-filters", "3",
+        "--max-sort-keys", "2", "--scan-budget", "999", "--source-timeout-ms", "250",
+        "--compress-min-bytes", "4096", "--max-export-cells", "50000",
+    ];
+    let server = start(config(&args), synthetic(10, 10)).await;
+    let mut client = server.connect().await;
+    let limits = client.request(json!({"type": "limits_request"}), "limits_response").await;
+    let expected = json!({
+        "maxCellsPerSlice": 1234, "maxBuffer": 7, "maxFilters": 3, "maxSortKeys": 2,
+        "scanBudget": 999, "sourceTimeoutMs": 250, "compressMinBytes": 4096,
+        "maxExportCells": 50000, "maxRowsPerResponse": 1000,
+    });
+    for (key, value) in expected.as_object().unwrap() {
+        assert_eq!(&limits[key], value, "{}", key);
+    }
+    // Unset optional limits are left out rather than sent as null.
+    assert!(limits.get("maxConnections").is_none(), "{}", limits);
+}
+
+#[tokio::test]
+async fn an_overloaded_server_closes_with_a_reason_and_a_reconnect_delay() {
+    let overloaded = config(&["--max-connections", "1", "--reconnect-delay-ms", "200"]);
+    let server = start(overloaded, synthetic(10, 10)).await;
+    let mut first = server.connect().await;
+    let mut second = server.connect().await;
+    let Message::Close(Some(close)) = second.recv_frame().await else {
+        panic!("expected a close frame");
+    };
+    assert_eq!(u16::from(close.code), 1013);
+    let payload: serde_json::Value = serde_json::from_str(&close.reason).unwrap();
+    assert_eq!(payload["reason"], "overloaded");
+    // Overloaded servers ask for five times the base delay.
+    assert_eq!(payload["retryAfterMs"], 1000);
+    first.request(json!({"type": "metadata_request"}), "metadata_response").await;
+}
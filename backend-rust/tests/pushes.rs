@@ -0,0 +1,159 @@
+//! Pushes to other connections: edits, range subscriptions and resets.
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use sheets_ws_server::source::GrowingSource;
+use sheets_ws_server::watch_row_count;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn update(row: u64, col: u32, value: &str) -> serde_json::Value {
+    json!({"type": "cell_update", "row": row, "col": col, "value": value})
+}
+
+#[tokio::test]
+async fn a_range_subscription_pushes_only_edits_inside_it() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut watcher = server.connect().await;
+    let mut editor = server.connect().await;
+    let subscribe = json!({
+        "type": "subscribe_range",
+        "subscriptionId": "top",
+        "startRow": 0,
+        "startCol": 0,
+        "rowCount": 10,
+        "colCount": 5,
+    });
+    watcher.request(subscribe, "range_subscribed").await;
+
+    editor.request(update(3, 2, "inside"), "cells_updated").await;
+    let push = watcher.recv_type("cells_updated").await;
+    assert_eq!(push["cells"][0]["value"], "inside");
+
+    editor.request(update(50, 7, "outside"), "cells_updated").await;
+    watcher.expect_silence(Duration::from_millis(200)).await;
+
+    let unsubscribe = json!({"type": "unsubscribe_range", "subscriptionId": "top"});
+    let reply = watcher.request(unsubscribe, "range_unsubscribed").await;
+    assert_eq!(reply["found"], true);
+    editor.request(update(50, 7, "everywhere"), "cells_updated").await;
+    let push = watcher.recv_type("cells_updated").await;
+    assert_eq!(push["cells"][0]["value"], "everywhere");
+}
+
+#[tokio::test]
+async fn an_admin_reset_clears_edits_and_tells_everyone_else() {
+    let server = start(config(&["--admin-token", "secret"]), synthetic(100, 10)).await;
+    let mut admin = server.connect().await;
+    let mut other = server.connect().await;
+    admin.request(update(1, 1, "edited"), "cells_updated").await;
+    other.recv_type("cells_updated").await;
+
+    let wrong = json!({"type": "admin_reset", "token": "guess"});
+    assert_eq!(admin.request_error(wrong).await["code"], "forbidden");
+    let cell = json!({"type": "cell_request", "row": 1, "col": 1});
+    assert_eq!(admin.request(cell.clone(), "cell_response").await["value"], "edited");
+
+    admin.request(json!({"type": "admin_reset", "token": "secret"}), "reset").await;
+    other.recv_type("reset").await;
+    assert_eq!(other.request(cell, "cell_response").await["value"], "R2C B");
+}
+
+#[tokio::test]
+async fn the_stress_route_pushes_at_about_the_configured_rate() {
+    let flags = ["--stress-rate", "50", "--stress-rows", "10", "--stress-cols", "3"];
+    let server = start(config(&flags), synthetic(100, 10)).await;
+    let mut client = server.connect_to("/ws-stress").await;
+    let window = Duration::from_secs(1);
+    let deadline = tokio::time::Instant::now() + window;
+    let mut pushes = 0;
+    while let Ok(msg) = tokio::time::timeout_at(deadline, client.recv()).await {
+        if msg["type"] != "cells_updated" {
+            continue;
+        }
+        let cell = &msg["cells"][0];
+        assert!(cell["row"].as_u64().unwrap() < 10 && cell["col"].as_u64().unwrap() < 3, "{}", msg);
+        pushes += 1;
+    }
+    assert!((35..=65).contains(&pushes), "{} pushes in {:?}", pushes, window);
+}
+
+fn rows(from: u64, to: u64) -> Vec<Vec<String>> {
+    (from..to).map(|row| vec![row.to_string(), format!("row {}", row)]).collect()
+}
+
+#[tokio::test]
+async fn a_loading_table_is_approximate_until_the_final_count_is_pushed() {
+    let source = Arc::new(GrowingSource::default());
+    source.append(rows(0, 100));
+    let server = start(config(&[]), Box::new(source.clone())).await;
+    let mut client = server.connect().await;
+    let metadata = client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+    assert_eq!((&metadata["maxRows"], &metadata["approximate"]), (&json!(100), &json!(true)));
+
+    watch_row_count(server.state.clone());
+    source.append(rows(100, 250));
+    source.finish();
+    let last = loop {
+        let update = client.recv_type("metadata_update").await;
+        if update["approximate"] == false {
+            break update;
+        }
+        assert!(update["maxRows"].as_u64().unwrap() <= 250, "{}", update);
+    };
+    assert_eq!(last["maxRows"], 250);
+    let metadata = client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+    assert_eq!(metadata["maxRows"], 250);
+    assert!(metadata.get("approximate").is_none(), "{}", metadata);
+}
+
+#[tokio::test]
+async fn a_single_edit_pushes_exactly_its_own_coordinate() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut watcher = server.connect().await;
+    let mut editor = server.connect().await;
+    let subscribe = json!({
+        "type": "subscribe_range",
+        "subscriptionId": "all",
+        "startRow": 0,
+        "startCol": 0,
+        "rowCount": 100,
+        "colCount": 10,
+    });
+    watcher.request(subscribe, "range_subscribed").await;
+    editor.request(update(42, 7, "changed"), "cells_updated").await;
+    let push = watcher.recv_type("cells_updated").await;
+    let cells = push["cells"].as_array().unwrap();
+    assert_eq!(cells.len(), 1, "{}", push);
+    assert_eq!((&cells[0]["row"], &cells[0]["col"]), (&json!(42), &json!(7)));
+    assert_eq!(cells[0]["value"], "changed");
+    watcher.expect_silence(Duration::from_millis(100)).await;
+}
+
+#[tokio::test]
+async fn appended_rows_are_pushed_to_a_tail_at_the_bottom_and_not_one_scrolled_up() {
+    let source = Arc::new(GrowingSource::default());
+    source.append(rows(0, 100));
+    let server = start(config(&[]), Box::new(source.clone())).await;
+    let mut bottom = server.connect().await;
+    let mut scrolled = server.connect().await;
+    for (client, row) in [(&mut bottom, 90), (&mut scrolled, 40)] {
+        let tail = json!({"type": "tail_request", "enabled": true});
+        assert_eq!(client.request(tail, "tail_response").await["enabled"], true);
+        client.request(slice_at(row, 0, 10, 2), "slice_response").await;
+    }
+
+    watch_row_count(server.state.clone());
+    source.append(rows(100, 105));
+    let push = bottom.recv_type("tail_rows").await;
+    assert_eq!((&push["startRow"], &push["startCol"]), (&json!(100), &json!(0)));
+    assert_eq!(push["cellsByRow"], json!(rows(100, 105)));
+    assert_eq!((&push["maxRows"], &push["height"]), (&json!(105), &json!(2100)));
+
+    let quiet = tokio::time::Instant::now() + Duration::from_millis(300);
+    while let Ok(msg) = tokio::time::timeout_at(quiet, scrolled.recv()).await {
+        assert_ne!(msg["type"], "tail_rows", "{}", msg);
+    }
+}
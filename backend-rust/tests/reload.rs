@@ -0,0 +1,34 @@
+//! Reloading `--config-file` on `SIGHUP`. The signal goes to the whole test
+//! process, so this file keeps to one test.
+
+#![cfg(unix)]
+
+mod common;
+
+use common::*;
+use sheets_ws_server::reload_on_hangup;
+use std::time::Duration;
+
+#[tokio::test]
+async fn a_hangup_picks_up_a_cap_changed_in_the_config_file() {
+    let path = format!("{}/reload.conf", env!("CARGO_TARGET_TMPDIR"));
+    std::fs::write(&path, "--max-cells-per-slice 1000\n").unwrap();
+    let server = start(config(&["--config-file", &path]), synthetic(1000, 100)).await;
+    let mut client = server.connect().await;
+    let wide = client.request(slice_at(0, 0, 50, 100), "slice_response").await;
+    assert_eq!((&wide["rowCount"], &wide["clamped"]), (&10.into(), &true.into()));
+
+    reload_on_hangup(server.state.clone());
+    std::fs::write(&path, "# raised\n--max-cells-per-slice 5000\n").unwrap();
+    let pid = std::process::id().to_string();
+    let status = std::process::Command::new("kill").args(["-HUP", &pid]).status().unwrap();
+    assert!(status.success());
+    for _ in 0..100 {
+        if server.state.settings().max_cells_per_slice == 5000 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let wide = client.request(slice_at(0, 0, 50, 100), "slice_response").await;
+    assert_eq!((&wide["rowCount"], &wide["clamped"]), (&50.into(), &false.into()));
+}
@@ -0,0 +1,75 @@
+//! The server on runtimes built, and served, the way `main` does it.
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use sheets_ws_server::{bind_listener, router, AppState};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+#[test]
+fn a_single_worker_thread_still_serves_requests() {
+    let config = config(&["--worker-threads", "1"]);
+    let threads = config.resolve_worker_threads().unwrap();
+    assert_eq!(threads, 1);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let server = start(config, synthetic(1_000, 10)).await;
+        let mut first = server.connect().await;
+        let mut second = server.connect().await;
+        // A scan on the blocking pool while the one worker answers the other client.
+        first.send(json!({"type": "column_stats_request", "col": 0})).await;
+        let slice = second.request(slice_at(0, 0, 3, 3), "slice_response").await;
+        assert_eq!(slice["cellsByRow"][0][0], "R1C A");
+        first.recv_type("column_stats_response").await;
+    });
+}
+
+#[test]
+fn zero_worker_threads_is_refused() {
+    let config = sheets_ws_server::config::Config::from_args(["--worker-threads=0".to_string()]);
+    assert!(config.is_err_and(|err| err.contains("at least 1")));
+}
+
+#[tokio::test]
+async fn the_socket_needs_the_connect_info_service_and_opens_with_it() {
+    let state = Arc::new(AppState::new(config(&[]), synthetic(10, 10)));
+    let server = start_with_state(state.clone()).await;
+    let mut request = format!("ws://{}/ws", server.addr).into_client_request().unwrap();
+    request.headers_mut().insert("user-agent", "runtime-test/1.0".parse().unwrap());
+    let (ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    let mut client = Client { ws };
+    client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+
+    // Served without connect info, the handler's extractor refuses the upgrade.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = router(state);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let refused = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await;
+    assert!(refused.is_err());
+}
+
+#[tokio::test]
+async fn a_burst_of_clients_queues_on_a_custom_backlog() {
+    // Room for the whole burst, so none waits on a retried SYN.
+    let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 64).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = Arc::new(AppState::new(config(&[]), synthetic(10, 10)));
+    let app = router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    let server = Server { state, addr };
+
+    let clients = (0..32).map(|_| server.connect());
+    let mut clients = futures_util::future::join_all(clients).await;
+    for client in &mut clients {
+        let slice = client.request(slice_at(0, 0, 1, 1), "slice_response").await;
+        assert_eq!(slice["cellsByRow"][0][0], "R1C A");
+    }
+}
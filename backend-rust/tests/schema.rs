@@ -0,0 +1,60 @@
+//! `--dump-schema`, run as the client's type generation runs it.
+
+use serde_json::Value;
+use sheets_ws_server::protocol::CLIENT_MESSAGE_TYPES;
+use std::process::Command;
+
+/// The `oneOf` branch of `message` whose `type` is `kind`.
+fn variant<'a>(schema: &'a Value, message: &str, kind: &str) -> &'a Value {
+    let branches = schema["definitions"][message]["oneOf"].as_array().unwrap();
+    let tag = |branch: &Value| branch["properties"]["type"]["enum"][0] == kind;
+    branches.iter().find(|branch| tag(branch)).unwrap_or_else(|| panic!("no {}", kind))
+}
+
+fn dumped_schema() -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_sheets_ws_server"))
+        .arg("--dump-schema")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).expect("schema is JSON")
+}
+
+#[test]
+fn the_slice_request_schema_names_every_field_in_camel_case() {
+    let schema = dumped_schema();
+    let slice = variant(&schema, "ClientMessage", "slice_request");
+    let properties = slice["properties"].as_object().expect("SliceRequest properties");
+    let required = [
+        "screenWidth", "screenHeight", "horizontalBuffer", "verticalBuffer",
+        "defaultColumnWidth", "defaultRowHeight", "scrollLeft", "scrollTop",
+    ];
+    for field in required {
+        assert!(properties.contains_key(field), "{} missing", field);
+        assert!(slice["required"].as_array().unwrap().contains(&field.into()), "{}", field);
+    }
+    for field in ["streamViewport", "orientation", "dirty", "coalesceKey", "viewGeneration"] {
+        assert!(properties.contains_key(field), "{} missing", field);
+    }
+    for field in properties.keys() {
+        assert!(!field.contains('_'), "{} is not camelCase", field);
+    }
+    // Row positions past 2^53 may come as strings.
+    assert!(properties["scrollTop"].to_string().contains("string"), "{}", properties["scrollTop"]);
+}
+
+#[test]
+fn the_known_message_types_are_the_ones_in_the_schema() {
+    // A variant left out of CLIENT_MESSAGE_TYPES would be refused as an
+    // unknown type whenever one of its fields is wrong.
+    let schema = dumped_schema();
+    let branches = schema["definitions"]["ClientMessage"]["oneOf"].as_array().unwrap();
+    let mut tags: Vec<&str> = branches
+        .iter()
+        .map(|branch| branch["properties"]["type"]["enum"][0].as_str().unwrap())
+        .collect();
+    let mut known = CLIENT_MESSAGE_TYPES.to_vec();
+    tags.sort_unstable();
+    known.sort_unstable();
+    assert_eq!(tags, known);
+}
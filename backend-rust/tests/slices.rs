@@ -0,0 +1,458 @@
+//! Slice requests and the shape of their responses.
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use sheets_ws_server::protocol::MinSliceResponse;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+#[tokio::test]
+async fn a_wide_viewport_gives_up_rows_to_the_cell_cap() {
+    let server = start(config(&["--max-cells-per-slice", "1000"]), synthetic(1000, 300)).await;
+    let mut client = server.connect().await;
+    let slice = client.request(slice_at(0, 0, 50, 200), "slice_response").await;
+    assert_eq!((slice["colCount"].clone(), slice["rowCount"].clone()), (json!(200), json!(5)));
+    assert_eq!(slice["clamped"], true);
+    assert_eq!(slice["cellsByRow"].as_array().unwrap().len(), 5);
+
+    let small = client.request(slice_at(0, 0, 10, 10), "slice_response").await;
+    assert_eq!((small["rowCount"].clone(), small["clamped"].clone()), (json!(10), json!(false)));
+}
+
+/// A sparse `cellsByRow` expanded back to every cell, blanks as `""`.
+fn expand_sparse(slice: &serde_json::Value) -> Vec<Vec<&str>> {
+    let cols = slice["colCount"].as_u64().unwrap() as usize;
+    let rows = slice["cellsByRow"].as_array().unwrap();
+    rows.iter()
+        .map(|row| match row.as_array() {
+            None => vec![""; cols],
+            Some(cells) => cells.iter().map(|cell| cell.as_str().unwrap_or_default()).collect(),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn sparse_slices_send_blank_rows_as_null_and_expand_to_blanks() {
+    let rows: &[&[&str]] = &[&["a", "", "c"], &["", "", ""], &["", "e", ""]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let dense = client.request(slice_at(0, 0, 3, 3), "slice_response").await;
+    let sparse = with(slice_at(0, 0, 3, 3), json!({"sparse": true}));
+    let sparse = client.request(sparse, "slice_response").await;
+    assert_eq!(sparse["cellsByRow"], json!([["a", null, "c"], null, [null, "e", null]]));
+    let dense: Vec<Vec<String>> = serde_json::from_value(dense["cellsByRow"].clone()).unwrap();
+    assert_eq!(expand_sparse(&sparse), dense);
+}
+
+#[tokio::test]
+async fn configured_headers_label_columns_and_the_rest_keep_letters() {
+    let server = start(config(&["--headers", "Name,Age"]), synthetic(10, 4)).await;
+    let mut client = server.connect().await;
+    let slice = client.request(slice_at(0, 0, 2, 4), "slice_response").await;
+    assert_eq!(slice["colLetters"], json!(["Name", "Age", "C", "D"]));
+    let metadata = client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+    assert_eq!(metadata["colNames"], json!(["Name", "Age"]));
+}
+
+#[tokio::test]
+async fn an_unchanged_slice_is_not_modified_until_an_edit_lands_in_it() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    let first = client.request(slice_at(0, 0, 5, 5), "slice_response").await;
+    let etag = first["etag"].clone();
+    let again = with(slice_at(0, 0, 5, 5), json!({"ifNoneMatch": etag}));
+    assert_eq!(client.request(again.clone(), "not_modified").await["etag"], etag);
+
+    let edit = json!({"type": "cell_update", "row": 2, "col": 2, "value": "changed"});
+    client.request(edit, "cells_updated").await;
+    let fresh = client.request(again, "slice_response").await;
+    assert_ne!(fresh["etag"], etag);
+    assert_eq!(fresh["cellsByRow"][2][2], "changed");
+}
+
+#[tokio::test]
+async fn extreme_scroll_offsets_and_buffers_clamp_to_the_table() {
+    let server = start(config(&[]), synthetic(1000, 50)).await;
+    let mut client = server.connect().await;
+    let wrapped = json!({"scrollTop": u64::MAX, "scrollLeft": u64::MAX});
+    let error = client.request_error(with(slice_at(0, 0, 5, 5), wrapped)).await;
+    assert_eq!(error["code"], "scroll_out_of_range");
+
+    // Four table extents past the end is the furthest offset still clamped.
+    let far = json!({
+        "scrollTop": 4 * 1000 * 20,
+        "scrollLeft": 4 * 50 * 100,
+        "verticalBuffer": u32::MAX,
+        "horizontalBuffer": u32::MAX,
+        "screenHeight": u32::MAX,
+        "screenWidth": u32::MAX,
+    });
+    let slice = client.request(with(slice_at(0, 0, 5, 5), far), "slice_response").await;
+    let start_row = slice["startRow"].as_u64().unwrap();
+    let start_col = slice["startCol"].as_u64().unwrap();
+    let row_count = slice["rowCount"].as_u64().unwrap();
+    let col_count = slice["colCount"].as_u64().unwrap();
+    assert!(start_row + row_count <= 1000, "rows {}+{}", start_row, row_count);
+    assert!(start_col + col_count <= 50, "cols {}+{}", start_col, col_count);
+    assert_eq!(slice["cellsByRow"].as_array().unwrap().len() as u64, row_count);
+
+    let buffered = json!({"verticalBuffer": u32::MAX, "horizontalBuffer": u32::MAX});
+    let slice = client.request(with(slice_at(998, 48, 5, 5), buffered), "slice_response").await;
+    let end_row = slice["startRow"].as_u64().unwrap() + slice["rowCount"].as_u64().unwrap();
+    let end_col = slice["startCol"].as_u64().unwrap() + slice["colCount"].as_u64().unwrap();
+    assert_eq!((end_row, end_col), (1000, 50));
+    let last = slice["cellsByRow"].as_array().unwrap().last().cloned().unwrap();
+    assert_eq!(last.as_array().unwrap().last().unwrap(), "R1000C AX");
+}
+
+#[tokio::test]
+async fn missing_cells_read_as_the_placeholder_and_empty_ones_stay_empty() {
+    let rows: &[&[&str]] = &[&["a", "", "c"], &["d"]];
+    let server = start(config(&["--missing-value", "N/A"]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let slice = client.request(slice_at(0, 0, 2, 3), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["a", "", "c"], ["d", "N/A", "N/A"]]));
+}
+
+#[tokio::test]
+async fn a_second_slice_of_the_same_region_is_served_from_the_cell_cache() {
+    let (source, reads) = probe(synthetic(1000, 10), Duration::ZERO);
+    let server = start(config(&["--cell-cache-size", "1000"]), source).await;
+    let mut first = server.connect().await;
+    first.request(slice_at(10, 0, 5, 5), "slice_response").await;
+    let misses = reads.load(Ordering::Relaxed);
+    assert_eq!(misses, 25);
+
+    let mut second = server.connect().await;
+    let slice = second.request(slice_at(10, 0, 5, 5), "slice_response").await;
+    assert_eq!(reads.load(Ordering::Relaxed), misses);
+    assert_eq!(slice["cellsByRow"][0][0], "R11C A");
+    let metadata = second.request(json!({"type": "metadata_request"}), "metadata_response").await;
+    assert_eq!(metadata["cellCache"]["hits"], 25);
+    assert_eq!(metadata["cellCache"]["misses"], 25);
+}
+
+#[tokio::test]
+async fn the_min_schema_carries_the_same_slice_under_short_keys() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    let full = client.request(slice_at(3, 2, 4, 3), "slice_response").await;
+    let min = with(slice_at(3, 2, 4, 3), json!({"schema": "min"}));
+    let mut min = client.request(min, "slice_min").await;
+    let pairs = [
+        ("r", "startRow"),
+        ("n", "rowCount"),
+        ("c", "startCol"),
+        ("w", "colCount"),
+        ("l", "colLetters"),
+        ("d", "cellsByRow"),
+        ("k", "clamped"),
+        ("i", "rowIds"),
+        ("e", "etag"),
+    ];
+    for (short, long) in pairs {
+        assert_eq!(min[short], full[long], "{} against {}", short, long);
+    }
+
+    min.as_object_mut().unwrap().remove("type");
+    let parsed: MinSliceResponse = serde_json::from_value(min.clone()).unwrap();
+    assert_eq!((parsed.start_row, parsed.col_count), (3, 3));
+    assert_eq!(serde_json::to_value(&parsed).unwrap(), min);
+}
+
+#[tokio::test]
+async fn a_greater_than_rule_styles_only_the_cells_above_it() {
+    let rows: &[&[&str]] =
+        &[&["50", "500"], &["150", "1"], &["100", "1"], &["abc", "1"], &["101", "1"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let rule = json!({"col": 0, "op": "gt", "value": "100", "style": {"color": "red"}});
+    let set = json!({"type": "set_conditional_format", "rules": [rule]});
+    client.request(set, "conditional_format_set").await;
+    let styled = with(slice_at(0, 0, 5, 2), json!({"styled": true}));
+    let slice = client.request(styled, "slice_response").await;
+    let red = json!({"color": "red"});
+    let expected = json!([{"row": 1, "col": 0, "style": red}, {"row": 4, "col": 0, "style": red}]);
+    assert_eq!(slice["styles"], expected);
+}
+
+#[tokio::test]
+async fn offsets_past_four_table_extents_are_out_of_range() {
+    let server = start(config(&[]), synthetic(1000, 50)).await;
+    let mut client = server.connect().await;
+    let past_top = with(slice_at(0, 0, 5, 5), json!({"scrollTop": 4 * 1000 * 20 + 1}));
+    let error = client.request_error(past_top).await;
+    assert_eq!(error["code"], "scroll_out_of_range");
+    assert!(error["message"].as_str().unwrap().starts_with("scrollTop"), "{}", error);
+
+    let past_left = with(slice_at(0, 0, 5, 5), json!({"scrollLeft": 4 * 50 * 100 + 1}));
+    let error = client.request_error(past_left).await;
+    assert_eq!(error["code"], "scroll_out_of_range");
+    assert!(error["message"].as_str().unwrap().starts_with("scrollLeft"), "{}", error);
+
+    let just_past_end = slice_at(1000, 50, 5, 5);
+    let slice = client.request(just_past_end, "slice_response").await;
+    assert_eq!(slice["startRow"].as_u64().unwrap() + slice["rowCount"].as_u64().unwrap(), 1000);
+}
+
+/// `colCount` letters, `rowCount` row ids and rows, and `colCount` cells in
+/// each row that is sent; in column orientation, `rowCount` in each column.
+fn assert_shape(slice: &serde_json::Value) {
+    let rows = slice["rowCount"].as_u64().unwrap() as usize;
+    let cols = slice["colCount"].as_u64().unwrap() as usize;
+    assert_eq!(slice["colLetters"].as_array().unwrap().len(), cols, "{}", slice);
+    assert_eq!(slice["rowIds"].as_array().unwrap().len(), rows, "{}", slice);
+    let (lines, count, width) = match slice.get("cellsByCol") {
+        Some(by_col) => (by_col, cols, rows),
+        None => (&slice["cellsByRow"], rows, cols),
+    };
+    let lines = lines.as_array().unwrap();
+    assert_eq!(lines.len(), count, "{}", slice);
+    for line in lines.iter().filter(|line| !line.is_null()) {
+        assert_eq!(line.as_array().unwrap().len(), width, "{}", slice);
+    }
+}
+
+#[tokio::test]
+async fn every_viewport_shape_agrees_with_its_counts() {
+    let server = start(config(&["--max-cells-per-slice", "500"]), synthetic(1000, 60)).await;
+    let mut client = server.connect().await;
+    let viewports = [
+        slice_at(0, 0, 10, 10),
+        slice_at(0, 0, 40, 40),
+        slice_at(995, 55, 10, 10),
+        slice_at(1000, 60, 10, 10),
+        with(slice_at(0, 0, 1, 1), json!({"screenWidth": 0, "screenHeight": 0})),
+        with(slice_at(10, 10, 5, 5), json!({"verticalBuffer": 3, "horizontalBuffer": 3})),
+        with(slice_at(0, 0, 5, 5), json!({"sparse": true})),
+        with(slice_at(990, 0, 20, 5), json!({"distinguishNull": true})),
+        with(slice_at(5, 5, 5, 8), json!({"orientation": "column"})),
+    ];
+    for viewport in viewports {
+        let slice = client.request(viewport, "slice_response").await;
+        assert_shape(&slice);
+    }
+}
+
+#[tokio::test]
+async fn neighbor_hints_describe_the_four_slices_one_screen_away() {
+    let server = start(config(&[]), synthetic(10_000, 100)).await;
+    let mut client = server.connect().await;
+    let middle = with(slice_at(100, 20, 10, 5), json!({"includeNeighbors": true}));
+    let slice = client.request(middle, "slice_response").await;
+    let hints = slice["neighborHints"].as_array().unwrap();
+    let directions: Vec<&serde_json::Value> = hints.iter().map(|hint| &hint["direction"]).collect();
+    assert_eq!(directions, ["up", "down", "left", "right"]);
+
+    for hint in hints {
+        let request = json!({"scrollTop": hint["scrollTop"], "scrollLeft": hint["scrollLeft"]});
+        let fetched = client.request(with(slice_at(0, 0, 10, 5), request), "slice_response").await;
+        assert_eq!(fetched["etag"], hint["etag"], "{}", hint);
+        assert_eq!(fetched["startRow"], hint["startRow"], "{}", hint);
+        assert_eq!(fetched["startCol"], hint["startCol"], "{}", hint);
+        let actual = fetched.to_string().len() as f64;
+        let estimated = hint["estimatedBytes"].as_f64().unwrap();
+        assert!((actual * 0.5..actual * 1.5).contains(&estimated), "{} for {}", estimated, actual);
+    }
+}
+
+#[tokio::test]
+async fn a_slow_source_times_out_without_stalling_the_connection() {
+    let (source, _) = probe(synthetic(1000, 26), Duration::from_millis(5));
+    let server = start(config(&["--source-timeout-ms", "50"]), source).await;
+    let mut client = server.connect().await;
+    let started = std::time::Instant::now();
+    let error = client.request_error(slice_at(0, 0, 20, 10)).await;
+    assert_eq!(error["code"], "source_timeout");
+    let metadata = json!({"type": "metadata_request"});
+    client.request(metadata, "metadata_response").await;
+    // The 200 cells would take a second; the handler answered long before.
+    assert!(started.elapsed() < Duration::from_millis(800), "took {:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn buffers_past_the_maximum_are_clamped_to_it() {
+    let server = start(config(&["--max-buffer", "3"]), synthetic(1000, 100)).await;
+    let mut client = server.connect().await;
+    let huge = json!({"verticalBuffer": 4_000_000_000u32, "horizontalBuffer": u32::MAX});
+    let slice = client.request(with(slice_at(50, 50, 4, 4), huge), "slice_response").await;
+    let capped = json!({"verticalBuffer": 3, "horizontalBuffer": 3});
+    let expected = client.request(with(slice_at(50, 50, 4, 4), capped), "slice_response").await;
+    for field in ["startRow", "startCol", "rowCount", "colCount"] {
+        assert_eq!(slice[field], expected[field], "{}", field);
+    }
+    assert_eq!((&slice["rowCount"], &slice["colCount"]), (&json!(10), &json!(10)));
+}
+
+#[tokio::test]
+async fn only_an_edit_that_differs_from_the_source_is_dirty() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    let edit = json!({"type": "cell_update", "row": 3, "col": 2, "value": "edited"});
+    client.request(edit, "cells_updated").await;
+    // Writing back what the source holds leaves nothing to mark.
+    let same = json!({"type": "cell_update", "row": 1, "col": 1, "value": "R2C B"});
+    client.request(same, "cells_updated").await;
+
+    let plain = client.request(slice_at(0, 0, 5, 5), "slice_response").await;
+    assert!(plain.get("dirty").is_none(), "{}", plain);
+    let marked = with(slice_at(0, 0, 5, 5), json!({"dirty": true}));
+    let slice = client.request(marked, "slice_response").await;
+    assert_eq!(slice["dirty"], json!([[3, 2]]));
+}
+
+#[tokio::test]
+async fn the_extent_is_rows_times_height_until_a_row_is_resized() {
+    let server = start(config(&[]), synthetic(1000, 30)).await;
+    let mut client = server.connect().await;
+    let defaults = json!({"defaultRowHeight": 24, "defaultColumnWidth": 100});
+    let extent = with(json!({"type": "extent_request"}), defaults);
+    let uniform = client.request(extent.clone(), "extent_response").await;
+    assert_eq!((&uniform["height"], &uniform["width"]), (&json!(24_000), &json!(3_000)));
+
+    let tall = json!({"type": "set_row_height", "row": 7, "height": 60});
+    let resized = client.request(tall, "extent_response").await;
+    assert_eq!((&resized["height"], &resized["width"]), (&json!(24_036), &json!(3_000)));
+    let narrow = json!({"type": "set_col_width", "col": 0, "width": 40});
+    assert_eq!(client.request(narrow, "extent_response").await["width"], 2_940);
+    assert_eq!(client.request(extent, "extent_response").await["height"], 24_036);
+
+    let reset = json!({"type": "set_row_height", "row": 7});
+    assert_eq!(client.request(reset, "extent_response").await["height"], 24_000);
+}
+
+#[tokio::test]
+async fn column_orientation_is_the_row_slice_transposed() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    let rows = client.request(slice_at(5, 2, 4, 3), "slice_response").await;
+    let by_col = with(slice_at(5, 2, 4, 3), json!({"orientation": "column"}));
+    let cols = client.request(by_col, "slice_response").await;
+    assert!(cols.get("cellsByRow").is_none(), "{}", cols);
+    let rows = rows["cellsByRow"].as_array().unwrap();
+    let cols = cols["cellsByCol"].as_array().unwrap();
+    assert_eq!((rows.len(), cols.len()), (4, 3));
+    for (r, row) in rows.iter().enumerate() {
+        for (c, cell) in row.as_array().unwrap().iter().enumerate() {
+            assert_eq!(&cols[c][r], cell, "cell ({}, {})", r, c);
+        }
+    }
+    assert_eq!(cols[2][0], "R6C E");
+}
+
+#[tokio::test]
+async fn a_streamed_slice_sends_each_row_then_the_end() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    client.send(with(slice_at(3, 1, 4, 2), json!({"streamViewport": true}))).await;
+    for row in 3..7 {
+        let msg = client.recv().await;
+        assert_eq!(msg["type"], "slice_row", "{}", msg);
+        assert_eq!((&msg["row"], &msg["rowId"]), (&json!(row), &json!(row)));
+        assert_eq!(msg["startCol"], 1);
+        let cells = json!([format!("R{}C B", row + 1), format!("R{}C C", row + 1)]);
+        assert_eq!(msg["cells"], cells);
+    }
+    let end = client.recv().await;
+    assert_eq!(end["type"], "slice_end");
+    let window = (&end["startRow"], &end["rowCount"], &end["colCount"]);
+    assert_eq!(window, (&json!(3), &json!(4), &json!(2)));
+    assert_eq!(end["colLetters"], json!(["B", "C"]));
+}
+
+#[tokio::test]
+async fn a_pinned_middle_column_stays_on_the_left_when_scrolled_right() {
+    let server = start(config(&[]), synthetic(100, 40)).await;
+    let mut client = server.connect().await;
+    let pin = json!({"type": "pin_column_request", "col": 5, "side": "left"});
+    let pinned = client.request(pin, "pin_column_response").await;
+    assert_eq!(pinned["pinnedLeft"], json!([5]));
+
+    let slice = client.request(slice_at(2, 20, 2, 4), "slice_response").await;
+    let left = slice["pinnedLeftCols"].as_array().unwrap();
+    assert_eq!(left.len(), 1, "{}", slice);
+    assert_eq!((&left[0]["col"], &left[0]["label"]), (&json!(5), &json!("F")));
+    assert_eq!(left[0]["cells"], json!(["R3C F", "R4C F"]));
+    let body = slice["bodyCols"].as_array().unwrap();
+    assert!(!body.is_empty() && !body.contains(&json!(5)), "{}", slice);
+
+    // Scrolled back over it, the body still leaves the pinned column out.
+    let near = client.request(slice_at(2, 3, 2, 4), "slice_response").await;
+    assert!(!near["bodyCols"].as_array().unwrap().contains(&json!(5)), "{}", near);
+    assert_eq!(near["pinnedLeftCols"][0]["col"], 5);
+}
+
+#[tokio::test]
+async fn distinguish_null_sends_missing_cells_as_null_and_empty_ones_as_empty() {
+    let rows: &[&[&str]] = &[&["a", ""], &["b"]];
+    let server = start(config(&["--missing-value", "-"]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let plain = client.request(slice_at(0, 0, 2, 2), "slice_response").await;
+    assert_eq!(plain["cellsByRow"], json!([["a", ""], ["b", "-"]]));
+    let typed = with(slice_at(0, 0, 2, 2), json!({"distinguishNull": true}));
+    let typed = client.request(typed, "slice_response").await;
+    assert_eq!(typed["cellsByRow"], json!([["a", ""], ["b", null]]));
+}
+
+#[tokio::test]
+async fn line_counts_give_each_row_its_tallest_cell() {
+    let rows: &[&[&str]] = &[&["one", "two\nlines"], &["a\nb\nc", "d"], &["e", "f"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let plain = client.request(slice_at(0, 0, 3, 2), "slice_response").await;
+    assert!(plain.get("rowLineCounts").is_none());
+    let counted = with(slice_at(0, 0, 3, 2), json!({"lineCounts": true}));
+    let counted = client.request(counted, "slice_response").await;
+    assert_eq!(counted["rowLineCounts"], json!([2, 3, 1]));
+}
+
+#[tokio::test]
+async fn fields_leave_out_col_letters_and_keep_the_cells() {
+    let server = start(config(&[]), synthetic(100, 10)).await;
+    let mut client = server.connect().await;
+    let full = client.request(slice_at(0, 0, 2, 2), "slice_response").await;
+    assert_eq!(full["colLetters"], json!(["A", "B"]));
+    let trimmed = with(slice_at(0, 0, 2, 2), json!({"fields": ["cells_by_row"]}));
+    let trimmed = client.request(trimmed, "slice_response").await;
+    assert!(trimmed.get("colLetters").is_none(), "{}", trimmed);
+    assert_eq!(trimmed["cellsByRow"], full["cellsByRow"]);
+    assert_eq!((&trimmed["startRow"], &trimmed["colCount"]), (&json!(0), &json!(2)));
+}
+
+#[tokio::test]
+async fn jumping_to_a_cell_pushes_scroll_to_with_its_offsets() {
+    let server = start(config(&[]), synthetic(1000, 10)).await;
+    let mut client = server.connect().await;
+    let jump = json!({"type": "jump_to_cell", "row": 500, "col": 3});
+    assert_eq!(client.request_error(jump.clone()).await["code"], "bad_request");
+
+    client.request(slice_at(0, 0, 5, 5), "slice_response").await;
+    let tall = json!({"type": "set_row_height", "row": 7, "height": 60});
+    client.request(tall, "extent_response").await;
+    let narrow = json!({"type": "set_col_width", "col": 0, "width": 40});
+    client.request(narrow, "extent_response").await;
+    let scroll = client.request(jump, "scroll_to").await;
+    assert_eq!((&scroll["scrollTop"], &scroll["scrollLeft"]), (&json!(10_040), &json!(240)));
+    assert_eq!((&scroll["row"], &scroll["position"]), (&json!(500), &json!(500)));
+    assert_eq!(scroll["col"], 3);
+}
+
+#[tokio::test]
+async fn the_freeze_boundary_is_the_summed_width_of_the_pinned_columns() {
+    let server = start(config(&[]), synthetic(100, 40)).await;
+    let mut client = server.connect().await;
+    let plain = client.request(slice_at(0, 10, 2, 4), "slice_response").await;
+    assert!(plain.get("pinnedLeftWidth").is_none(), "{}", plain);
+
+    for (col, width) in [(0, 80), (2, 150)] {
+        let pin = json!({"type": "pin_column_request", "col": col, "side": "left"});
+        client.request(pin, "pin_column_response").await;
+        let resize = json!({"type": "set_col_width", "col": col, "width": width});
+        client.request(resize, "extent_response").await;
+    }
+    let slice = client.request(slice_at(0, 10, 2, 4), "slice_response").await;
+    assert_eq!(slice["pinnedLeftWidth"], 230);
+    assert!(slice.get("pinnedRightWidth").is_none(), "{}", slice);
+}
@@ -0,0 +1,164 @@
+//! Column stats, distinct values and overviews.
+
+mod common;
+
+use common::*;
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+fn stats(col: u32) -> serde_json::Value {
+    json!({"type": "column_stats_request", "col": col})
+}
+
+#[tokio::test]
+async fn stats_cover_the_numbers_in_the_sessions_view() {
+    let rows: &[&[&str]] = &[&["5"], &["12"], &["-3"], &["40"], &["n/a"], &[""]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let all = client.request(stats(0), "column_stats_response").await;
+    assert_eq!((all["count"].clone(), all["numericCount"].clone()), (json!(5), json!(4)));
+    assert_eq!((all["min"].as_f64(), all["max"].as_f64()), (Some(-3.0), Some(40.0)));
+    assert_eq!(all["sum"].as_f64(), Some(54.0));
+    let scanned = (all["scannedRows"].clone(), all["budgetExhausted"].clone());
+    assert_eq!(scanned, (json!(6), json!(false)));
+
+    let filter = json!({"type": "filter_request", "col": 0, "op": "gt", "value": "6"});
+    client.request(filter, "view_response").await;
+    let visible = client.request(stats(0), "column_stats_response").await;
+    assert_eq!(visible["numericCount"], 2);
+    assert_eq!((visible["min"].as_f64(), visible["max"].as_f64()), (Some(12.0), Some(40.0)));
+
+    // Another session's view is its own, cache or no cache.
+    let mut other = server.connect().await;
+    let unfiltered = other.request(stats(0), "column_stats_response").await;
+    assert_eq!(unfiltered["min"].as_f64(), Some(-3.0));
+
+    let edit = json!({"type": "cell_update", "row": 3, "col": 0, "value": "100"});
+    client.request(edit, "cells_updated").await;
+    let edited = client.request(stats(0), "column_stats_response").await;
+    assert_eq!(edited["max"].as_f64(), Some(100.0));
+}
+
+#[tokio::test]
+async fn cancelling_a_slow_scan_stops_it_early() {
+    let (source, reads) = probe(synthetic(1_000_000, 2), Duration::from_micros(50));
+    let server = start(config(&["--scan-budget", "1000000"]), source).await;
+    let mut client = server.connect().await;
+    client.send(json!({"type": "column_stats_request", "col": 0, "requestId": "scan"})).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let cancel = json!({"type": "cancel_request", "requestId": "scan"});
+    assert_eq!(client.request(cancel.clone(), "cancel_response").await["found"], true);
+    let stats = client.recv_type("column_stats_response").await;
+    assert_eq!((&stats["requestId"], &stats["canceled"]), (&json!("scan"), &json!(true)));
+    assert!(reads.load(Ordering::Relaxed) < 100_000, "read {:?} cells", reads);
+
+    assert_eq!(client.request(cancel, "cancel_response").await["found"], false);
+}
+
+fn distinct(col: u32) -> serde_json::Value {
+    json!({"type": "distinct_values_request", "col": col})
+}
+
+#[tokio::test]
+async fn distinct_values_list_a_small_column_and_truncate_a_wide_one() {
+    let numbers: Vec<String> = (0..200).map(|n| (n * 7 % 200).to_string()).collect();
+    let rows: Vec<Vec<&str>> = numbers
+        .iter()
+        .enumerate()
+        .map(|(i, number)| vec![["true", "false"][i % 2], number.as_str()])
+        .collect();
+    let rows: Vec<&[&str]> = rows.iter().map(Vec::as_slice).collect();
+    let server = start(config(&["--distinct-values-cap", "50"]), inline(&rows)).await;
+    let mut client = server.connect().await;
+    let booleans = client.request(distinct(0), "distinct_values_response").await;
+    assert_eq!(booleans["values"], json!(["false", "true"]));
+    assert_eq!(booleans["truncated"], false);
+
+    let integers = client.request(distinct(1), "distinct_values_response").await;
+    assert_eq!(integers["truncated"], true);
+    let values = integers["values"].as_array().unwrap();
+    assert_eq!(values.len(), 50);
+    let numbers: Vec<f64> =
+        values.iter().map(|value| value.as_str().unwrap().parse().unwrap()).collect();
+    assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", numbers);
+
+    let edit = json!({"type": "cell_update", "row": 3, "col": 0, "value": "maybe"});
+    client.request(edit, "cells_updated").await;
+    let booleans = client.request(distinct(0), "distinct_values_response").await;
+    assert_eq!(booleans["values"], json!(["false", "maybe", "true"]));
+}
+
+#[tokio::test]
+async fn a_scan_budget_short_of_the_table_reports_exhaustion() {
+    let numbers: Vec<String> = (0..1_000).map(|row| (row % 10).to_string()).collect();
+    let mut rows: Vec<[&str; 1]> = numbers.iter().map(|number| [number.as_str()]).collect();
+    rows[500] = ["9999"];
+    let rows: Vec<&[&str]> = rows.iter().map(|row| &row[..]).collect();
+    let server = start(config(&["--scan-budget", "100"]), inline(&rows)).await;
+    let mut client = server.connect().await;
+    let partial = client.request(stats(0), "column_stats_response").await;
+    assert_eq!((&partial["scannedRows"], &partial["budgetExhausted"]), (&json!(100), &json!(true)));
+    // The outlier lies past the budget, so the partial answer misses it.
+    assert_eq!(partial["max"].as_f64(), Some(9.0));
+    let values = client.request(distinct(0), "distinct_values_response").await;
+    assert_eq!((&values["scannedRows"], &values["budgetExhausted"]), (&json!(100), &json!(true)));
+
+    let server = start(config(&["--scan-budget", "1000"]), inline(&rows)).await;
+    let mut client = server.connect().await;
+    let whole = client.request(stats(0), "column_stats_response").await;
+    assert_eq!((&whole["scannedRows"], &whole["budgetExhausted"]), (&json!(1000), &json!(false)));
+    assert_eq!(whole["max"].as_f64(), Some(9999.0));
+}
+
+#[tokio::test]
+async fn an_overview_has_the_requested_buckets_of_plausible_fill() {
+    // Half of every row is blank, wherever the samples fall.
+    let rows = vec![&["a", "b", "", ""][..]; 10_000];
+    let server = start(config(&["--scan-budget", "8000"]), inline(&rows)).await;
+    let mut client = server.connect().await;
+    let request = json!({"type": "overview_request", "buckets": 20});
+    let overview = client.request(request, "overview_response").await;
+    let fill = overview["fill"].as_array().unwrap();
+    let fill: Vec<f64> = fill.iter().map(|f| f.as_f64().unwrap()).collect();
+    assert_eq!(fill.len(), 20);
+    assert!(fill.iter().all(|&f| f == 0.5), "{:?}", fill);
+    // 8000 cells over 20 buckets of 4 columns is 100 of each bucket's 500 rows.
+    assert_eq!((&overview["scannedRows"], &overview["sampled"]), (&json!(2000), &json!(true)));
+    assert_eq!(overview["totalRows"], 10_000);
+
+    let full = start(config(&[]), synthetic(30, 4)).await;
+    let mut client = full.connect().await;
+    let request = json!({"type": "overview_request", "buckets": 40});
+    let overview = client.request(request.clone(), "overview_response").await;
+    let fill = overview["fill"].as_array().unwrap();
+    assert_eq!(fill.len(), 40);
+    // 30 rows fill 30 of the 40 buckets; the rest hold none.
+    assert_eq!(fill.iter().filter(|&f| f == 1.0).count(), 30);
+    assert_eq!(fill.iter().filter(|&f| f == 0.0).count(), 10);
+    // Edits count too: blanking a cell of the last row empties a quarter of
+    // the last bucket.
+    let blank = json!({"type": "cell_update", "row": 29, "col": 0, "value": ""});
+    client.request(blank, "cells_updated").await;
+    let overview = client.request(request, "overview_response").await;
+    assert_eq!(overview["fill"][39], 0.75);
+}
+
+#[tokio::test]
+async fn a_second_scan_past_the_inflight_cap_is_refused_until_the_first_ends() {
+    let (source, _) = probe(synthetic(1_000_000, 2), Duration::from_micros(50));
+    let capped = config(&["--max-inflight", "1", "--scan-budget", "1000000"]);
+    let server = start(capped, source).await;
+    let mut client = server.connect().await;
+    client.send(json!({"type": "column_stats_request", "col": 0, "requestId": "slow"})).await;
+    let second = client.request_error(stats(1)).await;
+    assert_eq!(second["code"], "too_many_inflight");
+    // Quick requests are not operations and still go through.
+    client.request(slice_at(0, 0, 2, 2), "slice_response").await;
+
+    let cancel = json!({"type": "cancel_request", "requestId": "slow"});
+    client.request(cancel, "cancel_response").await;
+    client.recv_type("column_stats_response").await;
+    let distinct = json!({"type": "distinct_values_request", "col": 0});
+    client.request(distinct, "distinct_values_response").await;
+}
@@ -0,0 +1,41 @@
+//! `--validate-only`, run as operators run it.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Writes `text` to a file named `name` under the target's scratch directory.
+fn data_file(name: &str, text: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, text).unwrap();
+    path
+}
+
+/// The exit code and standard output of validating `path`.
+fn validate(path: &PathBuf) -> (Option<i32>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_sheets_ws_server"))
+        .arg("--validate-only")
+        .arg("--csv")
+        .arg(path)
+        .output()
+        .unwrap();
+    (output.status.code(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn a_ragged_file_is_reported_row_by_row_and_fails() {
+    let path = data_file("ragged.csv", "id,name,age\n1,Ada,36\n2,Bob\n3,Cy,41,extra\n");
+    let (code, report) = validate(&path);
+    assert_eq!(code, Some(1));
+    assert!(report.contains("3 rows, 4 columns"), "{}", report);
+    assert!(report.contains("warning: data row 2 has 2 fields, the header has 3"), "{}", report);
+    assert!(report.contains("warning: data row 3 has 4 fields, the header has 3"), "{}", report);
+}
+
+#[test]
+fn a_clean_file_reports_its_types_and_passes() {
+    let path = data_file("clean.csv", "id,price,paid\n1,2.50,true\n2,3,false\n");
+    let (code, report) = validate(&path);
+    assert_eq!(code, Some(0), "{}", report);
+    assert!(report.contains("2 rows, 3 columns"), "{}", report);
+    assert!(report.contains("  id: integer\n  price: number\n  paid: boolean\n"), "{}", report);
+}
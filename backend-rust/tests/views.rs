@@ -0,0 +1,274 @@
+//! Filters and sorts: which rows a session's slices reach, and in what order.
+
+mod common;
+
+use common::*;
+use serde_json::json;
+
+fn filter(col: u32, op: &str, value: &str) -> serde_json::Value {
+    json!({"type": "filter_request", "col": col, "op": op, "value": value})
+}
+
+#[tokio::test]
+async fn row_ids_after_a_filter_are_the_surviving_physical_rows() {
+    let rows: &[&[&str]] = &[&["1"], &["8"], &["3"], &["9"], &["7"], &["2"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let plain = client.request(slice_at(1, 0, 3, 1), "slice_response").await;
+    assert_eq!(plain["rowIds"], json!([1, 2, 3]));
+
+    let view = client.request(filter(0, "gt", "5"), "view_response").await;
+    assert_eq!(view["visibleRows"], 3);
+    assert_eq!(view["budgetExhausted"], false);
+    let slice = client.request(slice_at(0, 0, 3, 1), "slice_response").await;
+    assert_eq!(slice["rowIds"], json!([1, 3, 4]));
+    assert_eq!(slice["cellsByRow"], json!([["8"], ["9"], ["7"]]));
+
+    let cleared = client.request(json!({"type": "clear_filters_request"}), "view_response").await;
+    assert_eq!(cleared["visibleRows"], 6);
+}
+
+#[tokio::test]
+async fn a_view_rebuild_stops_at_the_scan_budget() {
+    let server = start(config(&["--scan-budget", "100"]), synthetic(1000, 2)).await;
+    let mut client = server.connect().await;
+    let view = client.request(filter(0, "contains", "C"), "view_response").await;
+    assert_eq!(view["visibleRows"], 100);
+    assert_eq!(view["budgetExhausted"], true);
+    let slice = client.request(slice_at(99, 0, 5, 1), "slice_response").await;
+    assert_eq!(slice["rowIds"], json!([99]));
+}
+
+#[tokio::test]
+async fn a_filter_reads_edits_made_before_it() {
+    let rows: &[&[&str]] = &[&["b"], &["c"], &["a"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let edit = json!({"type": "cell_update", "row": 1, "col": 0, "value": "a"});
+    client.request(edit, "cells_updated").await;
+    client.request(filter(0, "equals", "a"), "view_response").await;
+    let slice = client.request(slice_at(0, 0, 3, 1), "slice_response").await;
+    assert_eq!(slice["rowIds"], json!([1, 2]));
+}
+
+#[tokio::test]
+async fn a_sort_reads_edits_made_before_it() {
+    let rows: &[&[&str]] = &[&["b"], &["c"], &["a"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let edit = json!({"type": "cell_update", "row": 1, "col": 0, "value": "0"});
+    client.request(edit, "cells_updated").await;
+    let sort = json!({"type": "sort_request", "keys": [{"col": 0}]});
+    client.request(sort, "view_response").await;
+    let slice = client.request(slice_at(0, 0, 3, 1), "slice_response").await;
+    assert_eq!(slice["rowIds"], json!([1, 2, 0]));
+}
+
+#[tokio::test]
+async fn rows_request_after_a_sort_returns_rows_in_sort_order() {
+    let rows: &[&[&str]] = &[&["30", "c"], &["10", "a"], &["40", "d"], &["20", "b"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let sort = json!({"type": "sort_request", "keys": [{"col": 0, "descending": true}]});
+    client.request(sort, "view_response").await;
+    let request = json!({"type": "rows_request", "start": 0, "count": 3, "startCol": 0});
+    let reply = client.request(with(request, json!({"colCount": 2})), "rows_response").await;
+    assert_eq!(reply["rowIds"], json!([2, 0, 3]));
+    assert_eq!(reply["cellsByRow"], json!([["40", "d"], ["30", "c"], ["20", "b"]]));
+}
+
+#[tokio::test]
+async fn a_formatted_number_column_sorts_by_value_and_says_so() {
+    let rows: &[&[&str]] = &[&["10"], &["9"], &["$1,200"], &["100"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let sort = json!({"type": "sort_request", "keys": [{"col": 0}]});
+    client.request(sort, "view_response").await;
+    let keyed = with(slice_at(0, 0, 4, 1), json!({"sortKeys": true}));
+    let slice = client.request(keyed, "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["9"], ["10"], ["100"], ["$1,200"]]));
+    assert_eq!(slice["sortKeys"], json!([{"row": 3, "col": 0, "value": 1200.0}]));
+}
+
+#[tokio::test]
+async fn a_sort_on_a_formula_column_reads_edits_to_its_inputs() {
+    let rows: &[&[&str]] = &[&["1", "2", ""], &["3", "4", ""], &["5", "6", ""]];
+    let server = start(config(&["--formula", "C=A+B"]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let edit = json!({"type": "cell_update", "row": 1, "col": 0, "value": "100"});
+    client.request(edit, "cells_updated").await;
+    let sort = json!({"type": "sort_request", "keys": [{"col": 2, "descending": true}]});
+    client.request(sort, "view_response").await;
+    let slice = client.request(slice_at(0, 0, 3, 3), "slice_response").await;
+    assert_eq!(slice["rowIds"], json!([1, 2, 0]));
+}
+
+#[tokio::test]
+async fn a_value_set_filter_keeps_rows_matching_any_value_and_ands_with_others() {
+    let rows: &[&[&str]] =
+        &[&["red", "1"], &["blue", "2"], &["green", "3"], &["red", "4"], &["blue", "5"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let set = json!({"type": "filter_in_request", "col": 0, "values": ["red", "green"]});
+    let view = client.request(set, "view_response").await;
+    assert_eq!(view["visibleRows"], 3);
+    let slice = client.request(slice_at(0, 0, 5, 2), "slice_response").await;
+    assert_eq!(slice["rowIds"], json!([0, 2, 3]));
+
+    let view = client.request(filter(1, "gte", "3"), "view_response").await;
+    assert_eq!(view["visibleRows"], 2);
+    let slice = client.request(slice_at(0, 0, 5, 2), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["green", "3"], ["red", "4"]]));
+}
+
+#[tokio::test]
+async fn a_filter_expression_builds_the_view_and_a_bad_one_says_where() {
+    let server = start(config(&["--headers", "name,age,city"]), synthetic(100, 3)).await;
+    let mut client = server.connect().await;
+    let expr = |text: &str| json!({"type": "filter_expr_request", "expr": text});
+    let compound = r#"name contains "C A" AND NOT (city = "R1C C" OR age contains "9")"#;
+    let view = client.request(expr(compound), "view_response").await;
+    // Of R1 to R100, the city drops R1 and the age the 19 numbers with a 9.
+    let slice = client.request(slice_at(0, 0, 3, 1), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["R2C A"], ["R3C A"], ["R4C A"]]));
+    assert_eq!(view["visibleRows"], 80);
+
+    let error = client.request_error(expr(r#"age > 30 AND"#)).await;
+    assert_eq!(error["code"], "bad_request");
+    assert!(error["message"].as_str().unwrap().ends_with("at position 12"), "{}", error);
+    let deep = format!("{}age > 30{}", "(".repeat(1_000), ")".repeat(1_000));
+    let error = client.request_error(expr(&deep)).await;
+    assert_eq!(error["code"], "bad_request");
+    assert!(error["message"].as_str().unwrap().starts_with("nested more than"), "{}", error);
+}
+
+#[tokio::test]
+async fn paging_by_cursor_walks_the_view_once_in_order() {
+    let server = start(config(&[]), synthetic(23, 2)).await;
+    let mut client = server.connect().await;
+    client.request(filter(0, "contains", "1"), "view_response").await;
+    let (mut seen, mut cursor, mut pages) = (Vec::new(), None::<String>, 0);
+    loop {
+        let mut page = json!({"type": "page_request", "pageSize": 4});
+        if let Some(cursor) = &cursor {
+            page["cursor"] = json!(cursor);
+        }
+        let page = client.request(page, "page_response").await;
+        pages += 1;
+        for row in page["cellsByRow"].as_array().unwrap() {
+            seen.push(row[0].as_str().unwrap().to_string());
+        }
+        match page.get("nextCursor") {
+            Some(next) => cursor = Some(next.as_str().unwrap().to_string()),
+            None => break,
+        }
+    }
+    let expected: Vec<String> = [1, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 21]
+        .iter()
+        .map(|row| format!("R{}C A", row))
+        .collect();
+    assert_eq!(seen, expected);
+    assert_eq!(pages, 3);
+    let bad = json!({"type": "page_request", "pageSize": 4, "cursor": "nonsense"});
+    assert_eq!(client.request_error(bad).await["code"], "bad_request");
+}
+
+#[tokio::test]
+async fn filters_and_sort_keys_stop_at_their_limits() {
+    let limits = config(&["--max-filters", "2", "--max-sort-keys", "2"]);
+    let server = start(limits, synthetic(50, 5)).await;
+    let mut client = server.connect().await;
+    client.request(filter(0, "contains", "C"), "view_response").await;
+    client.request(filter(1, "contains", "C"), "view_response").await;
+    // Replacing a column's filter does not add one.
+    client.request(filter(1, "contains", "R"), "view_response").await;
+    let third = client.request_error(filter(2, "contains", "C")).await;
+    assert_eq!(third["code"], "too_many_filters");
+    let view = client.request(slice_at(0, 0, 1, 1), "slice_response").await;
+    assert_eq!(view["cellsByRow"], json!([["R1C A"]]));
+
+    let sort = |cols: &[u32]| {
+        let keys: Vec<_> = cols.iter().map(|col| json!({"col": col})).collect();
+        json!({"type": "sort_request", "keys": keys})
+    };
+    client.request(sort(&[0, 1]), "view_response").await;
+    let error = client.request_error(sort(&[0, 1, 2])).await;
+    assert_eq!(error["code"], "too_many_sort_keys");
+}
+
+#[tokio::test]
+async fn the_sort_state_lists_both_keys_in_order_with_their_directions() {
+    let server = start(config(&[]), synthetic(20, 5)).await;
+    let mut client = server.connect().await;
+    let keys = json!([{"col": 3, "descending": true}, {"col": 1}]);
+    let view = client.request(json!({"type": "sort_request", "keys": keys}), "view_response").await;
+    let expected = json!([{"col": 3, "descending": true}, {"col": 1, "descending": false}]);
+    assert_eq!(view["sort"], expected);
+    let metadata = json!({"type": "metadata_request"});
+    assert_eq!(client.request(metadata.clone(), "metadata_response").await["sort"], expected);
+
+    client.request(json!({"type": "sort_request", "keys": []}), "view_response").await;
+    let unsorted = client.request(metadata, "metadata_response").await;
+    assert!(unsorted.get("sort").is_none(), "{}", unsorted);
+}
+
+#[tokio::test]
+async fn case_insensitive_and_regex_filters_match_and_bad_patterns_are_refused() {
+    let rows: &[&[&str]] = &[&["Apple"], &["apple pie"], &["APPLET"], &["banana"], &["grape"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let kept = |view: &serde_json::Value| view["visibleRows"].as_u64().unwrap();
+    let cased = client.request(filter(0, "contains", "apple"), "view_response").await;
+    assert_eq!(kept(&cased), 1);
+    let any_case = with(filter(0, "contains", "apple"), json!({"caseInsensitive": true}));
+    assert_eq!(kept(&client.request(any_case, "view_response").await), 3);
+    let slice = client.request(slice_at(0, 0, 3, 1), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["Apple"], ["apple pie"], ["APPLET"]]));
+
+    let pattern = client.request(filter(0, "regex", "^[a-z]+$"), "view_response").await;
+    assert_eq!(kept(&pattern), 2);
+    let slice = client.request(slice_at(0, 0, 2, 1), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["banana"], ["grape"]]));
+    let bad = client.request_error(filter(0, "regex", "(unclosed")).await;
+    assert_eq!(bad["code"], "bad_regex");
+}
+
+#[tokio::test]
+async fn a_natural_sort_key_puts_a2_before_a10() {
+    let rows: &[&[&str]] = &[&["a2"], &["a10"], &["a1"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let sort = |natural: bool| {
+        json!({"type": "sort_request", "keys": [{"col": 0, "natural": natural}]})
+    };
+    client.request(sort(false), "view_response").await;
+    let plain = client.request(slice_at(0, 0, 3, 1), "slice_response").await;
+    assert_eq!(plain["cellsByRow"], json!([["a1"], ["a10"], ["a2"]]));
+    client.request(sort(true), "view_response").await;
+    let natural = client.request(slice_at(0, 0, 3, 1), "slice_response").await;
+    assert_eq!(natural["cellsByRow"], json!([["a1"], ["a2"], ["a10"]]));
+}
+
+#[tokio::test]
+async fn a_filter_bumps_the_generation_and_a_slice_expecting_the_old_one_is_flagged() {
+    let rows: &[&[&str]] = &[&["1"], &["8"], &["3"], &["9"]];
+    let server = start(config(&[]), inline(rows)).await;
+    let mut client = server.connect().await;
+    let before = client.request(slice_at(0, 0, 4, 1), "slice_response").await;
+    let old = before["viewGeneration"].as_u64().unwrap();
+    assert_eq!(before["viewChanged"], false);
+
+    let view = client.request(filter(0, "gt", "5"), "view_response").await;
+    let new = view["viewGeneration"].as_u64().unwrap();
+    assert!(new > old, "{} after {}", new, old);
+
+    let stale = with(slice_at(0, 0, 4, 1), json!({"viewGeneration": old}));
+    let flagged = client.request(stale.clone(), "slice_response").await;
+    assert_eq!((&flagged["viewChanged"], &flagged["viewGeneration"]), (&json!(true), &json!(new)));
+    assert_eq!(flagged["cellsByRow"], json!([["8"], ["9"]]));
+    let rejected = with(stale, json!({"onViewChange": "reject"}));
+    let rejected = client.request(rejected, "view_changed").await;
+    assert_eq!((&rejected["viewGeneration"], &rejected["visibleRows"]), (&json!(new), &json!(2)));
+    let current = with(slice_at(0, 0, 4, 1), json!({"viewGeneration": new}));
+    assert_eq!(client.request(current, "slice_response").await["viewChanged"], false);
+}
@@ -0,0 +1,136 @@
+//! Excel workbooks opened the way `--xlsx` opens them.
+
+mod common;
+
+use common::*;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde_json::json;
+use sheets_ws_server::source::DataSource;
+use sheets_ws_server::xlsx::XlsxSource;
+use std::io::Write;
+
+/// A zip archive of `parts`, deflating every other one so both storage
+/// methods are read.
+fn zip(parts: &[(&str, &str)]) -> Vec<u8> {
+    let (mut out, mut directory) = (Vec::new(), Vec::new());
+    for (i, (name, text)) in parts.iter().enumerate() {
+        let mut crc = flate2::Crc::new();
+        crc.update(text.as_bytes());
+        let (method, data) = match i % 2 {
+            0 => (0u16, text.as_bytes().to_vec()),
+            _ => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(text.as_bytes()).unwrap();
+                (8, encoder.finish().unwrap())
+            }
+        };
+        let offset = out.len() as u32;
+        let sizes = [crc.sum(), data.len() as u32, text.len() as u32];
+        let header = |signature: u32, lead: &[u8]| {
+            let mut header = signature.to_le_bytes().to_vec();
+            header.extend(lead);
+            header.extend(method.to_le_bytes());
+            header.extend([0, 0, 0, 0]);
+            sizes.iter().for_each(|size| header.extend(size.to_le_bytes()));
+            header.extend((name.len() as u16).to_le_bytes());
+            header.extend([0, 0]);
+            header
+        };
+        out.extend(header(0x0403_4b50, &[20, 0, 0, 0]));
+        out.extend(name.as_bytes());
+        out.extend(&data);
+        directory.extend(header(0x0201_4b50, &[20, 0, 20, 0, 0, 0]));
+        directory.extend([0; 10]);
+        directory.extend(offset.to_le_bytes());
+        directory.extend(name.as_bytes());
+    }
+    let (start, size) = (out.len() as u32, directory.len() as u32);
+    out.extend(directory);
+    out.extend(0x0605_4b50u32.to_le_bytes());
+    out.extend([0; 4]);
+    out.extend([(parts.len() as u16).to_le_bytes(), (parts.len() as u16).to_le_bytes()].concat());
+    out.extend(size.to_le_bytes());
+    out.extend(start.to_le_bytes());
+    out.extend([0, 0]);
+    out
+}
+
+/// A workbook with a `People` sheet of shared strings, numbers and a
+/// boolean, and a `Dates` sheet of a date-formatted number and an inline
+/// string, after a skipped cell.
+fn workbook() -> Vec<u8> {
+    let workbook = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<workbook xmlns="http://schemas.openxmlformats.org/spreadsheetml/2006/main"
+  xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships">
+  <sheets>
+    <sheet name="People" sheetId="1" r:id="rId1"/>
+    <sheet name="Dates" sheetId="2" r:id="rId2"/>
+  </sheets>
+</workbook>"#;
+    let rels = r#"<Relationships
+  xmlns="http://schemas.openxmlformats.org/package/2006/relationships">
+  <Relationship Id="rId2" Type="worksheet" Target="worksheets/sheet2.xml"/>
+  <Relationship Id="rId1" Type="worksheet" Target="/xl/worksheets/sheet1.xml"/>
+</Relationships>"#;
+    let shared = r#"<sst count="4" uniqueCount="4"><si><t>name</t></si><si><t>age</t></si>
+  <si><r><t>Ada </t></r><r><t>&amp; co</t></r></si><si><t>when</t></si></sst>"#;
+    let styles = r#"<styleSheet><numFmts count="1">
+  <numFmt numFmtId="164" formatCode="yyyy\-mm\-dd"/></numFmts>
+  <cellXfs count="2"><xf numFmtId="0"/><xf numFmtId="164"/></cellXfs></styleSheet>"#;
+    let people = r#"<worksheet><sheetData>
+  <row r="1"><c r="A1" t="s"><v>0</v></c><c r="B1" t="s"><v>1</v></c>
+    <c r="C1" t="inlineStr"><is><t>member</t></is></c></row>
+  <row r="2"><c r="A2" t="s"><v>2</v></c><c r="B2"><v>36.5</v></c><c r="C2" t="b"><v>1</v></c></row>
+</sheetData></worksheet>"#;
+    let dates = r#"<worksheet><sheetData>
+  <row r="1"><c r="A1" t="s"><v>3</v></c><c r="C1" t="inlineStr"><is><t>note</t></is></c></row>
+  <row r="2"><c r="A2" s="1"><v>45352</v></c><c r="C2" t="inlineStr"><is><t>leap</t></is></c></row>
+</sheetData></worksheet>"#;
+    zip(&[
+        ("xl/workbook.xml", workbook),
+        ("xl/_rels/workbook.xml.rels", rels),
+        ("xl/sharedStrings.xml", shared),
+        ("xl/styles.xml", styles),
+        ("xl/worksheets/sheet1.xml", people),
+        ("xl/worksheets/sheet2.xml", dates),
+    ])
+}
+
+#[test]
+fn each_sheet_reads_as_its_own_table() {
+    let bytes = workbook();
+    let people = XlsxSource::from_bytes(&bytes, None).unwrap();
+    assert_eq!(people.sheet_names, ["People", "Dates"]);
+    assert_eq!(people.sheet, "People");
+    assert_eq!(people.headers, ["name", "age", "member"]);
+    assert_eq!((people.row_count(), people.col_count()), (1, 3));
+    assert_eq!(people.cell(0, 0).as_deref(), Some("Ada & co"));
+    assert_eq!(people.cell(0, 1).as_deref(), Some("36.5"));
+    assert_eq!(people.cell(0, 2).as_deref(), Some("true"));
+
+    let dates = XlsxSource::from_bytes(&bytes, Some("Dates")).unwrap();
+    assert_eq!(dates.headers, ["when", "", "note"]);
+    assert_eq!(dates.cell(0, 0).as_deref(), Some("2024-03-01"));
+    assert_eq!(dates.cell(0, 1).as_deref(), Some(""));
+    assert_eq!(dates.cell(0, 2).as_deref(), Some("leap"));
+
+    let missing = XlsxSource::from_bytes(&bytes, Some("Totals")).err().unwrap();
+    assert_eq!(missing, r#"no sheet named "Totals"; the workbook has People, Dates"#);
+    let damaged = XlsxSource::from_bytes(&bytes[..bytes.len() / 2], None).err().unwrap();
+    assert_eq!(damaged, "not an xlsx file (no zip directory)");
+}
+
+#[tokio::test]
+async fn metadata_lists_the_sheets_of_the_workbook() {
+    let source = XlsxSource::from_bytes(&workbook(), Some("Dates")).unwrap();
+    let mut config = config(&[]);
+    (config.headers, config.sheets) = (source.headers.clone(), source.sheet_names.clone());
+    let server = start(config, Box::new(source)).await;
+    let mut client = server.connect().await;
+    let metadata = client.request(json!({"type": "metadata_request"}), "metadata_response").await;
+    assert_eq!(metadata["sheets"], json!(["People", "Dates"]));
+    assert_eq!(metadata["colNames"], json!(["when", "", "note"]));
+    let slice = client.request(slice_at(0, 0, 1, 3), "slice_response").await;
+    assert_eq!(slice["cellsByRow"], json!([["2024-03-01", "", "leap"]]));
+}
@@ -146,6 +146,20 @@ export default function Page() {
         if (msg.type === "slice_response") {
           latestSliceRef.current = msg;
           redraw();
+          return;
+        }
+        if (msg.type === "error" && msg.code === "scroll_out_of_range") {
+          // Our offset was nonsense (e.g. a wrapped negative); start over at the top.
+          scroller.scrollTop = 0;
+          scroller.scrollLeft = 0;
+          lastSentKey = "";
+          requestSlice();
+          return;
+        }
+        if (msg.type === "reset") {
+          // The server dropped all edits and filters; fetch the window again.
+          lastSentKey = "";
+          requestSlice();
         }
       };
 
@@ -300,7 +314,7 @@ export default function Page() {
             rowCount: number;
             startCol: number;
             colCount: number;
-            cellsByRow: string[][];
+            cellsByRow: ((string | null)[] | null)[];
           }
         | null;
 
@@ -5,7 +5,10 @@ type SliceResponse = {
   startCol: number;
   colCount: number;
   colLetters: string[];
-  cellsByRow: string[][];
+  // Sparse slices send null for blank cells and for fully blank rows.
+  cellsByRow: ((string | null)[] | null)[];
+  // Physical row id per returned row; differs from position under a filter.
+  rowIds?: number[];
 };
 
 export function drawGridAndCells(
@@ -104,7 +107,7 @@ export function drawGridAndCells(
   for (let r = 0; r < msg.rowCount; r++) {
     const xCenter = headerColWidth / 2;
     const yCenter = headerRowHeight + offsetY + r * rh + rh / 2;
-    const rowNumber = (msg.startRow + r + 1).toString();
+    const rowNumber = ((msg.rowIds?.[r] ?? msg.startRow + r) + 1).toString();
     if (yCenter + rh / 2 < headerRowHeight || yCenter - rh / 2 > height) continue;
     ctx.fillText(rowNumber, xCenter, yCenter);
   }
@@ -116,6 +119,7 @@ export function drawGridAndCells(
   const rows = Math.min(msg.rowCount, msg.cellsByRow.length);
   for (let r = 0; r < rows; r++) {
     const row = msg.cellsByRow[r];
+    if (!row) continue;
     const cols = Math.min(msg.colCount, row.length);
     const y = headerRowHeight + offsetY + (r + 1) * rh - baselineOffset;
     for (let c = 0; c < cols; c++) {